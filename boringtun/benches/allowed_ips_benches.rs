@@ -0,0 +1,30 @@
+use boringtun::device::allowed_ips::AllowedIps;
+use criterion::{black_box, Criterion};
+use std::net::IpAddr;
+
+fn build_allowed_ips() -> AllowedIps<usize> {
+    let mut map = AllowedIps::new();
+    for i in 0..1024usize {
+        map.insert(IpAddr::from([10, (i >> 8) as u8, i as u8, 0]), 24, i);
+    }
+    map
+}
+
+pub fn bench_allowed_ips_find(c: &mut Criterion) {
+    let mut group = c.benchmark_group("allowed_ips_find");
+    let map = build_allowed_ips();
+    let octets = [10, 2, 17, 42];
+
+    group.bench_function("find", |b| {
+        b.iter(|| map.find(IpAddr::from(black_box(octets))));
+    });
+
+    group.bench_function("find_raw_v4", |b| {
+        b.iter(|| map.find_raw_v4(black_box(octets)));
+    });
+
+    group.finish();
+}
+
+criterion::criterion_group!(allowed_ips_benches, bench_allowed_ips_find);
+criterion::criterion_main!(allowed_ips_benches);
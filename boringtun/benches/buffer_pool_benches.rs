@@ -0,0 +1,32 @@
+use boringtun::device::buffer_pool::BufferPool;
+use criterion::{black_box, BenchmarkId, Criterion, Throughput};
+
+pub fn bench_buffer_pool(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet_buffer");
+
+    for size in [128, 1420] {
+        group.throughput(Throughput::Elements(1));
+
+        group.bench_with_input(BenchmarkId::new("allocate", size), &size, |b, &size| {
+            let packet = vec![0u8; size];
+            b.iter(|| {
+                let buf = black_box(packet.to_vec());
+                drop(buf);
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("pool", size), &size, |b, &size| {
+            let pool = BufferPool::new(1420, 256);
+            let packet = vec![0u8; size];
+            b.iter(|| {
+                let buf = black_box(pool.get_copy(&packet));
+                pool.put(buf);
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion::criterion_group!(buffer_pool_benches, bench_buffer_pool);
+criterion::criterion_main!(buffer_pool_benches);
@@ -0,0 +1,221 @@
+use boringtun::device::peer::{AllowedIP, Peer};
+use boringtun::device::transport::DirectUdp;
+use boringtun::device::MakeExternalBoringtunNoop;
+use boringtun::noise::handshake::parse_handshake_anon;
+use boringtun::noise::rate_limiter::RateLimiter;
+use boringtun::noise::{Packet, RawSession, Tunn, TunnResult};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use criterion::{black_box, BatchSize, Criterion};
+use parking_lot::Mutex;
+use rand_core::OsRng;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn default_route_peer() -> Peer {
+    let peer_key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+    let tunnel = Tunn::new(
+        StaticSecret::random_from_rng(OsRng),
+        peer_key,
+        None,
+        None,
+        0,
+        None,
+    )
+    .unwrap();
+    let mut allowed_ips: Vec<AllowedIP> = (0..256u32)
+        .map(|i| AllowedIP {
+            addr: IpAddr::from([10, i as u8, 0, 0]),
+            cidr: 16,
+        })
+        .collect();
+    allowed_ips.push("0.0.0.0/0".parse().unwrap());
+    Peer::new(
+        tunnel,
+        0,
+        None,
+        &allowed_ips,
+        None,
+        Arc::new(MakeExternalBoringtunNoop),
+        Arc::new(DirectUdp),
+    )
+}
+
+pub fn bench_source_check(c: &mut Criterion) {
+    let mut group = c.benchmark_group("source_check");
+    let peer = default_route_peer();
+    let src = IpAddr::from([192, 0, 2, 1]);
+
+    group.bench_function("lookup", |b| {
+        peer.set_sole_peer(false);
+        b.iter(|| peer.is_allowed_ip(black_box(src)));
+    });
+
+    group.bench_function("sole_peer", |b| {
+        peer.set_sole_peer(true);
+        b.iter(|| peer.is_allowed_ip(black_box(src)));
+    });
+
+    group.finish();
+}
+
+/// An initiation from a known source as the listening sockets handle it, with the anonymous
+/// parse finding the peer first or handed to the tunnel of the peer cached for the source
+pub fn bench_init_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("init_lookup");
+    let secrets = [(); 2].map(|_| StaticSecret::random_from_rng(OsRng));
+    let publics = [0, 1].map(|i| PublicKey::from(&secrets[i]));
+    let mut initiator = Tunn::new(secrets[0].clone(), publics[1], None, None, 1, None).unwrap();
+    // Far above the initiations of the bench, so none is answered with a cookie
+    let limiter = Arc::new(RateLimiter::new(&publics[1], u64::MAX));
+    let mut responder =
+        Tunn::new(secrets[1].clone(), publics[0], None, None, 2, Some(limiter)).unwrap();
+    let mut init = move || {
+        let mut dst = vec![0u8; 148];
+        match initiator.format_handshake_initiation(&mut dst, true) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        }
+    };
+    let mut dst = vec![0u8; 2048];
+
+    group.bench_function("anonymous", |b| {
+        b.iter_batched(
+            &mut init,
+            |init| {
+                if let Ok(Packet::HandshakeInit(p)) = Tunn::parse_incoming_packet(&init) {
+                    black_box(parse_handshake_anon(&secrets[1], &publics[1], &p).is_ok());
+                }
+                black_box(responder.decapsulate(None, &init, &mut dst));
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.bench_function("source_cache", |b| {
+        b.iter_batched(
+            &mut init,
+            |init| {
+                black_box(responder.decapsulate(None, &init, &mut dst));
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.finish();
+}
+
+/// Both ends of one session
+fn session_pair() -> (Tunn, Tunn) {
+    let secrets = [(); 2].map(|_| StaticSecret::random_from_rng(OsRng));
+    let keys = [[1u8; 32], [2u8; 32]];
+    let indices = [1 << 8, 2 << 8];
+    let raw = |me: usize| RawSession {
+        static_private: secrets[me].clone(),
+        peer_static_public: PublicKey::from(&secrets[1 - me]),
+        local_index: indices[me],
+        remote_index: indices[1 - me],
+        send_key: keys[me],
+        recv_key: keys[1 - me],
+        send_nonce: 0,
+        recv_nonce: 0,
+        replay_bitmap: [0; 16],
+        is_initiator: me == 0,
+    };
+    (
+        Tunn::from_raw_session(raw(0)).unwrap(),
+        Tunn::from_raw_session(raw(1)).unwrap(),
+    )
+}
+
+/// `n` data packets from `sender`, each carrying an IPv4 packet of `len` bytes
+fn data_packets(sender: &mut Tunn, n: usize, len: usize) -> Vec<Vec<u8>> {
+    let mut inner = vec![0u8; len];
+    inner[0] = 0x45;
+    inner[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    inner[12..16].copy_from_slice(&[10, 0, 0, 2]);
+    let mut dst = vec![0u8; len + 64];
+    (0..n)
+        .map(|_| match sender.encapsulate(&inner, &mut dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => panic!("Expected a data packet"),
+        })
+        .collect()
+}
+
+/// Time `threads` threads decapsulating `iters` packets each with `decapsulate`, the packets
+/// dealt round robin so the threads stay within the replay window of each other
+fn parallel_decapsulate(
+    sender: &mut Tunn,
+    threads: usize,
+    iters: u64,
+    decapsulate: &(dyn Fn(&[u8], &mut [u8]) + Sync),
+) -> Duration {
+    const BATCH: u64 = 512;
+    let mut elapsed = Duration::ZERO;
+    let mut left = iters;
+    while left > 0 {
+        let batch = left.min(BATCH) as usize;
+        let packets = data_packets(sender, batch * threads, 1420);
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for t in 0..threads {
+                let packets = &packets;
+                scope.spawn(move || {
+                    let mut dst = vec![0u8; 2048];
+                    for data in packets.iter().skip(t).step_by(threads) {
+                        decapsulate(data, &mut dst);
+                    }
+                });
+            }
+        });
+        elapsed += start.elapsed();
+        left -= batch as u64;
+    }
+    elapsed
+}
+
+/// Several threads receiving from one peer, decrypting under the lock on its tunnel or through
+/// `Peer::decapsulate`, which takes the lock only to account for the decrypted packets
+pub fn bench_parallel_decapsulate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parallel_decapsulate");
+    let threads = 4;
+
+    group.bench_function("tunnel_lock", |b| {
+        let (mut sender, receiver) = session_pair();
+        let receiver = Mutex::new(receiver);
+        b.iter_custom(|iters| {
+            parallel_decapsulate(&mut sender, threads, iters, &|data, dst| {
+                black_box(receiver.lock().decapsulate(None, data, dst));
+            })
+        });
+    });
+
+    group.bench_function("data_path", |b| {
+        let (mut sender, receiver) = session_pair();
+        let peer = Peer::new(
+            receiver,
+            2,
+            None,
+            &[],
+            None,
+            Arc::new(MakeExternalBoringtunNoop),
+            Arc::new(DirectUdp),
+        );
+        b.iter_custom(|iters| {
+            parallel_decapsulate(&mut sender, threads, iters, &|data, dst| {
+                black_box(peer.decapsulate(None, data, dst));
+            })
+        });
+    });
+
+    group.finish();
+}
+
+criterion::criterion_group!(
+    peer_benches,
+    bench_source_check,
+    bench_init_lookup,
+    bench_parallel_decapsulate
+);
+criterion::criterion_main!(peer_benches);
@@ -0,0 +1,100 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use crate::device::Error;
+
+/// Which address family to use when an endpoint resolves to both, and which families to listen on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFamilyPref {
+    V4Only,
+    V6Only,
+    PreferV4,
+    /// Matches the `getaddrinfo` default ordering
+    #[default]
+    PreferV6,
+}
+
+impl AddressFamilyPref {
+    pub fn allows_v4(self) -> bool {
+        self != AddressFamilyPref::V6Only
+    }
+
+    pub fn allows_v6(self) -> bool {
+        self != AddressFamilyPref::V4Only
+    }
+
+    /// Pick the address to use among resolved ones. Within a family the resolver order is kept.
+    pub fn select(self, addrs: &[SocketAddr]) -> Option<SocketAddr> {
+        let v4 = addrs.iter().find(|addr| addr.is_ipv4());
+        let v6 = addrs.iter().find(|addr| addr.is_ipv6());
+        match self {
+            AddressFamilyPref::V4Only => v4,
+            AddressFamilyPref::V6Only => v6,
+            AddressFamilyPref::PreferV4 => v4.or(v6),
+            AddressFamilyPref::PreferV6 => v6.or(v4),
+        }
+        .copied()
+    }
+
+    /// Resolve a `host:port` endpoint with the system resolver
+    pub fn resolve(self, endpoint: &str) -> Result<SocketAddr, Error> {
+        self.resolve_with(endpoint, |endpoint| {
+            endpoint.to_socket_addrs().map(Iterator::collect)
+        })
+    }
+
+    /// Resolve a `host:port` endpoint with the given resolver
+    pub fn resolve_with<F>(self, endpoint: &str, resolve: F) -> Result<SocketAddr, Error>
+    where
+        F: FnOnce(&str) -> io::Result<Vec<SocketAddr>>,
+    {
+        let addrs = resolve(endpoint)?;
+        self.select(&addrs).ok_or_else(|| {
+            Error::InvalidConfig(format!("{} has no address allowed by {:?}", endpoint, self))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_both(_: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![
+            "192.0.2.1:51820".parse().unwrap(),
+            "[2001:db8::1]:51820".parse().unwrap(),
+            "192.0.2.2:51820".parse().unwrap(),
+        ])
+    }
+
+    #[test]
+    fn test_resolve_with_preference() {
+        let v4: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let endpoint = "peer.example:51820";
+
+        let resolve = |pref: AddressFamilyPref| pref.resolve_with(endpoint, resolve_both).unwrap();
+        assert_eq!(resolve(AddressFamilyPref::default()), v6);
+        assert_eq!(resolve(AddressFamilyPref::PreferV6), v6);
+        assert_eq!(resolve(AddressFamilyPref::PreferV4), v4);
+        assert_eq!(resolve(AddressFamilyPref::V4Only), v4);
+        assert_eq!(resolve(AddressFamilyPref::V6Only), v6);
+    }
+
+    #[test]
+    fn test_resolve_missing_family() {
+        let resolve_v4 = |_: &str| Ok(vec!["192.0.2.1:51820".parse().unwrap()]);
+        assert!(AddressFamilyPref::V6Only
+            .resolve_with("peer.example:51820", resolve_v4)
+            .is_err());
+        assert_eq!(
+            AddressFamilyPref::PreferV6
+                .resolve_with("peer.example:51820", resolve_v4)
+                .unwrap(),
+            "192.0.2.1:51820".parse::<SocketAddr>().unwrap()
+        );
+    }
+}
@@ -8,7 +8,7 @@ use ip_network_table::IpNetworkTable;
 
 use std::collections::VecDeque;
 use std::iter::FromIterator;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// A trie of IP/cidr addresses
 #[derive(Default)]
@@ -52,10 +52,54 @@ impl<D> AllowedIps<D> {
         self.ips.longest_match(key).map(|(_net, data)| data)
     }
 
+    /// Like `find`, for callers that already know the address is IPv4
+    pub fn find_raw_v4(&self, octets: [u8; 4]) -> Option<&D> {
+        self.ips
+            .longest_match_ipv4(Ipv4Addr::from(octets))
+            .map(|(_net, data)| data)
+    }
+
+    /// Like `find`, for callers that already know the address is IPv6
+    pub fn find_raw_v6(&self, octets: [u8; 16]) -> Option<&D> {
+        self.ips
+            .longest_match_ipv6(Ipv6Addr::from(octets))
+            .map(|(_net, data)| data)
+    }
+
+    /// Number of networks in the trie
+    pub fn len(&self) -> usize {
+        let (v4, v6) = self.ips.len();
+        v4 + v6
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn remove(&mut self, predicate: &dyn Fn(&D) -> bool) {
         self.ips.retain(|_, v| !predicate(v));
     }
 
+    /// Check if an entry equal to or less specific than the given network already covers it
+    pub fn covers(&self, key: IpAddr, cidr: u32) -> bool {
+        self.ips.iter().any(|(net, _)| {
+            u32::from(net.netmask()) <= cidr
+                && IpNetwork::new_truncate(key, net.netmask()).ok() == Some(net)
+        })
+    }
+
+    /// Remove all entries more specific than the given network, that are covered by it
+    pub fn remove_covered(&mut self, key: IpAddr, cidr: u32) {
+        let network = match IpNetwork::new_truncate(key, cidr as u8) {
+            Ok(network) => network,
+            Err(_) => return,
+        };
+        self.ips.retain(|net, _| {
+            !(u32::from(net.netmask()) > cidr
+                && IpNetwork::new_truncate(net.network_address(), cidr as u8).ok() == Some(network))
+        });
+    }
+
     pub fn iter(&self) -> Iter<D> {
         Iter(
             self.ips
@@ -112,6 +156,32 @@ mod tests {
         assert_eq!(map.find(IpAddr::from([45, 25, 15, 1])), Some(&'6'));
     }
 
+    #[test]
+    fn test_allowed_ips_find_raw() {
+        let map = build_allowed_ips();
+        let v4 = [
+            [127, 0, 0, 1],
+            [127, 0, 255, 255],
+            [127, 1, 255, 255],
+            [127, 1, 15, 255],
+            [255, 1, 15, 2],
+            [45, 25, 15, 3],
+            [20, 0, 0, 100],
+        ];
+        for octets in v4 {
+            assert_eq!(map.find_raw_v4(octets), map.find(IpAddr::from(octets)));
+        }
+
+        let v6 = [
+            Ipv6Addr::from([553, 0, 0, 1, 0, 0, 0, 0]),
+            Ipv6Addr::from([553, 0, 0, 1, 0, 0, 0, 1]),
+        ];
+        for addr in v6 {
+            assert_eq!(map.find_raw_v6(addr.octets()), map.find(IpAddr::from(addr)));
+        }
+        assert_eq!(map.find_raw_v6(v6[0].octets()), Some(&'7'));
+    }
+
     #[test]
     fn test_allowed_ips_remove() {
         let mut map = build_allowed_ips();
@@ -137,6 +207,42 @@ mod tests {
         assert_eq!(map_iter.next(), None);
     }
 
+    #[test]
+    fn test_allowed_ips_covers() {
+        let map = build_allowed_ips();
+        assert!(map.covers(IpAddr::from([127, 0, 1, 0]), 24));
+        assert!(map.covers(IpAddr::from([127, 0, 0, 0]), 16));
+        assert!(!map.covers(IpAddr::from([127, 0, 0, 0]), 8));
+        assert!(!map.covers(IpAddr::from([128, 0, 0, 0]), 24));
+        assert!(map.covers(IpAddr::from([553, 0, 0, 1, 0, 0, 0, 0]), 128));
+        assert!(!map.covers(IpAddr::from([553, 0, 0, 1, 0, 0, 0, 0]), 64));
+    }
+
+    #[test]
+    fn test_allowed_ips_remove_covered() {
+        let mut map = build_allowed_ips();
+        map.remove_covered(IpAddr::from([127, 0, 0, 0]), 8);
+
+        let mut map_iter = map.iter();
+        assert_eq!(
+            map_iter.next(),
+            Some((&'6', IpAddr::from([45, 25, 15, 0]), 30))
+        );
+        assert_eq!(
+            map_iter.next(),
+            Some((&'5', IpAddr::from([60, 25, 15, 1]), 32))
+        );
+        assert_eq!(
+            map_iter.next(),
+            Some((&'4', IpAddr::from([255, 1, 15, 0]), 24))
+        );
+        assert_eq!(
+            map_iter.next(),
+            Some((&'7', IpAddr::from([553, 0, 0, 1, 0, 0, 0, 0]), 128))
+        );
+        assert_eq!(map_iter.next(), None);
+    }
+
     #[test]
     fn test_allowed_ips_iter() {
         let map = build_allowed_ips();
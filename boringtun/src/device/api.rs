@@ -3,17 +3,21 @@
 
 use super::dev_lock::LockReadGuard;
 use super::drop_privileges::get_saved_ids;
+use super::peer::Peer;
 use super::{AllowedIP, Device, Error, SocketAddr};
 use crate::device::Action;
+use crate::noise::parse_public_key;
 use crate::serialization::KeyBytes;
 use crate::x25519;
 use hex::encode as encode_hex;
 use libc::*;
+use std::collections::HashMap;
 use std::fs::{create_dir, remove_file};
 use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 const SOCK_DIR: &str = "/var/run/wireguard/";
 
@@ -38,7 +42,15 @@ impl Device {
     /// Register the api handler for this Device. The api handler receives stream connections on a Unix socket
     /// with a known path: /var/run/wireguard/{tun_name}.sock.
     pub fn register_api_handler(&mut self) -> Result<(), Error> {
-        let path = format!("{}/{}.sock", SOCK_DIR, self.iface.name()?);
+        let name = match &self.iface {
+            Some(iface) => iface.name()?,
+            None => {
+                return Err(Error::InvalidConfig(
+                    "the uapi socket is named after the tun interface".to_owned(),
+                ))
+            }
+        };
+        let path = format!("{}/{}.sock", SOCK_DIR, name);
 
         create_sock_dir();
 
@@ -119,7 +131,7 @@ impl Device {
                 }
 
                 // Periodically read the mtu of the interface in case it changes
-                if let Ok(mtu) = d.iface.mtu() {
+                if let Some(Ok(mtu)) = d.iface.as_ref().map(|iface| iface.mtu()) {
                     d.mtu.store(mtu, Ordering::Relaxed);
                 }
 
@@ -171,6 +183,15 @@ fn api_get<W: Write>(writer: &mut BufWriter<W>, d: &Device) -> i32 {
         writeln!(writer, "private_key={}", encode_hex(k.0.to_bytes()));
     }
 
+    // Not part of the protocol, and not `public_key`, which starts a peer
+    if let Some(public_key) = d.public_key() {
+        writeln!(
+            writer,
+            "own_public_key={}",
+            encode_hex(public_key.as_bytes())
+        );
+    }
+
     if d.listen_port != 0 {
         writeln!(writer, "listen_port={}", d.listen_port);
     }
@@ -179,68 +200,102 @@ fn api_get<W: Write>(writer: &mut BufWriter<W>, d: &Device) -> i32 {
         writeln!(writer, "fwmark={}", fwmark);
     }
 
-    for (k, peer) in d.peers.iter() {
-        let (keepalive, last_handshake_time, stats) = {
-            let tun = peer.tunnel.lock();
-            (
-                tun.persistent_keepalive(),
-                tun.last_handshake_time(),
-                tun.stats(),
-            )
-        };
-
-        writeln!(writer, "public_key={}", encode_hex(k.as_bytes()));
+    // Not part of the protocol, `wg` skips keys it doesn't know
+    if d.rxq_overflow.enabled.load(Ordering::Relaxed) {
+        let (v4, v6) = d.rxq_ovfl_drops();
+        writeln!(writer, "rxq_ovfl_drops_v4={}", v4);
+        writeln!(writer, "rxq_ovfl_drops_v6={}", v6);
+    }
 
-        if let Some(ref key) = peer.preshared_key() {
-            writeln!(writer, "preshared_key={}", encode_hex(key));
-        }
+    for (k, peer) in sorted_peers(&d.peers) {
+        api_get_peer(writer, k, peer);
+    }
+    0
+}
 
-        if let Some(keepalive) = keepalive {
-            writeln!(writer, "persistent_keepalive_interval={}", keepalive);
-        }
+/// Peers ordered by public key, so dumps of the same peer set are identical
+fn sorted_peers(
+    peers: &HashMap<x25519::PublicKey, Arc<Peer>>,
+) -> Vec<(&x25519::PublicKey, &Arc<Peer>)> {
+    let mut sorted: Vec<_> = peers.iter().collect();
+    sorted.sort_unstable_by_key(|(k, _)| k.as_bytes());
+    sorted
+}
 
-        if let Some(ref addr) = peer.endpoint().addr {
-            writeln!(writer, "endpoint={}", addr);
-        }
+#[allow(unused_must_use)]
+fn api_get_peer<W: Write>(writer: &mut BufWriter<W>, k: &x25519::PublicKey, peer: &Peer) {
+    let (keepalive, last_handshake_time, stats) = {
+        let tun = peer.tunnel.lock();
+        (
+            tun.persistent_keepalive(),
+            tun.last_handshake_time(),
+            tun.stats(),
+        )
+    };
+
+    writeln!(writer, "public_key={}", encode_hex(k.as_bytes()));
+
+    if let Some(ref key) = peer.preshared_key() {
+        writeln!(writer, "preshared_key={}", encode_hex(key));
+    }
 
-        for AllowedIP { addr, cidr } in peer.allowed_ips() {
-            writeln!(writer, "allowed_ip={}/{}", addr, cidr);
-        }
+    if let Some(keepalive) = keepalive {
+        writeln!(writer, "persistent_keepalive_interval={}", keepalive);
+    }
 
-        if let Some(last_handshake_time) = last_handshake_time {
-            writeln!(
-                writer,
-                "last_handshake_time_sec={}",
-                last_handshake_time.as_secs()
-            );
-            writeln!(
-                writer,
-                "last_handshake_time_nsec={}",
-                last_handshake_time.subsec_nanos()
-            );
-        }
+    if let Some(ref addr) = peer.endpoint().addr {
+        writeln!(writer, "endpoint={}", addr);
+    }
 
-        let (_, tx_bytes, rx_bytes, ..) = stats;
+    for AllowedIP { addr, cidr } in peer.allowed_ips() {
+        writeln!(writer, "allowed_ip={}/{}", addr, cidr);
+    }
 
-        writeln!(writer, "rx_bytes={}", rx_bytes);
-        writeln!(writer, "tx_bytes={}", tx_bytes);
+    if let Some(last_handshake_time) = last_handshake_time {
+        writeln!(
+            writer,
+            "last_handshake_time_sec={}",
+            last_handshake_time.as_secs()
+        );
+        writeln!(
+            writer,
+            "last_handshake_time_nsec={}",
+            last_handshake_time.subsec_nanos()
+        );
     }
-    0
+
+    writeln!(writer, "rx_bytes={}", stats.rx_bytes);
+    writeln!(writer, "tx_bytes={}", stats.tx_bytes);
+    writeln!(writer, "send_queue_len={}", peer.send_queue_len());
+    writeln!(writer, "decrypt_failures={}", peer.decrypt_failures());
 }
 
 fn api_set<R: Read>(reader: &mut BufReader<R>, d: &mut LockReadGuard<Device>) -> i32 {
+    // Read the whole request and resolve its hostname endpoints first, so the device isn't held
+    // locked for writing while the resolver takes its time
+    let mut request = vec![];
+    let mut cmd = String::new();
+    while reader.read_line(&mut cmd).is_ok() {
+        cmd.pop(); // remove newline if any
+        if cmd.is_empty() {
+            break;
+        }
+        request.push(std::mem::take(&mut cmd));
+    }
+    let resolved: HashMap<&str, SocketAddr> = request
+        .iter()
+        .filter_map(|cmd| cmd.strip_prefix("endpoint="))
+        .filter(|val| val.parse::<SocketAddr>().is_err())
+        .filter_map(|val| Some((val, d.resolve_endpoint(val).ok()?)))
+        .collect();
+
     d.try_writeable(
         |device| device.trigger_yield(),
         |device| {
             device.cancel_yield();
 
-            let mut cmd = String::new();
-
-            while reader.read_line(&mut cmd).is_ok() {
-                cmd.pop(); // remove newline if any
-                if cmd.is_empty() {
-                    return 0; // Done
-                }
+            let mut lines = request.iter().map(String::as_str);
+            while let Some(cmd) = lines.next() {
                 {
                     let parsed_cmd: Vec<&str> = cmd.split('=').collect();
                     if parsed_cmd.len() != 2 {
@@ -252,12 +307,17 @@ fn api_set<R: Read>(reader: &mut BufReader<R>, d: &mut LockReadGuard<Device>) ->
                     match key {
                         "private_key" => match val.parse::<KeyBytes>() {
                             Ok(key_bytes) => {
-                                device.set_key(x25519::StaticSecret::from(key_bytes.0))
+                                if device
+                                    .set_key(x25519::StaticSecret::from(key_bytes.0))
+                                    .is_err()
+                                {
+                                    return EEXIST;
+                                }
                             }
                             Err(_) => return EINVAL,
                         },
                         "listen_port" => match val.parse::<u16>() {
-                            Ok(port) => match device.open_listen_socket(port) {
+                            Ok(port) => match device.bind_dual(port) {
                                 Ok(()) => {}
                                 Err(_) => return EADDRINUSE,
                             },
@@ -280,21 +340,19 @@ fn api_set<R: Read>(reader: &mut BufReader<R>, d: &mut LockReadGuard<Device>) ->
                             Ok(false) => {}
                             Err(_) => return EINVAL,
                         },
-                        "public_key" => match val.parse::<KeyBytes>() {
+                        "public_key" => match parse_public_key(val) {
                             // Indicates a new peer section
-                            Ok(key_bytes) => {
-                                return api_set_peer(
-                                    reader,
-                                    device,
-                                    x25519::PublicKey::from(key_bytes.0),
-                                )
+                            Ok(pub_key) => {
+                                return api_set_peer(&mut lines, &resolved, device, pub_key)
+                            }
+                            Err(err) => {
+                                tracing::warn!(message = "Invalid peer public key", error = %err);
+                                return EINVAL;
                             }
-                            Err(_) => return EINVAL,
                         },
                         _ => return EINVAL,
                     }
                 }
-                cmd.clear();
             }
 
             0
@@ -303,24 +361,26 @@ fn api_set<R: Read>(reader: &mut BufReader<R>, d: &mut LockReadGuard<Device>) ->
     .unwrap_or(EIO)
 }
 
-fn api_set_peer<R: Read>(
-    reader: &mut BufReader<R>,
+/// Apply the peer section of `pub_key`, the `lines` up to the end of the request, taking the
+/// hostname endpoints from the addresses `resolved` for them
+fn api_set_peer<'a>(
+    lines: &mut impl Iterator<Item = &'a str>,
+    resolved: &HashMap<&str, SocketAddr>,
     d: &mut Device,
     pub_key: x25519::PublicKey,
 ) -> i32 {
-    let mut cmd = String::new();
-
     let mut update_only = false;
     let mut remove = false;
     let mut replace_ips = false;
     let mut endpoint = None;
+    // Set for an endpoint line, to the hostname it gave if any
+    let mut endpoint_hostname: Option<Option<String>> = None;
     let mut keepalive = None;
     let mut public_key = pub_key;
     let mut preshared_key = None;
     let mut allowed_ips: Vec<AllowedIP> = vec![];
-    while reader.read_line(&mut cmd).is_ok() {
-        cmd.pop(); // remove newline if any
-        if cmd.is_empty() {
+    loop {
+        let Some(cmd) = lines.next() else {
             let res = d.update_peer(
                 public_key,
                 update_only,
@@ -332,8 +392,13 @@ fn api_set_peer<R: Read>(
                 preshared_key,
             );
             allowed_ips.clear(); //clear the vector content after update
+            if res.is_ok() && !remove {
+                if let Some(hostname) = endpoint_hostname {
+                    let _ = d.set_endpoint_hostname(&public_key, hostname.as_deref());
+                }
+            }
             return res.and(Ok(0)).unwrap_or(EINVAL);
-        }
+        };
         {
             let parsed_cmd: Vec<&str> = cmd.splitn(2, '=').collect();
             if parsed_cmd.len() != 2 {
@@ -354,8 +419,18 @@ fn api_set_peer<R: Read>(
                     Err(_) => return EINVAL,
                 },
                 "endpoint" => match val.parse::<SocketAddr>() {
-                    Ok(addr) => endpoint = Some(addr),
-                    Err(_) => return EINVAL,
+                    Ok(addr) => {
+                        endpoint = Some(addr);
+                        endpoint_hostname = Some(None);
+                    }
+                    // Not an address, a hostname resolved with the request
+                    Err(_) => match resolved.get(val) {
+                        Some(&addr) => {
+                            endpoint = Some(addr);
+                            endpoint_hostname = Some(Some(val.to_owned()));
+                        }
+                        None => return EINVAL,
+                    },
                 },
                 "persistent_keepalive_interval" => match val.parse::<u16>() {
                     Ok(interval) => keepalive = Some(interval),
@@ -385,6 +460,11 @@ fn api_set_peer<R: Read>(
                     if res.is_err() {
                         return EINVAL;
                     }
+                    if !remove {
+                        if let Some(hostname) = endpoint_hostname.take() {
+                            let _ = d.set_endpoint_hostname(&public_key, hostname.as_deref());
+                        }
+                    }
                     replace_ips = false;
                     endpoint = None;
                     keepalive = None;
@@ -404,7 +484,57 @@ fn api_set_peer<R: Read>(
                 _ => return EINVAL,
             }
         }
-        cmd.clear();
     }
-    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::transport::DirectUdp;
+    use crate::device::MakeExternalBoringtunNoop;
+    use crate::noise::Tunn;
+    use rand::SeedableRng;
+
+    fn create_peers(n: usize) -> Vec<(x25519::PublicKey, Arc<Peer>)> {
+        let mut rng = rand::rngs::StdRng::from_entropy();
+        let own_key = x25519::StaticSecret::random_from_rng(&mut rng);
+        (0..n)
+            .map(|i| {
+                let public_key =
+                    x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(&mut rng));
+                let tunnel =
+                    Tunn::new(own_key.clone(), public_key, None, None, i as u32, None).unwrap();
+                let allowed_ip: AllowedIP = format!("10.0.{}.0/24", i).parse().unwrap();
+                let peer = Peer::new(
+                    tunnel,
+                    i as u32,
+                    None,
+                    &[allowed_ip],
+                    None,
+                    Arc::new(MakeExternalBoringtunNoop),
+                    Arc::new(DirectUdp),
+                );
+                (public_key, Arc::new(peer))
+            })
+            .collect()
+    }
+
+    fn dump_peers(peers: &HashMap<x25519::PublicKey, Arc<Peer>>) -> Vec<u8> {
+        let mut writer = BufWriter::new(vec![]);
+        for (k, peer) in sorted_peers(peers) {
+            api_get_peer(&mut writer, k, peer);
+        }
+        writer.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_peer_dump_is_deterministic() {
+        let peers = create_peers(16);
+        let forward: HashMap<_, _> = peers.iter().cloned().collect();
+        let backward: HashMap<_, _> = peers.iter().rev().cloned().collect();
+
+        let dump = dump_peers(&forward);
+        assert_eq!(dump, dump_peers(&forward));
+        assert_eq!(dump, dump_peers(&backward));
+    }
 }
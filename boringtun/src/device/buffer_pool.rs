@@ -0,0 +1,111 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Recycles packet buffers, so packets that have to outlive the receive or encapsulate call,
+/// like the ones queued by a rate limit or a congested socket, don't cost an allocation each.
+/// Packets handled within the call use the buffers of the handler thread instead. Safe to share
+/// between threads, the lock is only held to push or pop a buffer.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    /// Capacity of the buffers handed out
+    buffer_size: usize,
+    /// Maximum number of idle buffers kept
+    max_buffers: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_buffers: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            buffer_size,
+            max_buffers: AtomicUsize::new(max_buffers),
+        }
+    }
+
+    /// An empty buffer with room for `buffer_size` bytes. Nothing of its previous use can be
+    /// read back, growing it fills with zeros.
+    pub fn get(&self) -> Vec<u8> {
+        match self.buffers.lock().pop() {
+            Some(buf) => buf,
+            None => Vec::with_capacity(self.buffer_size),
+        }
+    }
+
+    /// A buffer holding a copy of `data`
+    pub fn get_copy(&self, data: &[u8]) -> Vec<u8> {
+        let mut buf = self.get();
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    /// Return a buffer for reuse, it is freed instead if the pool is full
+    pub fn put(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        // Don't pool buffers that grew large, or were never big enough
+        if buf.capacity() != self.buffer_size {
+            return;
+        }
+        let mut buffers = self.buffers.lock();
+        if buffers.len() < self.max_buffers.load(Ordering::Relaxed) {
+            buffers.push(buf);
+        }
+    }
+
+    /// Change how many idle buffers are kept, freeing the extra ones
+    pub fn set_max_buffers(&self, max_buffers: usize) {
+        self.max_buffers.store(max_buffers, Ordering::Relaxed);
+        self.buffers.lock().truncate(max_buffers);
+    }
+
+    /// Number of idle buffers in the pool
+    pub fn len(&self) -> usize {
+        self.buffers.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reused_buffer_is_cleared() {
+        let pool = BufferPool::new(64, 4);
+        let buf = pool.get_copy(&[0xaa; 64]);
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!(pool.len(), 1);
+
+        let mut buf = pool.get();
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(buf.is_empty());
+        buf.resize(64, 0);
+        assert_eq!(buf, vec![0u8; 64]);
+    }
+
+    #[test]
+    fn test_pool_bounded() {
+        let pool = BufferPool::new(64, 2);
+        let buffers: Vec<_> = (0..3).map(|_| pool.get()).collect();
+        for buf in buffers {
+            pool.put(buf);
+        }
+        assert_eq!(pool.len(), 2);
+
+        pool.set_max_buffers(1);
+        assert_eq!(pool.len(), 1);
+
+        // Buffers of another size are not kept
+        let buf = pool.get();
+        pool.put(Vec::with_capacity(128));
+        assert!(pool.is_empty());
+        pool.put(buf);
+        assert_eq!(pool.len(), 1);
+    }
+}
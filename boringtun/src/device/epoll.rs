@@ -23,7 +23,8 @@ pub enum WaitResult<'a, H> {
 /// Implements a registry of pollable events
 pub struct EventPoll<H: Sized> {
     events: Mutex<Vec<Option<Box<Event<H>>>>>,
-    epoll: RawFd, // The OS epoll
+    parked: Mutex<Vec<RawFd>>, // Events left disabled by `EventGuard::park`
+    epoll: RawFd,              // The OS epoll
 }
 
 /// A type that hold a reference to a triggered Event
@@ -64,6 +65,7 @@ impl<H: Sync + Send> EventPoll<H> {
 
         Ok(EventPoll {
             events: Mutex::new(vec![]),
+            parked: Mutex::new(vec![]),
             epoll,
         })
     }
@@ -307,6 +309,17 @@ impl<H: Sync + Send> EventPoll<H> {
         };
     }
 
+    /// Enable again the events disabled with `EventGuard::park`
+    pub fn rearm_parked(&self) {
+        let parked = std::mem::take(&mut *self.parked.lock());
+        let mut events = self.events.lock();
+        for fd in parked {
+            if let Some(Some(event)) = events.get_mut(fd as usize) {
+                unsafe { epoll_ctl(self.epoll, EPOLL_CTL_MOD, fd, &mut event.event) };
+            }
+        }
+    }
+
     /// Stop a notification
     pub fn stop_notification(&self, notification_event: &EventRef) {
         let events = self.events.lock();
@@ -338,6 +351,7 @@ impl<H> EventPoll<H> {
     /// This function is only safe to call when the event loop is not running,
     /// otherwise the memory of the handler may get freed while in use.
     pub unsafe fn clear_event_by_fd(&self, index: RawFd) {
+        self.parked.lock().retain(|&fd| fd != index);
         let mut events = self.events.lock();
         assert!(index >= 0);
         if events[index as usize].take().is_some() {
@@ -386,6 +400,12 @@ impl<'a, H> EventGuard<'a, H> {
         std::mem::forget(self); // Don't call the regular drop that would enable the event
     }
 
+    /// Leave the event disabled until `EventPoll::rearm_parked`, without removing it
+    pub fn park(self) {
+        self.poll.parked.lock().push(self.event.fd);
+        std::mem::forget(self); // Don't call the regular drop that would enable the event
+    }
+
     pub fn fd(&self) -> i32 {
         self.event.fd
     }
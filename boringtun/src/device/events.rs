@@ -0,0 +1,103 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! One stream of the significant events of a device, for applications that would rather not
+//! register callbacks on every peer. See `Device::subscribe`.
+
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+use crate::noise::errors::WireGuardError;
+use crate::x25519;
+
+/// Events queued for a subscriber before new ones are dropped
+pub const EVENT_QUEUE_LEN: usize = 256;
+
+/// Something that happened to a peer of the device
+#[derive(Clone, Debug)]
+pub enum DeviceEvent {
+    PeerAdded {
+        public_key: x25519::PublicKey,
+    },
+    PeerRemoved {
+        public_key: x25519::PublicKey,
+    },
+    HandshakeCompleted {
+        public_key: x25519::PublicKey,
+    },
+    /// Set by the configuration, or learned from an authenticated packet from a new address
+    EndpointChanged {
+        public_key: x25519::PublicKey,
+        endpoint: SocketAddr,
+    },
+    /// A packet to or from the peer failed, or its timers did. At most 10 are published a
+    /// second for each peer, `suppressed` counts the errors of the peer left out since the last.
+    Error {
+        public_key: x25519::PublicKey,
+        error: WireGuardError,
+        suppressed: u64,
+    },
+    /// An optional socket option failed and the socket is used without it, see
+    /// `Device::set_strict_sockopts`. `public_key` is `None` for the listen sockets.
+    SockOptFailed {
+        public_key: Option<x25519::PublicKey>,
+        option: &'static str,
+        error: String,
+    },
+}
+
+/// Copies each event to every subscriber. Publishing never blocks: a subscriber with a full
+/// queue misses the event, and the miss is counted.
+#[derive(Default)]
+pub(crate) struct EventFanout {
+    subscribers: Mutex<Vec<SyncSender<DeviceEvent>>>,
+    dropped: AtomicU64,
+}
+
+impl EventFanout {
+    pub(crate) fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (tx, rx) = sync_channel(EVENT_QUEUE_LEN);
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    /// Subscribers whose receiver is gone are forgotten
+    pub(crate) fn publish(&self, event: DeviceEvent) {
+        let mut subscribers = self.subscribers.lock();
+        subscribers.retain(|tx| match tx.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fanout_overflow() {
+        let fanout = EventFanout::default();
+        let slow = fanout.subscribe();
+        let gone = fanout.subscribe();
+        drop(gone);
+
+        let public_key = x25519::PublicKey::from([1u8; 32]);
+        for _ in 0..EVENT_QUEUE_LEN + 10 {
+            fanout.publish(DeviceEvent::PeerAdded { public_key });
+        }
+        assert_eq!(fanout.subscribers.lock().len(), 1);
+        assert_eq!(fanout.dropped(), 10);
+        assert_eq!(slow.try_iter().count(), EVENT_QUEUE_LEN);
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Simulated loss, latency and reordering on the listen and connected sockets, to test how an
+//! application copes with a bad network without an emulator. Only built with the `test-utils`
+//! feature. See `Device::set_fault_injection`.
+
+use std::collections::VecDeque;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::device::transport::Transport;
+use crate::device::{Error, MakeExternalBoringtun};
+
+/// The faults to inject. The default injects none.
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+    /// Chance of losing each datagram sent or received, from 0 to 1
+    pub drop_probability: f64,
+    /// Added to each datagram sent
+    pub latency: Duration,
+    /// Chance of holding a sent datagram back until the next one overtook it, from 0 to 1
+    pub reorder_probability: f64,
+    /// Seeds the random choices, so a failing run can be repeated
+    pub seed: u64,
+}
+
+/// A datagram to send, and where to: an address for the listen sockets, the socket for
+/// connected ones
+pub(crate) type Datagram<D = SocketAddr> = (Vec<u8>, D);
+
+pub(crate) struct FaultInjector<D = SocketAddr> {
+    config: FaultConfig,
+    /// State of the splitmix64 generator behind the random choices
+    state: u64,
+    /// Datagrams held back by the latency, with when they are due
+    delayed: VecDeque<(Instant, Datagram<D>)>,
+    /// A datagram waiting for the next one to overtake it
+    reordered: Option<Datagram<D>>,
+}
+
+impl<D> FaultInjector<D> {
+    pub(crate) fn new(config: FaultConfig) -> Self {
+        FaultInjector {
+            state: config.seed,
+            config,
+            delayed: VecDeque::new(),
+            reordered: None,
+        }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    /// Whether to lose a datagram just received
+    pub(crate) fn drop_received(&mut self) -> bool {
+        self.chance(self.config.drop_probability)
+    }
+
+    /// Pass `packet` for `addr` through the faults. Returns the datagrams to send by now, which
+    /// may be none, or include datagrams held back before.
+    pub(crate) fn on_send(&mut self, packet: &[u8], addr: D) -> Vec<Datagram<D>> {
+        if self.chance(self.config.drop_probability) {
+            return self.take_due();
+        }
+        if self.reordered.is_none() && self.chance(self.config.reorder_probability) {
+            self.reordered = Some((packet.to_vec(), addr));
+            return self.take_due();
+        }
+
+        let due = Instant::now() + self.config.latency;
+        self.delayed.push_back((due, (packet.to_vec(), addr)));
+        if let Some(held) = self.reordered.take() {
+            self.delayed.push_back((due, held));
+        }
+        self.take_due()
+    }
+
+    /// The datagrams whose latency passed
+    pub(crate) fn take_due(&mut self) -> Vec<Datagram<D>> {
+        let now = Instant::now();
+        let mut due = vec![];
+        while let Some((at, _)) = self.delayed.front() {
+            if *at > now {
+                break;
+            }
+            due.extend(self.delayed.pop_front().map(|(_, datagram)| datagram));
+        }
+        due
+    }
+
+    /// Like `take_due`, called by the device timers. A datagram held back for reordering that
+    /// nothing overtook goes too, so it isn't held forever.
+    pub(crate) fn on_tick(&mut self) -> Vec<Datagram<D>> {
+        let mut due = self.take_due();
+        due.extend(self.reordered.take());
+        due
+    }
+}
+
+/// Passes the datagrams of connected sockets through their own `FaultInjector` on the way to
+/// `inner`, so connected endpoints see the faults the listen sockets do. Held back datagrams
+/// keep a duplicate of their socket, and are sent even if the peer closed it meanwhile.
+pub(crate) struct FaultyTransport {
+    inner: Arc<dyn Transport>,
+    faults: Mutex<Option<FaultInjector<socket2::Socket>>>,
+}
+
+impl FaultyTransport {
+    pub(crate) fn new(inner: Arc<dyn Transport>) -> Self {
+        FaultyTransport {
+            inner,
+            faults: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn set_config(&self, config: FaultConfig) {
+        *self.faults.lock() = Some(FaultInjector::new(config));
+    }
+
+    /// Send the datagrams that are due, see `FaultInjector::on_tick`
+    pub(crate) fn on_tick(&self) {
+        let due = match self.faults.lock().as_mut() {
+            Some(faults) => faults.on_tick(),
+            None => return,
+        };
+        self.send_now(due);
+    }
+
+    fn send_now(&self, datagrams: Vec<Datagram<socket2::Socket>>) {
+        for (packet, conn) in datagrams {
+            let _ = self.inner.send(&conn, &packet);
+        }
+    }
+}
+
+impl Transport for FaultyTransport {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        port: u16,
+        protect: &dyn MakeExternalBoringtun,
+    ) -> Result<socket2::Socket, Error> {
+        self.inner.connect(addr, port, protect)
+    }
+
+    fn send(&self, conn: &socket2::Socket, packet: &[u8]) -> io::Result<usize> {
+        let mut faults = self.faults.lock();
+        let due = match faults.as_mut() {
+            // Lost datagrams look sent, like they would on a real network
+            Some(faults) => faults.on_send(packet, conn.try_clone()?),
+            None => {
+                drop(faults);
+                return self.inner.send(conn, packet);
+            }
+        };
+        drop(faults);
+        self.send_now(due);
+        Ok(packet.len())
+    }
+
+    fn recv(&self, conn: &socket2::Socket, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        loop {
+            let len = self.inner.recv(conn, buf)?;
+            let lost = self
+                .faults
+                .lock()
+                .as_mut()
+                .map_or(false, |faults| faults.drop_received());
+            if !lost {
+                return Ok(len);
+            }
+        }
+    }
+
+    fn set_mark(&self, conn: &socket2::Socket, mark: u32) -> io::Result<()> {
+        self.inner.set_mark(conn, mark)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:51820".parse().unwrap()
+    }
+
+    #[test]
+    fn test_no_faults() {
+        let mut faults = FaultInjector::new(FaultConfig::default());
+        for i in 0..100u8 {
+            assert_eq!(faults.on_send(&[i], addr()), vec![(vec![i], addr())]);
+            assert!(!faults.drop_received());
+        }
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut faults = FaultInjector::new(FaultConfig {
+            drop_probability: 0.3,
+            seed: 7,
+            ..Default::default()
+        });
+        let sent: usize = (0..1000u32)
+            .map(|i| faults.on_send(&i.to_le_bytes(), addr()).len())
+            .sum();
+        assert!((600..800).contains(&sent), "{} of 1000 sent", sent);
+
+        let mut faults = FaultInjector::new(FaultConfig {
+            reorder_probability: 1.0,
+            ..Default::default()
+        });
+        assert!(faults.on_send(&[1], addr()).is_empty());
+        assert_eq!(
+            faults.on_send(&[2], addr()),
+            vec![(vec![2], addr()), (vec![1], addr())]
+        );
+        assert!(faults.on_send(&[3], addr()).is_empty());
+        assert_eq!(faults.on_tick(), vec![(vec![3], addr())]);
+
+        let mut faults = FaultInjector::new(FaultConfig {
+            latency: Duration::from_millis(20),
+            ..Default::default()
+        });
+        assert!(faults.on_send(&[1], addr()).is_empty());
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(faults.take_due(), vec![(vec![1], addr())]);
+    }
+
+    #[test]
+    fn test_faulty_transport() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let transport = FaultyTransport::new(Arc::new(crate::device::transport::DirectUdp));
+        let conn = transport
+            .connect(
+                receiver.local_addr().unwrap(),
+                0,
+                &crate::device::MakeExternalBoringtunNoop,
+            )
+            .unwrap();
+        transport.set_config(FaultConfig {
+            reorder_probability: 1.0,
+            ..Default::default()
+        });
+
+        // The first datagram is held back until the second overtook it
+        assert_eq!(transport.send(&conn, &[1]).unwrap(), 1);
+        drop(conn);
+        let conn = transport
+            .connect(
+                receiver.local_addr().unwrap(),
+                0,
+                &crate::device::MakeExternalBoringtunNoop,
+            )
+            .unwrap();
+        transport.send(&conn, &[2]).unwrap();
+        let mut buf = [0u8; 1];
+        for expected in [2, 1] {
+            receiver.recv(&mut buf).unwrap();
+            assert_eq!(buf[0], expected);
+        }
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Parsing of the IP packets carried inside the tunnel
+
+use std::convert::TryFrom;
+use std::net::Ipv6Addr;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+/// Don't Fragment in the flags and fragment offset of an IPv4 header
+const IPV4_DF: u16 = 0x4000;
+const ICMP: u8 = 1;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_FRAG_NEEDED: u8 = 4;
+const ICMP_SOURCE_QUENCH: u8 = 4;
+const ICMP_REDIRECT: u8 = 5;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_PARAMETER_PROBLEM: u8 = 12;
+/// Fragment offset in the flags and fragment offset of an IPv4 header
+const IPV4_FRAGMENT_OFFSET: u16 = 0x1fff;
+const ICMPV6: u8 = 58;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+/// ICMPv6 types below this are errors, the rest informational (RFC 4443)
+const ICMPV6_INFORMATIONAL: u8 = 128;
+/// An ICMPv6 error quotes as much of the original packet as fits in the minimum IPv6 MTU
+const ICMPV6_MAX_QUOTED: usize = 1280 - IPV6_HEADER_LEN - 8;
+/// Bytes of the original datagram quoted past its header in an ICMP error, as RFC 792 asks
+const ICMP_QUOTED_PAYLOAD: usize = 8;
+
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTH: u8 = 51;
+const DESTINATION: u8 = 60;
+const MOBILITY: u8 = 135;
+const HOST_IDENTITY: u8 = 139;
+const SHIM6: u8 = 140;
+
+/// The Internet checksum of `data`, the ones' complement of the ones' complement sum of its
+/// 16 bit words. Summing over data that includes a correct checksum gives 0.
+pub(crate) fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Whether `packet` is an IPv4 packet with the Don't Fragment bit set
+pub(crate) fn ipv4_dont_fragment(packet: &[u8]) -> bool {
+    packet.len() >= IPV4_HEADER_LEN
+        && packet[0] >> 4 == 4
+        && u16::from_be_bytes([packet[6], packet[7]]) & IPV4_DF != 0
+}
+
+/// What `decrement_ttl` made of a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TtlDecrement {
+    /// Decremented, or not IP at all and passed unchanged
+    Forward,
+    /// No hops left to be forwarded, left as it was
+    Expired,
+    /// An IPv4 or IPv6 header too short or with a bad IPv4 header length, left as it was
+    Malformed,
+}
+
+/// Decrement the TTL of the IPv4, or the hop limit of the IPv6 `packet`, as a router forwarding
+/// it would, fixing up the IPv4 header checksum.
+pub(crate) fn decrement_ttl(packet: &mut [u8]) -> TtlDecrement {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            if header_len < IPV4_HEADER_LEN || header_len > packet.len() {
+                return TtlDecrement::Malformed;
+            }
+            if packet[8] <= 1 {
+                return TtlDecrement::Expired;
+            }
+            packet[8] -= 1;
+            let header = &mut packet[..header_len];
+            header[10..12].copy_from_slice(&[0, 0]);
+            let header_checksum = checksum(header);
+            header[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+            TtlDecrement::Forward
+        }
+        Some(6) => {
+            if packet.len() < IPV6_HEADER_LEN {
+                return TtlDecrement::Malformed;
+            }
+            if packet[7] <= 1 {
+                return TtlDecrement::Expired;
+            }
+            packet[7] -= 1;
+            TtlDecrement::Forward
+        }
+        _ => TtlDecrement::Forward,
+    }
+}
+
+/// An ICMP time exceeded in transit message telling the source of `packet`, IPv4 or IPv6, that
+/// it ran out of hops. Like `icmp_frag_needed` it appears to come from the destination of the
+/// packet, the device having no address of its own. `None` if `packet` has no complete header,
+/// or is one no ICMP error may answer, see `icmpv4_error` and `icmpv6_error`.
+pub(crate) fn icmp_time_exceeded(packet: &[u8]) -> Option<Vec<u8>> {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => icmpv4_error(packet, ICMP_TIME_EXCEEDED, 0, [0; 4]),
+        Some(6) => icmpv6_error(packet, ICMPV6_TIME_EXCEEDED, 0),
+        _ => None,
+    }
+}
+
+/// An ICMP fragmentation needed message telling the source of the IPv4 `packet` that it
+/// exceeds `mtu`. It appears to come from the destination of the packet, so the source takes it
+/// as belonging to the flow. `None` if `packet` has no complete IPv4 header.
+pub(crate) fn icmp_frag_needed(packet: &[u8], mtu: u16) -> Option<Vec<u8>> {
+    let [hi, lo] = mtu.to_be_bytes();
+    icmpv4_error(
+        packet,
+        ICMP_DEST_UNREACHABLE,
+        ICMP_FRAG_NEEDED,
+        [0, 0, hi, lo],
+    )
+}
+
+/// An ICMP error of `kind` and `code` about the IPv4 `packet`, from its destination to its
+/// source, with `rest` in the second word of the ICMP header. `None` for packets no ICMP error
+/// may be sent about (RFC 1812 4.3.2.7): ICMP errors themselves, so two hosts can't keep each
+/// other answering, and fragments but the first.
+fn icmpv4_error(packet: &[u8], kind: u8, code: u8, rest: [u8; 4]) -> Option<Vec<u8>> {
+    if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = usize::from(packet[0] & 0x0f) * 4;
+    if header_len < IPV4_HEADER_LEN || header_len > packet.len() {
+        return None;
+    }
+    if u16::from_be_bytes([packet[6], packet[7]]) & IPV4_FRAGMENT_OFFSET != 0 {
+        return None;
+    }
+    if packet[9] == ICMP
+        && matches!(
+            packet.get(header_len),
+            Some(&ICMP_DEST_UNREACHABLE)
+                | Some(&ICMP_TIME_EXCEEDED)
+                | Some(&ICMP_SOURCE_QUENCH)
+                | Some(&ICMP_REDIRECT)
+                | Some(&ICMP_PARAMETER_PROBLEM)
+        )
+    {
+        return None;
+    }
+    let quoted = packet
+        .get(..header_len + ICMP_QUOTED_PAYLOAD)
+        .unwrap_or(packet);
+    let total_len = IPV4_HEADER_LEN + 8 + quoted.len();
+
+    let mut reply = vec![0u8; total_len];
+    reply[0] = 0x45;
+    reply[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    reply[8] = 64;
+    reply[9] = ICMP;
+    reply[12..16].copy_from_slice(&packet[16..20]);
+    reply[16..20].copy_from_slice(&packet[12..16]);
+    let header_checksum = checksum(&reply[..IPV4_HEADER_LEN]);
+    reply[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+    let icmp = &mut reply[IPV4_HEADER_LEN..];
+    icmp[0] = kind;
+    icmp[1] = code;
+    icmp[4..8].copy_from_slice(&rest);
+    icmp[8..].copy_from_slice(quoted);
+    let icmp_checksum = checksum(icmp);
+    icmp[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+    Some(reply)
+}
+
+/// An ICMPv6 error of `kind` and `code` about the IPv6 `packet`, from its destination to its
+/// source. `None` for ICMPv6 errors, which no error may answer (RFC 4443 2.4), and for packets
+/// whose headers don't parse, so the payload can't be told apart from one.
+fn icmpv6_error(packet: &[u8], kind: u8, code: u8) -> Option<Vec<u8>> {
+    let meta = parse_ipv6(packet)?;
+    if meta.next_header == ICMPV6
+        && packet
+            .get(meta.payload_offset)
+            .map_or(true, |&kind| kind < ICMPV6_INFORMATIONAL)
+    {
+        return None;
+    }
+    let quoted = &packet[..packet.len().min(ICMPV6_MAX_QUOTED)];
+    let payload_len = 8 + quoted.len();
+
+    let mut reply = vec![0u8; IPV6_HEADER_LEN + payload_len];
+    reply[0] = 0x60;
+    reply[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+    reply[6] = ICMPV6;
+    reply[7] = 64;
+    reply[8..24].copy_from_slice(&packet[24..40]);
+    reply[24..40].copy_from_slice(&packet[8..24]);
+    reply[IPV6_HEADER_LEN] = kind;
+    reply[IPV6_HEADER_LEN + 1] = code;
+    reply[IPV6_HEADER_LEN + 8..].copy_from_slice(quoted);
+
+    // The checksum covers a pseudo header of the addresses, length and next header
+    let mut pseudo = Vec::with_capacity(40 + payload_len);
+    pseudo.extend_from_slice(&reply[8..40]);
+    pseudo.extend_from_slice(&(payload_len as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, ICMPV6]);
+    pseudo.extend_from_slice(&reply[IPV6_HEADER_LEN..]);
+    let icmp_checksum = checksum(&pseudo);
+    reply[IPV6_HEADER_LEN + 2..IPV6_HEADER_LEN + 4].copy_from_slice(&icmp_checksum.to_be_bytes());
+    Some(reply)
+}
+
+/// The addresses of an IPv6 packet, and where its payload starts past any extension headers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Ipv6Meta {
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+    /// Protocol of the payload, e.g. 6 for TCP, or 59 when there is none
+    pub next_header: u8,
+    /// Offset of the payload from the start of the packet
+    pub payload_offset: usize,
+}
+
+/// Parse an IPv6 packet, walking the extension header chain to the payload. Returns `None` for
+/// anything but a complete IPv6 packet, including one whose headers run past its payload length.
+pub(crate) fn parse_ipv6(buf: &[u8]) -> Option<Ipv6Meta> {
+    if buf.len() < IPV6_HEADER_LEN || buf[0] >> 4 != 6 {
+        return None;
+    }
+    let packet_len = IPV6_HEADER_LEN + usize::from(u16::from_be_bytes([buf[4], buf[5]]));
+    let packet = buf.get(..packet_len)?;
+
+    let mut next_header = packet[6];
+    let mut offset = IPV6_HEADER_LEN;
+    loop {
+        let header_len = match next_header {
+            HOP_BY_HOP | ROUTING | DESTINATION | MOBILITY | HOST_IDENTITY | SHIM6 => {
+                (usize::from(*packet.get(offset + 1)?) + 1) * 8
+            }
+            FRAGMENT => 8,
+            AUTH => (usize::from(*packet.get(offset + 1)?) + 2) * 4,
+            _ => break,
+        };
+        if offset + header_len > packet.len() {
+            return None;
+        }
+        next_header = packet[offset];
+        offset += header_len;
+    }
+
+    Some(Ipv6Meta {
+        src: Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap()),
+        dst: Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap()),
+        next_header,
+        payload_offset: offset,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UDP: u8 = 17;
+
+    /// An IPv6 packet with the given extension headers, each given as its type and length in
+    /// bytes, followed by a UDP payload of 8 bytes
+    fn packet(headers: &[(u8, usize)]) -> Vec<u8> {
+        let mut packet = vec![0u8; IPV6_HEADER_LEN];
+        packet[0] = 0x60;
+        packet[6] = headers.first().map_or(UDP, |(kind, _)| *kind);
+        packet[8..24].copy_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        packet[24..40].copy_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        for (i, (kind, len)) in headers.iter().enumerate() {
+            let mut header = vec![0u8; *len];
+            header[0] = headers.get(i + 1).map_or(UDP, |(kind, _)| *kind);
+            header[1] = match *kind {
+                FRAGMENT => 0,
+                AUTH => (len / 4 - 2) as u8,
+                _ => (len / 8 - 1) as u8,
+            };
+            packet.extend_from_slice(&header);
+        }
+        packet.extend_from_slice(&[0u8; 8]);
+        let payload_len = (packet.len() - IPV6_HEADER_LEN) as u16;
+        packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_extension_header_chain() {
+        let meta = parse_ipv6(&packet(&[])).unwrap();
+        assert_eq!(meta.src, "fd00::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(meta.dst, "fd00::2".parse::<Ipv6Addr>().unwrap());
+        assert_eq!((meta.next_header, meta.payload_offset), (UDP, 40));
+
+        let meta = parse_ipv6(&packet(&[(HOP_BY_HOP, 8), (ROUTING, 24)])).unwrap();
+        assert_eq!((meta.next_header, meta.payload_offset), (UDP, 72));
+
+        let meta = parse_ipv6(&packet(&[(HOP_BY_HOP, 16), (FRAGMENT, 8), (AUTH, 24)])).unwrap();
+        assert_eq!((meta.next_header, meta.payload_offset), (UDP, 88));
+    }
+
+    #[test]
+    fn test_icmp_frag_needed() {
+        let mut packet = vec![0u8; 1500];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&1500u16.to_be_bytes());
+        packet[6..8].copy_from_slice(&IPV4_DF.to_be_bytes());
+        packet[8] = 64;
+        packet[9] = UDP;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 1, 1]);
+        let header_checksum = checksum(&packet[..20]);
+        packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+        assert!(ipv4_dont_fragment(&packet));
+
+        let reply = icmp_frag_needed(&packet, 1420).unwrap();
+        assert_eq!(reply.len(), 20 + 8 + 28);
+        assert_eq!(checksum(&reply[..20]), 0);
+        assert_eq!(reply[9], ICMP);
+        assert_eq!(&reply[12..16], &[10, 0, 1, 1]);
+        assert_eq!(&reply[16..20], &[10, 0, 0, 1]);
+        let icmp = &reply[20..];
+        assert_eq!(checksum(icmp), 0);
+        assert_eq!((icmp[0], icmp[1]), (3, 4));
+        assert_eq!(u16::from_be_bytes([icmp[6], icmp[7]]), 1420);
+        assert_eq!(&icmp[8..], &packet[..28]);
+
+        packet[6] = 0;
+        assert!(!ipv4_dont_fragment(&packet));
+        assert_eq!(icmp_frag_needed(&packet[..19], 1420), None);
+    }
+
+    #[test]
+    fn test_decrement_ttl() {
+        let mut v4 = vec![0u8; 28];
+        v4[0] = 0x45;
+        v4[2..4].copy_from_slice(&28u16.to_be_bytes());
+        v4[8] = 2;
+        v4[9] = UDP;
+        v4[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        v4[16..20].copy_from_slice(&[10, 0, 1, 1]);
+        let header_checksum = checksum(&v4[..20]);
+        v4[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+        assert_eq!(decrement_ttl(&mut v4), TtlDecrement::Forward);
+        assert_eq!(v4[8], 1);
+        assert_eq!(checksum(&v4[..20]), 0);
+        let expired = v4.clone();
+        assert_eq!(decrement_ttl(&mut v4), TtlDecrement::Expired);
+        assert_eq!(v4, expired);
+
+        let mut v6 = packet(&[]);
+        v6[7] = 2;
+        assert_eq!(decrement_ttl(&mut v6), TtlDecrement::Forward);
+        assert_eq!(v6[7], 1);
+        assert_eq!(decrement_ttl(&mut v6), TtlDecrement::Expired);
+        assert_eq!(v6[7], 1);
+
+        let mut short = vec![0x45; 10];
+        assert_eq!(decrement_ttl(&mut short), TtlDecrement::Malformed);
+        assert_eq!(short, vec![0x45; 10]);
+
+        // A header length shorter than the header, or longer than the packet
+        for ihl in [0x41, 0x42, 0x4f] {
+            let mut bad = expired.clone();
+            bad[0] = ihl;
+            bad[8] = 64;
+            let before = bad.clone();
+            assert_eq!(decrement_ttl(&mut bad), TtlDecrement::Malformed);
+            assert_eq!(bad, before);
+        }
+
+        let mut not_ip = vec![0u8; 28];
+        assert_eq!(decrement_ttl(&mut not_ip), TtlDecrement::Forward);
+    }
+
+    #[test]
+    fn test_icmp_time_exceeded() {
+        let mut v4 = vec![0u8; 100];
+        v4[0] = 0x45;
+        v4[8] = 1;
+        v4[9] = UDP;
+        v4[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        v4[16..20].copy_from_slice(&[10, 0, 1, 1]);
+        let reply = icmp_time_exceeded(&v4).unwrap();
+        assert_eq!(reply.len(), 20 + 8 + 28);
+        assert_eq!(checksum(&reply[..20]), 0);
+        assert_eq!(&reply[12..16], &[10, 0, 1, 1]);
+        assert_eq!(&reply[16..20], &[10, 0, 0, 1]);
+        let icmp = &reply[20..];
+        assert_eq!(checksum(icmp), 0);
+        assert_eq!((icmp[0], icmp[1]), (11, 0));
+        assert_eq!(&icmp[8..], &v4[..28]);
+
+        let v6 = packet(&[]);
+        let reply = icmp_time_exceeded(&v6).unwrap();
+        let meta = parse_ipv6(&reply).unwrap();
+        assert_eq!(meta.src, "fd00::2".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(meta.dst, "fd00::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!((meta.next_header, meta.payload_offset), (ICMPV6, 40));
+        let icmp = &reply[40..];
+        assert_eq!((icmp[0], icmp[1]), (3, 0));
+        assert_eq!(&icmp[8..], &v6[..]);
+        let mut pseudo = reply[8..40].to_vec();
+        pseudo.extend_from_slice(&(icmp.len() as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, ICMPV6]);
+        pseudo.extend_from_slice(icmp);
+        assert_eq!(checksum(&pseudo), 0);
+
+        // A long packet is quoted only up to the minimum MTU
+        let mut long = packet(&[]);
+        long.resize(2000, 0);
+        let payload_len = (long.len() - IPV6_HEADER_LEN) as u16;
+        long[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        assert_eq!(icmp_time_exceeded(&long).unwrap().len(), 1280);
+
+        assert_eq!(icmp_time_exceeded(&v6[..39]), None);
+        assert_eq!(icmp_time_exceeded(&[]), None);
+
+        // Never about an ICMP error, nor a fragment but the first
+        let mut error = reply_to_v4_error();
+        assert_eq!(icmp_time_exceeded(&error), None);
+        error[20] = 8;
+        assert!(icmp_time_exceeded(&error).is_some());
+        v4[6..8].copy_from_slice(&1u16.to_be_bytes());
+        assert_eq!(icmp_time_exceeded(&v4), None);
+
+        let mut v6_error = packet(&[]);
+        v6_error[6] = ICMPV6;
+        v6_error[40] = ICMPV6_TIME_EXCEEDED;
+        assert_eq!(icmp_time_exceeded(&v6_error), None);
+        v6_error[40] = ICMPV6_INFORMATIONAL;
+        assert!(icmp_time_exceeded(&v6_error).is_some());
+    }
+
+    /// An IPv4 ICMP time exceeded, out of hops itself
+    fn reply_to_v4_error() -> Vec<u8> {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[8] = 1;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 1, 1]);
+        let mut error = icmp_time_exceeded(&packet).unwrap();
+        error[8] = 1;
+        error
+    }
+
+    #[test]
+    fn test_malformed_chain() {
+        let valid = packet(&[(HOP_BY_HOP, 8), (ROUTING, 24)]);
+
+        // Truncated anywhere
+        for len in 0..valid.len() {
+            assert_eq!(parse_ipv6(&valid[..len]), None);
+        }
+
+        // A header length reaching past the payload
+        let mut too_long = valid.clone();
+        too_long[49] = 4;
+        assert_eq!(parse_ipv6(&too_long), None);
+
+        // The payload length cuts the chain short
+        let mut short_payload = valid.clone();
+        short_payload[4..6].copy_from_slice(&20u16.to_be_bytes());
+        assert_eq!(parse_ipv6(&short_payload), None);
+
+        let mut not_v6 = valid;
+        not_v6[0] = 0x45;
+        assert_eq!(parse_ipv6(&not_v6), None);
+    }
+}
@@ -492,8 +492,9 @@ mod tests {
         assert_eq!(
             wg.wg_get(),
             format!(
-                "private_key={}\nlisten_port={}\nerrno=0\n\n",
+                "private_key={}\nown_public_key={}\nlisten_port={}\nerrno=0\n\n",
                 encode(private_key.as_bytes()),
+                encode(PublicKey::from(&private_key).as_bytes()),
                 port
             )
         );
@@ -522,6 +523,7 @@ mod tests {
             wg.wg_get(),
             format!(
                 "private_key={}\n\
+                 own_public_key={}\n\
                  listen_port={}\n\
                  public_key={}\n\
                  endpoint={}\n\
@@ -529,8 +531,11 @@ mod tests {
                  allowed_ip={}/{}\n\
                  rx_bytes=0\n\
                  tx_bytes=0\n\
+                 send_queue_len=0\n\
+                 decrypt_failures=0\n\
                  errno=0\n\n",
                 encode(private_key.as_bytes()),
+                encode(PublicKey::from(&private_key).as_bytes()),
                 port,
                 encode(peer_pub_key.as_bytes()),
                 endpoint,
@@ -0,0 +1,68 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Process wide record of the static keys used by live devices. Two devices with the same key
+//! answer each other's handshakes, which is hard to tell apart from a network problem.
+
+use parking_lot::{const_mutex, Mutex};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
+
+use crate::x25519;
+
+/// The claims of the live devices, by public key. Claims unregister themselves when dropped.
+static CLAIMS: Mutex<BTreeMap<[u8; 32], Vec<Weak<KeyClaim>>>> = const_mutex(BTreeMap::new());
+
+/// A device's use of a static key, held for as long as the device has the key
+pub(crate) struct KeyClaim {
+    public_key: [u8; 32],
+}
+
+/// Register the use of `public_key`. Returns the claim, and whether another live device holds
+/// a claim on the same key.
+pub(crate) fn claim(public_key: &x25519::PublicKey) -> (Arc<KeyClaim>, bool) {
+    let claim = Arc::new(KeyClaim {
+        public_key: *public_key.as_bytes(),
+    });
+    let mut claims = CLAIMS.lock();
+    let holders = claims.entry(claim.public_key).or_default();
+    holders.retain(|holder| holder.strong_count() > 0);
+    let conflict = !holders.is_empty();
+    holders.push(Arc::downgrade(&claim));
+    (claim, conflict)
+}
+
+impl Drop for KeyClaim {
+    fn drop(&mut self) {
+        let mut claims = CLAIMS.lock();
+        if let Some(holders) = claims.get_mut(&self.public_key) {
+            // This claim can't be upgraded anymore, so it goes too
+            holders.retain(|holder| holder.strong_count() > 0);
+            if holders.is_empty() {
+                claims.remove(&self.public_key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+
+    #[test]
+    fn test_claims_released() {
+        let public_key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+
+        let (first, conflict) = claim(&public_key);
+        assert!(!conflict);
+        let (second, conflict) = claim(&public_key);
+        assert!(conflict);
+
+        drop(first);
+        drop(second);
+        assert!(!CLAIMS.lock().contains_key(public_key.as_bytes()));
+        let (_third, conflict) = claim(&public_key);
+        assert!(!conflict);
+    }
+}
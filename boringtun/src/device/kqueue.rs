@@ -25,6 +25,7 @@ pub struct EventPoll<H: Sized> {
     events: Mutex<Vec<Option<Box<Event<H>>>>>, // Events with a file descriptor
     custom: Mutex<Vec<Option<Box<Event<H>>>>>, // Other events (i.e. timers & notifiers)
     signals: Mutex<Vec<Option<Box<Event<H>>>>>, // Signal handlers
+    parked: Mutex<Vec<RawFd>>,                 // Events left disabled by `EventGuard::park`
     kqueue: RawFd,                             // The OS kqueue
 }
 
@@ -78,6 +79,7 @@ impl<H: Send + Sync> EventPoll<H> {
             events: Mutex::new(vec![]),
             custom: Mutex::new(vec![]),
             signals: Mutex::new(vec![]),
+            parked: Mutex::new(vec![]),
             kqueue,
         })
     }
@@ -271,6 +273,17 @@ impl<H: Send + Sync> EventPoll<H> {
         unsafe { kevent(self.kqueue, &kev, 1, null_mut(), 0, null()) };
     }
 
+    /// Enable again the events disabled with `EventGuard::park`
+    pub fn rearm_parked(&self) {
+        let parked = std::mem::take(&mut *self.parked.lock());
+        let events = self.events.lock();
+        for fd in parked {
+            if let Some(Some(event)) = events.get(fd as usize) {
+                unsafe { kevent(self.kqueue, &event.event, 1, null_mut(), 0, null()) };
+            }
+        }
+    }
+
     pub fn stop_notification(&self, notification_event: &EventRef) {
         let events = self.custom.lock();
         let ev_index = -notification_event.trigger - 1; // Custom events have negative index from -1
@@ -293,6 +306,7 @@ impl<H: Send + Sync> EventPoll<H> {
 impl<H> EventPoll<H> {
     // This function is only safe to call when the event loop is not running
     pub unsafe fn clear_event_by_fd(&self, index: RawFd) {
+        self.parked.lock().retain(|&fd| fd != index);
         let (mut events, index) = if index >= 0 {
             (self.events.lock(), index as usize)
         } else {
@@ -330,8 +344,20 @@ impl<'a, H> EventGuard<'a, H> {
         std::mem::forget(self); // Don't call the regular drop that would enable the event
     }
 
+    /// Leave the event disabled until `EventPoll::rearm_parked`, without removing it
+    pub fn park(self) {
+        self.poll
+            .parked
+            .lock()
+            .push(self.event.event.ident as RawFd);
+        std::mem::forget(self); // Don't call the regular drop that would enable the event
+    }
+
     /// Stub: only used for Linux-specific features.
     pub fn fd(&self) -> i32 {
         -1
     }
+
+    /// Stub: the send queues drain on the timer tick here.
+    pub fn notify_writable(&mut self, _enabled: bool) {}
 }
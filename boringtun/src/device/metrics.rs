@@ -0,0 +1,224 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Counters of the device and its peers in the text formats of metrics collectors. The values
+//! are gathered once by `collect_metrics`, and each format only formats them.
+
+use super::Device;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MetricKind {
+    /// Only ever grows, until the device restarts
+    Counter,
+    Gauge,
+}
+
+/// One value, of the device or of a single peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Metric {
+    pub name: &'static str,
+    /// Hex public key of the peer, `None` for the device as a whole
+    pub peer: Option<String>,
+    pub kind: MetricKind,
+    pub value: u64,
+}
+
+impl Metric {
+    fn device(name: &'static str, kind: MetricKind, value: u64) -> Self {
+        Metric {
+            name,
+            peer: None,
+            kind,
+            value,
+        }
+    }
+}
+
+impl Device {
+    pub(crate) fn collect_metrics(&self) -> Vec<Metric> {
+        let health = self.health();
+        let mut metrics = vec![
+            Metric::device("peers", MetricKind::Gauge, health.peers as u64),
+            Metric::device(
+                "established_peers",
+                MetricKind::Gauge,
+                health.established_peers as u64,
+            ),
+            Metric::device(
+                "oversized_datagrams",
+                MetricKind::Counter,
+                self.oversized_datagrams(),
+            ),
+            Metric::device(
+                "invalid_inner_packets",
+                MetricKind::Counter,
+                self.invalid_inner_packets(),
+            ),
+            Metric::device(
+                "send_buffer_full",
+                MetricKind::Counter,
+                self.send_buffer_full(),
+            ),
+            Metric::device("dropped_events", MetricKind::Counter, self.dropped_events()),
+            Metric::device(
+                "suppressed_responses",
+                MetricKind::Counter,
+                self.suppressed_responses(),
+            ),
+        ];
+        let (rxq_v4, rxq_v6) = self.rxq_ovfl_drops();
+        metrics.push(Metric::device(
+            "rxq_ovfl_drops_v4",
+            MetricKind::Counter,
+            rxq_v4 as u64,
+        ));
+        metrics.push(Metric::device(
+            "rxq_ovfl_drops_v6",
+            MetricKind::Counter,
+            rxq_v6 as u64,
+        ));
+
+        for peer in self.peers.values() {
+            let stats = peer.tunnel.lock().stats();
+            let (tx_bytes, rx_bytes) = (stats.tx_bytes, stats.rx_bytes);
+            let values = [
+                ("tx_bytes", MetricKind::Counter, tx_bytes as u64),
+                ("rx_bytes", MetricKind::Counter, rx_bytes as u64),
+                (
+                    "decrypt_failures",
+                    MetricKind::Counter,
+                    peer.decrypt_failures(),
+                ),
+                (
+                    "send_buffer_full",
+                    MetricKind::Counter,
+                    peer.send_buffer_full(),
+                ),
+                (
+                    "rate_limit_drops",
+                    MetricKind::Counter,
+                    peer.rate_limit_drops(),
+                ),
+                ("egress_drops", MetricKind::Counter, peer.egress_drops()),
+                (
+                    "send_queue_len",
+                    MetricKind::Gauge,
+                    peer.send_queue_len() as u64,
+                ),
+            ];
+            metrics.extend(values.iter().map(|&(name, kind, value)| Metric {
+                name,
+                peer: Some(peer.public_key.1.clone()),
+                kind,
+                value,
+            }));
+        }
+        metrics
+    }
+
+    /// The metrics as StatsD lines, `<prefix>.peer.<public key>.tx_bytes:1024|c` for a peer and
+    /// `<prefix>.established_peers:3|g` for the device. Counters are sent as their running
+    /// total, not as the change since the last export.
+    pub fn metrics_statsd(&self, prefix: &str) -> Vec<String> {
+        self.collect_metrics()
+            .iter()
+            .map(|metric| statsd_line(prefix, metric))
+            .collect()
+    }
+
+    /// The metrics in the Prometheus text exposition format, `<prefix>_peer_tx_bytes_total{peer=
+    /// "<public key>"} 1024` for a peer and `<prefix>_established_peers 3` for the device, ready
+    /// to serve from a `/metrics` endpoint.
+    pub fn metrics_prometheus(&self, prefix: &str) -> String {
+        let mut metrics = self.collect_metrics();
+        // Each metric name gets one TYPE line, followed by all its samples. The sort is stable,
+        // so the peers keep the same order under every name.
+        metrics.sort_by_key(|metric| (metric.peer.is_some(), metric.name));
+        let mut text = String::new();
+        let mut last_name = None;
+        for metric in &metrics {
+            let name = prometheus_name(prefix, metric);
+            if last_name.as_ref() != Some(&name) {
+                let kind = match metric.kind {
+                    MetricKind::Counter => "counter",
+                    MetricKind::Gauge => "gauge",
+                };
+                text.push_str(&format!("# TYPE {} {}\n", name, kind));
+            }
+            text.push_str(&prometheus_sample(&name, metric));
+            text.push('\n');
+            last_name = Some(name);
+        }
+        text
+    }
+}
+
+fn prometheus_name(prefix: &str, metric: &Metric) -> String {
+    let scope = if metric.peer.is_some() { "_peer" } else { "" };
+    let suffix = match metric.kind {
+        MetricKind::Counter => "_total",
+        MetricKind::Gauge => "",
+    };
+    format!("{}{}_{}{}", prefix, scope, metric.name, suffix)
+}
+
+/// Hex public keys need no escaping as label values
+fn prometheus_sample(name: &str, metric: &Metric) -> String {
+    match &metric.peer {
+        Some(peer) => format!("{}{{peer=\"{}\"}} {}", name, peer, metric.value),
+        None => format!("{} {}", name, metric.value),
+    }
+}
+
+fn statsd_line(prefix: &str, metric: &Metric) -> String {
+    let kind = match metric.kind {
+        MetricKind::Counter => "c",
+        MetricKind::Gauge => "g",
+    };
+    match &metric.peer {
+        Some(peer) => format!(
+            "{}.peer.{}.{}:{}|{}",
+            prefix, peer, metric.name, metric.value, kind
+        ),
+        None => format!("{}.{}:{}|{}", prefix, metric.name, metric.value, kind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statsd_format() {
+        let peer = Metric {
+            name: "tx_bytes",
+            peer: Some("ab01".to_owned()),
+            kind: MetricKind::Counter,
+            value: 1024,
+        };
+        assert_eq!(statsd_line("wg", &peer), "wg.peer.ab01.tx_bytes:1024|c");
+
+        let device = Metric::device("established_peers", MetricKind::Gauge, 3);
+        assert_eq!(statsd_line("wg", &device), "wg.established_peers:3|g");
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let peer = Metric {
+            name: "tx_bytes",
+            peer: Some("ab01".to_owned()),
+            kind: MetricKind::Counter,
+            value: 1024,
+        };
+        let name = prometheus_name("wg", &peer);
+        assert_eq!(name, "wg_peer_tx_bytes_total");
+        assert_eq!(
+            prometheus_sample(&name, &peer),
+            "wg_peer_tx_bytes_total{peer=\"ab01\"} 1024"
+        );
+
+        let device = Metric::device("established_peers", MetricKind::Gauge, 3);
+        let name = prometheus_name("wg", &device);
+        assert_eq!(prometheus_sample(&name, &device), "wg_established_peers 3");
+    }
+}
@@ -1,13 +1,29 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+pub mod address_family;
 pub mod allowed_ips;
 pub mod api;
+pub mod buffer_pool;
 mod dev_lock;
 pub mod drop_privileges;
+pub mod events;
+#[cfg(feature = "test-utils")]
+pub mod faults;
+mod inner;
 #[cfg(test)]
 mod integration_tests;
+mod key_registry;
+mod metrics;
+pub mod packet_io;
 pub mod peer;
+mod source_cache;
+mod token_bucket;
+pub mod transport;
+pub mod validate;
+mod wg_log;
+#[cfg(all(target_os = "linux", feature = "zerocopy"))]
+pub mod zerocopy;
 
 #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
 #[path = "kqueue.rs"]
@@ -25,27 +41,45 @@ pub mod tun;
 #[path = "tun_linux.rs"]
 pub mod tun;
 
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::io::{self, BufReader, BufWriter};
-use std::mem::MaybeUninit;
+use std::mem::{self, MaybeUninit};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::os::fd::RawFd;
 #[cfg(not(target_os = "windows"))]
 use std::os::unix::io::AsRawFd;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
 use std::sync::Arc;
 use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::noise::errors::WireGuardError;
 use crate::noise::handshake::parse_handshake_anon;
-use crate::noise::rate_limiter::RateLimiter;
-use crate::noise::{Packet, Tunn, TunnResult};
+use crate::noise::rate_limiter::{RateLimiter, HALF_OPEN_HANDSHAKE_SIZE};
+use crate::noise::{Packet, Tunn, TunnResult, DATA_OVERHEAD_SZ};
 use crate::x25519;
+use address_family::AddressFamilyPref;
 use allowed_ips::AllowedIps;
-use peer::{AllowedIP, Peer};
+use buffer_pool::BufferPool;
+use events::{DeviceEvent, EventFanout};
+use key_registry::KeyClaim;
+use packet_io::{PacketSink, PacketSource, PacketSourceWaker};
+use peer::{AllowedIP, Peer, PeerUpdate, Received};
 use poll::{EventPoll, EventRef, WaitResult};
 use rand_core::{OsRng, RngCore};
 use socket2::{Domain, Protocol, Type};
+use source_cache::SourceCache;
+use token_bucket::TokenBucket;
+#[cfg(target_os = "linux")]
+use transport::bind_to_vrf;
+#[cfg(unix)]
+use transport::set_reuse_port;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use transport::{set_path_mtu_discovery, set_recv_pktinfo};
+use transport::{set_reuse_addr, set_ttl};
+use transport::{DirectUdp, Transport};
 use tun::TunSocket;
 
 use dev_lock::{Lock, LockReadGuard};
@@ -54,7 +88,18 @@ use thiserror::Error;
 const HANDSHAKE_RATE_LIMIT: u64 = 100; // The number of handshakes per second we can tolerate before using cookies
 
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
+const IPV4_UDP_HEADER_SZ: usize = 28; // The IPv4 and UDP headers around a datagram
+const IPV6_UDP_HEADER_SZ: usize = 48; // The IPv6 and UDP headers around a datagram
 const MAX_ITR: usize = 100; // Number of packets to handle per handler call
+const DEFAULT_BUFFER_POOL_SIZE: usize = 256; // Idle packet buffers kept for reuse
+const MAX_SEND_RETRIES: u32 = 5; // Most times a refused handshake message or keepalive is tried again
+const DEFAULT_PRESSURE_THRESHOLD: f64 = 10.0; // Handshakes refused per second for the load that make `under_pressure` true
+const DEFAULT_ENDPOINT_RESOLUTION_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(120); // How often hostname endpoints are resolved again
+const ICMP_ERRORS_PER_SEC: u64 = 100; // ICMP errors the device generates per second, in bursts of as many (RFC 1812 4.3.2.8)
+const UNKNOWN_INDEX_LOOKUPS_PER_SEC: u64 = 100; // Searches of the peers for the source of a data packet with an unknown index
+const PATH_MTU_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5); // How often the timers look for what path MTU discovery learned
+const STALE_HANDSHAKE_AGE: std::time::Duration = std::time::Duration::from_secs(135); // Rekeying after 120 seconds, plus time for retries
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -76,7 +121,6 @@ pub enum Error {
     SetSockOpt(String),
     #[error("Invalid tunnel name")]
     InvalidTunnelName,
-    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
     #[error("Get sockopt error: {0}")]
     GetSockOpt(io::Error),
     #[error("Get socket error: {0}")]
@@ -92,13 +136,29 @@ pub enum Error {
     ApiSocket(io::Error),
     #[error("Set tunnel error: Failed to get device lock when setting tunnel")]
     SetTunnel,
+    #[error("Invalid device configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Too many peers")]
+    TooManyPeers,
+    #[error("More than {0} allowed IPs for the peer")]
+    TooManyAllowedIps(usize),
+    #[error("Probe error: {0}")]
+    Probe(String),
+    #[error("Keepalive error: {0:?}")]
+    Keepalive(WireGuardError),
+    #[error("Another device uses the same private key")]
+    DuplicateKey,
+    #[error("The peer's public key is the device's own")]
+    SelfPeer,
 }
 
 // What the event loop should do after a handler returns
 enum Action {
-    Continue, // Continue the loop
-    Yield,    // Yield the read lock and acquire it again
-    Exit,     // Stop the loop
+    Continue,       // Continue the loop
+    Yield,          // Yield the read lock and acquire it again
+    Park,           // Leave the event disabled until the parked events are rearmed
+    Writable(bool), // Continue, and wake the handler again once the fd is writable, or stop that
+    Exit,           // Stop the loop
 }
 
 // Event handler function
@@ -140,14 +200,35 @@ pub struct DeviceConfig {
 
 pub struct Device {
     key_pair: Option<(x25519::StaticSecret, x25519::PublicKey)>,
+    /// Registers the key with the other devices of the process, see `set_strict_key_check`
+    key_claim: Option<Arc<KeyClaim>>,
+    strict_key_check: bool,
     queue: Arc<EventPoll<Handler>>,
 
     listen_port: u16,
     fwmark: Option<u32>,
+    /// Name of the VRF the sockets are bound to, see `set_vrf`
+    vrf: Option<String>,
+    /// See `set_listen_reuse_port`
+    listen_reuse_port: bool,
+    /// See `set_outer_ttl`
+    outer_ttl: Option<u8>,
+    /// See `set_strict_sockopts`
+    strict_sockopts: bool,
+    /// See `set_path_mtu_discovery`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    path_mtu_discovery: bool,
+    /// When `update_path_mtus` last ran
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    path_mtus_updated: parking_lot::Mutex<Option<std::time::Instant>>,
     #[cfg(not(target_os = "linux"))]
     update_seq: u32,
 
-    iface: Arc<TunSocket>,
+    /// `None` when built with packet io
+    iface: Option<Arc<TunSocket>>,
+    /// Where decrypted packets go, the tun interface or the packet sink
+    sink: Arc<dyn PacketSink>,
+    packet_source_waker: Option<PacketSourceWaker>,
     closed: bool,
     udp4: Option<socket2::Socket>,
     udp6: Option<socket2::Socket>,
@@ -166,14 +247,90 @@ pub struct Device {
 
     mtu: AtomicUsize,
 
+    datagram_limit: DatagramLimit,
+
+    inner_validation: InnerValidation,
+
+    /// See `set_decrement_inner_ttl`
+    decrement_inner_ttl: AtomicBool,
+    /// See `set_icmp_frag_needed`
+    icmp_frag_needed: AtomicBool,
+    /// Paces the ICMP errors the device generates, see `admit_icmp_error`
+    icmp_error_limit: TokenBucket,
+    /// Bounds the searches for the peer sending from a source, see `rehandshake_unknown_index`
+    unknown_index_limit: TokenBucket,
+
+    /// See `set_eager_rehandshake_on_unknown_index`
+    eager_rehandshake: AtomicBool,
+
+    /// The peer timers are run by `collect_pending_tx` instead of the event loop
+    external_timers: AtomicBool,
+
+    rxq_overflow: RxqOverflow,
+    /// See `set_recv_pktinfo`
+    recv_pktinfo: AtomicBool,
+
+    handshake_source_filter: HandshakeSourceFilter,
+
+    receive_pause: ReceivePause,
+
+    address_family_pref: AddressFamilyPref,
+
     rate_limiter: Option<Arc<RateLimiter>>,
+    /// Half open handshakes allowed before initiations need a cookie, see `set_handshake_memory_limit`
+    half_open_limit: Option<usize>,
+    /// See `set_require_valid_mac1_before_response`
+    require_mac1: bool,
+    /// See `set_handshake_send_retries`
+    handshake_send_retries: AtomicU32,
+    /// See `set_pressure_threshold`
+    pressure_threshold: f64,
+    /// See `set_endpoint_resolution_interval`
+    endpoint_resolution_interval: std::time::Duration,
+    /// Queues the hostnames for the resolver thread, see `resolve_in_background`
+    resolver: parking_lot::Mutex<Option<mpsc::Sender<Resolution>>>,
+    /// See `set_roam_on_keepalive_only`
+    roam_on_keepalive_only: bool,
+    /// See `set_source_cache_size`
+    source_cache: parking_lot::Mutex<SourceCache<Arc<Peer>>>,
+    /// Whether `source_cache` has room, so the packets skip its lock when it doesn't
+    source_cache_enabled: AtomicBool,
+    /// See `set_fault_injection`
+    #[cfg(feature = "test-utils")]
+    faults: parking_lot::Mutex<Option<faults::FaultInjector>>,
+    /// Wraps `transport`, the faults of the connected sockets
+    #[cfg(feature = "test-utils")]
+    connected_faults: Arc<faults::FaultyTransport>,
+
+    /// Buffers for packets that outlive a handler call, those queued by a rate limit and the
+    /// encrypted ones in the send queues. Sized for a data packet of an MTU sized packet.
+    buffer_pool: BufferPool,
+
+    max_peers: Option<usize>,
+    default_keepalive: Option<u16>,
+    /// See `set_max_allowed_ips_per_peer`
+    max_allowed_ips_per_peer: Option<usize>,
+
+    /// Log like the kernel module, see `set_wg_compat_logging`
+    wg_compat_logging: AtomicBool,
+
+    /// Milliseconds since the epoch of the last peer error, 0 for none, see `health`
+    last_error: AtomicU64,
+    ready_min_established: AtomicUsize,
+
+    /// Sends that failed with `ENOBUFS`, see `send_buffer_full`
+    send_buffer_full: AtomicU64,
+
+    events: EventFanout,
+
+    transport: Arc<dyn Transport>,
 
     #[cfg(target_os = "linux")]
     uapi_fd: i32,
 }
 
 struct ThreadData {
-    iface: Arc<TunSocket>,
+    sink: Arc<dyn PacketSink>,
     src_buf: [u8; MAX_UDP_SIZE],
     dst_buf: [u8; MAX_UDP_SIZE],
     #[cfg(not(target_os = "linux"))]
@@ -186,9 +343,24 @@ impl DeviceHandle {
     }
 
     pub fn new_with_tun(tun: TunSocket, config: DeviceConfig) -> Result<DeviceHandle, Error> {
-        let n_threads = config.n_threads;
-        let mut wg_interface = Device::new_with_tun(tun, config)?;
-        wg_interface.open_listen_socket(0)?; // Start listening on a random port
+        Self::start(DeviceBuilder::new(tun, config))
+    }
+
+    /// Exchange plaintext packets with `sink` and `source` instead of a tun interface, see
+    /// [`packet_io`]
+    pub fn new_with_packet_io(
+        sink: Arc<dyn PacketSink>,
+        source: Arc<dyn PacketSource>,
+        mtu: usize,
+        config: DeviceConfig,
+    ) -> Result<DeviceHandle, Error> {
+        Self::start(DeviceBuilder::with_packet_io(sink, source, config).tun_mtu(mtu))
+    }
+
+    fn start(builder: DeviceBuilder) -> Result<DeviceHandle, Error> {
+        let n_threads = builder.config.n_threads;
+        // Start listening on a random port
+        let wg_interface = builder.listen_port(0).build()?;
 
         let interface_lock = Arc::new(Lock::new(wg_interface));
 
@@ -246,6 +418,27 @@ impl DeviceHandle {
         self.device.read().drop_connected_sockets();
     }
 
+    /// Release the capacity the peer tables kept from peers since removed: rebuilds the peer maps
+    /// and the allowed IPs trie from the remaining entries. The rebuild runs alongside the data
+    /// path, which only stops for the new tables to be swapped in.
+    pub fn compact(&self) -> CompactStats {
+        let tables = Cell::new(None);
+        self.device
+            .read()
+            .try_writeable(
+                |device| {
+                    // No other writer gets in from here, so the copies stay current
+                    tables.set(Some(device.compacted_peer_tables()));
+                    device.trigger_yield();
+                },
+                |device| {
+                    device.cancel_yield();
+                    device.swap_peer_tables(tables.take().unwrap())
+                },
+            )
+            .unwrap()
+    }
+
     #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "tvos")))]
     pub fn wait(&mut self) {
         while let Some(thread) = self.threads.pop() {
@@ -267,6 +460,10 @@ impl DeviceHandle {
 
     #[cfg(not(target_os = "linux"))]
     pub fn set_iface(&mut self, new_iface: TunSocket) -> Result<(), Error> {
+        if self.device.read().iface.is_none() {
+            // Built with packet io
+            return Err(Error::SetTunnel);
+        }
         // Even though device struct is not being written to, we still take a write lock on device to stop the event loop
         // The event loop must be stopped so that the old iface event handler can be safelly cleared.
         // See clear_event_by_fd() function description
@@ -277,11 +474,15 @@ impl DeviceHandle {
                 |device| {
                     (device.update_seq, _) = device.update_seq.overflowing_add(1);
                     // Because the event loop is stopped now, this is safe (see clear_event_by_fd() comment)
-                    unsafe {
-                        device.queue.clear_event_by_fd(device.iface.as_raw_fd());
+                    if let Some(iface) = &device.iface {
+                        unsafe {
+                            device.queue.clear_event_by_fd(iface.as_raw_fd());
+                        }
                     }
-                    device.iface = Arc::new(new_iface.set_non_blocking()?);
-                    device.register_iface_handler(device.iface.clone())?;
+                    let iface = Arc::new(new_iface.set_non_blocking()?);
+                    device.register_iface_handler(Arc::clone(&iface))?;
+                    device.sink = Arc::clone(&iface) as Arc<dyn PacketSink>;
+                    device.iface = Some(iface);
                     device.cancel_yield();
 
                     Ok(())
@@ -302,18 +503,26 @@ impl DeviceHandle {
             #[cfg(not(target_os = "linux"))]
             if device_lock.update_seq != thread_local.update_seq {
                 thread_local.update_seq = device_lock.update_seq;
-                thread_local.iface = device_lock.iface.clone();
+                thread_local.sink = Arc::clone(&device_lock.sink);
             }
             // The event loop keeps a read lock on the device, because we assume write access is rarely needed
             let queue = Arc::clone(&device_lock.queue);
 
             loop {
                 match queue.wait() {
-                    WaitResult::Ok(handler) => {
+                    WaitResult::Ok(mut handler) => {
                         let action = (*handler)(&mut device_lock, &mut thread_local);
                         match action {
                             Action::Continue => {}
+                            Action::Writable(enabled) => handler.notify_writable(enabled),
                             Action::Yield => break,
+                            Action::Park => {
+                                handler.park();
+                                // Resumed while the handler ran, before it was parked
+                                if !device_lock.receive_pause.is_paused() {
+                                    queue.rearm_parked();
+                                }
+                            }
                             Action::Exit => {
                                 device_lock.try_writeable(|_| {}, |dev| dev.closed = true);
                                 device_lock.trigger_exit();
@@ -339,23 +548,24 @@ impl DeviceHandle {
         let t_local = ThreadData {
             src_buf: [0u8; MAX_UDP_SIZE],
             dst_buf: [0u8; MAX_UDP_SIZE],
-            iface: if _thread_id == 0 || !device_lock.config.use_multi_queue {
-                // For the first thread use the original iface
-                Arc::clone(&device_lock.iface)
-            } else {
-                // For for the rest create a new iface queue
-                let iface_local = Arc::new(
-                    TunSocket::new(&device_lock.iface.name().unwrap())
-                        .unwrap()
-                        .set_non_blocking()
-                        .unwrap(),
-                );
-
-                device_lock
-                    .register_iface_handler(Arc::clone(&iface_local))
-                    .ok();
-
-                iface_local
+            sink: match &device_lock.iface {
+                Some(iface) if _thread_id != 0 && device_lock.config.use_multi_queue => {
+                    // For for the rest create a new iface queue
+                    let iface_local = Arc::new(
+                        TunSocket::new(&iface.name().unwrap())
+                            .unwrap()
+                            .set_non_blocking()
+                            .unwrap(),
+                    );
+
+                    device_lock
+                        .register_iface_handler(Arc::clone(&iface_local))
+                        .ok();
+
+                    iface_local as Arc<dyn PacketSink>
+                }
+                // For the first thread use the original iface, or the packet sink
+                _ => Arc::clone(&device_lock.sink),
             },
         };
 
@@ -363,7 +573,7 @@ impl DeviceHandle {
         let t_local = ThreadData {
             src_buf: [0u8; MAX_UDP_SIZE],
             dst_buf: [0u8; MAX_UDP_SIZE],
-            iface: Arc::clone(&device_lock.iface),
+            sink: Arc::clone(&device_lock.sink),
             update_seq: device_lock.update_seq,
         };
 
@@ -389,10 +599,19 @@ impl Device {
             {
                 peer.shutdown_endpoint(); // close open udp socket and free the closure
                 self.peers_by_idx.remove(&peer.index());
+                self.source_cache
+                    .lock()
+                    .remove_matching(|cached| Arc::ptr_eq(cached, &peer));
             }
             self.peers_by_ip
                 .remove(&|p: &Arc<Peer>| Arc::ptr_eq(&peer, p));
 
+            self.note_sole_peer();
+
+            self.wg_log_peer_destroyed(&peer);
+            self.events.publish(DeviceEvent::PeerRemoved {
+                public_key: *pub_key,
+            });
             tracing::info!("Peer removed");
         }
     }
@@ -416,15 +635,16 @@ impl Device {
         }
 
         if let Some(peer) = self.peers.get(&pub_key) {
-            if let Some(endpoint) = endpoint {
-                peer.set_endpoint(endpoint);
-            }
-
+            // First, so an update over the allowed IPs limit changes nothing
             if replace_ips {
+                peer.set_allowed_ips(&allowed_ips)?;
                 self.peers_by_ip.remove(&|p| Arc::ptr_eq(&peer, p));
-                peer.set_allowed_ips(&allowed_ips);
             } else {
-                peer.add_allowed_ips(&allowed_ips);
+                peer.add_allowed_ips(&allowed_ips)?;
+            }
+
+            if let Some(endpoint) = endpoint {
+                self.set_peer_endpoint(peer, endpoint);
             }
 
             if let Some(keepalive) = keepalive {
@@ -460,6 +680,24 @@ impl Device {
         keepalive: Option<u16>,
         preshared_key: Option<[u8; 32]>,
     ) -> Result<Arc<Peer>, Error> {
+        if let Some(max_peers) = self.max_peers {
+            if self.peers.len() >= max_peers {
+                return Err(Error::TooManyPeers);
+            }
+        }
+
+        // The handshakes with ourselves would never lead anywhere
+        if let Some((_, own_key)) = &self.key_pair {
+            if pub_key.as_bytes() == own_key.as_bytes() {
+                return Err(Error::SelfPeer);
+            }
+        }
+
+        // Before the peer builds its allowed IPs, however many
+        if let Some(max) = self.max_allowed_ips_per_peer {
+            Peer::check_allowed_ip_count(allowed_ips, max)?;
+        }
+
         let next_index = self.next_index();
         let device_key_pair = self
             .key_pair
@@ -470,7 +708,7 @@ impl Device {
             device_key_pair.0.clone(),
             pub_key.clone(),
             preshared_key,
-            keepalive,
+            keepalive.or(self.default_keepalive),
             next_index,
             None,
         )
@@ -483,16 +721,25 @@ impl Device {
             &allowed_ips,
             preshared_key,
             self.config.protect.clone(),
+            Arc::clone(&self.transport),
         ));
+        if let Some(max) = self.max_allowed_ips_per_peer {
+            peer.set_max_allowed_ips(max);
+        }
 
         self.peers.insert(pub_key, Arc::clone(&peer));
         self.peers_by_idx.insert(next_index, Arc::clone(&peer));
+        self.note_sole_peer();
 
         for AllowedIP { addr, cidr } in allowed_ips {
             self.peers_by_ip
                 .insert(*addr, *cidr as _, Arc::clone(&peer));
         }
 
+        self.wg_log_peer_created(&peer);
+        self.events.publish(DeviceEvent::PeerAdded {
+            public_key: pub_key,
+        });
         tracing::info!("Peer added");
 
         Ok(peer)
@@ -503,66 +750,13 @@ impl Device {
     }
 
     pub fn new_with_tun(tun: TunSocket, config: DeviceConfig) -> Result<Device, Error> {
-        let poll = EventPoll::<Handler>::new()?;
-
-        // Create a tunnel device
-        let iface = Arc::new(tun.set_non_blocking()?);
-        let mtu = iface.mtu()?;
-
-        #[cfg(not(target_os = "linux"))]
-        let uapi_fd = -1;
-        #[cfg(target_os = "linux")]
-        let uapi_fd = config.uapi_fd;
-
-        let mut device = Device {
-            queue: Arc::new(poll),
-            iface,
-            closed: false,
-            config,
-            exit_notice: Default::default(),
-            yield_notice: Default::default(),
-            fwmark: Default::default(),
-            key_pair: Default::default(),
-            listen_port: Default::default(),
-            next_index: Default::default(),
-            peers: Default::default(),
-            peers_by_idx: Default::default(),
-            peers_by_ip: AllowedIps::new(),
-            udp4: Default::default(),
-            udp6: Default::default(),
-            cleanup_paths: Default::default(),
-            mtu: AtomicUsize::new(mtu),
-            rate_limiter: None,
-            #[cfg(target_os = "linux")]
-            uapi_fd,
-            #[cfg(not(target_os = "linux"))]
-            update_seq: 0,
-        };
-
-        if device.config.open_uapi_socket {
-            if uapi_fd >= 0 {
-                device.register_api_fd(uapi_fd)?;
-            } else {
-                device.register_api_handler()?;
-            }
-        }
-        device.register_iface_handler(Arc::clone(&device.iface))?;
-        device.register_notifiers()?;
-        device.register_timers()?;
-
-        #[cfg(target_os = "macos")]
-        {
-            // Only for macOS write the actual socket name into WG_TUN_NAME_FILE
-            if let Ok(name_file) = std::env::var("WG_TUN_NAME_FILE") {
-                std::fs::write(&name_file, device.iface.name().unwrap().as_bytes()).unwrap();
-                device.cleanup_paths.push(name_file);
-            }
-        }
-
-        Ok(device)
+        DeviceBuilder::new(tun, config).build()
     }
 
-    fn open_listen_socket(&mut self, mut port: u16) -> Result<(), Error> {
+    /// Listen on `port` over both IPv4 and IPv6, 0 picks a random port. The IPv6 socket is
+    /// v6-only. If it can't be opened, the device keeps serving IPv4 peers only.
+    /// Families excluded by the address family preference are not opened.
+    pub fn bind_dual(&mut self, mut port: u16) -> Result<(), Error> {
         // Binds the network facing interfaces
         // First close any existing open socket, and remove them from the event loop
         if let Some(s) = self.udp4.take() {
@@ -581,34 +775,535 @@ impl Device {
         }
 
         // Then open new sockets and bind to the port
-        let udp_sock4 = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        udp_sock4.set_reuse_address(true)?;
-        udp_sock4.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
-        udp_sock4.set_nonblocking(true)?;
-        self.config.protect.make_external(udp_sock4.as_raw_fd());
+        let pref = self.address_family_pref;
+        let udp_sock4 = if pref.allows_v4() {
+            let udp_sock4 = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            self.apply_listen_reuse(&udp_sock4)?;
+            self.apply_vrf(&udp_sock4)?;
+            udp_sock4.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+            self.apply_optional_sockopts(&udp_sock4, None)?;
+            udp_sock4.set_nonblocking(true)?;
+            self.config.protect.make_external(udp_sock4.as_raw_fd());
+
+            if port == 0 {
+                // Random port was assigned
+                port = udp_sock4.local_addr()?.as_socket().unwrap().port();
+            }
+            Some(udp_sock4)
+        } else {
+            None
+        };
+
+        let udp_sock6 = if pref.allows_v6() {
+            match self.open_listen_socket_v6(port) {
+                Ok(udp_sock6) => {
+                    self.config.protect.make_external(udp_sock6.as_raw_fd());
+                    if port == 0 {
+                        port = udp_sock6.local_addr()?.as_socket().unwrap().port();
+                    }
+                    Some(udp_sock6)
+                }
+                Err(err) if udp_sock4.is_some() => {
+                    tracing::warn!(message = "Failed to open IPv6 listen socket, serving IPv4 only", error = ?err);
+                    None
+                }
+                Err(err) => return Err(err),
+            }
+        } else {
+            None
+        };
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.rxq_overflow.enabled.load(Ordering::Relaxed) {
+            for sock in udp_sock4.iter().chain(udp_sock6.iter()) {
+                self.optional_sockopt("SO_RXQ_OVFL", set_rxq_ovfl(sock), None)?;
+            }
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.recv_pktinfo.load(Ordering::Relaxed) {
+            for sock in udp_sock4.iter().chain(udp_sock6.iter()) {
+                set_recv_pktinfo(sock)?;
+            }
+        }
 
-        if port == 0 {
-            // Random port was assigned
-            port = udp_sock4.local_addr()?.as_socket().unwrap().port();
+        for sock in udp_sock4.iter().chain(udp_sock6.iter()) {
+            self.register_udp_handler(sock.try_clone().unwrap())?;
         }
+        self.udp4 = udp_sock4;
+        self.udp6 = udp_sock6;
 
+        self.listen_port = port;
+
+        Ok(())
+    }
+
+    fn open_listen_socket_v6(&self, port: u16) -> Result<socket2::Socket, Error> {
         let udp_sock6 = socket2::Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
-        udp_sock6.set_reuse_address(true)?;
+        udp_sock6.set_only_v6(true)?;
+        self.apply_listen_reuse(&udp_sock6)?;
+        self.apply_vrf(&udp_sock6)?;
         udp_sock6.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into())?;
+        self.apply_optional_sockopts(&udp_sock6, None)?;
         udp_sock6.set_nonblocking(true)?;
-        self.config.protect.make_external(udp_sock6.as_raw_fd());
+        Ok(udp_sock6)
+    }
+
+    /// Limit the allowed IPs of each peer to `n` prefixes, so a misbehaving control plane can't
+    /// grow the routing trie without bound. Updates that would go past it fail with
+    /// `Error::TooManyAllowedIps` and change nothing, replacing the whole set only counts the
+    /// new one. Peers already over the limit keep their allowed IPs. 0 removes the limit, the
+    /// default.
+    pub fn set_max_allowed_ips_per_peer(&mut self, n: usize) {
+        self.max_allowed_ips_per_peer = if n == 0 { None } else { Some(n) };
+        for peer in self.peers.values() {
+            peer.set_max_allowed_ips(self.max_allowed_ips_per_peer.unwrap_or(usize::MAX));
+        }
+    }
 
-        self.register_udp_handler(udp_sock4.try_clone().unwrap())?;
-        self.register_udp_handler(udp_sock6.try_clone().unwrap())?;
-        self.udp4 = Some(udp_sock4);
-        self.udp6 = Some(udp_sock6);
+    /// Set which address family to use for endpoints resolving to both. `V4Only` and `V6Only`
+    /// also restrict the listen sockets opened by the next `bind_dual`.
+    pub fn set_address_family_preference(&mut self, pref: AddressFamilyPref) {
+        self.address_family_pref = pref;
+    }
 
-        self.listen_port = port;
+    /// Reconfigure a peer at once with `Peer::apply_config`, routing its new allowed IPs.
+    /// Returns the previous values of the updated fields.
+    pub fn apply_peer_config(
+        &mut self,
+        pub_key: &x25519::PublicKey,
+        update: PeerUpdate,
+    ) -> Result<PeerUpdate, Error> {
+        let peer = self
+            .peers
+            .get(pub_key)
+            .ok_or_else(|| Error::InvalidConfig("Unknown peer".to_owned()))?;
+        let replace_ips = update.allowed_ips.is_some();
+        let previous = peer.apply_config(update)?;
+        if replace_ips {
+            self.peers_by_ip.remove(&|p| Arc::ptr_eq(peer, p));
+            for AllowedIP { addr, cidr } in peer.allowed_ips() {
+                self.peers_by_ip.insert(addr, cidr as _, Arc::clone(peer));
+            }
+        }
+        Ok(previous)
+    }
+
+    /// Copies of the peer tables sized for the remaining entries, see `DeviceHandle::compact`
+    fn compacted_peer_tables(&self) -> PeerTables {
+        let mut peers_by_ip = AllowedIps::new();
+        for (peer, addr, cidr) in self.peers_by_ip.iter() {
+            peers_by_ip.insert(addr, cidr as _, Arc::clone(peer));
+        }
+        PeerTables {
+            peers: self
+                .peers
+                .iter()
+                .map(|(key, peer)| (*key, Arc::clone(peer)))
+                .collect(),
+            peers_by_idx: self
+                .peers_by_idx
+                .iter()
+                .map(|(idx, peer)| (*idx, Arc::clone(peer)))
+                .collect(),
+            peers_by_ip,
+        }
+    }
+
+    fn swap_peer_tables(&mut self, tables: PeerTables) -> CompactStats {
+        let bytes_before = self.peer_table_bytes();
+        self.peers = tables.peers;
+        self.peers_by_idx = tables.peers_by_idx;
+        self.peers_by_ip = tables.peers_by_ip;
+        CompactStats {
+            bytes_before,
+            bytes_after: self.peer_table_bytes(),
+        }
+    }
+
+    /// Estimate of the memory held by the peer tables, counting the allocated slots of the maps
+    /// and the entries of the trie. Excludes the peers themselves.
+    fn peer_table_bytes(&self) -> usize {
+        self.peers.capacity() * mem::size_of::<(x25519::PublicKey, Arc<Peer>)>()
+            + self.peers_by_idx.capacity() * mem::size_of::<(u32, Arc<Peer>)>()
+            + self.peers_by_ip.len() * mem::size_of::<(IpAddr, u8, Arc<Peer>)>()
+    }
+
+    /// Send a keepalive to a peer right away, over its connected socket or the listen socket,
+    /// see `Peer::send_keepalive_now`
+    pub fn send_keepalive_now(
+        &self,
+        pub_key: &x25519::PublicKey,
+        handshake_if_no_session: bool,
+    ) -> Result<(), Error> {
+        let peer = self
+            .peers
+            .get(pub_key)
+            .ok_or_else(|| Error::InvalidConfig("Unknown peer".to_owned()))?;
+        if peer.endpoint().conn.is_some() {
+            return peer.send_keepalive_now(handshake_if_no_session);
+        }
+
+        let addr = peer
+            .endpoint()
+            .addr
+            .ok_or_else(|| Error::Connect("No endpoint".to_owned()))?;
+        let mut buf = [0u8; peer::KEEPALIVE_BUF_SIZE];
+        if let Some(packet) = peer.format_keepalive(handshake_if_no_session, &mut buf)? {
+            self.send_retrying(peer, packet, |packet| {
+                peer.send_handshake(packet, Some(addr))
+                    .unwrap_or_else(|| self.send_to_listener(packet, addr))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The peers with a handshake initiation of ours awaiting a response, e.g. to cancel them with
+    /// `Peer::cancel_handshake` before shutting down. Locks each tunnel in turn.
+    pub fn pending_handshakes(&self) -> Vec<x25519::PublicKey> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.handshake_pending())
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Drop all session state of the peer and start a new handshake from scratch, see
+    /// `Peer::reset_session`, sending the initiation whether the endpoint is connected or not
+    pub fn reset_session(&self, pub_key: &x25519::PublicKey) -> Result<(), Error> {
+        let peer = self
+            .peers
+            .get(pub_key)
+            .ok_or_else(|| Error::InvalidConfig("Unknown peer".to_owned()))?;
+        if peer.endpoint().conn.is_some() {
+            return peer.reset_session();
+        }
+
+        let mut buf = [0u8; peer::KEEPALIVE_BUF_SIZE];
+        let packet = peer.format_reset(&mut buf);
+        if let (Some(packet), Some(addr)) = (packet, peer.endpoint().addr) {
+            self.send_retrying(peer, packet, |packet| {
+                peer.send_handshake(packet, Some(addr))
+                    .unwrap_or_else(|| self.send_to_listener(packet, addr))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Send the handshakes of a peer over `sock`, see `Peer::set_handshake_socket`, and handle
+    /// what arrives on it like on the connected endpoint socket, so responses to handshakes sent
+    /// there reach the peer too. `sock` must be connected to the endpoint of the peer.
+    pub fn set_handshake_socket(
+        &self,
+        pub_key: &x25519::PublicKey,
+        sock: socket2::Socket,
+    ) -> Result<(), Error> {
+        let peer = self
+            .peers
+            .get(pub_key)
+            .ok_or_else(|| Error::InvalidConfig("Unknown peer".to_owned()))?;
+        let addr = sock
+            .peer_addr()?
+            .as_socket()
+            .ok_or_else(|| Error::InvalidConfig("Not an IP socket".to_owned()))?;
+        sock.set_nonblocking(true)?;
+        peer.set_handshake_socket(Some(sock.try_clone()?));
+        self.register_conn_handler(Arc::clone(peer), sock, addr.ip())
+    }
+
+    /// How many idle packet buffers to keep for reuse. They hold the packets queued by a rate
+    /// limit, see `Peer::set_rate_limit`, and by a congested socket, see
+    /// `Peer::set_send_queue_capacity`. Packets going straight through use the buffers of the
+    /// handler threads, pooled or not.
+    pub fn set_buffer_pool_size(&self, size: usize) {
+        self.buffer_pool.set_max_buffers(size);
+    }
+
+    /// Entries and bytes held by handshakes we answered, that the initiator is yet to confirm
+    pub fn handshake_memory_usage(&self) -> (usize, usize) {
+        let entries = self.rate_limiter.as_ref().map_or(0, |r| r.half_open());
+        (entries, entries * HALF_OPEN_HANDSHAKE_SIZE)
+    }
+
+    /// Cap the memory held by half open handshakes to `max_bytes`. Once reached, initiations are
+    /// answered with cookie replies, so only initiators proving their address get a session.
+    pub fn set_handshake_memory_limit(&mut self, max_bytes: Option<usize>) {
+        self.half_open_limit = max_bytes.map(|max_bytes| max_bytes / HALF_OPEN_HANDSHAKE_SIZE);
+        if let Some(rate_limiter) = self.rate_limiter.as_ref() {
+            rate_limiter.set_half_open_limit(self.half_open_limit);
+        }
+    }
+
+    /// Only answer initiations carrying a valid MAC1 with a handshake response, true by
+    /// default. Under load a valid MAC2, proving the sender's address, is required as well.
+    /// Turning this off is only ever useful to debug a peer computing MAC1 wrong.
+    pub fn set_require_valid_mac1_before_response(&mut self, required: bool) {
+        self.require_mac1 = required;
+        if let Some(rate_limiter) = self.rate_limiter.as_ref() {
+            rate_limiter.set_require_mac1(required);
+        }
+    }
+
+    /// Try again up to `retries` times to send a handshake message or keepalive the socket
+    /// had no room for. The packet is held by the peer and tried again whenever its send queue
+    /// drains, the event loop never waits for the socket. Only the last such packet of each peer
+    /// is held, and at most 5 retries are made. Data packets are never retried, they would hold
+    /// up the ones behind them. 0, the default, drops every packet the socket refuses.
+    pub fn set_handshake_send_retries(&self, retries: u32) {
+        self.handshake_send_retries
+            .store(retries.min(MAX_SEND_RETRIES), Ordering::Relaxed);
+    }
+
+    /// Send `packet` to `peer` with `send`. A handshake message or keepalive refused by a
+    /// congested socket is held by the peer, to retry as `set_handshake_send_retries` asks.
+    fn send_retrying(
+        &self,
+        peer: &Peer,
+        packet: &[u8],
+        send: impl FnOnce(&[u8]) -> io::Result<usize>,
+    ) -> io::Result<usize> {
+        let res = send(packet);
+        let retries = self.handshake_send_retries.load(Ordering::Relaxed);
+        // Only classify the packet when it may be retried, this runs for every data packet
+        if retries > 0 {
+            if let Err(err) = &res {
+                if is_congested(err) && is_control_packet(packet) {
+                    peer.hold_control_packet(packet, retries);
+                }
+            }
+        }
+        res
+    }
+
+    /// Initiations not answered with a handshake response for failing MAC1, or MAC2 under
+    /// load, since the private key was last set
+    pub fn suppressed_responses(&self) -> u64 {
+        self.rate_limiter
+            .as_ref()
+            .map_or(0, |r| r.suppressed_responses())
+    }
+
+    /// Handshake messages the rate limiter refused per second for the load, over the last 5
+    /// seconds: initiations and responses answered with a cookie reply, or dropped
+    pub fn rate_limiter_drop_rate(&self) -> f64 {
+        self.rate_limiter.as_ref().map_or(0.0, |r| r.drop_rate())
+    }
+
+    /// Whether `rate_limiter_drop_rate` exceeds the threshold set with
+    /// `set_pressure_threshold`, a sign the device gets more handshakes than it can take
+    pub fn under_pressure(&self) -> bool {
+        self.rate_limiter_drop_rate() > self.pressure_threshold
+    }
+
+    /// The drop rate above which the device is `under_pressure`, 10 per second by default
+    pub fn set_pressure_threshold(&mut self, drops_per_sec: f64) {
+        self.pressure_threshold = drops_per_sec;
+    }
 
+    /// Resolve a `host:port` endpoint, picking the address according to the family preference
+    pub fn resolve_endpoint(&self, endpoint: &str) -> Result<SocketAddr, Error> {
+        self.address_family_pref.resolve(endpoint)
+    }
+
+    /// Resolve the endpoint of the peer `pub_key` from the `host:port` `hostname` again
+    /// periodically, following a dynamic DNS name as its address changes, or stop with `None`.
+    /// The endpoint itself is left as it is until the next resolution.
+    pub fn set_endpoint_hostname(
+        &self,
+        pub_key: &x25519::PublicKey,
+        hostname: Option<&str>,
+    ) -> Result<(), Error> {
+        let peer = self
+            .peers
+            .get(pub_key)
+            .ok_or_else(|| Error::InvalidConfig("Unknown peer".to_owned()))?;
+        // Peers configured together resolve at different times from the start
+        let due = std::time::Instant::now() + self.endpoint_resolution_interval.mul_f64(jitter());
+        peer.set_endpoint_hostname(hostname.map(str::to_owned), due);
         Ok(())
     }
 
-    fn set_key(&mut self, private_key: x25519::StaticSecret) {
+    /// How often the hostname endpoints set with `set_endpoint_hostname` are resolved again, 2
+    /// minutes by default. Each peer is resolved at a random point between half and one and a
+    /// half of the interval after the last time, so many peers on the same DNS provider don't
+    /// query it together. After a failure the peer is tried again after 5 seconds, doubling with
+    /// every failure in a row up to 30 minutes. Zero stops the resolutions.
+    pub fn set_endpoint_resolution_interval(&mut self, interval: std::time::Duration) {
+        self.endpoint_resolution_interval = interval;
+    }
+
+    /// Only move the endpoint of a peer to the source of its handshake messages and keepalives,
+    /// not of data packets. Either way only packets that authenticated as from the peer move it,
+    /// see `Tunn::take_authenticated`, so a spoofed source address can't redirect a peer, but a
+    /// captured data packet replayed ahead of the original from elsewhere could still win a race
+    /// to the counter. Keepalives are rarely worth capturing, and replayed handshake messages
+    /// fail to authenticate: an initiation needs a newer timestamp than the last one, a response
+    /// the initiation it answers still in flight. Off by default.
+    pub fn set_roam_on_keepalive_only(&mut self, enabled: bool) {
+        self.roam_on_keepalive_only = enabled;
+    }
+
+    /// Remember the peer of the last `n` source addresses packets arrived from, so the listening
+    /// sockets hand a handshake initiation from one of them to the tunnel of its peer without
+    /// the anonymous parse finding the peer first, which costs as much as the tunnel handling
+    /// it. The other packets name the index of their peer, which is found as fast without the
+    /// cache. An entry is only used while its peer is still at that endpoint, and an initiation
+    /// the tunnel of the cached peer finds is from another key falls back to the full lookup, so
+    /// a stale entry never sends a packet to the wrong peer. Changing the endpoint, roaming or
+    /// removing a peer drops its entries. 0, the default, disables the cache.
+    pub fn set_source_cache_size(&self, n: usize) {
+        self.source_cache.lock().set_capacity(n);
+        self.source_cache_enabled.store(n > 0, Ordering::Relaxed);
+    }
+
+    /// The peer cached for `src`, if it is still at `src`
+    fn cached_source_peer(&self, src: SocketAddr) -> Option<Arc<Peer>> {
+        if !self.source_cache_enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut cache = self.source_cache.lock();
+        let peer = cache.get(&src)?;
+        if peer.endpoint().addr == Some(src) {
+            return Some(Arc::clone(peer));
+        }
+        cache.remove(&src);
+        None
+    }
+
+    /// Apply the endpoints resolved since the last call, and start resolving the hostnames due,
+    /// on the resolver thread so a slow resolver doesn't hold up the timers.
+    fn reresolve_endpoints(&self) {
+        let interval = self.endpoint_resolution_interval;
+        if interval.is_zero() {
+            return;
+        }
+        let now = std::time::Instant::now();
+        for peer in self.peers.values() {
+            if let Some(addr) = peer.take_resolution(now, interval, jitter) {
+                self.set_peer_endpoint(peer, addr);
+            }
+            if let Some(hostname) = peer.start_resolution(now) {
+                self.resolve_in_background(Resolution {
+                    peer: Arc::clone(peer),
+                    hostname,
+                    pref: self.address_family_pref,
+                });
+            }
+        }
+    }
+
+    /// Hand `resolution` to the resolver thread, started with the first one, which resolves the
+    /// hostnames of all the peers one after the other and ends with the device. A peer has at
+    /// most one resolution in flight, so no more are queued than there are peers.
+    fn resolve_in_background(&self, resolution: Resolution) {
+        let mut resolver = self.resolver.lock();
+        let sender = resolver.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel::<Resolution>();
+            let spawned = thread::Builder::new()
+                .name("boringtun-resolver".to_owned())
+                .spawn(move || {
+                    for Resolution {
+                        peer,
+                        hostname,
+                        pref,
+                    } in receiver
+                    {
+                        let result = pref.resolve(&hostname).map_err(|err| {
+                            tracing::warn!(message = "Failed to resolve endpoint", hostname = %hostname, error = ?err);
+                        });
+                        peer.finish_resolution(&hostname, result);
+                    }
+                });
+            if let Err(err) = spawned {
+                tracing::error!(message = "Failed to start the resolver thread", error = ?err);
+            }
+            sender
+        });
+        if let Err(mpsc::SendError(resolution)) = sender.send(resolution) {
+            // The thread never started, fail the resolution so it is tried again with a backoff
+            *resolver = None;
+            resolution
+                .peer
+                .finish_resolution(&resolution.hostname, Err(()));
+        }
+    }
+
+    /// Whether the IPv4 and IPv6 listen sockets are open
+    pub fn listen_families(&self) -> (bool, bool) {
+        (self.udp4.is_some(), self.udp6.is_some())
+    }
+
+    /// The fds of the open listen sockets, IPv4 first, for an event loop of the embedder to poll.
+    /// They close when the sockets are reopened, by `bind_dual` or a new listen port or fwmark,
+    /// so query again after reconfiguring. See `Peer::socket_fd` for connected sockets.
+    pub fn listen_fds(&self) -> Vec<RawFd> {
+        self.udp4
+            .iter()
+            .chain(self.udp6.iter())
+            .map(|sock| sock.as_raw_fd())
+            .collect()
+    }
+
+    /// Send from the listen socket of the family of `addr`
+    fn send_to_listener(&self, packet: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        #[cfg(feature = "test-utils")]
+        if let Some(faults) = self.faults.lock().as_mut() {
+            // Lost datagrams look sent, like they would on a real network
+            for (packet, addr) in faults.on_send(packet, addr) {
+                let _ = self.send_to_listener_now(&packet, addr);
+            }
+            return Ok(packet.len());
+        }
+        self.send_to_listener_now(packet, addr)
+    }
+
+    /// Send `packet` to the endpoint of `peer`, over the connected socket or a listen socket
+    fn send_to_endpoint(&self, peer: &Peer, packet: &[u8]) -> io::Result<usize> {
+        let endpoint = peer.endpoint();
+        match (&endpoint.conn, endpoint.addr) {
+            (Some(conn), _) => peer.transport().send(conn, packet),
+            (None, Some(addr)) => self.send_to_listener(packet, addr),
+            (None, None) => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn send_to_listener_now(&self, packet: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let udp = match addr {
+            SocketAddr::V4(_) => self.udp4.as_ref(),
+            SocketAddr::V6(_) => self.udp6.as_ref(),
+        };
+        match udp {
+            Some(udp) => udp.send_to(packet, &addr.into()),
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "No listen socket for the address family",
+            )),
+        }
+    }
+
+    /// Lose, delay and reorder the datagrams of the listen and connected sockets as `config`
+    /// says, to test recovery from a bad network. Loss applies to datagrams sent and received,
+    /// latency and reordering to those sent. Delayed datagrams go out with the next send or
+    /// timer tick, so within 250ms of being due. Connected sockets draw their faults apart from
+    /// the listen sockets, from the same seed. `FaultConfig::default()` injects no faults.
+    #[cfg(feature = "test-utils")]
+    pub fn set_fault_injection(&self, config: faults::FaultConfig) {
+        self.connected_faults.set_config(config.clone());
+        *self.faults.lock() = Some(faults::FaultInjector::new(config));
+    }
+
+    #[cfg(feature = "test-utils")]
+    fn release_delayed_sends(&self) {
+        self.connected_faults.on_tick();
+        let due = match self.faults.lock().as_mut() {
+            Some(faults) => faults.on_tick(),
+            None => return,
+        };
+        for (packet, addr) in due {
+            let _ = self.send_to_listener_now(&packet, addr);
+        }
+    }
+
+    fn set_key(&mut self, private_key: x25519::StaticSecret) -> Result<(), Error> {
         let mut bad_peers = vec![];
 
         let public_key = x25519::PublicKey::from(&private_key);
@@ -617,10 +1312,24 @@ impl Device {
         // x25519 (rightly) doesn't let us expose secret keys for comparison.
         // If the public keys are the same, then the private keys are the same.
         if Some(&public_key) == self.key_pair.as_ref().map(|p| &p.1) {
-            return;
+            return Ok(());
         }
 
+        let (key_claim, conflict) = key_registry::claim(&public_key);
+        if conflict {
+            if self.strict_key_check {
+                return Err(Error::DuplicateKey);
+            }
+            tracing::warn!(
+                message = "Another device uses the same private key",
+                public_key = hex::encode(public_key.as_bytes())
+            );
+        }
+        self.key_claim = Some(key_claim);
+
         let rate_limiter = Arc::new(RateLimiter::new(&public_key, HANDSHAKE_RATE_LIMIT));
+        rate_limiter.set_half_open_limit(self.half_open_limit);
+        rate_limiter.set_require_mac1(self.require_mac1);
 
         for peer in self.peers.values_mut() {
             if peer
@@ -646,35 +1355,229 @@ impl Device {
         for _ in bad_peers {
             unimplemented!();
         }
+        Ok(())
     }
 
-    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
-    fn set_fwmark(&mut self, mark: u32) -> Result<(), Error> {
-        self.fwmark = Some(mark);
+    /// Refuse a private key another live device of the process uses, instead of only warning
+    pub fn set_strict_key_check(&mut self, strict: bool) {
+        self.strict_key_check = strict;
+    }
 
-        // First set fwmark on listeners
-        if let Some(ref sock) = self.udp4 {
-            sock.set_mark(mark)?;
-        }
+    /// Mark the datagrams of the tunnel with `mark`, from the listening sockets and the connected
+    /// sockets of the peers, so a new routing policy takes all of them at once. Sockets opened
+    /// later are marked too. A socket failing doesn't stop the others from being marked, the
+    /// error names every one that failed. Only Linux has marks, elsewhere this just remembers it.
+    pub fn set_fwmark(&mut self, mark: u32) -> Result<(), Error> {
+        self.fwmark = Some(mark);
 
-        if let Some(ref sock) = self.udp6 {
-            sock.set_mark(mark)?;
+        let mut failed = vec![];
+        let listeners = [
+            ("IPv4 listen socket", &self.udp4),
+            ("IPv6 listen socket", &self.udp6),
+        ];
+        for (name, sock) in listeners.iter() {
+            if let Some(sock) = sock {
+                if let Err(err) = self.transport.set_mark(sock, mark) {
+                    failed.push(format!("{}: {}", name, err));
+                }
+            }
         }
 
-        // Then on all currently connected sockets
-        for peer in self.peers.values() {
+        for (key, peer) in self.peers.iter() {
             if let Some(ref sock) = peer.endpoint().conn {
-                sock.set_mark(mark)?
+                if let Err(err) = peer.transport().set_mark(sock, mark) {
+                    failed.push(format!("peer {}: {}", base64::encode(key.as_bytes()), err));
+                }
             }
         }
 
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            let err = Error::SetSockOpt(format!("fwmark {}: {}", mark, failed.join(", ")));
+            self.optional_sockopt("fwmark", Err(err), None)
+        }
+    }
+
+    /// Mark `sock` with the fwmark set with `set_fwmark`, if any
+    fn apply_fwmark(&self, sock: &socket2::Socket) -> Result<(), Error> {
+        if let Some(mark) = self.fwmark {
+            self.transport.set_mark(sock, mark)?;
+        }
+        Ok(())
+    }
+
+    /// Bind the listening sockets and the connected sockets of the peers to the VRF named
+    /// `vrf_name`, so they route with its table. Sockets opened later are bound too. See
+    /// `transport::bind_to_vrf` for how this interacts with the fwmark and which datagrams the
+    /// sockets still receive.
+    #[cfg(target_os = "linux")]
+    pub fn set_vrf(&mut self, vrf_name: &str) -> Result<(), Error> {
+        self.vrf = Some(vrf_name.to_owned());
+
+        for sock in self.udp4.iter().chain(self.udp6.iter()) {
+            bind_to_vrf(sock, vrf_name)?;
+        }
+
+        for peer in self.peers.values() {
+            if let Some(ref sock) = peer.endpoint().conn {
+                bind_to_vrf(sock, vrf_name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bind `sock` to the VRF set with `set_vrf`, if any
+    fn apply_vrf(&self, sock: &socket2::Socket) -> Result<(), Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(vrf) = &self.vrf {
+            bind_to_vrf(sock, vrf)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = sock;
+        Ok(())
+    }
+
+    /// Open the listen sockets with `SO_REUSEPORT` from the next `bind_dual` on, so
+    /// other devices or processes listening the same way share the port, the kernel spreading
+    /// the datagrams over them. Connected peer sockets keep `SO_REUSEADDR` only, see
+    /// `transport::set_reuse_port` for why. Ignored where `SO_REUSEPORT` doesn't exist.
+    pub fn set_listen_reuse_port(&mut self, enabled: bool) {
+        self.listen_reuse_port = enabled;
+    }
+
+    /// Send the datagrams of the tunnel with a TTL, or IPv6 hop limit, of `ttl`, from the
+    /// listening sockets and the connected sockets of the peers. Sockets opened later use it
+    /// too. See `transport::set_ttl` for using 255 with GTSM.
+    pub fn set_outer_ttl(&mut self, ttl: u8) -> Result<(), Error> {
+        self.outer_ttl = Some(ttl);
+
+        for sock in self.udp4.iter().chain(self.udp6.iter()) {
+            self.optional_sockopt("TTL", set_ttl(sock, ttl), None)?;
+        }
+
+        for peer in self.peers.values() {
+            if let Some(ref sock) = peer.endpoint().conn {
+                self.optional_sockopt("TTL", set_ttl(sock, ttl), Some(peer))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Give the bound `sock` the TTL set with `set_outer_ttl`, if any
+    fn apply_outer_ttl(&self, sock: &socket2::Socket) -> Result<(), Error> {
+        if let Some(ttl) = self.outer_ttl {
+            set_ttl(sock, ttl)?;
+        }
+        Ok(())
+    }
+
+    /// Let the optional socket options fail without failing what sets them: the fwmark, the
+    /// TTL and `SO_RXQ_OVFL`, nice to have but e.g. needing capabilities a container may lack.
+    /// Their failures are then logged and published as `DeviceEvent::SockOptFailed`, and the
+    /// socket is used without them. Binding, connecting and the VRF fail either way. Strict, the
+    /// default, fails on any of them, from `bind_dual` or from the setter. The socket connected
+    /// when a peer roams is the exception, it is always used, failures only logged and published.
+    pub fn set_strict_sockopts(&mut self, strict: bool) {
+        self.strict_sockopts = strict;
+    }
+
+    /// The result `res` of setting `option` on a socket, of `peer` or a listen socket when
+    /// `None`, by the policy of `set_strict_sockopts`
+    fn optional_sockopt<E: Into<Error>>(
+        &self,
+        option: &'static str,
+        res: Result<(), E>,
+        peer: Option<&Peer>,
+    ) -> Result<(), Error> {
+        match res {
+            Ok(()) => Ok(()),
+            Err(err) if self.strict_sockopts => Err(err.into()),
+            Err(err) => {
+                self.sockopt_failed(option, err.into(), peer);
+                Ok(())
+            }
+        }
+    }
+
+    /// Log and publish that `option` could not be set, the socket being used without it
+    fn sockopt_failed(&self, option: &'static str, err: Error, peer: Option<&Peer>) {
+        tracing::warn!(message = "Failed to set an optional socket option", option = option, error = ?err);
+        self.events.publish(DeviceEvent::SockOptFailed {
+            public_key: peer.map(|peer| x25519::PublicKey::from(peer.public_key.0)),
+            option,
+            error: err.to_string(),
+        });
+    }
+
+    /// Give the bound `sock`, of `peer` or a listen socket, the TTL and fwmark of the device
+    fn apply_optional_sockopts(
+        &self,
+        sock: &socket2::Socket,
+        peer: Option<&Peer>,
+    ) -> Result<(), Error> {
+        self.optional_sockopt("TTL", self.apply_outer_ttl(sock), peer)?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.path_mtu_discovery && peer.is_some() {
+            let res = set_path_mtu_discovery(sock);
+            self.optional_sockopt("path MTU discovery", res, peer)?;
+        }
+        self.optional_sockopt("fwmark", self.apply_fwmark(sock), peer)
+    }
+
+    /// Connect a socket to the endpoint of `peer`, which just roamed, see
+    /// `Peer::connect_endpoint`, and set it up like the listen sockets. The socket is used even
+    /// when setting it up fails, whatever `set_strict_sockopts` says: its datagrams take the new
+    /// path either way, so the failures are only logged and published as
+    /// `DeviceEvent::SockOptFailed`.
+    fn connect_roamed_endpoint(&self, peer: &Peer) -> Result<socket2::Socket, Error> {
+        let sock = peer.connect_endpoint(self.listen_port)?;
+        // Rebinding a connected socket resets its cached route
+        let mut results = vec![
+            ("VRF", self.apply_vrf(&sock)),
+            ("TTL", self.apply_outer_ttl(&sock)),
+        ];
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.path_mtu_discovery {
+            results.push((
+                "path MTU discovery",
+                set_path_mtu_discovery(&sock).map_err(Error::from),
+            ));
+        }
+        results.push(("fwmark", self.apply_fwmark(&sock)));
+        for (option, res) in results {
+            if let Err(err) = res {
+                self.sockopt_failed(option, err, Some(peer));
+            }
+        }
+        Ok(sock)
+    }
+
+    fn apply_listen_reuse(&self, sock: &socket2::Socket) -> Result<(), Error> {
+        set_reuse_addr(sock)?;
+        #[cfg(unix)]
+        if self.listen_reuse_port {
+            set_reuse_port(sock)?;
+        }
         Ok(())
     }
 
+    /// Let a peer skip checking the sources of its packets while it is the only one, see
+    /// `Peer::is_allowed_ip`
+    fn note_sole_peer(&self) {
+        let sole = self.peers.len() == 1;
+        for peer in self.peers.values() {
+            peer.sole_peer.store(sole, Ordering::Relaxed);
+        }
+    }
+
     fn clear_peers(&mut self) {
         self.peers.clear();
         self.peers_by_idx.clear();
         self.peers_by_ip.clear();
+        self.source_cache.lock().clear();
     }
 
     fn register_notifiers(&mut self) -> Result<(), Error> {
@@ -707,46 +1610,31 @@ impl Device {
         self.queue.new_periodic_event(
             // Execute the timed function of every peer in the list
             Box::new(|d, t| {
-                let peer_map = &d.peers;
-
-                let (udp4, udp6) = match (d.udp4.as_ref(), d.udp6.as_ref()) {
-                    (Some(udp4), Some(udp6)) => (udp4, udp6),
-                    _ => return Action::Continue,
-                };
+                #[cfg(feature = "test-utils")]
+                d.release_delayed_sends();
+                d.reresolve_endpoints();
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                d.update_path_mtus();
+
+                // Once handed over, the timers only run from `collect_pending_tx`
+                if d.external_timers.load(Ordering::Relaxed) {
+                    return Action::Continue;
+                }
 
                 // Go over each peer and invoke the timer function
-                for peer in peer_map.values() {
-                    let endpoint_addr = match peer.endpoint().addr {
-                        Some(addr) => addr,
-                        None => continue,
-                    };
-
-                    let res = {
-                        let mut tun = peer.tunnel.lock();
-                        tun.update_timers(&mut t.dst_buf[..])
-                    };
-                    match res {
-                        TunnResult::Done => {}
-                        TunnResult::Err(WireGuardError::ConnectionExpired) => {
-                            peer.shutdown_endpoint(); // close open udp socket
-                        }
-                        TunnResult::Err(e) => tracing::error!(message = "Timer error", error = ?e),
-                        TunnResult::WriteToNetwork(packet) => {
-                            let res = match endpoint_addr {
-                                SocketAddr::V4(_) => {
-                                    udp4.send_to(packet, &endpoint_addr.into())
-                                }
-                                SocketAddr::V6(_) => {
-                                    udp6.send_to(packet, &endpoint_addr.into())
-                                }
-                            };
-
-                            if let Err(err) = res {
-                                tracing::warn!(message = "Failed to send timers request", error = ?err, dst = ?endpoint_addr);
-                            }
+                for peer in d.peers.values() {
+                    d.run_peer_timers(peer, &mut t.dst_buf[..], &mut |packet, endpoint_addr| {
+                        if let Err(err) = d.send_retrying(peer, packet, |packet| {
+                            peer.send_handshake(packet, Some(endpoint_addr))
+                                .unwrap_or_else(|| d.send_to_listener(packet, endpoint_addr))
+                        }) {
+                            d.record_send_error(Some(peer), &err);
+                            tracing::warn!(message = "Failed to send timers request", error = ?err, dst = ?endpoint_addr);
                         }
-                        _ => panic!("Unexpected result from update_timers"),
-                    };
+                    });
+                    if peer.send_queue_len() > 0 || peer.holds_control_packet() {
+                        d.drain_send_queue(peer);
+                    }
                 }
                 Action::Continue
             }),
@@ -755,6 +1643,80 @@ impl Device {
         Ok(())
     }
 
+    /// Run the timers of `peer`, passing each packet due to its endpoint to `send`: handshake
+    /// initiations and keepalives, and the packets held back by the rate limit that fit the
+    /// budget by now. Peers without an endpoint are skipped.
+    fn run_peer_timers(
+        &self,
+        peer: &Peer,
+        dst: &mut [u8],
+        send: &mut dyn FnMut(&[u8], SocketAddr),
+    ) {
+        let endpoint_addr = match peer.endpoint().addr {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        let res = {
+            let mut tun = peer.tunnel.lock();
+            tun.update_timers(dst)
+        };
+        match res {
+            TunnResult::Done => {}
+            TunnResult::Err(WireGuardError::ConnectionExpired) => {
+                peer.shutdown_endpoint(); // close open udp socket
+                peer.session_expired();
+            }
+            TunnResult::Err(e) => {
+                self.publish_error(peer, e);
+                tracing::error!(message = "Timer error", error = ?e)
+            }
+            TunnResult::WriteToNetwork(packet) => {
+                let endpoint_addr = if matches!(
+                    Tunn::parse_incoming_packet(packet),
+                    Ok(Packet::HandshakeInit(_))
+                ) {
+                    peer.next_candidate().unwrap_or(endpoint_addr)
+                } else {
+                    endpoint_addr
+                };
+                self.wg_log_sent(peer, packet, Some(endpoint_addr));
+                send(packet, endpoint_addr);
+            }
+            _ => panic!("Unexpected result from update_timers"),
+        };
+
+        peer.check_blackhole();
+
+        for queued in peer.take_admitted_outbound() {
+            let res = {
+                let mut tun = peer.tunnel.lock();
+                tun.encapsulate(&queued, dst)
+            };
+            if let TunnResult::WriteToNetwork(packet) = res {
+                send(packet, endpoint_addr);
+            }
+            self.buffer_pool.put(queued);
+        }
+    }
+
+    /// Run the timers of every peer and return the packets due, for the caller to send to the
+    /// endpoints of the peers, e.g. in one batch. The first call hands the peer timers over to
+    /// the caller for good: the event loop stops running them, so nothing fires twice, and this
+    /// must be called at least every 250ms from then on.
+    pub fn collect_pending_tx(&self) -> Vec<(Arc<Peer>, Vec<u8>)> {
+        self.external_timers.store(true, Ordering::Relaxed);
+
+        let mut pending = vec![];
+        let mut dst = vec![0u8; MAX_UDP_SIZE];
+        for peer in self.peers.values() {
+            self.run_peer_timers(peer, &mut dst, &mut |packet, _| {
+                pending.push((Arc::clone(peer), packet.to_vec()));
+            });
+        }
+        pending
+    }
+
     pub fn trigger_yield(&self) {
         self.queue
             .trigger_notification(self.yield_notice.as_ref().unwrap())
@@ -781,10 +1743,14 @@ impl Device {
     }
 
     fn register_udp_handler(&self, udp: socket2::Socket) -> Result<(), Error> {
+        let is_v4 = udp.local_addr()?.as_socket().map_or(false, |a| a.is_ipv4());
         self.queue.new_event(
             udp.as_raw_fd(),
             Box::new(move |d, t| {
                 // Handler that handles anonymous packets over UDP
+                if d.receive_pause.is_paused() {
+                    return Action::Park;
+                }
                 let mut iter = MAX_ITR;
                 let (private_key, public_key) = d.key_pair.as_ref().expect("Key not set");
 
@@ -796,14 +1762,27 @@ impl Device {
                 // bytes to the buffer, so this casting is safe.
                 let src_buf =
                     unsafe { &mut *(&mut t.src_buf[..] as *mut [u8] as *mut [MaybeUninit<u8>]) };
-                while let Ok((packet_len, addr)) = udp.recv_from(src_buf) {
+                while let Ok((packet_len, addr, ifindex)) =
+                    d.recv_from_listener(&udp, is_v4, src_buf)
+                {
+                    if !d.datagram_limit.admit(packet_len) {
+                        continue;
+                    }
+                    #[cfg(feature = "test-utils")]
+                    if d.faults.lock().as_mut().map_or(false, |faults| faults.drop_received()) {
+                        continue;
+                    }
                     let packet = &t.src_buf[..packet_len];
+                    if !d.handshake_source_filter.admit(packet, addr.as_socket().unwrap().ip()) {
+                        continue;
+                    }
                     // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
                     let parsed_packet =
                         match rate_limiter.verify_packet(Some(addr.as_socket().unwrap().ip()), packet, &mut t.dst_buf) {
                             Ok(packet) => packet,
                             Err(TunnResult::WriteToNetwork(cookie)) => {
-                                if let Err(err) = udp.send_to(cookie, &addr) {
+                                if let Err(err) = d.send_to_listener(cookie, addr.as_socket().unwrap()) {
+                                    d.record_send_error(None, &err);
                                     tracing::warn!(message = "Failed to send cookie", error = ?err, dst = ?addr);
                                 }
                                 continue;
@@ -811,53 +1790,129 @@ impl Device {
                             Err(_) => continue,
                         };
 
-                    let peer = match &parsed_packet {
-                        Packet::HandshakeInit(p) => {
-                            parse_handshake_anon(private_key, public_key, p)
-                                .ok()
-                                .and_then(|hh| {
-                                    d.peers.get(&x25519::PublicKey::from(hh.peer_static_public))
-                                })
-                        }
-                        Packet::HandshakeResponse(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketCookieReply(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketData(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
+                    let src = addr.as_socket().unwrap();
+                    let lookup = |packet: &Packet| match packet {
+                        Packet::HandshakeInit(p) => parse_handshake_anon(private_key, public_key, p)
+                            .ok()
+                            .and_then(|hh| {
+                                d.peers.get(&x25519::PublicKey::from(hh.peer_static_public))
+                            })
+                            .cloned(),
+                        Packet::HandshakeResponse(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)).cloned(),
+                        Packet::PacketCookieReply(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)).cloned(),
+                        Packet::PacketData(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)).cloned(),
                     };
-
-                    let peer = match peer {
-                        None => continue,
+                    // An initiation from a cached source goes straight to the tunnel of its peer,
+                    // skipping the anonymous parse
+                    let cached = match &parsed_packet {
+                        Packet::HandshakeInit(_) => d.cached_source_peer(src),
+                        _ => None,
+                    };
+                    let mut from_cache = cached.is_some();
+                    let peer = match cached.or_else(|| lookup(&parsed_packet)) {
+                        None => {
+                            if let Packet::PacketData(_) = parsed_packet {
+                                d.rehandshake_unknown_index(src, &mut t.dst_buf[..]);
+                            }
+                            continue;
+                        }
                         Some(peer) => peer,
                     };
 
                     let mut flush = false; // Are there packets to send from the queue?
-                    let res = {
-                        let mut tun = peer.tunnel.lock();
-                        tun.handle_verified_packet(parsed_packet, &mut t.dst_buf[..])
+                    let mut received = peer.receive_verified(parsed_packet, &mut t.dst_buf[..]);
+                    // The initiation is from another peer now sending from that source, which the
+                    // tunnel of the cached one tells before changing any of its state
+                    let peer = if from_cache && matches!(received.result, TunnResult::Err(WireGuardError::WrongKey)) {
+                        d.source_cache.lock().remove(&src);
+                        from_cache = false;
+                        let parsed_packet = match Tunn::parse_incoming_packet(packet) {
+                            Ok(packet) => packet,
+                            Err(_) => continue,
+                        };
+                        let peer = match lookup(&parsed_packet) {
+                            None => continue,
+                            Some(peer) => peer,
+                        };
+                        received = peer.receive_verified(parsed_packet, &mut t.dst_buf[..]);
+                        peer
+                    } else {
+                        peer
                     };
+                    let peer = &peer;
+                    let Received {
+                        result: res,
+                        authenticated,
+                        handshake_completed,
+                        rtt,
+                    } = received;
+                    if let Some(rtt) = rtt {
+                        peer.record_rtt(rtt);
+                    }
+                    if handshake_completed {
+                        peer.handshake_completed();
+                        d.events.publish(DeviceEvent::HandshakeCompleted {
+                            public_key: x25519::PublicKey::from(peer.public_key.0),
+                        });
+                    }
+                    if authenticated {
+                        if let Some(src) = addr.as_socket() {
+                            peer.record_rx_source(src);
+                        }
+                        peer.record_rx_ifindex(ifindex);
+                    }
+                    if !matches!(res, TunnResult::Err(_)) {
+                        d.wg_log_received(peer, &t.src_buf[..packet_len], addr.as_socket());
+                    }
+                    let carries_data = matches!(
+                        res,
+                        TunnResult::WriteToTunnelV4(..) | TunnResult::WriteToTunnelV6(..)
+                    );
+                    // Packets failing to authenticate end here, and a few others never authenticated
+                    // are kept from moving the endpoint below
                     match res {
                         TunnResult::Done => {}
                         TunnResult::Err(err) => {
+                            peer.record_decapsulate_error(&err, packet);
+                            d.publish_error(peer, err);
                             tracing::warn!(message = "Failed to handle packet", error = ?err);
+                            let mut init = [0u8; peer::KEEPALIVE_BUF_SIZE];
+                            if let Some(packet) = d.eager_rehandshake(peer, &err, addr.as_socket(), &mut init) {
+                                d.wg_log_sent(peer, packet, addr.as_socket());
+                                if let Err(err) = d.send_retrying(peer, packet, |packet| peer.send_handshake(packet, addr.as_socket()).unwrap_or_else(|| d.send_to_listener(packet, addr.as_socket().unwrap()))) {
+                                    d.record_send_error(Some(peer), &err);
+                                    tracing::warn!(message = "Failed to send packet", error = ?err, dst = ?addr);
+                                }
+                            }
                             continue;
                         },
                         TunnResult::WriteToNetwork(packet) => {
                             flush = true;
-                            if let Err(err) = udp.send_to(packet, &addr) {
+                            d.wg_log_sent(peer, packet, addr.as_socket());
+                            if let Err(err) = d.send_retrying(peer, packet, |packet| peer.send_handshake(packet, addr.as_socket()).unwrap_or_else(|| d.send_to_listener(packet, addr.as_socket().unwrap()))) {
+                                d.record_send_error(Some(peer), &err);
                                 tracing::warn!(message = "Failed to send packet", error = ?err, dst = ?addr);
                             }
                         }
                         TunnResult::WriteToTunnelV4(packet, addr) => {
+                            peer.record_decrypted();
+                            if !d.inner_validation.admit(packet) {
+                                continue;
+                            }
                             if let Some(callback) = &d.config.firewall_process_inbound_callback {
                                 if !callback(&peer.public_key.0, packet) {
                                     continue;
                                 }
                             }
 
-                            if peer.is_allowed_ip(addr) {
-                                t.iface.write4(packet);
+                            if peer.is_allowed_ip(addr)
+                                && d.forward_inner_ttl(packet)
+                                && peer.admit_egress(packet)
+                            {
+                                t.sink.write4(packet);
                                 tracing::trace!(
                                     message = "Writing packet to tunnel v4",
-                                    interface = ?t.iface.name(),
+                                    interface = ?d.iface_name(),
                                     packet_length = packet.len(),
                                     src_addr = ?addr,
                                     public_key = peer.public_key.1
@@ -865,16 +1920,23 @@ impl Device {
                             }
                         }
                         TunnResult::WriteToTunnelV6(packet, addr) => {
+                            peer.record_decrypted();
+                            if !d.inner_validation.admit(packet) {
+                                continue;
+                            }
                             if let Some(callback) = &d.config.firewall_process_inbound_callback {
                                 if !callback(&peer.public_key.0, packet) {
                                     continue;
                                 }
                             }
-                            if peer.is_allowed_ip(addr) {
-                                t.iface.write6(packet);
+                            if peer.is_allowed_ip(addr)
+                                && d.forward_inner_ttl(packet)
+                                && peer.admit_egress(packet)
+                            {
+                                t.sink.write6(packet);
                                 tracing::trace!(
                                     message = "Writing packet to tunnel v6",
-                                    interface = ?t.iface.name(),
+                                    interface = ?d.iface_name(),
                                     packet_length = packet.len(),
                                     src_addr = ?addr,
                                     public_key = peer.public_key.1
@@ -895,7 +1957,8 @@ impl Device {
                                 break;
                             };
 
-                            if let Err(err) = udp.send_to(packet, &addr) {
+                            if let Err(err) = d.send_to_listener(packet, addr.as_socket().unwrap()) {
+                                d.record_send_error(Some(peer), &err);
                                 tracing::warn!(message = "Failed to flush queue", error = ?err, dst = ?addr);
                             }
                         }
@@ -904,14 +1967,24 @@ impl Device {
                     // This packet was OK, that means we want to create a connected socket for this peer
                     let addr = addr.as_socket().unwrap();
                     let ip_addr = addr.ip();
-                    peer.set_endpoint(addr);
-                    if d.config.use_connected_socket {
-                        // No need for aditional checking, as from this point all packets will arive to connected socket handler
-                        if let Ok(sock) = peer.connect_endpoint(d.listen_port) {
-                            d.register_conn_handler(Arc::clone(peer), sock, ip_addr)
-                                .unwrap();
+                    // A peer without an endpoint learns it here, unless told not to
+                    let may_roam = authenticated && !(d.roam_on_keepalive_only && carries_data);
+                    if may_roam && (peer.endpoint().addr.is_some() || peer.endpoint_learning()) {
+                        d.set_peer_endpoint(peer, addr);
+                        if d.config.use_connected_socket {
+                            // No need for aditional checking, as from this point all packets will arive to connected socket handler
+                            if let Ok(sock) = d.connect_roamed_endpoint(peer) {
+                                d.register_conn_handler(Arc::clone(peer), sock, ip_addr)
+                                    .unwrap();
+                            }
                         }
                     }
+                    if !from_cache
+                        && d.source_cache_enabled.load(Ordering::Relaxed)
+                        && peer.endpoint().addr == Some(src)
+                    {
+                        d.source_cache.lock().insert(src, Arc::clone(peer));
+                    }
 
                     iter -= 1;
                     if iter == 0 {
@@ -936,6 +2009,21 @@ impl Device {
                 // The conn_handler handles packet received from a connected UDP socket, associated
                 // with a known peer, this saves us the hustle of finding the right peer. If another
                 // peer gets the same ip, it will be ignored until the socket does not expire.
+                // The socket may be the handshake socket of the peer, so only handshake messages
+                // go back over it, anything else takes the path of the endpoint.
+                if d.receive_pause.is_paused() {
+                    return Action::Park;
+                }
+                // Woken as the socket turned writable, or to read: send what waits for it first
+                let is_endpoint =
+                    peer.endpoint().conn.as_ref().map(|c| c.as_raw_fd()) == Some(udp.as_raw_fd());
+                let mut congested = false;
+                if is_endpoint && (peer.send_queue_len() > 0 || peer.holds_control_packet()) {
+                    let queued = peer.send_queue_len();
+                    // A drain without progress is congested past the socket, the interface queue
+                    // say, so leave it to the tick rather than spin on a writable socket
+                    congested = !d.drain_send_queue(&peer) && peer.send_queue_len() < queued;
+                }
                 let mut iter = MAX_ITR;
 
                 // Safety: the `recv_from` implementation promises not to write uninitialised
@@ -943,42 +2031,109 @@ impl Device {
                 let src_buf =
                     unsafe { &mut *(&mut t.src_buf[..] as *mut [u8] as *mut [MaybeUninit<u8>]) };
 
-                while let Ok(read_bytes) = udp.recv(src_buf) {
+                loop {
+                    let read_bytes = match peer.transport().recv(&udp, src_buf) {
+                        Ok(read_bytes) => read_bytes,
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => {
+                            // Clear the error so the following reads are not failed by it too
+                            let pending = take_error(&udp);
+                            tracing::warn!(
+                                message = "Endpoint socket error",
+                                error = ?err,
+                                pending = ?pending,
+                                public_key = peer.public_key.1
+                            );
+                            break;
+                        }
+                    };
+                    if !d.datagram_limit.admit(read_bytes) {
+                        continue;
+                    }
                     let mut flush = false;
 
-                    let res = {
-                        let mut tun = peer.tunnel.lock();
-                        tun.decapsulate(
-                            Some(peer_addr),
-                            &t.src_buf[..read_bytes],
-                            &mut t.dst_buf[..],
-                        )
-                    };
+                    let Received {
+                        result: res,
+                        authenticated,
+                        handshake_completed,
+                        rtt,
+                    } = peer.decapsulate(
+                        Some(peer_addr),
+                        &t.src_buf[..read_bytes],
+                        &mut t.dst_buf[..],
+                    );
+                    if let Some(rtt) = rtt {
+                        peer.record_rtt(rtt);
+                    }
+                    if handshake_completed {
+                        peer.handshake_completed();
+                        d.events.publish(DeviceEvent::HandshakeCompleted {
+                            public_key: x25519::PublicKey::from(peer.public_key.0),
+                        });
+                    }
+                    if authenticated {
+                        // A connected socket only receives from the endpoint
+                        if let Some(src) = peer.endpoint().addr {
+                            peer.record_rx_source(src);
+                        }
+                    }
+                    if !matches!(res, TunnResult::Err(_)) {
+                        d.wg_log_received(&peer, &t.src_buf[..read_bytes], peer.endpoint().addr);
+                    }
 
                     match res {
                         TunnResult::Done => {}
                         TunnResult::Err(e) => {
+                            peer.record_decapsulate_error(&e, &t.src_buf[..read_bytes]);
+                            d.publish_error(&peer, e);
                             tracing::error!(message="Decapsulate error",
                             error=?e,
-                            public_key = peer.public_key.1)
+                            public_key = peer.public_key.1);
+                            // A connected socket only receives from the endpoint
+                            let endpoint = peer.endpoint().addr;
+                            let mut init = [0u8; peer::KEEPALIVE_BUF_SIZE];
+                            if let Some(packet) =
+                                d.eager_rehandshake(&peer, &e, endpoint, &mut init)
+                            {
+                                d.wg_log_sent(&peer, packet, endpoint);
+                                if let Err(err) = d.send_retrying(&peer, packet, |packet| {
+                                    peer.send_handshake(packet, None)
+                                        .unwrap_or_else(|| d.send_to_endpoint(&peer, packet))
+                                }) {
+                                    d.record_send_error(Some(&peer), &err);
+                                    tracing::warn!(message="Failed to write packet", error = ?err);
+                                }
+                            }
                         }
                         TunnResult::WriteToNetwork(packet) => {
                             flush = true;
-                            if let Err(err) = udp.send(packet) {
+                            d.wg_log_sent(&peer, packet, peer.endpoint().addr);
+                            if let Err(err) = d.send_retrying(&peer, packet, |packet| {
+                                peer.send_handshake(packet, None)
+                                    .unwrap_or_else(|| d.send_to_endpoint(&peer, packet))
+                            }) {
+                                d.record_send_error(Some(&peer), &err);
                                 tracing::warn!(message="Failed to write packet", error = ?err);
                             }
                         }
                         TunnResult::WriteToTunnelV4(packet, addr) => {
+                            peer.record_decrypted();
+                            if !d.inner_validation.admit(packet) {
+                                continue;
+                            }
                             if let Some(callback) = &d.config.firewall_process_inbound_callback {
                                 if !callback(&peer.public_key.0, packet) {
                                     continue;
                                 }
                             }
-                            if peer.is_allowed_ip(addr) {
-                                t.iface.write4(packet);
+                            if peer.is_allowed_ip(addr)
+                                && d.forward_inner_ttl(packet)
+                                && peer.admit_egress(packet)
+                            {
+                                t.sink.write4(packet);
                                 tracing::trace!(
                                     message = "Writing packet to tunnel v4",
-                                    interface = ?t.iface.name(),
+                                    interface = ?d.iface_name(),
                                     packet_length = packet.len(),
                                     src_addr = ?addr,
                                     public_key = peer.public_key.1
@@ -986,16 +2141,23 @@ impl Device {
                             }
                         }
                         TunnResult::WriteToTunnelV6(packet, addr) => {
+                            peer.record_decrypted();
+                            if !d.inner_validation.admit(packet) {
+                                continue;
+                            }
                             if let Some(callback) = &d.config.firewall_process_inbound_callback {
                                 if !callback(&peer.public_key.0, packet) {
                                     continue;
                                 }
                             }
-                            if peer.is_allowed_ip(addr) {
-                                t.iface.write6(packet);
+                            if peer.is_allowed_ip(addr)
+                                && d.forward_inner_ttl(packet)
+                                && peer.admit_egress(packet)
+                            {
+                                t.sink.write6(packet);
                                 tracing::trace!(
                                     message = "Writing packet to tunnel v6",
-                                    interface = ?t.iface.name(),
+                                    interface = ?d.iface_name(),
                                     packet_length = packet.len(),
                                     src_addr = ?addr,
                                     public_key = peer.public_key.1
@@ -1014,7 +2176,8 @@ impl Device {
                             let TunnResult::WriteToNetwork(packet) = res else {
                                 break;
                             };
-                            if let Err(err) = udp.send(packet) {
+                            if let Err(err) = d.send_to_endpoint(&peer, packet) {
+                                d.record_send_error(Some(&peer), &err);
                                 tracing::warn!(message="Failed to flush queue", error = ?err);
                             }
                         }
@@ -1025,7 +2188,7 @@ impl Device {
                         break;
                     }
                 }
-                Action::Continue
+                Action::Writable(congested)
             }),
         )?;
         Ok(())
@@ -1041,14 +2204,11 @@ impl Device {
                 // * Determine peer based on packet destination ip
                 // * Encapsulate the packet for the given peer
                 // * Send encapsulated packet to the peer's endpoint
-                let mtu = d.mtu.load(Ordering::Relaxed);
+                // Packets over the MTU are read whole, for `encapsulate_outbound` to handle
+                let max_len = MAX_UDP_SIZE - DATA_OVERHEAD_SZ;
 
-                let udp4 = d.udp4.as_ref().expect("Not connected");
-                let udp6 = d.udp6.as_ref().expect("Not connected");
-
-                let peers = &d.peers_by_ip;
                 for _ in 0..MAX_ITR {
-                    let src = match iface.read(&mut t.src_buf[..mtu]) {
+                    let src = match iface.read(&mut t.src_buf[..max_len]) {
                         Ok(src) => src,
                         Err(Error::IfaceRead(e)) => {
                             let ek = e.kind();
@@ -1068,137 +2228,3186 @@ impl Device {
                         }
                     };
 
-                    let dst_addr = match Tunn::dst_address(src) {
-                        Some(addr) => addr,
-                        None => continue,
-                    };
+                    d.encapsulate_outbound(src, &mut t.dst_buf[..]);
+                }
+                Action::Continue
+            }),
+        )?;
+        Ok(())
+    }
 
-                    let peer = match peers.find(dst_addr) {
-                        Some(peer) => peer,
-                        None => continue,
-                    };
+    fn register_packet_source_handler(
+        &mut self,
+        source: Arc<dyn PacketSource>,
+    ) -> Result<(), Error> {
+        let event = self.queue.new_notifier(Box::new(move |d, t| {
+            // Same flow as the iface handler, triggered by the application through the waker
+            let waker = d.packet_source_waker.as_ref().unwrap();
+            waker.clear();
+            // Packets over the MTU are read whole, for `encapsulate_outbound` to handle
+            let max_len = MAX_UDP_SIZE - DATA_OVERHEAD_SZ;
+
+            for _ in 0..MAX_ITR {
+                let len = match source.read(&mut t.src_buf[..max_len]) {
+                    Some(len) => len,
+                    None => return Action::Continue,
+                };
+                if let Some(src) = t.src_buf[..max_len].get(..len) {
+                    d.encapsulate_outbound(src, &mut t.dst_buf[..]);
+                }
+            }
+            // Let the other events run before reading on
+            waker.wake();
+            Action::Continue
+        }))?;
+        self.packet_source_waker = Some(PacketSourceWaker::new(Arc::clone(&self.queue), event));
+        Ok(())
+    }
 
+    /// Encapsulate a plaintext packet read from the tun interface or the packet source, and
+    /// send it to the peer it is routed to
+    fn encapsulate_outbound(&self, src: &[u8], dst: &mut [u8]) {
+        let dst_addr = match Tunn::dst_address(src) {
+            Some(addr) => addr,
+            None => return,
+        };
 
-                    if let Some(callback) = &d.config.firewall_process_outbound_callback {
-                        if !callback(&peer.public_key.0, src) {
-                            continue;
+        let peer = match self.peers_by_ip.find(dst_addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        if self.icmp_frag_needed.load(Ordering::Relaxed) && inner::ipv4_dont_fragment(src) {
+            let mtu = self.inner_mtu(peer);
+            if src.len() > mtu {
+                tracing::trace!(
+                    message = "Packet exceeds the MTU",
+                    len = src.len(),
+                    mtu = mtu
+                );
+                let reply = inner::icmp_frag_needed(src, mtu.min(u16::MAX as usize) as u16);
+                if let Some(reply) = reply.filter(|_| self.admit_icmp_error()) {
+                    self.sink.write4(&reply);
+                }
+                return;
+            }
+        }
+
+        if let Some(callback) = &self.config.firewall_process_outbound_callback {
+            if !callback(&peer.public_key.0, src) {
+                return;
+            }
+        }
+
+        if !peer.admit_outbound(src, &self.buffer_pool) {
+            return;
+        }
+
+        let res = {
+            let mut tun = peer.tunnel.lock();
+            tun.encapsulate(src, dst)
+        };
+        match res {
+            TunnResult::Done => {}
+            TunnResult::Err(e) => {
+                self.publish_error(peer, e);
+                tracing::error!(message = "Encapsulate error",
+                    error = ?e,
+                    public_key = peer.public_key.1)
+            }
+            TunnResult::WriteToNetwork(packet) => {
+                // Queued packets go first, while the socket is still congested new ones queue
+                // up behind them
+                if peer.send_queue_len() > 0 && !self.drain_send_queue(peer) {
+                    if peer.try_enqueue_pooled(packet, &self.buffer_pool).is_err() {
+                        tracing::debug!(
+                            message = "Send queue full, dropping packet",
+                            public_key = peer.public_key.1
+                        );
+                    }
+                    return;
+                }
+                let endpoint = peer.endpoint();
+                self.wg_log_sent(peer, packet, endpoint.addr);
+                if let Some(conn) = endpoint.conn.as_ref() {
+                    // Prefer to send using the connected socket
+                    if let Err(err) = self
+                        .send_retrying(peer, packet, |packet| peer.transport().send(conn, packet))
+                    {
+                        self.record_send_error(Some(peer), &err);
+                        if is_congested(&err)
+                            && peer.try_enqueue_pooled(packet, &self.buffer_pool).is_ok()
+                        {
+                            return;
                         }
+                        tracing::debug!(message = "Failed to send packet with the connected socket", error = ?err);
+                        drop(endpoint);
+                        peer.shutdown_endpoint();
+                    } else {
+                        tracing::trace!(
+                            "Pkt -> ConnSock ({:?}), len: {}, dst_addr: {}",
+                            endpoint.addr,
+                            packet.len(),
+                            dst_addr
+                        );
                     }
-
-                    let res = {
-                        let mut tun = peer.tunnel.lock();
-                        tun.encapsulate(src, &mut t.dst_buf[..])
-                    };
-                    match res {
-                        TunnResult::Done => {}
-                        TunnResult::Err(e) => {
-                            tracing::error!(message = "Encapsulate error",
-                                error = ?e,
-                                public_key = peer.public_key.1)
+                } else if let Some(addr @ SocketAddr::V4(_)) = endpoint.addr {
+                    if let Err(err) = self
+                        .send_retrying(peer, packet, |packet| self.send_to_listener(packet, addr))
+                    {
+                        self.record_send_error(Some(peer), &err);
+                        if is_congested(&err)
+                            && peer.try_enqueue_pooled(packet, &self.buffer_pool).is_ok()
+                        {
+                            return;
                         }
-                        TunnResult::WriteToNetwork(packet) => {
-                            let endpoint = peer.endpoint();
-                            if let Some(conn) = endpoint.conn.as_ref() {
-                                // Prefer to send using the connected socket
-                                if let Err(err) = conn.send(packet) {
-                                    tracing::debug!(message = "Failed to send packet with the connected socket", error = ?err);
-                                    drop(endpoint);
-                                    peer.shutdown_endpoint();
-                                } else {
-                                    tracing::trace!(
-                                        "Pkt -> ConnSock ({:?}), len: {}, dst_addr: {}",
-                                        endpoint.addr,
-                                        packet.len(),
-                                        dst_addr
-                                    );
-                                }
-                            } else if let Some(addr @ SocketAddr::V4(_)) = endpoint.addr {
-                                if let Err(err) = udp4.send_to(packet, &addr.into()) {
-                                    tracing::warn!(message = "Failed to write packet to network v4", error = ?err, dst = ?addr);
-                                } else {
-                                    tracing::trace!(
-                                        message = "Writing packet to network v4",
-                                        interface = ?t.iface.name(),
-                                        packet_length = packet.len(),
-                                        src_addr = ?addr,
-                                        public_key = peer.public_key.1
-                                    );
-                                }
-                            } else if let Some(addr @ SocketAddr::V6(_)) = endpoint.addr {
-                                if let Err(err) = udp6.send_to(packet, &addr.into()) {
-                                    tracing::warn!(message = "Failed to write packet to network v6", error = ?err, dst = ?addr);
-                                } else {
-                                    tracing::trace!(
-                                        message = "Writing packet to network v6",
-                                        interface = ?t.iface.name(),
-                                        packet_length = packet.len(),
-                                        src_addr = ?addr,
-                                        public_key = peer.public_key.1
-                                    );
-                                }
-                            } else {
-                                tracing::error!("No endpoint");
-                            }
+                        tracing::warn!(message = "Failed to write packet to network v4", error = ?err, dst = ?addr);
+                    } else {
+                        tracing::trace!(
+                            message = "Writing packet to network v4",
+                            interface = ?self.iface_name(),
+                            packet_length = packet.len(),
+                            src_addr = ?addr,
+                            public_key = peer.public_key.1
+                        );
+                    }
+                } else if let Some(addr @ SocketAddr::V6(_)) = endpoint.addr {
+                    if let Err(err) = self
+                        .send_retrying(peer, packet, |packet| self.send_to_listener(packet, addr))
+                    {
+                        self.record_send_error(Some(peer), &err);
+                        if is_congested(&err)
+                            && peer.try_enqueue_pooled(packet, &self.buffer_pool).is_ok()
+                        {
+                            return;
                         }
-                        _ => panic!("Unexpected result from encapsulate"),
-                    };
+                        tracing::warn!(message = "Failed to write packet to network v6", error = ?err, dst = ?addr);
+                    } else {
+                        tracing::trace!(
+                            message = "Writing packet to network v6",
+                            interface = ?self.iface_name(),
+                            packet_length = packet.len(),
+                            src_addr = ?addr,
+                            public_key = peer.public_key.1
+                        );
+                    }
+                } else {
+                    tracing::error!("No endpoint");
                 }
-                Action::Continue
-            }),
-        )?;
-        Ok(())
+            }
+            _ => panic!("Unexpected result from encapsulate"),
+        };
     }
 
-    pub fn iface(&self) -> &TunSocket {
-        &self.iface
+    /// The tun interface, `None` when built with packet io
+    pub fn iface(&self) -> Option<&TunSocket> {
+        self.iface.as_deref()
     }
-}
 
-/// A basic linear-feedback shift register implemented as xorshift, used to
-/// distribute peer indexes across the 24-bit address space reserved for peer
-/// identification.
-/// The purpose is to obscure the total number of peers using the system and to
-/// ensure it requires a non-trivial amount of processing power and/or samples
-/// to guess other peers' indices. Anything more ambitious than this is wasted
-/// with only 24 bits of space.
-struct IndexLfsr {
-    initial: u32,
-    lfsr: u32,
-    mask: u32,
-}
+    fn iface_name(&self) -> Option<String> {
+        self.iface.as_ref().and_then(|iface| iface.name().ok())
+    }
 
-impl IndexLfsr {
-    /// Generate a random 24-bit nonzero integer
-    fn random_index() -> u32 {
-        const LFSR_MAX: u32 = 0xffffff; // 24-bit seed
-        loop {
-            let i = OsRng.next_u32() & LFSR_MAX;
-            if i > 0 {
-                // LFSR seed must be non-zero
-                return i;
-            }
-        }
+    /// Wakes the device to read from its packet source, `None` when built with a tun interface
+    pub fn packet_source_waker(&self) -> Option<PacketSourceWaker> {
+        self.packet_source_waker.clone()
     }
 
-    /// Generate the next value in the pseudorandom sequence
-    fn next(&mut self) -> u32 {
-        // 24-bit polynomial for randomness. This is arbitrarily chosen to
-        // inject bitflips into the value.
-        const LFSR_POLY: u32 = 0xd80000; // 24-bit polynomial
-        let value = self.lfsr - 1; // lfsr will never have value of 0
-        self.lfsr = (self.lfsr >> 1) ^ ((0u32.wrapping_sub(self.lfsr & 1u32)) & LFSR_POLY);
-        assert!(self.lfsr != self.initial, "Too many peers created");
-        value ^ self.mask
+    /// Stop processing datagrams received from the network, leaving the sockets open so the
+    /// kernel keeps queueing them. The event loop stops polling them until resumed. Once a socket receive buffer (`SO_RCVBUF`) is full, further
+    /// datagrams are dropped by the kernel, so the pause should be kept short.
+    pub fn pause_receive(&self) {
+        self.receive_pause.pause();
     }
-}
 
-impl Default for IndexLfsr {
-    fn default() -> Self {
-        let seed = Self::random_index();
-        IndexLfsr {
-            initial: seed,
-            lfsr: seed,
-            mask: Self::random_index(),
-        }
+    /// Resume processing datagrams, starting with those queued while paused
+    pub fn resume_receive(&self) {
+        self.receive_pause.resume();
+        self.queue.rearm_parked();
+    }
+
+    /// How long receiving has been paused for, `None` when not paused
+    pub fn receive_paused_for(&self) -> Option<std::time::Duration> {
+        self.receive_pause.paused_for()
+    }
+
+    /// Only accept handshake initiations from these source prefixes, an empty list accepts
+    /// all sources. This is a cheap pre-filter against scanners, not a substitute for crypto.
+    pub fn set_handshake_source_filter(&self, prefixes: Vec<(IpAddr, u8)>) {
+        self.handshake_source_filter.set(prefixes);
+    }
+
+    /// The interface public key, derived from the private key when it was set
+    pub fn public_key(&self) -> Option<x25519::PublicKey> {
+        self.key_pair.as_ref().map(|(_, public_key)| *public_key)
+    }
+
+    /// The interface public key in the base64 form used by WireGuard config files
+    pub fn public_key_base64(&self) -> Option<String> {
+        self.public_key()
+            .map(|public_key| base64::encode(public_key.as_bytes()))
+    }
+
+    /// Public keys of the peers labelled with `tag`, ordered by key
+    pub fn peers_with_tag(&self, tag: &str) -> Vec<x25519::PublicKey> {
+        tagged_peers(&self.peers, tag)
+    }
+
+    /// Every local index in use, mapped to the peer owning it. Includes each peer's
+    /// base index, as well as the indices of its current and previous sessions.
+    pub fn active_session_indices(&self) -> Vec<(u32, x25519::PublicKey)> {
+        let mut indices = vec![];
+        for (pub_key, peer) in self.peers.iter() {
+            indices.push((peer.index(), *pub_key));
+            for index in peer.tunnel.lock().session_indices() {
+                indices.push((index, *pub_key));
+            }
+        }
+        indices
+    }
+
+    /// Enable `SO_RXQ_OVFL` on the listening sockets, so the kernel reports how many datagrams
+    /// it dropped because a socket's receive queue was full
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_rxq_ovfl(&self) -> Result<(), Error> {
+        for sock in self.udp4.iter().chain(self.udp6.iter()) {
+            set_rxq_ovfl(sock)?;
+        }
+        self.rxq_overflow.enabled.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn set_rxq_ovfl(&self) -> Result<(), Error> {
+        Err(Error::SetSockOpt(
+            "SO_RXQ_OVFL is not supported on this platform".to_owned(),
+        ))
+    }
+
+    /// Learn which interface the packets of each peer arrive on, see `Peer::last_rx_ifindex`,
+    /// from the packet info of the listening sockets, see `transport::set_recv_pktinfo`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_recv_pktinfo(&self) -> Result<(), Error> {
+        for sock in self.udp4.iter().chain(self.udp6.iter()) {
+            set_recv_pktinfo(sock)?;
+        }
+        self.recv_pktinfo.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn set_recv_pktinfo(&self) -> Result<(), Error> {
+        Err(Error::SetSockOpt(
+            "Packet info is not supported on this platform".to_owned(),
+        ))
+    }
+
+    /// Do path MTU discovery on the connected sockets of the peers, and on those connected
+    /// later, see `transport::set_path_mtu_discovery`. The timers then drain the packet too big
+    /// messages every five seconds and keep `Peer::path_mtu` up to date. The listen sockets are
+    /// left alone, so this needs `use_connected_socket` to learn anything.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_path_mtu_discovery(&mut self) -> Result<(), Error> {
+        self.path_mtu_discovery = true;
+        for peer in self.peers.values() {
+            if let Some(ref sock) = peer.endpoint().conn {
+                let res = set_path_mtu_discovery(sock);
+                self.optional_sockopt("path MTU discovery", res, Some(peer))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn set_path_mtu_discovery(&mut self) -> Result<(), Error> {
+        Err(Error::SetSockOpt(
+            "Path MTU discovery is not supported on this platform".to_owned(),
+        ))
+    }
+
+    /// Learn the path MTU of each peer, see `set_path_mtu_discovery`. Every
+    /// `PATH_MTU_UPDATE_INTERVAL` only, it takes a few syscalls per peer.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn update_path_mtus(&self) {
+        if !self.path_mtu_discovery {
+            return;
+        }
+        let now = std::time::Instant::now();
+        {
+            let mut updated = self.path_mtus_updated.lock();
+            if updated.map_or(false, |updated| now - updated < PATH_MTU_UPDATE_INTERVAL) {
+                return;
+            }
+            *updated = Some(now);
+        }
+        for peer in self.peers.values() {
+            if let Err(err) = peer.update_path_mtu(now) {
+                tracing::debug!(message = "Failed to learn the path MTU", error = ?err, public_key = peer.public_key.1);
+            }
+        }
+    }
+
+    /// The number of datagrams the kernel dropped on the IPv4 and IPv6 listening sockets,
+    /// as last reported by `SO_RXQ_OVFL`. Also in the metrics, and in the UAPI get output once
+    /// enabled. For sockets of your own see `transport::recv_with_info`.
+    pub fn rxq_ovfl_drops(&self) -> (u32, u32) {
+        (
+            self.rxq_overflow.v4.load(Ordering::Relaxed),
+            self.rxq_overflow.v6.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Bytes queued for sending on the IPv4 and IPv6 listening sockets
+    pub fn outq_len(&self) -> Result<(usize, usize), Error> {
+        Ok((
+            self.udp4.as_ref().map_or(Ok(0), outq_len)?,
+            self.udp6.as_ref().map_or(Ok(0), outq_len)?,
+        ))
+    }
+
+    /// Bytes queued for reading on the IPv4 and IPv6 listening sockets
+    pub fn inq_len(&self) -> Result<(usize, usize), Error> {
+        Ok((
+            self.udp4.as_ref().map_or(Ok(0), inq_len)?,
+            self.udp6.as_ref().map_or(Ok(0), inq_len)?,
+        ))
+    }
+
+    /// Receive a datagram from a listening socket, recording the `SO_RXQ_OVFL` drop count if
+    /// enabled. Also returns the index of the interface it arrived on, with `set_recv_pktinfo`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn recv_from_listener(
+        &self,
+        udp: &socket2::Socket,
+        is_v4: bool,
+        buf: &mut [MaybeUninit<u8>],
+    ) -> io::Result<(usize, socket2::SockAddr, Option<u32>)> {
+        if !self.rxq_overflow.enabled.load(Ordering::Relaxed)
+            && !self.recv_pktinfo.load(Ordering::Relaxed)
+        {
+            let (len, addr) = udp.recv_from(buf)?;
+            return Ok((len, addr, None));
+        }
+
+        let info = transport::recv_with_info(udp, buf)?;
+        if let Some(count) = info.rxq_ovfl_drops {
+            let dropped = if is_v4 {
+                &self.rxq_overflow.v4
+            } else {
+                &self.rxq_overflow.v6
+            };
+            dropped.store(count, Ordering::Relaxed);
+        }
+        Ok((info.len, info.source.into(), info.ifindex))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn recv_from_listener(
+        &self,
+        udp: &socket2::Socket,
+        _is_v4: bool,
+        buf: &mut [MaybeUninit<u8>],
+    ) -> io::Result<(usize, socket2::SockAddr, Option<u32>)> {
+        let (len, addr) = udp.recv_from(buf)?;
+        Ok((len, addr, None))
+    }
+
+    /// Set the largest datagram accepted from the network, anything larger is dropped
+    pub fn set_max_datagram_size(&self, size: usize) {
+        self.datagram_limit.set_max_size(size);
+    }
+
+    /// The number of datagrams dropped for exceeding the maximum datagram size
+    pub fn oversized_datagrams(&self) -> u64 {
+        self.datagram_limit.dropped()
+    }
+
+    /// Check the headers of decrypted packets before they are written to the tunnel: the
+    /// version and lengths, and for IPv4 the header checksum. Off by default.
+    pub fn set_validate_inner_checksums(&self, enabled: bool) {
+        self.inner_validation
+            .enabled
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    /// Decrement the TTL, or hop limit, of decrypted packets before they are written to the
+    /// tunnel, as a router forwarding them would. Packets out of hops are dropped and answered
+    /// with an ICMP time exceeded through the tunnel, so traceroute sees a hop and routing loops
+    /// end. The device can't tell which packets the host keeps, so those addressed to the host
+    /// itself are decremented too, and one arriving with a TTL of 1 is dropped where a router
+    /// delivering it locally would have taken it. No ICMP error answers another, nor a fragment
+    /// but the first, and the device sends at most 100 ICMP errors a second. Packets with a
+    /// malformed IP header are dropped. Off by default, the tunnel passing packets as they are.
+    pub fn set_decrement_inner_ttl(&self, enabled: bool) {
+        self.decrement_inner_ttl.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Drop IPv4 packets with Don't Fragment set too big for the tunnel, and answer them with an
+    /// ICMP fragmentation needed through the tunnel, at most 100 ICMP errors a second. Too big
+    /// is over the MTU of the device or over what the path MTU learned for the peer, see
+    /// `Peer::path_mtu`, leaves room for. A tun interface hands over no packet beyond its own
+    /// MTU, so there only the path MTU makes a difference. Off by default, such packets are sent
+    /// like any other.
+    pub fn set_icmp_frag_needed(&self, enabled: bool) {
+        self.icmp_frag_needed.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The largest inner packet `peer` can be sent without fragmenting the datagram, for
+    /// `set_icmp_frag_needed`
+    fn inner_mtu(&self, peer: &Peer) -> usize {
+        let mtu = self.mtu.load(Ordering::Relaxed);
+        let Some(path_mtu) = peer.path_mtu() else {
+            return mtu;
+        };
+        let ip_udp = match peer.endpoint().addr {
+            Some(SocketAddr::V6(_)) => IPV6_UDP_HEADER_SZ,
+            _ => IPV4_UDP_HEADER_SZ,
+        };
+        mtu.min(usize::from(path_mtu).saturating_sub(ip_udp + DATA_OVERHEAD_SZ))
+    }
+
+    /// Decrement the TTL of the decrypted `packet` if `set_decrement_inner_ttl` asks to.
+    /// Returns whether to write it to the tunnel, when not the source may be sent an ICMP time
+    /// exceeded.
+    fn forward_inner_ttl(&self, packet: &mut [u8]) -> bool {
+        if !self.decrement_inner_ttl.load(Ordering::Relaxed) {
+            return true;
+        }
+        match inner::decrement_ttl(packet) {
+            inner::TtlDecrement::Forward => return true,
+            inner::TtlDecrement::Malformed => {
+                tracing::trace!(message = "Malformed inner packet", len = packet.len());
+                return false;
+            }
+            inner::TtlDecrement::Expired => {}
+        }
+        tracing::trace!(message = "Inner packet out of hops", len = packet.len());
+        if let Some(reply) = inner::icmp_time_exceeded(packet) {
+            if self.admit_icmp_error() {
+                // Fits the reply encapsulated, or the handshake initiation it may start instead
+                let mut dst = vec![0u8; reply.len() + peer::KEEPALIVE_BUF_SIZE];
+                self.encapsulate_outbound(&reply, &mut dst);
+            }
+        }
+        false
+    }
+
+    /// Whether the device may generate another ICMP error under `ICMP_ERRORS_PER_SEC`
+    fn admit_icmp_error(&self) -> bool {
+        self.icmp_error_limit.try_consume(1)
+    }
+
+    /// Start a handshake with a peer when it sends data for a session this side doesn't have,
+    /// e.g. one dropped by a restart, instead of waiting for the peer's timers to notice. That
+    /// is data for the expired session of a peer, or data with an index of no session at all
+    /// from the endpoint of a peer. Only for datagrams from the peer's endpoint, and at most once
+    /// per rekey timeout per peer, so spoofed packets can't turn the device into an amplifier.
+    /// The search for the peer of an unknown index is bounded device-wide too. Off by default.
+    pub fn set_eager_rehandshake_on_unknown_index(&self, enabled: bool) {
+        self.eager_rehandshake.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The initiation to send for `err` on a datagram from `src`, if eager rehandshakes apply
+    fn eager_rehandshake<'a>(
+        &self,
+        peer: &Peer,
+        err: &WireGuardError,
+        src: Option<SocketAddr>,
+        dst: &'a mut [u8],
+    ) -> Option<&'a [u8]> {
+        if !self.eager_rehandshake.load(Ordering::Relaxed)
+            || !matches!(err, WireGuardError::NoCurrentSession)
+            || src.is_none()
+            || src != peer.endpoint().addr
+        {
+            return None;
+        }
+        peer.eager_rehandshake(dst)
+    }
+
+    /// Start a handshake with the peer whose endpoint is `src`, for a data packet from there
+    /// with the index of no session of ours, see `set_eager_rehandshake_on_unknown_index`
+    fn rehandshake_unknown_index(&self, src: SocketAddr, dst: &mut [u8]) {
+        if !self.eager_rehandshake.load(Ordering::Relaxed)
+            || !self.unknown_index_limit.try_consume(1)
+        {
+            return;
+        }
+        let Some(peer) = self
+            .peers
+            .values()
+            .find(|peer| peer.endpoint().addr == Some(src))
+        else {
+            return;
+        };
+        if let Some(packet) = peer.eager_rehandshake(dst) {
+            self.wg_log_sent(peer, packet, Some(src));
+            if let Err(err) = self.send_retrying(peer, packet, |packet| {
+                peer.send_handshake(packet, Some(src))
+                    .unwrap_or_else(|| self.send_to_listener(packet, src))
+            }) {
+                self.record_send_error(Some(peer), &err);
+                tracing::warn!(message = "Failed to send packet", error = ?err, dst = ?src);
+            }
+        }
+    }
+
+    /// The number of decrypted packets dropped for a malformed header
+    pub fn invalid_inner_packets(&self) -> u64 {
+        self.inner_validation.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Packets dropped because the kernel ran out of buffer space to send them, in the socket or
+    /// in the queue of the interface. Unlike other send errors this is local congestion, a sign
+    /// to raise `SO_SNDBUF` or slow down rather than of a network problem.
+    pub fn send_buffer_full(&self) -> u64 {
+        self.send_buffer_full.load(Ordering::Relaxed)
+    }
+
+    /// Send what `peer` has queued, see `Peer::try_enqueue`, after the control packet it holds,
+    /// see `set_handshake_send_retries`. Returns whether the queue emptied.
+    fn drain_send_queue(&self, peer: &Peer) -> bool {
+        let endpoint = peer.endpoint();
+        match (&endpoint.conn, endpoint.addr) {
+            (Some(conn), _) => {
+                peer.retry_held_control(|packet| {
+                    peer.send_handshake(packet, None)
+                        .unwrap_or_else(|| peer.transport().send(conn, packet))
+                }) && peer.drain_send_queue(&self.buffer_pool, |packet| {
+                    peer.transport().send(conn, packet)
+                })
+            }
+            (None, Some(addr)) => {
+                peer.retry_held_control(|packet| {
+                    peer.send_handshake(packet, Some(addr))
+                        .unwrap_or_else(|| self.send_to_listener(packet, addr))
+                }) && peer.drain_send_queue(&self.buffer_pool, |packet| {
+                    self.send_to_listener(packet, addr)
+                })
+            }
+            (None, None) => false,
+        }
+    }
+
+    /// Count a failed send, to `peer` if it is known, when it failed for lack of buffer space
+    fn record_send_error(&self, peer: Option<&Peer>, err: &io::Error) {
+        if err.raw_os_error() != Some(libc::ENOBUFS) {
+            return;
+        }
+        self.send_buffer_full.fetch_add(1, Ordering::Relaxed);
+        if let Some(peer) = peer {
+            peer.record_send_buffer_full();
+        }
+    }
+
+    /// A snapshot of the peers and sockets of the device, cheap enough for a health endpoint.
+    /// Locks each tunnel in turn.
+    pub fn health(&self) -> DeviceHealth {
+        let mut health = DeviceHealth {
+            peers: self.peers.len(),
+            established_peers: 0,
+            stale_peers: 0,
+            last_error: match self.last_error.load(Ordering::Relaxed) {
+                0 => None,
+                millis => Some(UNIX_EPOCH + std::time::Duration::from_millis(millis)),
+            },
+            listening: self.udp4.is_some() || self.udp6.is_some(),
+            ready: false,
+        };
+        for peer in self.peers.values() {
+            match peer.tunnel.lock().time_since_last_handshake() {
+                Some(age) if age > STALE_HANDSHAKE_AGE => health.stale_peers += 1,
+                Some(_) => health.established_peers += 1,
+                None => {}
+            }
+        }
+        health.ready = health.listening
+            && health.established_peers >= self.ready_min_established.load(Ordering::Relaxed);
+        health
+    }
+
+    /// The state of every peer, ordered by public key. The tunnel of each peer is locked once,
+    /// so its handshake time, counters and keepalive are consistent with each other.
+    pub fn peer_snapshots(&self) -> Vec<PeerSnapshot> {
+        let mut snapshots: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(public_key, peer)| {
+                let (last_handshake, stats, persistent_keepalive) = {
+                    let tun = peer.tunnel.lock();
+                    (
+                        tun.last_handshake_time(),
+                        tun.stats(),
+                        tun.persistent_keepalive(),
+                    )
+                };
+                PeerSnapshot {
+                    public_key: *public_key,
+                    endpoint: peer.endpoint().addr,
+                    last_handshake: last_handshake.map(|since_epoch| UNIX_EPOCH + since_epoch),
+                    tx_bytes: stats.tx_bytes,
+                    rx_bytes: stats.rx_bytes,
+                    persistent_keepalive,
+                    allowed_ips: peer.allowed_ips(),
+                    send_queue_len: peer.send_queue_len(),
+                }
+            })
+            .collect();
+        snapshots.sort_unstable_by(|a, b| a.public_key.as_bytes().cmp(b.public_key.as_bytes()));
+        snapshots
+    }
+
+    /// The established peers `health` requires to report the device ready, 1 by default. With 0
+    /// a bound listen socket is enough.
+    pub fn set_ready_min_established_peers(&self, peers: usize) {
+        self.ready_min_established.store(peers, Ordering::Relaxed);
+    }
+
+    /// A channel receiving the events of all peers from now on, see [`events`]. A subscriber
+    /// that falls `events::EVENT_QUEUE_LEN` events behind misses the newer ones until it catches
+    /// up, so it can't stall the event loop. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        self.events.subscribe()
+    }
+
+    /// The number of events subscribers missed because their queue was full
+    pub fn dropped_events(&self) -> u64 {
+        self.events.dropped()
+    }
+
+    fn publish_error(&self, peer: &Peer, error: WireGuardError) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_error
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+        if let Some(suppressed) = peer.admit_error_event() {
+            self.events.publish(DeviceEvent::Error {
+                public_key: x25519::PublicKey::from(peer.public_key.0),
+                error,
+                suppressed,
+            });
+        }
+    }
+
+    fn set_peer_endpoint(&self, peer: &Peer, addr: SocketAddr) {
+        if peer.endpoint().addr == Some(addr) {
+            return;
+        }
+        {
+            let mut cache = self.source_cache.lock();
+            cache.remove_matching(|cached| cached.index() == peer.index());
+            cache.remove(&addr);
+        }
+        peer.set_endpoint(addr);
+        self.events.publish(DeviceEvent::EndpointChanged {
+            public_key: x25519::PublicKey::from(peer.public_key.0),
+            endpoint: addr,
+        });
+    }
+}
+
+/// A hostname endpoint for the resolver thread, see `Device::resolve_in_background`
+struct Resolution {
+    peer: Arc<Peer>,
+    hostname: String,
+    pref: AddressFamilyPref,
+}
+
+/// Kernel receive queue drop counts of the listening sockets, reported via `SO_RXQ_OVFL`
+#[derive(Default)]
+struct RxqOverflow {
+    enabled: AtomicBool,
+    v4: AtomicU32,
+    v6: AtomicU32,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_rxq_ovfl(sock: &socket2::Socket) -> Result<(), Error> {
+    transport::set_rxq_ovfl(sock).map_err(|err| Error::SetSockOpt(err.to_string()))
+}
+
+fn tagged_peers(
+    peers: &HashMap<x25519::PublicKey, Arc<Peer>>,
+    tag: &str,
+) -> Vec<x25519::PublicKey> {
+    let mut tagged: Vec<_> = peers
+        .iter()
+        .filter(|(_, peer)| peer.has_tag(tag))
+        .map(|(pub_key, _)| *pub_key)
+        .collect();
+    tagged.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    tagged
+}
+
+/// Bytes in the send queue of a socket not yet sent, via `SIOCOUTQ`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn outq_len(sock: &socket2::Socket) -> Result<usize, Error> {
+    queue_len(sock, libc::TIOCOUTQ as _)
+}
+
+/// Bytes in the receive queue of a socket not yet read, via `SIOCINQ`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn inq_len(sock: &socket2::Socket) -> Result<usize, Error> {
+    queue_len(sock, libc::FIONREAD as _)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn queue_len(sock: &socket2::Socket, request: libc::Ioctl) -> Result<usize, Error> {
+    let mut len: libc::c_int = 0;
+    match unsafe { libc::ioctl(sock.as_raw_fd(), request, &mut len) } {
+        -1 => Err(Error::IOCtl(io::Error::last_os_error())),
+        _ => Ok(len as usize),
+    }
+}
+
+/// Read and clear the pending asynchronous error of a socket, via `SO_ERROR`. On a connected
+/// socket this is where an ICMP port unreachable from the endpoint is reported.
+pub fn take_error(sock: &socket2::Socket) -> Result<Option<io::Error>, Error> {
+    sock.take_error().map_err(Error::GetSockOpt)
+}
+
+/// Whether a send failed only because the socket can't take more right now
+pub(crate) fn is_congested(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+/// A random number from 0 to 1
+fn jitter() -> f64 {
+    f64::from(OsRng.next_u32()) / f64::from(u32::MAX)
+}
+
+/// Whether `packet` is a handshake message, a cookie reply or a keepalive, rather than data
+fn is_control_packet(packet: &[u8]) -> bool {
+    match Tunn::parse_incoming_packet(packet) {
+        Ok(Packet::PacketData(_)) => packet.len() == DATA_OVERHEAD_SZ,
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn outq_len(_sock: &socket2::Socket) -> Result<usize, Error> {
+    Err(Error::IOCtl(io::ErrorKind::Unsupported.into()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn inq_len(_sock: &socket2::Socket) -> Result<usize, Error> {
+    Err(Error::IOCtl(io::ErrorKind::Unsupported.into()))
+}
+
+/// The state of a peer at one point, see `Device::peer_snapshots`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerSnapshot {
+    pub public_key: x25519::PublicKey,
+    pub endpoint: Option<SocketAddr>,
+    /// When the current session was established, `None` without a session
+    pub last_handshake: Option<SystemTime>,
+    pub tx_bytes: usize,
+    pub rx_bytes: usize,
+    pub persistent_keepalive: Option<u16>,
+    pub allowed_ips: Vec<AllowedIP>,
+    /// Datagrams waiting for the socket to take them, see `Peer::try_enqueue`
+    pub send_queue_len: usize,
+}
+
+/// A cheap summary of the state of the device for liveness and readiness probes, see
+/// `Device::health`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceHealth {
+    pub peers: usize,
+    /// Peers with a session from a handshake at most 135 seconds old, time enough to rekey
+    pub established_peers: usize,
+    /// Peers with a session whose handshake is older, they should have rekeyed by now
+    pub stale_peers: usize,
+    /// When a packet or timer of a peer last failed
+    pub last_error: Option<SystemTime>,
+    /// A listen socket is bound
+    pub listening: bool,
+    /// Listening, with at least the peers required by `set_ready_min_established_peers`
+    /// established
+    pub ready: bool,
+}
+
+/// The peer tables of a `Device`, as rebuilt by `DeviceHandle::compact`
+struct PeerTables {
+    peers: HashMap<x25519::PublicKey, Arc<Peer>>,
+    peers_by_idx: HashMap<u32, Arc<Peer>>,
+    peers_by_ip: AllowedIps<Arc<Peer>>,
+}
+
+/// Estimated memory of the peer tables before and after `DeviceHandle::compact`, in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactStats {
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+}
+
+/// An upper bound on the size of datagrams received from the network, with a
+/// count of the datagrams dropped for exceeding it.
+struct DatagramLimit {
+    max_size: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl DatagramLimit {
+    fn new(max_size: usize) -> Self {
+        DatagramLimit {
+            max_size: AtomicUsize::new(max_size.min(MAX_UDP_SIZE)),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn set_max_size(&self, max_size: usize) {
+        self.max_size
+            .store(max_size.min(MAX_UDP_SIZE), Ordering::Relaxed);
+    }
+
+    fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns false, and counts the drop, if a datagram of `len` bytes exceeds the limit
+    fn admit(&self, len: usize) -> bool {
+        if len > self.max_size.load(Ordering::Relaxed) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::trace!(message = "Dropping oversized datagram", len = len);
+            return false;
+        }
+        true
+    }
+}
+
+/// Optional sanity check of the IP header of decrypted packets, with a count of the packets
+/// dropped for failing it
+#[derive(Default)]
+struct InnerValidation {
+    enabled: AtomicBool,
+    dropped: AtomicU64,
+}
+
+impl InnerValidation {
+    /// Returns false, and counts the drop, if checking is enabled and `packet` is malformed
+    fn admit(&self, packet: &[u8]) -> bool {
+        if !self.enabled.load(Ordering::Relaxed) || valid_ip_header(packet) {
+            return true;
+        }
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        tracing::trace!(
+            message = "Dropping malformed inner packet",
+            len = packet.len()
+        );
+        false
+    }
+}
+
+fn valid_ip_header(packet: &[u8]) -> bool {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => {
+            if packet.len() < 20 {
+                return false;
+            }
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+            if header_len < 20 || header_len > total_len || total_len > packet.len() {
+                return false;
+            }
+            inner::checksum(&packet[..header_len]) == 0
+        }
+        Some(6) => inner::parse_ipv6(packet).is_some(),
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct ReceivePause {
+    paused: AtomicBool,
+    since: parking_lot::Mutex<Option<std::time::Instant>>,
+}
+
+impl ReceivePause {
+    fn pause(&self) {
+        let mut since = self.since.lock();
+        if since.is_none() {
+            *since = Some(std::time::Instant::now());
+        }
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    fn resume(&self) {
+        let mut since = self.since.lock();
+        *since = None;
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    fn paused_for(&self) -> Option<std::time::Duration> {
+        self.since.lock().map(|since| since.elapsed())
+    }
+
+    /// When paused, the receive handlers leave their socket alone and return `Action::Park`, so
+    /// the event doesn't fire again for the pending datagrams until `resume` rearms it
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+}
+
+/// Source prefixes handshake initiations are accepted from, checked before any crypto work.
+/// Empty means initiations are accepted from anywhere.
+#[derive(Default)]
+struct HandshakeSourceFilter {
+    enabled: AtomicBool,
+    prefixes: parking_lot::RwLock<AllowedIps<()>>,
+}
+
+impl HandshakeSourceFilter {
+    fn set(&self, prefixes: Vec<(IpAddr, u8)>) {
+        let mut allowed = self.prefixes.write();
+        allowed.clear();
+        for (addr, cidr) in &prefixes {
+            allowed.insert(*addr, *cidr as u32, ());
+        }
+        self.enabled.store(!prefixes.is_empty(), Ordering::Relaxed);
+    }
+
+    /// Returns false if `packet` is a handshake initiation from outside the filter
+    fn admit(&self, packet: &[u8], src: IpAddr) -> bool {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return true;
+        }
+        if !matches!(
+            Tunn::parse_incoming_packet(packet),
+            Ok(Packet::HandshakeInit(_))
+        ) {
+            return true;
+        }
+        if self.prefixes.read().find(src).is_some() {
+            return true;
+        }
+        tracing::trace!(message = "Dropping handshake initiation from filtered source", src = ?src);
+        false
+    }
+}
+
+/// Where the plaintext side of a device being built goes
+enum Plaintext {
+    Tun(TunSocket),
+    PacketIo(Arc<dyn PacketSink>, Arc<dyn PacketSource>),
+}
+
+/// Builds a [`Device`], validating the combination of options before anything is created
+pub struct DeviceBuilder {
+    plaintext: Plaintext,
+    config: DeviceConfig,
+    private_key: Option<x25519::StaticSecret>,
+    listen_port: Option<u16>,
+    fwmark: Option<u32>,
+    vrf: Option<String>,
+    listen_reuse_port: bool,
+    outer_ttl: Option<u8>,
+    strict_sockopts: bool,
+    max_peers: Option<usize>,
+    tun_mtu: Option<usize>,
+    default_keepalive: Option<u16>,
+    transport: Arc<dyn Transport>,
+    strict_key_check: bool,
+}
+
+impl DeviceBuilder {
+    pub fn new(tun: TunSocket, config: DeviceConfig) -> Self {
+        Self::with_plaintext(Plaintext::Tun(tun), config)
+    }
+
+    /// Exchange plaintext packets with `sink` and `source` instead of a tun interface, see
+    /// [`packet_io`]. There is no interface to read the MTU from, so `tun_mtu` must be set.
+    pub fn with_packet_io(
+        sink: Arc<dyn PacketSink>,
+        source: Arc<dyn PacketSource>,
+        config: DeviceConfig,
+    ) -> Self {
+        Self::with_plaintext(Plaintext::PacketIo(sink, source), config)
+    }
+
+    fn with_plaintext(plaintext: Plaintext, config: DeviceConfig) -> Self {
+        DeviceBuilder {
+            plaintext,
+            config,
+            private_key: None,
+            listen_port: None,
+            fwmark: None,
+            vrf: None,
+            listen_reuse_port: false,
+            outer_ttl: None,
+            strict_sockopts: true,
+            max_peers: None,
+            tun_mtu: None,
+            default_keepalive: None,
+            transport: Arc::new(DirectUdp),
+            strict_key_check: false,
+        }
+    }
+
+    pub fn private_key(mut self, private_key: x25519::StaticSecret) -> Self {
+        self.private_key = Some(private_key);
+        self
+    }
+
+    /// Open the listening sockets on this port, 0 picks a random port
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    /// Mark the listening sockets, requires a listen port
+    pub fn fwmark(mut self, mark: u32) -> Self {
+        self.fwmark = Some(mark);
+        self
+    }
+
+    /// Bind the sockets to the named VRF, see `Device::set_vrf`. Linux only.
+    pub fn vrf(mut self, vrf_name: &str) -> Self {
+        self.vrf = Some(vrf_name.to_owned());
+        self
+    }
+
+    /// Share the listen port with other sockets, see `Device::set_listen_reuse_port`. Rejected
+    /// by `build` together with a fixed `listen_port`, which any process of the same user could
+    /// then bind as well and take a share of the datagrams.
+    pub fn listen_reuse_port(mut self, enabled: bool) -> Self {
+        self.listen_reuse_port = enabled;
+        self
+    }
+
+    /// Send with this TTL, or IPv6 hop limit, see `Device::set_outer_ttl`
+    pub fn outer_ttl(mut self, ttl: u8) -> Self {
+        self.outer_ttl = Some(ttl);
+        self
+    }
+
+    /// Whether optional socket options fail building, see `Device::set_strict_sockopts`
+    pub fn strict_sockopts(mut self, strict: bool) -> Self {
+        self.strict_sockopts = strict;
+        self
+    }
+
+    pub fn protect(mut self, protect: Arc<dyn MakeExternalBoringtun>) -> Self {
+        self.config.protect = protect;
+        self
+    }
+
+    /// Refuse to add peers beyond this number
+    pub fn max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = Some(max_peers);
+        self
+    }
+
+    /// Use this MTU instead of the one reported by the tunnel interface
+    pub fn tun_mtu(mut self, mtu: usize) -> Self {
+        self.tun_mtu = Some(mtu);
+        self
+    }
+
+    /// Persistent keepalive for peers added without one
+    pub fn default_keepalive(mut self, keepalive: u16) -> Self {
+        self.default_keepalive = Some(keepalive);
+        self
+    }
+
+    /// Carry the datagrams of connected endpoints over this transport instead of plain UDP
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Fail to build with a private key another live device uses, see
+    /// `Device::set_strict_key_check`
+    pub fn strict_key_check(mut self, strict: bool) -> Self {
+        self.strict_key_check = strict;
+        self
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.max_peers == Some(0) {
+            return Err(Error::InvalidConfig("max_peers must be nonzero".to_owned()));
+        }
+
+        if let Some(mtu) = self.tun_mtu {
+            if mtu == 0 || mtu > MAX_UDP_SIZE {
+                return Err(Error::InvalidConfig(format!("invalid tun mtu {}", mtu)));
+            }
+        } else if let Plaintext::PacketIo(..) = self.plaintext {
+            return Err(Error::InvalidConfig(
+                "packet io requires a tun mtu".to_owned(),
+            ));
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        if self.vrf.is_some() {
+            return Err(Error::InvalidConfig(
+                "vrf is not supported on this platform".to_owned(),
+            ));
+        }
+
+        if self.fwmark.is_some() {
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            return Err(Error::InvalidConfig(
+                "fwmark is not supported on this platform".to_owned(),
+            ));
+
+            // The mark is set on the listening sockets, so they must be opened by the builder
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            if self.listen_port.is_none() {
+                return Err(Error::InvalidConfig(
+                    "fwmark requires a listen port".to_owned(),
+                ));
+            }
+        }
+
+        if self.listen_reuse_port && matches!(self.listen_port, Some(port) if port != 0) {
+            return Err(Error::InvalidConfig(
+                "listen_reuse_port can't be combined with a fixed listen port".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<Device, Error> {
+        self.validate()?;
+
+        let DeviceBuilder {
+            plaintext,
+            config,
+            private_key,
+            listen_port,
+            fwmark,
+            vrf,
+            listen_reuse_port,
+            outer_ttl,
+            strict_sockopts,
+            max_peers,
+            tun_mtu,
+            default_keepalive,
+            transport,
+            strict_key_check,
+        } = self;
+
+        let poll = EventPoll::<Handler>::new()?;
+        #[cfg(feature = "test-utils")]
+        let connected_faults = Arc::new(faults::FaultyTransport::new(transport));
+        #[cfg(feature = "test-utils")]
+        let transport: Arc<dyn Transport> = connected_faults.clone();
+
+        // Create a tunnel device
+        let (iface, sink, source): (_, Arc<dyn PacketSink>, _) = match plaintext {
+            Plaintext::Tun(tun) => {
+                let iface = Arc::new(tun.set_non_blocking()?);
+                (Some(Arc::clone(&iface)), iface, None)
+            }
+            Plaintext::PacketIo(sink, source) => (None, sink, Some(source)),
+        };
+        let mtu = match (tun_mtu, &iface) {
+            (Some(mtu), _) => mtu,
+            (None, Some(iface)) => iface.mtu()?,
+            // Checked by validate
+            (None, None) => unreachable!(),
+        };
+
+        #[cfg(not(target_os = "linux"))]
+        let uapi_fd = -1;
+        #[cfg(target_os = "linux")]
+        let uapi_fd = config.uapi_fd;
+
+        let mut device = Device {
+            queue: Arc::new(poll),
+            iface,
+            sink,
+            packet_source_waker: None,
+            closed: false,
+            config,
+            exit_notice: Default::default(),
+            yield_notice: Default::default(),
+            fwmark: Default::default(),
+            vrf,
+            listen_reuse_port,
+            outer_ttl,
+            strict_sockopts,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            path_mtu_discovery: false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            path_mtus_updated: Default::default(),
+            key_pair: Default::default(),
+            key_claim: None,
+            strict_key_check,
+            listen_port: Default::default(),
+            next_index: Default::default(),
+            peers: Default::default(),
+            peers_by_idx: Default::default(),
+            peers_by_ip: AllowedIps::new(),
+            udp4: Default::default(),
+            udp6: Default::default(),
+            cleanup_paths: Default::default(),
+            mtu: AtomicUsize::new(mtu),
+            datagram_limit: DatagramLimit::new(MAX_UDP_SIZE),
+            inner_validation: Default::default(),
+            decrement_inner_ttl: AtomicBool::new(false),
+            icmp_frag_needed: AtomicBool::new(false),
+            icmp_error_limit: TokenBucket::new(),
+            unknown_index_limit: TokenBucket::new(),
+            eager_rehandshake: AtomicBool::new(false),
+            external_timers: AtomicBool::new(false),
+            rxq_overflow: Default::default(),
+            recv_pktinfo: AtomicBool::new(false),
+            handshake_source_filter: Default::default(),
+            receive_pause: Default::default(),
+            address_family_pref: Default::default(),
+            half_open_limit: None,
+            require_mac1: true,
+            handshake_send_retries: AtomicU32::new(0),
+            pressure_threshold: DEFAULT_PRESSURE_THRESHOLD,
+            endpoint_resolution_interval: DEFAULT_ENDPOINT_RESOLUTION_INTERVAL,
+            resolver: parking_lot::Mutex::new(None),
+            roam_on_keepalive_only: false,
+            source_cache: parking_lot::Mutex::new(SourceCache::new(0)),
+            source_cache_enabled: AtomicBool::new(false),
+            #[cfg(feature = "test-utils")]
+            faults: Default::default(),
+            #[cfg(feature = "test-utils")]
+            connected_faults,
+            buffer_pool: BufferPool::new(mtu + DATA_OVERHEAD_SZ, DEFAULT_BUFFER_POOL_SIZE),
+            rate_limiter: None,
+            max_peers,
+            default_keepalive,
+            max_allowed_ips_per_peer: None,
+            wg_compat_logging: AtomicBool::new(false),
+            last_error: AtomicU64::new(0),
+            ready_min_established: AtomicUsize::new(1),
+            send_buffer_full: AtomicU64::new(0),
+            events: Default::default(),
+            transport,
+            #[cfg(target_os = "linux")]
+            uapi_fd,
+            #[cfg(not(target_os = "linux"))]
+            update_seq: 0,
+        };
+
+        device
+            .icmp_error_limit
+            .set_rate(ICMP_ERRORS_PER_SEC, ICMP_ERRORS_PER_SEC);
+        device
+            .unknown_index_limit
+            .set_rate(UNKNOWN_INDEX_LOOKUPS_PER_SEC, UNKNOWN_INDEX_LOOKUPS_PER_SEC);
+
+        if device.config.open_uapi_socket {
+            if uapi_fd >= 0 {
+                device.register_api_fd(uapi_fd)?;
+            } else {
+                device.register_api_handler()?;
+            }
+        }
+        if let Some(iface) = &device.iface {
+            device.register_iface_handler(Arc::clone(iface))?;
+        }
+        if let Some(source) = source {
+            device.register_packet_source_handler(source)?;
+        }
+        device.register_notifiers()?;
+        device.register_timers()?;
+
+        #[cfg(target_os = "macos")]
+        {
+            // Only for macOS write the actual socket name into WG_TUN_NAME_FILE
+            if let (Ok(name_file), Some(iface)) = (std::env::var("WG_TUN_NAME_FILE"), &device.iface)
+            {
+                std::fs::write(&name_file, iface.name().unwrap().as_bytes()).unwrap();
+                device.cleanup_paths.push(name_file);
+            }
+        }
+
+        if let Some(private_key) = private_key {
+            device.set_key(private_key)?;
+        }
+
+        if let Some(port) = listen_port {
+            device.bind_dual(port)?;
+        }
+
+        if let Some(mark) = fwmark {
+            device.set_fwmark(mark)?;
+        }
+
+        Ok(device)
+    }
+}
+
+/// A basic linear-feedback shift register implemented as xorshift, used to
+/// distribute peer indexes across the 24-bit address space reserved for peer
+/// identification.
+/// The purpose is to obscure the total number of peers using the system and to
+/// ensure it requires a non-trivial amount of processing power and/or samples
+/// to guess other peers' indices. Anything more ambitious than this is wasted
+/// with only 24 bits of space.
+struct IndexLfsr {
+    initial: u32,
+    lfsr: u32,
+    mask: u32,
+}
+
+impl IndexLfsr {
+    /// Generate a random 24-bit nonzero integer
+    fn random_index() -> u32 {
+        const LFSR_MAX: u32 = 0xffffff; // 24-bit seed
+        loop {
+            let i = OsRng.next_u32() & LFSR_MAX;
+            if i > 0 {
+                // LFSR seed must be non-zero
+                return i;
+            }
+        }
+    }
+
+    /// Generate the next value in the pseudorandom sequence
+    fn next(&mut self) -> u32 {
+        // 24-bit polynomial for randomness. This is arbitrarily chosen to
+        // inject bitflips into the value.
+        const LFSR_POLY: u32 = 0xd80000; // 24-bit polynomial
+        let value = self.lfsr - 1; // lfsr will never have value of 0
+        self.lfsr = (self.lfsr >> 1) ^ ((0u32.wrapping_sub(self.lfsr & 1u32)) & LFSR_POLY);
+        assert!(self.lfsr != self.initial, "Too many peers created");
+        value ^ self.mask
+    }
+}
+
+impl Default for IndexLfsr {
+    fn default() -> Self {
+        let seed = Self::random_index();
+        IndexLfsr {
+            initial: seed,
+            lfsr: seed,
+            mask: Self::random_index(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datagram_limit_drops_oversized() {
+        let limit = DatagramLimit::new(MAX_UDP_SIZE);
+        assert!(limit.admit(MAX_UDP_SIZE));
+        assert_eq!(limit.dropped(), 0);
+
+        limit.set_max_size(1500);
+        let oversized = vec![0u8; 1501];
+        assert!(limit.admit(1500));
+        assert!(!limit.admit(oversized.len()));
+        assert!(!limit.admit(MAX_UDP_SIZE));
+        assert_eq!(limit.dropped(), 2);
+    }
+
+    #[test]
+    fn test_datagram_limit_clamped_to_max_udp_size() {
+        let limit = DatagramLimit::new(usize::MAX);
+        assert_eq!(limit.max_size.load(Ordering::Relaxed), MAX_UDP_SIZE);
+    }
+
+    #[test]
+    fn test_inner_validation() {
+        // IPv4 header with a correct checksum, followed by 4 bytes of payload
+        let mut v4 = vec![
+            0x45, 0x00, 0x00, 0x18, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2, 1, 2, 3, 4,
+        ];
+        let mut sum: u32 = v4[..20]
+            .chunks(2)
+            .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+            .sum();
+        sum = (sum & 0xffff) + (sum >> 16);
+        v4[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+        let mut v6 = vec![0u8; 44];
+        v6[0] = 0x60;
+        v6[5] = 4;
+
+        let validation = InnerValidation::default();
+        let mut corrupted = v4.clone();
+        corrupted[15] ^= 1;
+        // Nothing is checked until enabled
+        assert!(validation.admit(&corrupted));
+
+        validation.enabled.store(true, Ordering::Relaxed);
+        assert!(validation.admit(&v4));
+        assert!(validation.admit(&v6));
+        assert!(!validation.admit(&corrupted));
+        // Length beyond the packet
+        assert!(!validation.admit(&v4[..22]));
+        let mut truncated_v6 = v6.clone();
+        truncated_v6[5] = 5;
+        assert!(!validation.admit(&truncated_v6));
+        // Wrong version
+        let mut bad_version = v4.clone();
+        bad_version[0] = 0x55;
+        assert!(!validation.admit(&bad_version));
+        assert_eq!(validation.dropped.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_decrement_inner_ttl() {
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .build()
+            .unwrap();
+        let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let allowed: AllowedIP = "10.0.1.0/24".parse().unwrap();
+        device
+            .update_peer(key, false, false, false, None, &[allowed], None, None)
+            .unwrap();
+
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&28u16.to_be_bytes());
+        packet[8] = 64;
+        packet[9] = 17;
+        packet[12..16].copy_from_slice(&[10, 0, 1, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 1]);
+        let header_checksum = inner::checksum(&packet[..20]);
+        packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+        // Off by default
+        assert!(device.forward_inner_ttl(&mut packet));
+        assert_eq!(packet[8], 64);
+
+        device.set_decrement_inner_ttl(true);
+        assert!(device.forward_inner_ttl(&mut packet));
+        assert_eq!(packet[8], 63);
+        assert_eq!(inner::checksum(&packet[..20]), 0);
+
+        // Out of hops: dropped, and the time exceeded goes to the tunnel of the source, which
+        // starts a handshake for it
+        packet[8] = 1;
+        assert!(!device.forward_inner_ttl(&mut packet));
+        let mut dst = vec![0u8; MAX_UDP_SIZE];
+        let peer = Arc::clone(&device.peers[&key]);
+        assert!(matches!(
+            peer.tunnel
+                .lock()
+                .format_handshake_initiation(&mut dst, false),
+            TunnResult::Done
+        ));
+
+        // A header length past the packet is dropped without touching it
+        let mut malformed = packet.clone();
+        malformed[0] = 0x4f;
+        malformed[8] = 64;
+        assert!(!device.forward_inner_ttl(&mut malformed));
+        assert_eq!(malformed[8], 64);
+
+        // The errors are paced, the first took one of the burst
+        for _ in 1..ICMP_ERRORS_PER_SEC {
+            assert!(!device.forward_inner_ttl(&mut packet));
+        }
+        assert!(!device.admit_icmp_error());
+    }
+
+    #[test]
+    fn test_tagged_peers() {
+        let static_private = x25519::StaticSecret::random_from_rng(OsRng);
+        let mut peers = HashMap::new();
+        let mut expected = vec![];
+        for i in 0..10 {
+            let peer_public =
+                x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+            let tunnel =
+                Tunn::new(static_private.clone(), peer_public, None, None, i, None).unwrap();
+            let peer = Peer::new(
+                tunnel,
+                i,
+                None,
+                &[],
+                None,
+                Arc::new(MakeExternalBoringtunNoop),
+                Arc::new(DirectUdp),
+            );
+            peer.add_tag("tier:free");
+            if i % 3 == 0 {
+                peer.add_tag("region:eu");
+                expected.push(peer_public);
+            }
+            peers.insert(peer_public, Arc::new(peer));
+        }
+        expected.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        assert_eq!(tagged_peers(&peers, "region:eu"), expected);
+        assert_eq!(tagged_peers(&peers, "tier:free").len(), 10);
+        assert!(tagged_peers(&peers, "region:us").is_empty());
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn test_queue_len() {
+        let receiver =
+            socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        receiver
+            .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        let sender = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        assert_eq!(inq_len(&receiver).unwrap(), 0);
+        assert_eq!(outq_len(&sender).unwrap(), 0);
+
+        sender
+            .send_to(&[0u8; 100], &receiver.local_addr().unwrap())
+            .unwrap();
+        // Loopback delivers synchronously, the datagram is waiting in the receive queue
+        assert_eq!(inq_len(&receiver).unwrap(), 100);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn test_take_error() {
+        // Grab a port that is then closed
+        let closed = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        closed
+            .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let sender = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        sender.connect(&closed_addr).unwrap();
+        assert!(take_error(&sender).unwrap().is_none());
+
+        sender.send(&[0u8; 100]).unwrap();
+        // Loopback reports the port unreachable synchronously
+        let err = take_error(&sender).unwrap().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(take_error(&sender).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_handshake_source_filter() {
+        let filter = HandshakeSourceFilter::default();
+        let mut init = vec![0u8; 148];
+        init[0] = 1;
+        let outside = IpAddr::from([192, 0, 2, 1]);
+        let inside = IpAddr::from([10, 1, 2, 3]);
+        assert!(filter.admit(&init, outside));
+
+        filter.set(vec![(IpAddr::from([10, 0, 0, 0]), 8)]);
+        assert!(filter.admit(&init, inside));
+        assert!(!filter.admit(&init, outside));
+
+        // Only initiations are filtered
+        let mut data = vec![0u8; 32];
+        data[0] = 4;
+        assert!(filter.admit(&data, outside));
+
+        filter.set(vec![]);
+        assert!(filter.admit(&init, outside));
+    }
+
+    #[test]
+    fn test_receive_pause() {
+        let pause = ReceivePause::default();
+        assert!(!pause.is_paused());
+        assert_eq!(pause.paused_for(), None);
+
+        pause.pause();
+        assert!(pause.is_paused());
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let paused_for = pause.paused_for().unwrap();
+        assert!(paused_for >= std::time::Duration::from_millis(10));
+
+        // Pausing again keeps the original start
+        pause.pause();
+        assert!(pause.paused_for().unwrap() >= paused_for);
+
+        pause.resume();
+        assert!(!pause.is_paused());
+        assert_eq!(pause.paused_for(), None);
+    }
+
+    struct NullPacketIo;
+
+    impl PacketSink for NullPacketIo {
+        fn write4(&self, _: &[u8]) {}
+        fn write6(&self, _: &[u8]) {}
+    }
+
+    impl PacketSource for NullPacketIo {
+        fn read(&self, _: &mut [u8]) -> Option<usize> {
+            None
+        }
+    }
+
+    /// A device without tun interface or uapi socket
+    fn packet_io_builder() -> DeviceBuilder {
+        let config = DeviceConfig {
+            n_threads: 1,
+            use_connected_socket: false,
+            #[cfg(target_os = "linux")]
+            use_multi_queue: false,
+            open_uapi_socket: false,
+            protect: Arc::new(MakeExternalBoringtunNoop),
+            firewall_process_inbound_callback: None,
+            firewall_process_outbound_callback: None,
+            #[cfg(target_os = "linux")]
+            uapi_fd: -1,
+        };
+        DeviceBuilder::with_packet_io(Arc::new(NullPacketIo), Arc::new(NullPacketIo), config)
+    }
+
+    /// A running device without tun interface or uapi socket, listening on a random port
+    fn packet_io_handle() -> DeviceHandle {
+        DeviceHandle::new_with_packet_io(
+            Arc::new(NullPacketIo),
+            Arc::new(NullPacketIo),
+            1420,
+            packet_io_builder().config,
+        )
+        .unwrap()
+    }
+
+    /// Two running devices over loopback, the responder already configured with the key of
+    /// the initiator but not where it is. Both exit when dropped.
+    struct Loopback {
+        initiator: DeviceHandle,
+        responder: DeviceHandle,
+        keys: [x25519::StaticSecret; 2],
+    }
+
+    impl Loopback {
+        fn new() -> Self {
+            let pair = Loopback {
+                initiator: packet_io_handle(),
+                responder: packet_io_handle(),
+                keys: [(); 2].map(|_| x25519::StaticSecret::random_from_rng(OsRng)),
+            };
+            pair.responder.send_uapi_cmd(&format!(
+                "set=1\nprivate_key={}\npublic_key={}\n\n",
+                hex::encode(pair.keys[1].to_bytes()),
+                hex::encode(pair.public(0).as_bytes()),
+            ));
+            pair
+        }
+
+        /// The key of the initiator for 0, of the responder for 1
+        fn public(&self, i: usize) -> x25519::PublicKey {
+            x25519::PublicKey::from(&self.keys[i])
+        }
+
+        fn responder_addr(&self) -> SocketAddr {
+            SocketAddr::from(([127, 0, 0, 1], self.responder.device.read().listen_port))
+        }
+
+        fn initiator_addr(&self) -> SocketAddr {
+            SocketAddr::from(([127, 0, 0, 1], self.initiator.device.read().listen_port))
+        }
+
+        /// Give the initiator its key and the responder as peer, with the uapi lines of
+        /// `peer_config`
+        fn configure_initiator(&self, peer_config: &str) {
+            self.initiator.send_uapi_cmd(&format!(
+                "set=1\nprivate_key={}\npublic_key={}\n{}\n",
+                hex::encode(self.keys[0].to_bytes()),
+                hex::encode(self.public(1).as_bytes()),
+                peer_config,
+            ));
+        }
+
+        /// `configure_initiator` with the responder as the endpoint
+        fn connect(&self, peer_config: &str) {
+            self.configure_initiator(&format!(
+                "endpoint={}\n{}",
+                self.responder_addr(),
+                peer_config
+            ));
+        }
+    }
+
+    impl Drop for Loopback {
+        fn drop(&mut self) {
+            for handle in [&mut self.initiator, &mut self.responder] {
+                handle.trigger_exit();
+                handle.wait();
+            }
+        }
+    }
+
+    /// The events received until a handshake completed, that one last. Panics if none does
+    /// within `timeout`.
+    fn wait_for_handshake(
+        events: &Receiver<DeviceEvent>,
+        timeout: std::time::Duration,
+    ) -> Vec<DeviceEvent> {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut received = vec![];
+        loop {
+            let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+            let event = events.recv_timeout(timeout).unwrap();
+            let completed = matches!(event, DeviceEvent::HandshakeCompleted { .. });
+            received.push(event);
+            if completed {
+                return received;
+            }
+        }
+    }
+
+    #[test]
+    fn test_packet_io_device() {
+        // No interface to read the MTU from
+        assert!(matches!(
+            packet_io_builder().build(),
+            Err(Error::InvalidConfig(_))
+        ));
+
+        let device = packet_io_builder().tun_mtu(1420).build().unwrap();
+        assert!(device.iface().is_none());
+        assert_eq!(device.mtu.load(Ordering::Relaxed), 1420);
+        device.packet_source_waker().unwrap().wake();
+    }
+
+    #[test]
+    fn test_device_builder() {
+        let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let mut device = packet_io_builder()
+            .tun_mtu(1280)
+            .private_key(private_key.clone())
+            .listen_port(0)
+            .max_peers(1)
+            .default_keepalive(25)
+            .build()
+            .unwrap();
+        assert_eq!(
+            device.public_key(),
+            Some(x25519::PublicKey::from(&private_key))
+        );
+        assert_ne!(device.listen_port, 0);
+        assert_eq!(device.mtu.load(Ordering::Relaxed), 1280);
+
+        // Peers added without a keepalive get the default, and no more than the maximum
+        let key = || x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let first = key();
+        device
+            .update_peer(first, false, false, false, None, &[], None, None)
+            .unwrap();
+        assert_eq!(
+            device.peers[&first].tunnel.lock().persistent_keepalive(),
+            Some(25)
+        );
+        assert!(matches!(
+            device.update_peer(key(), false, false, false, None, &[], None, None),
+            Err(Error::TooManyPeers)
+        ));
+
+        // Invalid combinations fail before anything is created
+        for builder in [
+            packet_io_builder().tun_mtu(1280).max_peers(0),
+            packet_io_builder().tun_mtu(0),
+            packet_io_builder().tun_mtu(MAX_UDP_SIZE + 1),
+            packet_io_builder()
+                .tun_mtu(1280)
+                .listen_port(51820)
+                .listen_reuse_port(true),
+        ] {
+            assert!(matches!(builder.build(), Err(Error::InvalidConfig(_))));
+        }
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        assert!(matches!(
+            packet_io_builder().tun_mtu(1280).fwmark(1).build(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_peer_snapshots() {
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .build()
+            .unwrap();
+        let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let endpoint: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let ip = AllowedIP {
+            addr: Ipv4Addr::new(10, 0, 0, 2).into(),
+            cidr: 32,
+        };
+        device
+            .update_peer(
+                key,
+                false,
+                false,
+                false,
+                Some(endpoint),
+                &[ip],
+                Some(25),
+                None,
+            )
+            .unwrap();
+        let peer = &device.peers[&key];
+        peer.set_send_queue_capacity(4);
+        peer.try_enqueue(&[0; 32]).unwrap();
+
+        assert_eq!(
+            device.peer_snapshots(),
+            [PeerSnapshot {
+                public_key: key,
+                endpoint: Some(endpoint),
+                last_handshake: None,
+                tx_bytes: 0,
+                rx_bytes: 0,
+                persistent_keepalive: Some(25),
+                allowed_ips: vec![ip],
+                send_queue_len: 1,
+            }]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn test_under_pressure() {
+        let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let own_key = x25519::PublicKey::from(&private_key);
+        let device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(private_key)
+            .build()
+            .unwrap();
+        let mut initiator = Tunn::new(
+            x25519::StaticSecret::random_from_rng(OsRng),
+            own_key,
+            None,
+            None,
+            0,
+            None,
+        )
+        .unwrap();
+        let mut init = vec![0u8; 148];
+        match initiator.format_handshake_initiation(&mut init, false) {
+            TunnResult::WriteToNetwork(packet) => assert_eq!(packet.len(), 148),
+            _ => unreachable!(),
+        }
+        assert!(!device.under_pressure());
+
+        // Past the handshake rate limit every initiation gets a cookie reply
+        let rate_limiter = device.rate_limiter.as_ref().unwrap();
+        let src = Some(IpAddr::from([192, 0, 2, 1]));
+        let mut dst = [0u8; 148];
+        for _ in 0..HANDSHAKE_RATE_LIMIT + 100 {
+            let _ = rate_limiter.verify_packet(src, &init, &mut dst);
+        }
+        assert_eq!(device.rate_limiter_drop_rate(), 20.0);
+        assert!(device.under_pressure());
+
+        mock_instant::MockClock::advance(std::time::Duration::from_secs(5));
+        assert_eq!(device.rate_limiter_drop_rate(), 0.0);
+        assert!(!device.under_pressure());
+    }
+
+    #[test]
+    fn test_self_peer() {
+        let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let own_key = x25519::PublicKey::from(&private_key);
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(private_key)
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            device.update_peer(own_key, false, false, false, None, &[], None, None),
+            Err(Error::SelfPeer)
+        ));
+        assert!(device.peers.is_empty());
+
+        let other_key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        device
+            .update_peer(other_key, false, false, false, None, &[], None, None)
+            .unwrap();
+        assert_eq!(device.peers.len(), 1);
+    }
+
+    #[test]
+    fn test_compact() {
+        let handle = packet_io_handle();
+        let mut keys = vec![];
+        let mut set = format!(
+            "set=1\nprivate_key={}\n",
+            hex::encode(x25519::StaticSecret::random_from_rng(OsRng).to_bytes())
+        );
+        for i in 0..200u32 {
+            let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+            set += &format!(
+                "public_key={}\nallowed_ip={}/24\n",
+                hex::encode(key.as_bytes()),
+                Ipv4Addr::from(0x0a00_0000 | (i << 8))
+            );
+            keys.push(key);
+        }
+        assert_eq!(handle.send_uapi_cmd(&(set + "\n")), "errno=0\n\n");
+        let mut remove = "set=1\n".to_owned();
+        for key in &keys[10..] {
+            remove += &format!("public_key={}\nremove=true\n", hex::encode(key.as_bytes()));
+        }
+        assert_eq!(handle.send_uapi_cmd(&(remove + "\n")), "errno=0\n\n");
+
+        let stats = handle.compact();
+        assert!(stats.bytes_after < stats.bytes_before);
+        let device = handle.device.read();
+        assert_eq!(device.peers.len(), 10);
+        assert_eq!(device.peers_by_ip.len(), 10);
+        for (i, key) in keys[..10].iter().enumerate() {
+            let addr = Ipv4Addr::from(0x0a00_0001 | ((i as u32) << 8));
+            let peer = device.peers_by_ip.find(addr.into()).unwrap();
+            assert_eq!(peer.public_key.0, key.to_bytes());
+        }
+        assert!(device
+            .peers_by_ip
+            .find(Ipv4Addr::new(10, 0, 10, 1).into())
+            .is_none());
+    }
+
+    #[test]
+    fn test_collect_pending_tx() {
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .build()
+            .unwrap();
+        let endpoint = "192.0.2.1:51820".parse().unwrap();
+        let with_endpoint = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let without = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        // The persistent keepalive wants a handshake right away
+        device
+            .update_peer(
+                with_endpoint,
+                false,
+                false,
+                false,
+                Some(endpoint),
+                &[],
+                Some(25),
+                None,
+            )
+            .unwrap();
+        device
+            .update_peer(without, false, false, false, None, &[], Some(25), None)
+            .unwrap();
+
+        let pending = device.collect_pending_tx();
+        assert!(device.external_timers.load(Ordering::Relaxed));
+        assert_eq!(pending.len(), 1);
+        let (peer, packet) = &pending[0];
+        assert_eq!(peer.public_key.0, with_endpoint.to_bytes());
+        assert_eq!(packet.len(), 148);
+
+        // The handshake is in progress, nothing more is due
+        assert!(device.collect_pending_tx().is_empty());
+    }
+
+    #[test]
+    fn test_endpoint_learning() {
+        let pair = Loopback::new();
+        let events = pair.responder.device.read().subscribe();
+
+        // The responder doesn't know where the initiator is
+        assert!(pair
+            .responder
+            .device
+            .read()
+            .send_keepalive_now(&pair.public(0), true)
+            .is_err());
+
+        pair.connect("persistent_keepalive_interval=1\n");
+        let learned = wait_for_handshake(&events, std::time::Duration::from_secs(10))
+            .into_iter()
+            .filter_map(|event| match event {
+                DeviceEvent::EndpointChanged {
+                    public_key,
+                    endpoint,
+                } => {
+                    assert_eq!(public_key, pair.public(0));
+                    Some(endpoint)
+                }
+                _ => None,
+            })
+            .last();
+        let expected = pair.initiator_addr();
+        assert_eq!(learned, Some(expected));
+
+        let device = pair.responder.device.read();
+        assert_eq!(
+            device.peers[&pair.public(0)].endpoint().addr,
+            Some(expected)
+        );
+        device.send_keepalive_now(&pair.public(0), false).unwrap();
+    }
+
+    #[test]
+    fn test_no_roam_on_decrypt_failure() {
+        let pair = Loopback::new();
+        let events = pair.responder.device.read().subscribe();
+
+        pair.connect("");
+        pair.initiator
+            .device
+            .read()
+            .send_keepalive_now(&pair.public(1), true)
+            .unwrap();
+        wait_for_handshake(&events, std::time::Duration::from_secs(10));
+        let expected = pair.initiator_addr();
+        let server_peer = Arc::clone(&pair.responder.device.read().peers[&pair.public(0)]);
+        assert_eq!(server_peer.endpoint().addr, Some(expected));
+        assert_eq!(server_peer.last_rx_source(), Some(expected));
+
+        // A data packet for the session, from elsewhere, that fails the AEAD tag
+        let index = pair.initiator.device.read().peers[&pair.public(1)]
+            .remote_index()
+            .unwrap();
+        let mut forged = vec![0u8; DATA_OVERHEAD_SZ + 16];
+        forged[0] = 4;
+        forged[4..8].copy_from_slice(&index.to_le_bytes());
+        forged[8..16].copy_from_slice(&1000u64.to_le_bytes());
+        let attacker = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        attacker.send_to(&forged, pair.responder_addr()).unwrap();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while server_peer.decrypt_failures() == 0 {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(server_peer.endpoint().addr, Some(expected));
+        assert_eq!(server_peer.last_rx_source(), Some(expected));
+        assert!(events
+            .try_iter()
+            .all(|event| !matches!(event, DeviceEvent::EndpointChanged { .. })));
+    }
+
+    #[test]
+    fn test_no_roam_on_replayed_initiation() {
+        let pair = Loopback::new();
+        let events = pair.responder.device.read().subscribe();
+        pair.connect("");
+        pair.initiator
+            .device
+            .read()
+            .send_keepalive_now(&pair.public(1), true)
+            .unwrap();
+        wait_for_handshake(&events, std::time::Duration::from_secs(10));
+        let peer = Arc::clone(&pair.responder.device.read().peers[&pair.public(0)]);
+        // Past the minimum handshake interval of the first handshake
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // A new initiation of the initiator, from a new address, captured on the way
+        let mut tun = Tunn::new(pair.keys[0].clone(), pair.public(1), None, None, 1, None).unwrap();
+        let mut dst = vec![0u8; 256];
+        let init = match tun.format_handshake_initiation(&mut dst, false) {
+            TunnResult::WriteToNetwork(init) => init.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let roamed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        roamed.send_to(&init, pair.responder_addr()).unwrap();
+        let roamed = roamed.local_addr().unwrap();
+        loop {
+            let event = events
+                .recv_timeout(std::time::Duration::from_secs(10))
+                .unwrap();
+            // The endpoint learned by the first handshake may be announced after it completed
+            if let DeviceEvent::EndpointChanged { endpoint, .. } = event {
+                if endpoint == roamed {
+                    break;
+                }
+            }
+        }
+
+        // Replayed right away from elsewhere, within the minimum handshake interval
+        let attacker = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        attacker.send_to(&init, pair.responder_addr()).unwrap();
+        loop {
+            let event = events
+                .recv_timeout(std::time::Duration::from_secs(10))
+                .unwrap();
+            assert!(!matches!(event, DeviceEvent::EndpointChanged { .. }));
+            if let DeviceEvent::Error {
+                error: WireGuardError::WrongTai64nTimestamp,
+                ..
+            } = event
+            {
+                break;
+            }
+        }
+        assert_eq!(peer.endpoint().addr, Some(roamed));
+        assert_eq!(peer.last_rx_source(), Some(roamed));
+    }
+
+    #[test]
+    #[cfg(feature = "test-utils")]
+    fn test_handshake_under_loss() {
+        let pair = Loopback::new();
+        let events = pair.initiator.device.read().subscribe();
+        // Both the initiations and the responses may be lost, on the initiator's side
+        pair.initiator
+            .device
+            .read()
+            .set_fault_injection(faults::FaultConfig {
+                drop_probability: 0.3,
+                latency: std::time::Duration::from_millis(20),
+                reorder_probability: 0.1,
+                seed: 1,
+            });
+
+        pair.connect("persistent_keepalive_interval=1\n");
+
+        // Lost initiations are retried every 5 seconds
+        let received = wait_for_handshake(&events, std::time::Duration::from_secs(60));
+        assert!(matches!(
+            received.last(),
+            Some(DeviceEvent::HandshakeCompleted { public_key }) if *public_key == pair.public(1)
+        ));
+    }
+
+    #[test]
+    fn test_subscribe_handshake() {
+        let pair = Loopback::new();
+        let events = pair.initiator.device.read().subscribe();
+
+        // The persistent keepalive starts the handshake
+        pair.connect("persistent_keepalive_interval=1\n");
+
+        let received = wait_for_handshake(&events, std::time::Duration::from_secs(10));
+        assert!(received.iter().any(|event| matches!(
+            event,
+            DeviceEvent::PeerAdded { public_key } if *public_key == pair.public(1)
+        )));
+        assert!(matches!(
+            received.last(),
+            Some(DeviceEvent::HandshakeCompleted { public_key }) if *public_key == pair.public(1)
+        ));
+        assert_eq!(pair.initiator.device.read().dropped_events(), 0);
+    }
+
+    #[test]
+    fn test_candidate_endpoints() {
+        let pair = Loopback::new();
+        let events = pair.initiator.device.read().subscribe();
+        pair.configure_initiator("");
+
+        // Never answers
+        let black_hole = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let working = pair.responder_addr();
+        let peer = Arc::clone(&pair.initiator.device.read().peers[&pair.public(1)]);
+        peer.set_candidate_endpoints(vec![black_hole.local_addr().unwrap(), working]);
+        pair.configure_initiator("persistent_keepalive_interval=1\n");
+
+        // The first initiation is lost, the retry after REKEY_TIMEOUT goes to the next candidate
+        wait_for_handshake(&events, std::time::Duration::from_secs(15));
+        assert_eq!(peer.endpoint().addr, Some(working));
+        assert_eq!(peer.next_candidate(), None);
+    }
+
+    /// Connects like `DirectUdp`, but every send fails with `errno`
+    struct FailingTransport {
+        errno: std::sync::atomic::AtomicI32,
+    }
+
+    impl Transport for FailingTransport {
+        fn connect(
+            &self,
+            addr: SocketAddr,
+            port: u16,
+            protect: &dyn MakeExternalBoringtun,
+        ) -> Result<socket2::Socket, Error> {
+            transport::DirectUdp.connect(addr, port, protect)
+        }
+
+        fn send(&self, _: &socket2::Socket, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(
+                self.errno.load(Ordering::Relaxed),
+            ))
+        }
+
+        fn recv(&self, conn: &socket2::Socket, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+            conn.recv(buf)
+        }
+    }
+
+    #[test]
+    fn test_send_buffer_full() {
+        let transport = Arc::new(FailingTransport {
+            errno: libc::ENOBUFS.into(),
+        });
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        let endpoint = "127.0.0.1:9".parse().unwrap();
+        let keys =
+            [(); 2].map(|_| x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng)));
+        let allowed: [AllowedIP; 2] = [
+            "10.0.1.0/24".parse().unwrap(),
+            "10.0.2.0/24".parse().unwrap(),
+        ];
+        for (key, allowed) in keys.iter().zip(&allowed) {
+            device
+                .update_peer(
+                    *key,
+                    false,
+                    false,
+                    false,
+                    Some(endpoint),
+                    &[*allowed],
+                    None,
+                    None,
+                )
+                .unwrap();
+            device.peers[key].connect_endpoint(0).unwrap();
+        }
+
+        let mut packet = vec![0u8; 100];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&100u16.to_be_bytes());
+        packet[9] = 17;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        let mut dst = vec![0u8; MAX_UDP_SIZE];
+
+        // The packet starts a handshake, the initiation is sent over the connected socket
+        packet[16..20].copy_from_slice(&[10, 0, 1, 1]);
+        device.encapsulate_outbound(&packet, &mut dst);
+        assert_eq!(device.send_buffer_full(), 1);
+        assert_eq!(device.peers[&keys[0]].send_buffer_full(), 1);
+
+        // Other errors are not counted
+        transport.errno.store(libc::ENETUNREACH, Ordering::Relaxed);
+        packet[16..20].copy_from_slice(&[10, 0, 2, 1]);
+        device.encapsulate_outbound(&packet, &mut dst);
+        assert_eq!(device.send_buffer_full(), 1);
+        assert_eq!(device.peers[&keys[1]].send_buffer_full(), 0);
+    }
+
+    #[test]
+    fn test_listen_fds() {
+        let handle = packet_io_handle();
+        let device = handle.device.read();
+        let fds = device.listen_fds();
+        assert_eq!(
+            fds.first(),
+            device.udp4.as_ref().map(|s| s.as_raw_fd()).as_ref()
+        );
+        assert_eq!(fds.len(), 1 + device.udp6.is_some() as usize);
+    }
+
+    #[test]
+    fn test_bind_dual() {
+        let mut device = packet_io_builder().tun_mtu(1420).build().unwrap();
+        assert_eq!(device.listen_families(), (false, false));
+
+        let ipv6 = std::net::UdpSocket::bind("[::1]:0").is_ok();
+        device.bind_dual(0).unwrap();
+        assert_eq!(device.listen_families(), (true, ipv6));
+        let port = device.listen_port;
+        assert_ne!(port, 0);
+        if let Some(udp6) = &device.udp6 {
+            assert_eq!(udp6.local_addr().unwrap().as_socket().unwrap().port(), port);
+            // IPv4 is left to the other socket
+            assert!(udp6.only_v6().unwrap());
+        }
+
+        // The old sockets are closed before the new ones take the port
+        device.bind_dual(port).unwrap();
+        assert_eq!(device.listen_families(), (true, ipv6));
+        assert_eq!(device.listen_port, port);
+        assert_eq!(device.listen_fds().len(), 1 + ipv6 as usize);
+    }
+
+    #[test]
+    fn test_sole_peer_skips_source_check() {
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .build()
+            .unwrap();
+        let keys =
+            [(); 2].map(|_| x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng)));
+        let everything: [AllowedIP; 2] = ["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()];
+        let v4 = IpAddr::from([10, 0, 0, 1]);
+        let v6: IpAddr = "fd00::1".parse().unwrap();
+
+        device
+            .update_peer(keys[0], false, false, false, None, &everything, None, None)
+            .unwrap();
+        let first = Arc::clone(&device.peers[&keys[0]]);
+        assert!(first.skips_source_check(v4));
+        assert!(first.skips_source_check(v6));
+
+        // A second peer brings the checks back
+        let narrow: AllowedIP = "10.1.0.0/16".parse().unwrap();
+        device
+            .update_peer(keys[1], false, false, false, None, &[narrow], None, None)
+            .unwrap();
+        let second = Arc::clone(&device.peers[&keys[1]]);
+        assert!(!first.skips_source_check(v4));
+        assert!(first.is_allowed_ip(v4));
+        assert!(!second.skips_source_check(v4));
+        assert!(!second.is_allowed_ip(v4));
+
+        device.remove_peer(&keys[1]);
+        assert!(first.skips_source_check(v4));
+
+        // And so does narrowing the allowed IPs, per family
+        first.set_allowed_ips(&everything[..1]).unwrap();
+        assert!(first.skips_source_check(v4));
+        assert!(!first.skips_source_check(v6));
+        assert!(!first.is_allowed_ip(v6));
+        first
+            .set_allowed_ips(&["10.0.0.0/8".parse().unwrap()])
+            .unwrap();
+        assert!(!first.skips_source_check(v4));
+        assert!(first.is_allowed_ip(v4));
+        assert!(!first.is_allowed_ip(IpAddr::from([192, 0, 2, 1])));
+    }
+
+    #[test]
+    fn test_endpoint_resolution() {
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .build()
+            .unwrap();
+        device.set_endpoint_resolution_interval(std::time::Duration::from_millis(1));
+        let keys =
+            [(); 2].map(|_| x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng)));
+        let addrs: [SocketAddr; 2] = [
+            "127.0.0.1:51820".parse().unwrap(),
+            "127.0.0.2:51820".parse().unwrap(),
+        ];
+        for (key, addr) in keys.iter().zip(addrs) {
+            device
+                .update_peer(*key, false, false, false, None, &[], None, None)
+                .unwrap();
+            device
+                .set_endpoint_hostname(key, Some(&addr.to_string()))
+                .unwrap();
+        }
+
+        // Both peers resolve on the one resolver thread
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while keys
+            .iter()
+            .zip(addrs)
+            .any(|(key, addr)| device.peers[key].endpoint().addr != Some(addr))
+        {
+            assert!(std::time::Instant::now() < deadline);
+            device.reresolve_endpoints();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(device.resolver.lock().is_some());
+    }
+
+    #[test]
+    fn test_source_cache_invalidated_on_roam() {
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .build()
+            .unwrap();
+        device.set_source_cache_size(8);
+        let keys =
+            [(); 2].map(|_| x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng)));
+        let addrs: [SocketAddr; 2] = [
+            "192.0.2.1:51820".parse().unwrap(),
+            "192.0.2.2:51820".parse().unwrap(),
+        ];
+        for (key, addr) in keys.iter().zip(addrs) {
+            device
+                .update_peer(*key, false, false, false, Some(addr), &[], None, None)
+                .unwrap();
+        }
+        let peers = keys.map(|key| Arc::clone(&device.peers[&key]));
+        let cache = |device: &Device, src: SocketAddr, peer: &Arc<Peer>| {
+            device.source_cache.lock().insert(src, Arc::clone(peer));
+        };
+
+        cache(&device, addrs[0], &peers[0]);
+        let hit = device.cached_source_peer(addrs[0]);
+        assert!(Arc::ptr_eq(&hit.unwrap(), &peers[0]));
+
+        // The first peer roams away and the second takes over its address
+        device.set_peer_endpoint(&peers[0], addrs[1]);
+        device.set_peer_endpoint(&peers[1], addrs[0]);
+        assert!(device.source_cache.lock().is_empty());
+        assert!(device.cached_source_peer(addrs[0]).is_none());
+
+        // Endpoints set elsewhere are caught when the entry is used
+        cache(&device, addrs[0], &peers[1]);
+        peers[1].set_endpoint(addrs[1]);
+        assert!(device.cached_source_peer(addrs[0]).is_none());
+
+        // Removing a peer drops its entries
+        cache(&device, addrs[1], &peers[1]);
+        device.remove_peer(&keys[1]);
+        assert!(device.source_cache.lock().is_empty());
+
+        device.set_source_cache_size(0);
+        cache(&device, addrs[1], &peers[0]);
+        assert!(device.cached_source_peer(addrs[1]).is_none());
+    }
+
+    #[test]
+    fn test_pending_handshakes() {
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .build()
+            .unwrap();
+        let keys =
+            [(); 2].map(|_| x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng)));
+        for key in keys {
+            device
+                .update_peer(key, false, false, false, None, &[], None, None)
+                .unwrap();
+        }
+        assert!(device.pending_handshakes().is_empty());
+
+        let mut buf = [0u8; 256];
+        let peer = Arc::clone(&device.peers[&keys[0]]);
+        peer.tunnel
+            .lock()
+            .format_handshake_initiation(&mut buf, false);
+        assert_eq!(device.pending_handshakes(), [keys[0]]);
+        assert!(peer.cancel_handshake());
+        assert!(device.pending_handshakes().is_empty());
+    }
+
+    #[test]
+    fn test_max_allowed_ips_per_peer() {
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .build()
+            .unwrap();
+        device.set_max_allowed_ips_per_peer(4);
+        let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let prefix = |i: u8| AllowedIP {
+            addr: IpAddr::from([10, i, 0, 0]),
+            cidr: 16,
+        };
+        device
+            .update_peer(key, false, false, false, None, &[prefix(0)], None, None)
+            .unwrap();
+        let peer = Arc::clone(&device.peers[&key]);
+
+        for i in 1..4 {
+            device
+                .update_peer(key, false, false, false, None, &[prefix(i)], None, None)
+                .unwrap();
+        }
+        // Adding a prefix it already has doesn't count
+        peer.add_allowed_ips(&[prefix(3)]).unwrap();
+        assert_eq!(peer.allowed_ip_count(), 4);
+
+        let endpoint = "192.0.2.1:51820".parse().unwrap();
+        assert!(matches!(
+            device.update_peer(
+                key,
+                false,
+                false,
+                false,
+                Some(endpoint),
+                &[prefix(4)],
+                None,
+                None
+            ),
+            Err(Error::TooManyAllowedIps(4))
+        ));
+        assert!(matches!(
+            peer.add_allowed_ips_normalized(&[prefix(5)]),
+            Err(Error::TooManyAllowedIps(4))
+        ));
+        // A rejected update changes nothing
+        assert_eq!(peer.allowed_ip_count(), 4);
+        assert!(device
+            .peers_by_ip
+            .find(IpAddr::from([10, 4, 0, 1]))
+            .is_none());
+        assert_eq!(peer.endpoint().addr, None);
+
+        // Replacing the set only counts the new one, even below a lowered limit
+        device.set_max_allowed_ips_per_peer(2);
+        peer.set_allowed_ips(&[prefix(7), prefix(8)]).unwrap();
+        assert_eq!(peer.allowed_ip_count(), 2);
+        assert!(peer
+            .set_allowed_ips(&[prefix(1), prefix(2), prefix(3)])
+            .is_err());
+        assert!(device
+            .new_peer(
+                x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng)),
+                None,
+                &[prefix(1), prefix(2), prefix(3)],
+                None,
+                None,
+            )
+            .is_err());
+        assert_eq!(device.peers.len(), 1);
+    }
+
+    /// Records the marks it is asked to set instead of setting them, refusing them on connected
+    /// sockets when `fail_connected` is set
+    #[derive(Default)]
+    struct MarkingTransport {
+        marks: parking_lot::Mutex<Vec<(RawFd, u32)>>,
+        fail_connected: AtomicBool,
+    }
+
+    impl Transport for MarkingTransport {
+        fn connect(
+            &self,
+            addr: SocketAddr,
+            port: u16,
+            protect: &dyn MakeExternalBoringtun,
+        ) -> Result<socket2::Socket, Error> {
+            DirectUdp.connect(addr, port, protect)
+        }
+
+        fn send(&self, conn: &socket2::Socket, packet: &[u8]) -> io::Result<usize> {
+            conn.send(packet)
+        }
+
+        fn recv(&self, conn: &socket2::Socket, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+            conn.recv(buf)
+        }
+
+        fn set_mark(&self, conn: &socket2::Socket, mark: u32) -> io::Result<()> {
+            if self.fail_connected.load(Ordering::Relaxed) && conn.peer_addr().is_ok() {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            self.marks.lock().push((conn.as_raw_fd(), mark));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_set_fwmark() {
+        let transport = Arc::new(MarkingTransport::default());
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .listen_port(0)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        let endpoint = "127.0.0.1:9".parse().unwrap();
+        let keys =
+            [(); 2].map(|_| x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng)));
+        let mut fds = device.listen_fds();
+        for key in &keys {
+            device
+                .update_peer(*key, false, false, false, Some(endpoint), &[], None, None)
+                .unwrap();
+            // The peer keeps a duplicate of the socket returned, and marks that one
+            device.peers[key].connect_endpoint(0).unwrap();
+            fds.push(device.peers[key].socket_fd().unwrap());
+        }
+
+        device.set_fwmark(7).unwrap();
+        let mut marked: Vec<_> = transport.marks.lock().drain(..).collect();
+        marked.sort_unstable();
+        fds.sort_unstable();
+        assert_eq!(marked, fds.iter().map(|&fd| (fd, 7)).collect::<Vec<_>>());
+
+        // A socket failing doesn't keep the others on the old mark
+        transport.fail_connected.store(true, Ordering::Relaxed);
+        match device.set_fwmark(8) {
+            Err(Error::SetSockOpt(msg)) => {
+                for key in &keys {
+                    assert!(msg.contains(&base64::encode(key.as_bytes())), "{}", msg);
+                }
+                assert!(!msg.contains("listen socket"), "{}", msg);
+            }
+            res => panic!("{:?}", res),
+        }
+        let listeners = device
+            .listen_fds()
+            .iter()
+            .map(|&fd| (fd, 8))
+            .collect::<Vec<_>>();
+        assert_eq!(*transport.marks.lock(), listeners);
+        assert_eq!(device.fwmark, Some(8));
+    }
+
+    #[test]
+    fn test_strict_sockopts() {
+        let transport = Arc::new(MarkingTransport::default());
+        let mut device = packet_io_builder()
+            .tun_mtu(1420)
+            .listen_port(0)
+            .fwmark(7)
+            .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        let events = device.subscribe();
+        let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        device
+            .update_peer(
+                key,
+                false,
+                false,
+                false,
+                Some("127.0.0.1:9".parse().unwrap()),
+                &[],
+                None,
+                None,
+            )
+            .unwrap();
+        let peer = Arc::clone(&device.peers[&key]);
+        transport.fail_connected.store(true, Ordering::Relaxed);
+
+        // The socket connected after the peer roamed is used even though it can't take the
+        // mark, strict or not, the failure reported on the side
+        for strict in [true, false] {
+            device.set_strict_sockopts(strict);
+            device.connect_roamed_endpoint(&peer).unwrap();
+            assert!(peer.socket_fd().is_some());
+            match events.try_recv() {
+                Ok(DeviceEvent::SockOptFailed {
+                    public_key, option, ..
+                }) => {
+                    assert_eq!(public_key, Some(key));
+                    assert_eq!(option, "fwmark");
+                }
+                event => panic!("{:?}", event),
+            }
+            peer.shutdown_endpoint();
+        }
+        assert!(device.set_fwmark(8).is_ok());
+    }
+
+    /// Refuses the first `failures` sends as if the socket buffer was full
+    struct FlakyTransport {
+        failures: AtomicUsize,
+        sends: AtomicUsize,
+    }
+
+    impl FlakyTransport {
+        fn failing(failures: usize) -> Self {
+            FlakyTransport {
+                failures: AtomicUsize::new(failures),
+                sends: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl Transport for FlakyTransport {
+        fn connect(
+            &self,
+            addr: SocketAddr,
+            port: u16,
+            protect: &dyn MakeExternalBoringtun,
+        ) -> Result<socket2::Socket, Error> {
+            DirectUdp.connect(addr, port, protect)
+        }
+
+        fn send(&self, conn: &socket2::Socket, packet: &[u8]) -> io::Result<usize> {
+            self.sends.fetch_add(1, Ordering::Relaxed);
+            if self.failures.load(Ordering::Relaxed) > 0 {
+                self.failures.fetch_sub(1, Ordering::Relaxed);
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            conn.send(packet)
+        }
+
+        fn recv(&self, conn: &socket2::Socket, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+            conn.recv(buf)
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_rxq_ovfl_drops_reported() {
+        let handle = packet_io_handle();
+        assert!(!handle.send_uapi_cmd("get=1\n\n").contains("rxq_ovfl_drops"));
+
+        handle.device.read().set_rxq_ovfl().unwrap();
+        let get = handle.send_uapi_cmd("get=1\n\n");
+        assert!(get.contains("rxq_ovfl_drops_v4=0\n"));
+        assert!(get.contains("rxq_ovfl_drops_v6=0\n"));
+        let metrics = handle.device.read().metrics_statsd("wg");
+        assert!(metrics.contains(&"wg.rxq_ovfl_drops_v4:0|c".to_owned()));
+    }
+
+    #[test]
+    fn test_own_public_key_reported() {
+        let handle = packet_io_handle();
+        assert!(!handle.send_uapi_cmd("get=1\n\n").contains("own_public_key"));
+
+        for _ in 0..2 {
+            let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+            let public_key = x25519::PublicKey::from(&private_key);
+            handle.send_uapi_cmd(&format!(
+                "set=1\nprivate_key={}\n\n",
+                hex::encode(private_key.to_bytes())
+            ));
+            assert_eq!(handle.device.read().public_key(), Some(public_key));
+            assert_eq!(
+                handle.device.read().public_key_base64(),
+                Some(base64::encode(public_key.as_bytes()))
+            );
+            assert!(handle.send_uapi_cmd("get=1\n\n").contains(&format!(
+                "\nown_public_key={}\n",
+                hex::encode(public_key.as_bytes())
+            )));
+        }
+    }
+
+    #[test]
+    fn test_uapi_hostname_endpoint() {
+        let handle = packet_io_handle();
+        let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let set = |endpoint: &str| {
+            handle.send_uapi_cmd(&format!(
+                "set=1\nprivate_key={}\npublic_key={}\nendpoint={}\n\n",
+                hex::encode(private_key.to_bytes()),
+                hex::encode(key.as_bytes()),
+                endpoint
+            ))
+        };
+
+        // Resolved before the device is locked, and applied with the rest of the request
+        assert_eq!(set("localhost:51820"), "errno=0\n\n");
+        let endpoint = handle.device.read().peers[&key].endpoint().addr.unwrap();
+        assert!(endpoint.ip().is_loopback());
+        assert_eq!(endpoint.port(), 51820);
+
+        // Nothing is applied for a hostname that doesn't resolve
+        assert_eq!(
+            set("peer.invalid:51821"),
+            format!("errno={}\n\n", libc::EINVAL)
+        );
+        assert_eq!(
+            handle.device.read().peers[&key].endpoint().addr,
+            Some(endpoint)
+        );
+    }
+
+    #[test]
+    fn test_handshake_send_retries() {
+        let handle = packet_io_handle();
+        let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        handle.send_uapi_cmd(&format!(
+            "set=1\nprivate_key={}\npublic_key={}\n\n",
+            hex::encode(x25519::StaticSecret::random_from_rng(OsRng).to_bytes()),
+            hex::encode(key.as_bytes()),
+        ));
+        let device = handle.device.read();
+        let peer = Arc::clone(&device.peers[&key]);
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let conn = DirectUdp
+            .connect(
+                receiver.local_addr().unwrap(),
+                0,
+                &MakeExternalBoringtunNoop,
+            )
+            .unwrap();
+
+        let mut initiation = vec![0u8; 148];
+        initiation[0] = 1;
+        let mut keepalive = vec![0u8; DATA_OVERHEAD_SZ];
+        keepalive[0] = 4;
+        let mut data = vec![0u8; DATA_OVERHEAD_SZ + 64];
+        data[0] = 4;
+
+        // Whether the packet was sent in the end, and how many sends that took. The device
+        // retries the held packet as its send queue drains
+        let send = |transport: &FlakyTransport, packet: &[u8]| {
+            let mut sent = device
+                .send_retrying(&peer, packet, |packet| transport.send(&conn, packet))
+                .is_ok();
+            while peer.holds_control_packet() {
+                peer.retry_held_control(|packet| {
+                    let res = transport.send(&conn, packet);
+                    sent = res.is_ok();
+                    res
+                });
+            }
+            (sent, transport.sends.load(Ordering::Relaxed))
+        };
+
+        // Off by default
+        assert_eq!(send(&FlakyTransport::failing(1), &initiation), (false, 1));
+
+        device.set_handshake_send_retries(2);
+        assert_eq!(send(&FlakyTransport::failing(2), &initiation), (true, 3));
+        assert_eq!(send(&FlakyTransport::failing(2), &keepalive), (true, 3));
+        assert_eq!(send(&FlakyTransport::failing(3), &initiation), (false, 3));
+        // Data is dropped right away
+        assert_eq!(send(&FlakyTransport::failing(1), &data), (false, 1));
+
+        device.set_handshake_send_retries(100);
+        assert_eq!(send(&FlakyTransport::failing(10), &initiation), (false, 6));
+    }
+
+    #[test]
+    fn test_health() {
+        let pair = Loopback::new();
+        let events = pair.initiator.device.read().subscribe();
+        pair.connect("");
+
+        let initiator = pair.initiator.device.read();
+        assert_eq!(
+            initiator.health(),
+            DeviceHealth {
+                peers: 1,
+                established_peers: 0,
+                stale_peers: 0,
+                last_error: None,
+                listening: true,
+                ready: false,
+            }
+        );
+        // Ready without any session when asked to be
+        initiator.set_ready_min_established_peers(0);
+        assert!(initiator.health().ready);
+        initiator.set_ready_min_established_peers(1);
+
+        initiator.send_keepalive_now(&pair.public(1), true).unwrap();
+        drop(initiator);
+        wait_for_handshake(&events, std::time::Duration::from_secs(10));
+        let health = pair.initiator.device.read().health();
+        assert_eq!((health.established_peers, health.stale_peers), (1, 0));
+        assert!(health.ready);
+    }
+
+    #[test]
+    fn test_pause_receive_device() {
+        let pair = Loopback::new();
+        let events = pair.initiator.device.read().subscribe();
+        pair.connect("");
+        pair.responder.device.read().pause_receive();
+
+        let initiator = pair.initiator.device.read();
+        initiator.send_keepalive_now(&pair.public(1), true).unwrap();
+        drop(initiator);
+        // The initiation waits in the socket of the responder
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(300);
+        while let Ok(event) =
+            events.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+        {
+            assert!(!matches!(event, DeviceEvent::HandshakeCompleted { .. }));
+        }
+
+        pair.responder.device.read().resume_receive();
+        wait_for_handshake(&events, std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_eager_rehandshake_on_unknown_index() {
+        let pair = Loopback::new();
+        let events = pair.initiator.device.read().subscribe();
+        pair.connect("");
+        pair.initiator
+            .device
+            .read()
+            .send_keepalive_now(&pair.public(1), true)
+            .unwrap();
+        wait_for_handshake(&events, std::time::Duration::from_secs(10));
+
+        // The responder forgets the session, as a restart would
+        pair.responder.send_uapi_cmd(&format!(
+            "set=1\npublic_key={}\nremove=true\npublic_key={}\nendpoint={}\n\n",
+            hex::encode(pair.public(0).as_bytes()),
+            hex::encode(pair.public(0).as_bytes()),
+            pair.initiator_addr(),
+        ));
+        let responder_events = pair.responder.device.read().subscribe();
+        let keepalive = || {
+            pair.initiator
+                .device
+                .read()
+                .send_keepalive_now(&pair.public(1), false)
+                .unwrap()
+        };
+
+        // Silent by default, the data of the old session only has an unknown index
+        keepalive();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(300);
+        while let Ok(event) = responder_events
+            .recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+        {
+            assert!(!matches!(event, DeviceEvent::HandshakeCompleted { .. }));
+        }
+
+        pair.responder
+            .device
+            .read()
+            .set_eager_rehandshake_on_unknown_index(true);
+        keepalive();
+        wait_for_handshake(&responder_events, std::time::Duration::from_secs(10));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_last_rx_ifindex() {
+        let pair = Loopback::new();
+        pair.responder.device.read().set_recv_pktinfo().unwrap();
+        let events = pair.initiator.device.read().subscribe();
+        pair.connect("");
+        let responder_peer = Arc::clone(&pair.responder.device.read().peers[&pair.public(0)]);
+        assert_eq!(responder_peer.last_rx_ifindex(), None);
+
+        pair.initiator
+            .device
+            .read()
+            .send_keepalive_now(&pair.public(1), true)
+            .unwrap();
+        wait_for_handshake(&events, std::time::Duration::from_secs(10));
+        let lo = unsafe { libc::if_nametoindex(b"lo\0".as_ptr() as *const libc::c_char) };
+        assert_ne!(lo, 0);
+        assert_eq!(responder_peer.last_rx_ifindex(), Some(lo));
+    }
+
+    #[test]
+    fn test_handshake_socket_response() {
+        let pair = Loopback::new();
+        let events = pair.initiator.device.read().subscribe();
+        pair.connect("");
+        let secondary = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        secondary.connect(pair.responder_addr()).unwrap();
+        let secondary_addr = secondary.local_addr().unwrap();
+        let initiator = pair.initiator.device.read();
+        initiator
+            .set_handshake_socket(&pair.public(1), socket2::Socket::from(secondary))
+            .unwrap();
+        let peer = Arc::clone(&initiator.peers[&pair.public(1)]);
+
+        // The response arrives on the handshake socket, the keepalive confirming the session
+        // goes over the endpoint socket
+        initiator.send_keepalive_now(&pair.public(1), true).unwrap();
+        drop(initiator);
+        wait_for_handshake(&events, std::time::Duration::from_secs(10));
+        let responder_peer = Arc::clone(&pair.responder.device.read().peers[&pair.public(0)]);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while responder_peer.last_rx_source() != Some(pair.initiator_addr()) {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_ne!(responder_peer.last_rx_source(), Some(secondary_addr));
+
+        // Closed along with the endpoint socket
+        peer.shutdown_endpoint();
+        let mut init = [0u8; peer::KEEPALIVE_BUF_SIZE];
+        init[0] = 1;
+        assert!(peer.send_handshake(&init[..148], None).is_none());
+    }
+
+    #[test]
+    fn test_source_cache_initiation_from_other_peer() {
+        let pair = Loopback::new();
+        let events = pair.initiator.device.read().subscribe();
+        let decoy = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        pair.responder.send_uapi_cmd(&format!(
+            "set=1\npublic_key={}\nendpoint={}\n\n",
+            hex::encode(decoy.as_bytes()),
+            pair.initiator_addr(),
+        ));
+        let responder = pair.responder.device.read();
+        responder.set_source_cache_size(8);
+        let decoy = Arc::clone(&responder.peers[&decoy]);
+        let peer = Arc::clone(&responder.peers[&pair.public(0)]);
+        responder
+            .source_cache
+            .lock()
+            .insert(pair.initiator_addr(), Arc::clone(&decoy));
+        drop(responder);
+
+        // The initiation the decoy's tunnel rejects still reaches the peer it is from, which
+        // then takes the entry
+        pair.connect("");
+        pair.initiator
+            .device
+            .read()
+            .send_keepalive_now(&pair.public(1), true)
+            .unwrap();
+        wait_for_handshake(&events, std::time::Duration::from_secs(10));
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        loop {
+            let cached = pair
+                .responder
+                .device
+                .read()
+                .cached_source_peer(pair.initiator_addr());
+            if cached.map_or(false, |cached| Arc::ptr_eq(&cached, &peer)) {
+                break;
+            }
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(decoy.tunnel.lock().time_since_last_handshake().is_none());
+    }
+
+    #[test]
+    fn test_probe() {
+        let pair = Loopback::new();
+        pair.connect("");
+        let peer = pair.initiator.device.read().peers[&pair.public(1)].clone();
+        let fd = peer.socket_fd();
+        assert!(fd.is_some());
+
+        // The first handshake, then a rekey of the session it set up
+        let rtt = peer.probe().unwrap();
+        assert_eq!(peer.last_rtt(), Some(rtt));
+        let index = peer.tunnel.lock().session_indices()[0];
+        peer.probe().unwrap();
+        assert_ne!(peer.tunnel.lock().session_indices()[0], index);
+        // Over the socket of the endpoint, which didn't change
+        assert_eq!(peer.socket_fd(), fd);
+        assert_eq!(peer.endpoint().addr, Some(pair.responder_addr()));
+    }
+
+    #[test]
+    #[cfg(feature = "test-utils")]
+    fn test_smoothed_rtt_over_delayed_transport() {
+        let pair = Loopback::new();
+        pair.connect("");
+        let peer = pair.initiator.device.read().peers[&pair.public(1)].clone();
+        let first = peer.probe().unwrap();
+        assert_eq!(peer.smoothed_rtt(), Some(first));
+
+        // Responses delayed by the responder, sent within a timer tick of being due
+        let latency = std::time::Duration::from_millis(100);
+        pair.responder
+            .device
+            .read()
+            .set_fault_injection(faults::FaultConfig {
+                latency,
+                ..Default::default()
+            });
+        let mut expected = first;
+        for _ in 0..4 {
+            let rtt = peer.probe().unwrap();
+            assert!(rtt >= latency && rtt < latency + std::time::Duration::from_secs(1));
+            assert_eq!(peer.last_rtt(), Some(rtt));
+
+            // Each round trip moves the average an eighth of the way towards it
+            let previous = expected;
+            expected = (expected * 7 + rtt) / 8;
+            assert_eq!(peer.smoothed_rtt(), Some(expected));
+            assert!(expected > previous && expected < rtt);
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingSink(parking_lot::Mutex<Vec<Vec<u8>>>);
+
+    impl PacketSink for RecordingSink {
+        fn write4(&self, packet: &[u8]) {
+            self.0.lock().push(packet.to_vec());
+        }
+        fn write6(&self, packet: &[u8]) {
+            self.0.lock().push(packet.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_icmp_frag_needed() {
+        let sink = Arc::new(RecordingSink::default());
+        let config = packet_io_builder().config;
+        let mut device =
+            DeviceBuilder::with_packet_io(sink.clone(), Arc::new(NullPacketIo), config)
+                .tun_mtu(1420)
+                .private_key(x25519::StaticSecret::random_from_rng(OsRng))
+                .build()
+                .unwrap();
+        device.set_icmp_frag_needed(true);
+        let key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let allowed: AllowedIP = "10.0.1.0/24".parse().unwrap();
+        device
+            .update_peer(key, false, false, false, None, &[allowed], None, None)
+            .unwrap();
+
+        let mut packet = vec![0u8; 1500];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&1500u16.to_be_bytes());
+        packet[6] = 0x40;
+        packet[8] = 64;
+        packet[9] = 17;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 1, 1]);
+        let mut dst = vec![0u8; MAX_UDP_SIZE];
+
+        // Don't Fragment set: dropped, and the source is told the MTU
+        device.encapsulate_outbound(&packet, &mut dst);
+        {
+            let replies = sink.0.lock();
+            assert_eq!(replies.len(), 1);
+            assert_eq!((replies[0][20], replies[0][21]), (3, 4));
+            assert_eq!(u16::from_be_bytes([replies[0][26], replies[0][27]]), 1420);
+            assert_eq!(&replies[0][16..20], &[10, 0, 0, 1]);
+        }
+        let peer = Arc::clone(&device.peers[&key]);
+        // Not handed to the tunnel, which would have started a handshake
+        assert!(!matches!(
+            peer.tunnel
+                .lock()
+                .format_handshake_initiation(&mut dst, false),
+            TunnResult::Done
+        ));
+
+        // Within the MTU of the device, but not of the path learned
+        packet.truncate(1400);
+        packet[2..4].copy_from_slice(&1400u16.to_be_bytes());
+        device.encapsulate_outbound(&packet, &mut dst);
+        assert_eq!(sink.0.lock().len(), 1);
+        peer.record_path_mtu(1400, std::time::Instant::now());
+        device.encapsulate_outbound(&packet, &mut dst);
+        {
+            let replies = sink.0.lock();
+            assert_eq!(replies.len(), 2);
+            let mtu = 1400 - IPV4_UDP_HEADER_SZ - DATA_OVERHEAD_SZ;
+            assert_eq!(
+                usize::from(u16::from_be_bytes([replies[1][26], replies[1][27]])),
+                mtu
+            );
+        }
+
+        // Don't Fragment clear: handed to the tunnel like any packet
+        let peer_key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        device.remove_peer(&key);
+        device
+            .update_peer(peer_key, false, false, false, None, &[allowed], None, None)
+            .unwrap();
+        packet[6] = 0;
+        device.encapsulate_outbound(&packet, &mut dst);
+        assert_eq!(sink.0.lock().len(), 2);
+        let peer = Arc::clone(&device.peers[&peer_key]);
+        assert!(matches!(
+            peer.tunnel
+                .lock()
+                .format_handshake_initiation(&mut dst, false),
+            TunnResult::Done
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_key() {
+        let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let build = |strict| {
+            packet_io_builder()
+                .tun_mtu(1420)
+                .private_key(private_key.clone())
+                .strict_key_check(strict)
+                .build()
+        };
+
+        let first = build(true).unwrap();
+        // Only warns by default
+        let second = build(false).unwrap();
+        assert!(matches!(build(true), Err(Error::DuplicateKey)));
+
+        drop(first);
+        drop(second);
+        assert!(build(true).is_ok());
     }
 }
@@ -0,0 +1,72 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Exchanging plaintext packets with the application instead of a tun device.
+//!
+//! A device built with `DeviceBuilder::with_packet_io` has no tun interface. Decrypted packets
+//! that pass the allowed IPs and the inbound firewall are handed to the `PacketSink`, and
+//! packets to encrypt are pulled from the `PacketSource`. Everything on the network side is
+//! unchanged: the device still owns its UDP sockets, runs the handshakes and timers in its
+//! event loop, and is configured over the same uapi commands. Only the uapi unix socket can't
+//! be opened, as it is named after the tun interface.
+//!
+//! The source is polled when the application calls `PacketSourceWaker::wake`, on an event loop
+//! thread. The loop reads until the source has nothing left, so one wake can cover a batch of
+//! packets, but a packet queued without a wake may wait for the next one.
+
+use std::sync::Arc;
+
+use super::poll::{EventPoll, EventRef};
+use super::tun::TunSocket;
+use super::Handler;
+
+/// Receives the decrypted IP packets of the device
+pub trait PacketSink: Send + Sync {
+    fn write4(&self, packet: &[u8]);
+    fn write6(&self, packet: &[u8]);
+}
+
+/// Supplies the IP packets the device encrypts and sends to its peers
+pub trait PacketSource: Send + Sync {
+    /// Copy the next packet into `dst` and return its length, or `None` if there is no packet
+    /// waiting. Packets longer than `dst`, which takes any packet a datagram can carry, should be
+    /// dropped. See `Device::set_icmp_frag_needed` for those over the MTU.
+    /// Called from the event loop, so it must not block.
+    fn read(&self, dst: &mut [u8]) -> Option<usize>;
+}
+
+impl PacketSink for TunSocket {
+    fn write4(&self, packet: &[u8]) {
+        TunSocket::write4(self, packet);
+    }
+
+    fn write6(&self, packet: &[u8]) {
+        TunSocket::write6(self, packet);
+    }
+}
+
+/// Tells the device its `PacketSource` has packets waiting
+#[derive(Clone)]
+pub struct PacketSourceWaker {
+    queue: Arc<EventPoll<Handler>>,
+    event: Arc<EventRef>,
+}
+
+impl PacketSourceWaker {
+    pub(super) fn new(queue: Arc<EventPoll<Handler>>, event: EventRef) -> Self {
+        PacketSourceWaker {
+            queue,
+            event: Arc::new(event),
+        }
+    }
+
+    /// Have an event loop thread read the source until it is empty
+    pub fn wake(&self) {
+        self.queue.trigger_notification(&self.event);
+    }
+
+    /// Acknowledge the wake, called before the source is read so no wake gets lost
+    pub(super) fn clear(&self) {
+        self.queue.stop_notification(&self.event);
+    }
+}
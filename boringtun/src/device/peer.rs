@@ -1,17 +1,55 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use parking_lot::{Mutex, RwLock};
-use socket2::{Domain, Protocol, Type};
-
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6};
+use parking_lot::{Condvar, Mutex, RwLock};
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV6};
+use std::os::fd::{AsRawFd, RawFd};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::device::buffer_pool::BufferPool;
+use crate::device::token_bucket::TokenBucket;
+use crate::device::transport::Transport;
+use crate::device::{is_congested, AllowedIps, Error, MakeExternalBoringtun};
+use crate::noise::errors::WireGuardError;
+use crate::noise::{
+    classify_packet, DataPath, MessageType, ObservedBehavior, Packet, Tunn, TunnResult,
+};
+
+/// How long `Peer::probe` waits for a response
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Minimum time between handshakes started for data on an unknown session, see
+/// `eager_rehandshake`
+const EAGER_REHANDSHAKE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Fits a keepalive as well as a handshake initiation
+pub(crate) const KEEPALIVE_BUF_SIZE: usize = 256;
+
+/// Maximum number of outbound packets held back by the rate limit
+const MAX_RATE_LIMITED_PACKETS: usize = 256;
+
+/// Error events a peer publishes per second, in bursts of as many, see `admit_error_event`
+const ERROR_EVENTS_PER_SEC: u64 = 10;
+
+/// Delay before resolving a hostname endpoint again after a failure, doubling with each failure
+/// in a row
+const RESOLUTION_RETRY: Duration = Duration::from_secs(5);
+const MAX_RESOLUTION_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Weight of a new round trip time in `Peer::smoothed_rtt` is one in this, as in TCP (RFC 6298)
+const RTT_SAMPLE_WEIGHT: u32 = 8;
 
-use crate::device::{AllowedIps, Error, MakeExternalBoringtun};
-use crate::noise::Tunn;
+/// A learned path MTU is forgotten after this, so a path that grew again is probed upward
+/// rather than pinned at the smallest MTU seen. The kernel ages what it learns the same way.
+const PATH_MTU_EXPIRY: Duration = Duration::from_secs(10 * 60);
 
-use std::os::fd::AsRawFd;
+/// Bits of `Peer::default_routes`
+const DEFAULT_ROUTE_V4: u8 = 1;
+const DEFAULT_ROUTE_V6: u8 = 2;
 
 #[derive(Default, Debug)]
 pub struct Endpoint {
@@ -19,19 +57,200 @@ pub struct Endpoint {
     pub conn: Option<socket2::Socket>,
 }
 
+/// What `Peer::decapsulate` did with a datagram
+pub struct Received<'a> {
+    pub result: TunnResult<'a>,
+    /// The datagram authenticated as from the peer, so it may move the endpoint, see
+    /// `Tunn::take_authenticated`
+    pub authenticated: bool,
+    /// See `Tunn::take_handshake_completed`
+    pub handshake_completed: bool,
+    /// See `Tunn::take_rtt_sample`
+    pub rtt: Option<Duration>,
+}
+
+impl<'a> Received<'a> {
+    fn take(result: TunnResult<'a>, tun: &mut Tunn) -> Self {
+        Received {
+            result,
+            authenticated: tun.take_authenticated(),
+            handshake_completed: tun.take_handshake_completed(),
+            rtt: tun.take_rtt_sample(),
+        }
+    }
+}
+
+/// Called with the added and the removed allowed IPs of a peer
+pub type AllowedIpsCallback = Arc<dyn Fn(&[AllowedIP], &[AllowedIP]) + Send + Sync>;
+
 pub struct Peer {
     /// The associated tunnel struct
     pub(crate) tunnel: Mutex<Tunn>,
+    /// Of `tunnel`, see `decapsulate`
+    data_path: Arc<DataPath>,
     /// Public key of this peer in raw bytes and hex formats
     pub(crate) public_key: ([u8; 32], String),
     /// The index the tunnel uses
     index: u32,
     endpoint: RwLock<Endpoint>,
+    /// See `set_handshake_socket`
+    handshake_conn: RwLock<Option<socket2::Socket>>,
+    /// See `set_endpoint_learning`
+    endpoint_learning: AtomicBool,
     allowed_ips: RwLock<AllowedIps<()>>,
+    /// The families `allowed_ips` routes entirely, kept along with it
+    default_routes: AtomicU8,
+    /// The peer is the only one of its device, kept by the device, see `is_allowed_ip`
+    pub(crate) sole_peer: AtomicBool,
+    /// Most prefixes `allowed_ips` may hold, see `Device::set_max_allowed_ips_per_peer`
+    max_allowed_ips: AtomicUsize,
     preshared_key: RwLock<Option<[u8; 32]>>,
     protect: Arc<dyn MakeExternalBoringtun>,
+    transport: Arc<dyn Transport>,
+    /// A session is up, and `on_session_expired` is yet to fire for it
+    session_up: AtomicBool,
+    on_handshake_complete: RwLock<Option<Box<dyn Fn(&[AllowedIP]) + Send + Sync>>>,
+    on_session_expired: RwLock<Option<Box<dyn Fn() + Send + Sync>>>,
+    on_allowed_ips_changed: RwLock<Option<AllowedIpsCallback>>,
+    /// Limits outbound bandwidth, measured on packets before encryption
+    rate_limit: TokenBucket,
+    queue_over_limit: AtomicBool,
+    rate_limited: Mutex<VecDeque<Vec<u8>>>,
+    rate_limit_drops: AtomicU64,
+    /// Limits the `DeviceEvent::Error` of the peer, see `admit_error_event`
+    error_events: TokenBucket,
+    /// Errors left unpublished by `error_events` since the last one published
+    suppressed_errors: AtomicU64,
+    /// Encrypted datagrams waiting for the socket to take them, see `try_enqueue`
+    send_queue: Mutex<VecDeque<Vec<u8>>>,
+    send_queue_capacity: AtomicUsize,
+    /// The last handshake message or keepalive a congested socket refused, and how many more
+    /// times to try it, see `hold_control_packet`
+    held_control: Mutex<Option<(Vec<u8>, u32)>>,
+    last_eager_rehandshake: Mutex<Option<Instant>>,
+    /// Initiations sent since the last completed handshake
+    handshake_attempts: AtomicU32,
+    /// Packets that failed the AEAD tag check
+    decrypt_failures: AtomicU64,
+    /// Sends that failed with `ENOBUFS`
+    send_buffer_full: AtomicU64,
+    /// The last packet from this peer decrypted successfully
+    decrypt_ok: AtomicBool,
+    /// Application defined labels, not used by the protocol
+    tags: RwLock<HashSet<String>>,
+    blackhole: Mutex<Option<BlackholeDetector>>,
+    candidates: Mutex<CandidateEndpoints>,
+    /// See `endpoint_hostname`
+    resolution: Mutex<Option<EndpointResolution>>,
+    rtt: Mutex<RttEstimate>,
+    /// Notified on every new round trip time, see `probe`
+    rtt_sampled: Condvar,
+    /// See `last_rx_source`
+    last_rx_source: Mutex<Option<SocketAddr>>,
+    /// See `last_rx_ifindex`, 0 for none as no interface has it
+    last_rx_ifindex: AtomicU32,
+    /// Where decapsulated packets may go, see `set_egress_filter`
+    egress_filter: RwLock<AllowedIps<()>>,
+    egress_drops: AtomicU64,
+    /// See `path_mtu`, with when it was learned
+    path_mtu: Mutex<Option<(u16, Instant)>>,
+}
+
+/// Endpoints tried in turn by successive handshake initiations, see `set_candidate_endpoints`
+#[derive(Default)]
+struct CandidateEndpoints {
+    addrs: Vec<SocketAddr>,
+    /// Index of the candidate the next initiation goes to
+    next: usize,
+    /// Index of the candidate the last initiation went to
+    current: usize,
+    /// A handshake completed with `current`, which is used until the session expires
+    pinned: bool,
+}
+
+/// Periodic resolution of a hostname endpoint, see `Device::set_endpoint_resolution_interval`
+struct EndpointResolution {
+    hostname: String,
+    /// When to resolve next
+    due: Instant,
+    /// Resolutions failed in a row
+    failures: u32,
+    in_flight: bool,
+    /// Of the resolution in flight, once it finished
+    result: Option<Result<SocketAddr, ()>>,
+}
+
+impl EndpointResolution {
+    /// Schedule the next resolution after one that succeeded or failed. `jitter`, from 0 to 1,
+    /// places it between half and one and a half of the delay, so peers resolved together drift
+    /// apart.
+    fn schedule(&mut self, ok: bool, now: Instant, interval: Duration, jitter: f64) {
+        let delay = if ok {
+            self.failures = 0;
+            interval
+        } else {
+            self.failures = self.failures.saturating_add(1);
+            RESOLUTION_RETRY
+                .checked_mul(1 << (self.failures - 1).min(16))
+                .map_or(MAX_RESOLUTION_BACKOFF, |delay| {
+                    delay.min(MAX_RESOLUTION_BACKOFF)
+                })
+        };
+        self.due = now + delay.mul_f64(0.5 + jitter);
+    }
+}
+
+/// Round trip times measured by the handshakes we initiate, see `Peer::smoothed_rtt`
+#[derive(Default)]
+struct RttEstimate {
+    last: Option<Duration>,
+    smoothed: Option<Duration>,
+    /// Measurements so far
+    samples: u64,
+}
+
+/// Watches for a session over which we send, but receive nothing
+struct BlackholeDetector {
+    interval: Duration,
+    callback: Arc<dyn Fn() + Send + Sync>,
+    window_start: Instant,
+    /// Byte counters of the tunnel at `window_start`
+    tx_bytes: usize,
+    rx_bytes: usize,
+}
+
+/// New configuration for `Peer::apply_config`, fields left `None` are not changed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerUpdate {
+    pub endpoint: Option<SocketAddr>,
+    /// Replaces all the allowed IPs of the peer
+    pub allowed_ips: Option<Vec<AllowedIP>>,
+    /// Persistent keepalive interval in seconds, 0 disables it
+    pub keepalive: Option<u16>,
+    /// All zeros removes the key
+    pub preshared_key: Option<[u8; 32]>,
+}
+
+/// What to do with outbound packets exceeding the rate limit of a peer
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OverLimit {
+    Drop,
+    /// Hold packets back until the budget allows them, dropping them once the queue is full
+    Queue,
 }
 
+/// The send queue of a peer has no room left, see `Peer::try_enqueue`
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct QueueFull;
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "send queue full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
 pub struct AllowedIP {
     pub addr: IpAddr,
@@ -56,6 +275,29 @@ impl FromStr for AllowedIP {
     }
 }
 
+impl AllowedIP {
+    /// Parse the comma separated list of an `AllowedIPs` line of a wg config, e.g.
+    /// `10.0.0.0/24, fd00::/64`. Like wg, a bare address stands for itself alone, /32 or /128.
+    /// The error names the element that failed, counted from 1.
+    pub fn parse_list(s: &str) -> Result<Vec<AllowedIP>, String> {
+        if s.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        s.split(',')
+            .map(str::trim)
+            .enumerate()
+            .map(|(i, element)| {
+                let parsed = match element.parse::<IpAddr>() {
+                    Ok(addr @ IpAddr::V4(_)) => Ok(AllowedIP { addr, cidr: 32 }),
+                    Ok(addr @ IpAddr::V6(_)) => Ok(AllowedIP { addr, cidr: 128 }),
+                    Err(_) => element.parse(),
+                };
+                parsed.map_err(|e| format!("Allowed IP {} ({:?}): {}", i + 1, element, e))
+            })
+            .collect()
+    }
+}
+
 impl Peer {
     pub fn new(
         tunnel: Tunn,
@@ -64,6 +306,7 @@ impl Peer {
         allowed_ips: &[AllowedIP],
         preshared_key: Option<[u8; 32]>,
         protect: Arc<dyn MakeExternalBoringtun>,
+        transport: Arc<dyn Transport>,
     ) -> Peer {
         let pub_key = tunnel.peer_static_public();
         let mut public_key_hex = String::with_capacity(32);
@@ -71,8 +314,10 @@ impl Peer {
             let pub_symbol = format!("{:02X}", byte);
             public_key_hex.push_str(&pub_symbol);
         }
+        let allowed_ips: AllowedIps<()> = allowed_ips.iter().map(|ip| (ip, ())).collect();
 
-        Peer {
+        let peer = Peer {
+            data_path: tunnel.data_path(),
             tunnel: Mutex::new(tunnel),
             public_key: (pub_key.to_bytes(), public_key_hex),
             index,
@@ -80,25 +325,71 @@ impl Peer {
                 addr: endpoint,
                 conn: None,
             }),
-            allowed_ips: RwLock::new(allowed_ips.iter().map(|ip| (ip, ())).collect()),
+            handshake_conn: RwLock::new(None),
+            endpoint_learning: AtomicBool::new(true),
+            default_routes: AtomicU8::new(default_routes(&allowed_ips)),
+            sole_peer: AtomicBool::new(false),
+            max_allowed_ips: AtomicUsize::new(usize::MAX),
+            allowed_ips: RwLock::new(allowed_ips),
             preshared_key: RwLock::new(preshared_key),
             protect,
-        }
+            transport,
+            session_up: AtomicBool::new(false),
+            on_handshake_complete: RwLock::new(None),
+            on_session_expired: RwLock::new(None),
+            on_allowed_ips_changed: RwLock::new(None),
+            rate_limit: TokenBucket::new(),
+            queue_over_limit: AtomicBool::new(false),
+            rate_limited: Mutex::new(VecDeque::new()),
+            rate_limit_drops: AtomicU64::new(0),
+            error_events: TokenBucket::new(),
+            suppressed_errors: AtomicU64::new(0),
+            send_queue: Mutex::new(VecDeque::new()),
+            held_control: Mutex::new(None),
+            send_queue_capacity: AtomicUsize::new(0),
+            last_eager_rehandshake: Mutex::new(None),
+            handshake_attempts: AtomicU32::new(0),
+            decrypt_failures: AtomicU64::new(0),
+            send_buffer_full: AtomicU64::new(0),
+            decrypt_ok: AtomicBool::new(false),
+            tags: RwLock::new(HashSet::new()),
+            blackhole: Mutex::new(None),
+            candidates: Mutex::new(CandidateEndpoints::default()),
+            resolution: Mutex::new(None),
+            rtt: Mutex::new(RttEstimate::default()),
+            rtt_sampled: Condvar::new(),
+            last_rx_source: Mutex::new(None),
+            last_rx_ifindex: AtomicU32::new(0),
+            egress_filter: RwLock::new(AllowedIps::new()),
+            egress_drops: AtomicU64::new(0),
+            path_mtu: Mutex::new(None),
+        };
+        peer.error_events
+            .set_rate(ERROR_EVENTS_PER_SEC, ERROR_EVENTS_PER_SEC);
+        peer
     }
 
     pub fn endpoint(&self) -> parking_lot::RwLockReadGuard<'_, Endpoint> {
         self.endpoint.read()
     }
 
+    /// Close the connected endpoint socket and the handshake socket, if any, which also ends
+    /// their handlers in the event loop of the device
     pub fn shutdown_endpoint(&self) {
         if let Some(conn) = self.endpoint.write().conn.take() {
             tracing::info!("Disconnecting from endpoint");
             conn.shutdown(Shutdown::Both).unwrap();
         }
+        self.set_handshake_socket(None);
     }
 
     pub fn set_endpoint(&self, addr: SocketAddr) {
-        let mut endpoint = self.endpoint.write();
+        self.move_endpoint(&mut self.endpoint.write(), addr);
+    }
+
+    /// Move `endpoint`, the locked endpoint of this peer, to `addr`, closing the socket
+    /// connected to the old address
+    fn move_endpoint(&self, endpoint: &mut Endpoint, addr: SocketAddr) {
         if endpoint.addr == Some(addr) {
             return;
         }
@@ -106,6 +397,137 @@ impl Peer {
             conn.shutdown(Shutdown::Both).unwrap();
         }
         endpoint.addr = Some(addr);
+        // Learned for the old path
+        *self.path_mtu.lock() = None;
+    }
+
+    /// Send the handshake messages of this peer over `sock`, a socket connected to the endpoint
+    /// and set up for the NAT traversal scheme at hand, while data keeps to the connected
+    /// endpoint or the listen socket. Handshakes for any other address than the one `sock` is
+    /// connected to, after the peer roamed say, take the usual path. `None` stops using it.
+    /// This only sends, `Device::set_handshake_socket` also receives on the socket. The socket is
+    /// closed by `shutdown_endpoint`, as when the peer is removed or its connection expires.
+    pub fn set_handshake_socket(&self, sock: Option<socket2::Socket>) {
+        if let Some(old) = std::mem::replace(&mut *self.handshake_conn.write(), sock) {
+            let _ = old.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Send `packet` over the handshake socket if it is a handshake message, and the socket is
+    /// connected to `dst`, the endpoint when `None`. Returns `None` when the usual path should
+    /// send it.
+    pub(crate) fn send_handshake(
+        &self,
+        packet: &[u8],
+        dst: Option<SocketAddr>,
+    ) -> Option<io::Result<usize>> {
+        if !matches!(
+            classify_packet(packet),
+            Some(MessageType::HandshakeInit | MessageType::HandshakeResponse)
+        ) {
+            return None;
+        }
+        let conn = self.handshake_conn.read();
+        let conn = conn.as_ref()?;
+        let dst = dst.or(self.endpoint.read().addr)?;
+        if conn.peer_addr().ok()?.as_socket() != Some(dst) {
+            return None;
+        }
+        Some(self.transport.send(conn, packet))
+    }
+
+    /// Whether a peer without an endpoint adopts the source of the first packet it sends us that
+    /// passes authentication, a handshake initiation in practice. On by default, as in WireGuard,
+    /// so a server learns where its clients are. Off, the peer can answer but not initiate
+    /// until an endpoint is configured. Peers with an endpoint still roam either way.
+    pub fn set_endpoint_learning(&self, enabled: bool) {
+        self.endpoint_learning.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn endpoint_learning(&self) -> bool {
+        self.endpoint_learning.load(Ordering::Relaxed)
+    }
+
+    /// Where the last packet from the peer that passed authentication came from. Usually the
+    /// endpoint, but not for a peer without one that doesn't learn it, or one whose packets
+    /// take another path than ours. A persistent difference points at asymmetric routing or a
+    /// NAT remapping.
+    pub fn last_rx_source(&self) -> Option<SocketAddr> {
+        *self.last_rx_source.lock()
+    }
+
+    pub(crate) fn record_rx_source(&self, addr: SocketAddr) {
+        *self.last_rx_source.lock() = Some(addr);
+    }
+
+    /// The index of the interface the last packet from the peer that passed authentication on
+    /// a listening socket arrived on, with `Device::set_recv_pktinfo`. `None` before one did,
+    /// and for one that came without packet info.
+    pub fn last_rx_ifindex(&self) -> Option<u32> {
+        match self.last_rx_ifindex.load(Ordering::Relaxed) {
+            0 => None,
+            ifindex => Some(ifindex),
+        }
+    }
+
+    pub(crate) fn record_rx_ifindex(&self, ifindex: Option<u32>) {
+        self.last_rx_ifindex
+            .store(ifindex.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// The path MTU to the endpoint most recently learned by path MTU discovery, see
+    /// `Device::set_path_mtu_discovery`. This is the size of the datagrams, so inner packets
+    /// must leave room for the WireGuard and UDP/IP overhead. `None` before anything was learned,
+    /// after the endpoint changed, and once what was learned is ten minutes old and not
+    /// confirmed since.
+    pub fn path_mtu(&self) -> Option<u16> {
+        self.path_mtu_at(Instant::now())
+    }
+
+    fn path_mtu_at(&self, now: Instant) -> Option<u16> {
+        match *self.path_mtu.lock() {
+            Some((mtu, learned)) if now.saturating_duration_since(learned) < PATH_MTU_EXPIRY => {
+                Some(mtu)
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn record_path_mtu(&self, mtu: u16, now: Instant) {
+        *self.path_mtu.lock() = Some((mtu, now));
+    }
+
+    /// Learn the path MTU from the connected endpoint socket, set up with
+    /// `transport::set_path_mtu_discovery`: the packet too big messages queued for it, then
+    /// what the kernel knows of the path, which takes the increases. Nothing without a
+    /// connected socket.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn update_path_mtu(&self, now: Instant) -> io::Result<()> {
+        let endpoint = self.endpoint.read();
+        let Some(conn) = endpoint.conn.as_ref() else {
+            return Ok(());
+        };
+        let too_big = crate::device::transport::take_packet_too_big(conn)?;
+        let mtu = match crate::device::transport::path_mtu(conn) {
+            Ok(mtu) => mtu,
+            // The socket may not be routed yet, the message still tells
+            Err(err) => too_big.ok_or(err)?,
+        };
+        drop(endpoint);
+        self.record_path_mtu(mtu, now);
+        Ok(())
+    }
+
+    /// Like `set_endpoint`, with the interface to reach an IPv6 link-local address through.
+    /// The scope is ignored for IPv4 addresses.
+    pub fn set_endpoint_with_scope(&self, addr: SocketAddr, scope_id: u32) {
+        let addr = match addr {
+            SocketAddr::V6(addr) => {
+                SocketAddrV6::new(*addr.ip(), addr.port(), addr.flowinfo(), scope_id).into()
+            }
+            addr => addr,
+        };
+        self.set_endpoint(addr);
     }
 
     pub fn connect_endpoint(&self, port: u16) -> Result<socket2::Socket, Error> {
@@ -119,20 +541,7 @@ impl Peer {
             .addr
             .expect("Attempt to connect to undefined endpoint");
 
-        let udp_conn =
-            socket2::Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
-        udp_conn.set_reuse_address(true)?;
-        let bind_addr = if addr.is_ipv4() {
-            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into()
-        } else {
-            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into()
-        };
-        udp_conn.bind(&bind_addr)?;
-        udp_conn.set_nonblocking(true)?;
-        // fw_mark is being set inside make_external(), so no need to set it twice as in Cloudflare's repo.
-        self.protect.make_external(udp_conn.as_raw_fd());
-        // Also mind that all socket setup functions should be called before .connect().
-        udp_conn.connect(&addr.into())?;
+        let udp_conn = self.transport.connect(addr, port, self.protect.as_ref())?;
 
         tracing::info!(
             message="Connected endpoint",
@@ -145,37 +554,810 @@ impl Peer {
         Ok(udp_conn)
     }
 
+    /// The fd of the connected endpoint socket, `None` when not connected. Only valid while that
+    /// socket lives: it closes when the peer roams or reconnects, so query again after every
+    /// endpoint change. The event loop polls a duplicate of the same socket.
+    pub fn socket_fd(&self) -> Option<RawFd> {
+        self.endpoint
+            .read()
+            .conn
+            .as_ref()
+            .map(|conn| conn.as_raw_fd())
+    }
+
+    /// Read and clear the pending error of the connected endpoint socket, if connected
+    pub fn take_endpoint_error(&self) -> Result<Option<std::io::Error>, Error> {
+        match &self.endpoint.read().conn {
+            Some(conn) => crate::device::take_error(conn),
+            None => Ok(None),
+        }
+    }
+
+    /// Send a keepalive over the connected endpoint right away, regardless of the persistent
+    /// keepalive timer. Without a session a handshake is started instead when
+    /// `handshake_if_no_session`, otherwise this fails.
+    pub fn send_keepalive_now(&self, handshake_if_no_session: bool) -> Result<(), Error> {
+        if self.endpoint.read().conn.is_none() {
+            return Err(Error::Connect("Not connected".to_owned()));
+        }
+
+        // Not holding the endpoint lock while the tunnel is locked
+        let mut buf = [0u8; KEEPALIVE_BUF_SIZE];
+        let Some(packet) = self.format_keepalive(handshake_if_no_session, &mut buf)? else {
+            return Ok(());
+        };
+        if let Some(res) = self.send_handshake(packet, None) {
+            res?;
+            return Ok(());
+        }
+        match &self.endpoint.read().conn {
+            Some(conn) => self.transport.send(conn, packet)?,
+            None => return Err(Error::Connect("Not connected".to_owned())),
+        };
+        Ok(())
+    }
+
+    /// Whether a handshake initiation we sent awaits a response, see `Tunn::handshake_pending`
+    pub fn handshake_pending(&self) -> bool {
+        self.tunnel.lock().handshake_pending()
+    }
+
+    /// Abort the handshake we initiated, keeping any established session, see
+    /// `Tunn::cancel_handshake`. Returns whether one was pending.
+    pub fn cancel_handshake(&self) -> bool {
+        self.tunnel.lock().cancel_handshake()
+    }
+
+    /// Drop the session state and start a new handshake, see `Tunn::reset`. The initiation is
+    /// sent over the handshake socket or the connected endpoint, if any, `Device::reset_session`
+    /// sends it either way. Fires `on_session_expired` if a session was up.
+    pub fn reset_session(&self) -> Result<(), Error> {
+        let mut buf = [0u8; KEEPALIVE_BUF_SIZE];
+        let Some(packet) = self.format_reset(&mut buf) else {
+            return Ok(());
+        };
+        if let Some(res) = self.send_handshake(packet, None) {
+            res?;
+            return Ok(());
+        }
+        if let Some(conn) = &self.endpoint.read().conn {
+            self.transport.send(conn, packet)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `None` when initiations are not allowed
+    pub(crate) fn format_reset<'a>(&self, dst: &'a mut [u8]) -> Option<&'a [u8]> {
+        let res = match self.tunnel.lock().reset(dst) {
+            TunnResult::WriteToNetwork(packet) => Some(&*packet),
+            _ => None,
+        };
+        self.session_expired();
+        res
+    }
+
+    /// Returns `None` when a handshake is already in progress
+    pub(crate) fn format_keepalive<'a>(
+        &self,
+        handshake_if_no_session: bool,
+        dst: &'a mut [u8],
+    ) -> Result<Option<&'a [u8]>, Error> {
+        let mut tun = self.tunnel.lock();
+        let res = if handshake_if_no_session && tun.remote_index().is_none() {
+            tun.format_handshake_initiation(dst, false)
+        } else {
+            tun.send_keepalive(dst)
+        };
+        match res {
+            TunnResult::WriteToNetwork(packet) => Ok(Some(packet)),
+            TunnResult::Done => Ok(None),
+            TunnResult::Err(e) => Err(Error::Keepalive(e)),
+            _ => unreachable!("Unexpected result formatting a keepalive"),
+        }
+    }
+
+    /// The transport carrying the datagrams of the connected endpoint
+    pub fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    /// Whether this side may initiate handshakes, see `Tunn::set_initiation_allowed`. Locks the
+    /// tunnel.
+    pub fn set_initiation_allowed(&self, allowed: bool) {
+        self.tunnel.lock().set_initiation_allowed(allowed);
+    }
+
+    /// Skip persistent keepalives while traffic flows, see `Tunn::set_adaptive_keepalive`.
+    /// Locks the tunnel.
+    pub fn set_adaptive_keepalive(&self, adaptive: bool) {
+        self.tunnel.lock().set_adaptive_keepalive(adaptive);
+    }
+
+    /// Call `cb` with the allowed IPs of this peer each time a handshake completes
+    pub fn on_handshake_complete(&self, cb: impl Fn(&[AllowedIP]) + Send + Sync + 'static) {
+        *self.on_handshake_complete.write() = Some(Box::new(cb));
+    }
+
+    /// Call `cb` when the session established by a completed handshake expires
+    pub fn on_session_expired(&self, cb: impl Fn() + Send + Sync + 'static) {
+        *self.on_session_expired.write() = Some(Box::new(cb));
+    }
+
+    /// Call `cb` with the added and the removed prefixes each time the allowed IPs change. It
+    /// runs once the new set is in place, without any lock of the peer held.
+    pub fn on_allowed_ips_changed(&self, cb: AllowedIpsCallback) {
+        *self.on_allowed_ips_changed.write() = Some(cb);
+    }
+
+    /// Try each of `addrs` in order, moving on to the next one each time a handshake initiation
+    /// goes unanswered, for a peer reachable over several paths. The first endpoint a handshake
+    /// completes with is kept until the session expires, then the search resumes from it. The
+    /// initiations are sent from the listen socket, as the endpoint keeps changing. An empty
+    /// list stops the search, leaving the endpoint as it is.
+    pub fn set_candidate_endpoints(&self, addrs: Vec<SocketAddr>) {
+        if let Some(&first) = addrs.first() {
+            self.set_endpoint(first);
+        }
+        *self.candidates.lock() = CandidateEndpoints {
+            addrs,
+            ..Default::default()
+        };
+    }
+
+    /// Called before sending a handshake initiation from the timers, returns the candidate to
+    /// send it to, which becomes the endpoint. `None` with no candidates or when one is pinned.
+    pub(crate) fn next_candidate(&self) -> Option<SocketAddr> {
+        let addr = {
+            let mut candidates = self.candidates.lock();
+            if candidates.pinned || candidates.addrs.is_empty() {
+                return None;
+            }
+            candidates.current = candidates.next;
+            candidates.next = (candidates.next + 1) % candidates.addrs.len();
+            candidates.addrs[candidates.current]
+        };
+        self.set_endpoint(addr);
+        Some(addr)
+    }
+
+    /// The hostname the endpoint is resolved from periodically, `None` for an endpoint
+    /// configured as an address
+    pub fn endpoint_hostname(&self) -> Option<String> {
+        self.resolution
+            .lock()
+            .as_ref()
+            .map(|resolution| resolution.hostname.clone())
+    }
+
+    /// Resolve the endpoint from `hostname` again from `due` on, or stop with `None`
+    pub(crate) fn set_endpoint_hostname(&self, hostname: Option<String>, due: Instant) {
+        *self.resolution.lock() = hostname.map(|hostname| EndpointResolution {
+            hostname,
+            due,
+            failures: 0,
+            in_flight: false,
+            result: None,
+        });
+    }
+
+    /// The hostname to resolve if a resolution is due by `now`, counted as in flight until
+    /// `finish_resolution`
+    pub(crate) fn start_resolution(&self, now: Instant) -> Option<String> {
+        let mut resolution = self.resolution.lock();
+        let resolution = resolution.as_mut()?;
+        if resolution.in_flight || resolution.due > now {
+            return None;
+        }
+        resolution.in_flight = true;
+        Some(resolution.hostname.clone())
+    }
+
+    /// Hand over the result of resolving `hostname`, dropped if the hostname changed meanwhile
+    pub(crate) fn finish_resolution(&self, hostname: &str, result: Result<SocketAddr, ()>) {
+        if let Some(resolution) = self.resolution.lock().as_mut() {
+            if resolution.in_flight && resolution.hostname == hostname {
+                resolution.result = Some(result);
+            }
+        }
+    }
+
+    /// Take the result of the finished resolution, if any, and schedule the next one in about
+    /// `interval`, sooner with a backoff after a failure. `jitter` is called for a number from 0
+    /// to 1 spreading the schedule. Returns the address resolved.
+    pub(crate) fn take_resolution(
+        &self,
+        now: Instant,
+        interval: Duration,
+        jitter: impl FnOnce() -> f64,
+    ) -> Option<SocketAddr> {
+        let mut resolution = self.resolution.lock();
+        let resolution = resolution.as_mut()?;
+        let result = resolution.result.take()?;
+        resolution.in_flight = false;
+        resolution.schedule(result.is_ok(), now, interval, jitter());
+        result.ok()
+    }
+
+    /// Call `cb` when a session is established, and over `interval` we sent data but received
+    /// nothing. An idle tunnel sends nothing, so does not trigger it.
+    pub fn set_blackhole_detector(
+        &self,
+        interval: Duration,
+        cb: impl Fn() + Send + Sync + 'static,
+    ) {
+        let stats = self.tunnel.lock().stats();
+        *self.blackhole.lock() = Some(BlackholeDetector {
+            interval,
+            callback: Arc::new(cb),
+            window_start: Instant::now(),
+            tx_bytes: stats.tx_bytes,
+            rx_bytes: stats.rx_bytes,
+        });
+    }
+
+    /// Must be called without holding the tunnel lock
+    pub(crate) fn check_blackhole(&self) {
+        let callback = {
+            let mut blackhole = self.blackhole.lock();
+            let Some(detector) = blackhole.as_mut() else {
+                return;
+            };
+            let now = Instant::now();
+            if now.duration_since(detector.window_start) < detector.interval {
+                return;
+            }
+
+            let stats = self.tunnel.lock().stats();
+            let (tx_bytes, rx_bytes) = (stats.tx_bytes, stats.rx_bytes);
+            let blackholed = stats.time_since_handshake.is_some()
+                && tx_bytes > detector.tx_bytes
+                && rx_bytes == detector.rx_bytes;
+            detector.window_start = now;
+            detector.tx_bytes = tx_bytes;
+            detector.rx_bytes = rx_bytes;
+            if !blackholed {
+                return;
+            }
+            Arc::clone(&detector.callback)
+        };
+
+        tracing::warn!(
+            message = "Sent without receiving over an established session",
+            public_key = self.public_key.1
+        );
+        callback();
+    }
+
+    /// Must be called without holding the tunnel lock
+    pub(crate) fn handshake_completed(&self) {
+        self.session_up.store(true, Ordering::Relaxed);
+        self.handshake_attempts.store(0, Ordering::Relaxed);
+        self.candidates.lock().pinned = true;
+        if let Some(cb) = self.on_handshake_complete.read().as_ref() {
+            cb(&self.allowed_ips());
+        }
+    }
+
+    /// A handshake initiation in reply to data for a session this side no longer has, at most
+    /// one per `EAGER_REHANDSHAKE_INTERVAL`. `None` when rate limited or a handshake is already
+    /// in progress. Locks the tunnel.
+    pub(crate) fn eager_rehandshake<'a>(&self, dst: &'a mut [u8]) -> Option<&'a [u8]> {
+        {
+            let mut last = self.last_eager_rehandshake.lock();
+            let now = Instant::now();
+            if last.map_or(false, |last| {
+                now.duration_since(last) < EAGER_REHANDSHAKE_INTERVAL
+            }) {
+                return None;
+            }
+            *last = Some(now);
+        }
+        match self.tunnel.lock().format_handshake_initiation(dst, false) {
+            TunnResult::WriteToNetwork(packet) => Some(packet),
+            _ => None,
+        }
+    }
+
+    /// Count an initiation sent, returns the attempts since the last completed handshake
+    pub(crate) fn initiation_sent(&self) -> u32 {
+        self.handshake_attempts.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Must be called without holding the tunnel lock
+    pub(crate) fn session_expired(&self) {
+        if !self.session_up.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        {
+            let mut candidates = self.candidates.lock();
+            if candidates.pinned {
+                candidates.pinned = false;
+                candidates.next = candidates.current;
+            }
+        }
+        if let Some(cb) = self.on_session_expired.read().as_ref() {
+            cb();
+        }
+    }
+
+    /// Check the endpoint answers handshakes, and measure the round trip time.
+    ///
+    /// This starts a regular handshake over the connected endpoint, or waits for the one in
+    /// progress, so the session is rekeyed and the usual retries and limits apply. The response
+    /// is received by the event loop of the device, this blocks until it arrives or the probe
+    /// times out.
+    pub fn probe(&self) -> Result<Duration, Error> {
+        if self.endpoint.read().conn.is_none() {
+            return Err(Error::Connect("Not connected".to_owned()));
+        }
+
+        let samples = self.rtt.lock().samples;
+        let mut buf = [0u8; KEEPALIVE_BUF_SIZE];
+        let res = {
+            let mut tun = self.tunnel.lock();
+            match tun.format_handshake_initiation(&mut buf, false) {
+                TunnResult::WriteToNetwork(packet) => Ok(Some(packet)),
+                TunnResult::Done if tun.handshake_pending() => Ok(None),
+                TunnResult::Done => Err(Error::Probe("Initiations not allowed".to_owned())),
+                TunnResult::Err(e) => Err(Error::Probe(format!("{:?}", e))),
+                _ => unreachable!("Unexpected result formatting a handshake initiation"),
+            }
+        };
+        if let Some(packet) = res? {
+            if let Some(res) = self.send_handshake(packet, None) {
+                res?;
+            } else if let Some(conn) = &self.endpoint.read().conn {
+                self.transport.send(conn, packet)?;
+            }
+        }
+
+        let deadline = Instant::now() + PROBE_TIMEOUT;
+        let mut rtt = self.rtt.lock();
+        while rtt.samples == samples {
+            if self.rtt_sampled.wait_until(&mut rtt, deadline).timed_out() {
+                return Err(Error::Probe("Timed out".to_owned()));
+            }
+        }
+        Ok(rtt.last.unwrap_or_default())
+    }
+
+    /// The round trip time to the peer, averaged over the handshakes this side initiated, probes
+    /// included, each new measurement weighing 1/8 as in TCP. `None` until the first one.
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        self.rtt.lock().smoothed
+    }
+
+    /// The round trip time last measured, see `smoothed_rtt`
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.rtt.lock().last
+    }
+
+    pub(crate) fn record_rtt(&self, sample: Duration) {
+        let mut rtt = self.rtt.lock();
+        rtt.last = Some(sample);
+        rtt.smoothed = Some(match rtt.smoothed {
+            Some(smoothed) => (smoothed * (RTT_SAMPLE_WEIGHT - 1) + sample) / RTT_SAMPLE_WEIGHT,
+            None => sample,
+        });
+        rtt.samples += 1;
+        self.rtt_sampled.notify_all();
+    }
+
+    /// Number of received data packets that failed the AEAD tag check. Handshakes failing to
+    /// decrypt are not counted, anyone can send one.
+    pub fn decrypt_failures(&self) -> u64 {
+        self.decrypt_failures.load(Ordering::Relaxed)
+    }
+
+    /// Packets to this peer dropped because the kernel ran out of send buffer space, see
+    /// `Device::send_buffer_full`
+    pub fn send_buffer_full(&self) -> u64 {
+        self.send_buffer_full.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_send_buffer_full(&self) {
+        self.send_buffer_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_decrypted(&self) {
+        // Avoid writing to the shared cache line on every packet
+        if !self.decrypt_ok.load(Ordering::Relaxed) {
+            self.decrypt_ok.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Count the error `datagram` failed to decapsulate with
+    pub(crate) fn record_decapsulate_error(&self, err: &WireGuardError, datagram: &[u8]) {
+        if !matches!(err, WireGuardError::InvalidAeadTag)
+            || !matches!(
+                Tunn::parse_incoming_packet(datagram),
+                Ok(Packet::PacketData(_))
+            )
+        {
+            return;
+        }
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+        // Only the first failure of a run is logged, a working tunnel starting to fail
+        // usually means the keys on either side changed
+        if self.decrypt_ok.swap(false, Ordering::Relaxed) {
+            tracing::warn!(
+                message = "Decryption failed after previous successes, possible key mismatch",
+                public_key = self.public_key.1
+            );
+        }
+    }
+
+    /// Limit outbound traffic to `bytes_per_sec`, with bursts of up to `burst` bytes.
+    /// A rate of 0 removes the limit.
+    pub fn set_rate_limit(&self, bytes_per_sec: u64, burst: u64) {
+        self.rate_limit.set_rate(bytes_per_sec, burst);
+    }
+
+    pub fn set_over_limit(&self, over_limit: OverLimit) {
+        self.queue_over_limit
+            .store(over_limit == OverLimit::Queue, Ordering::Relaxed);
+    }
+
+    /// Number of outbound packets dropped because of the rate limit
+    pub fn rate_limit_drops(&self) -> u64 {
+        self.rate_limit_drops.load(Ordering::Relaxed)
+    }
+
+    /// Whether another error of the peer may be published as a `DeviceEvent::Error`, under
+    /// `ERROR_EVENTS_PER_SEC`, with the number of errors suppressed since the last one was. A
+    /// flood of bad packets would otherwise have every receiving thread take the lock on the
+    /// subscribers for each of them.
+    pub(crate) fn admit_error_event(&self) -> Option<u64> {
+        if self.error_events.try_consume(1) {
+            Some(self.suppressed_errors.swap(0, Ordering::Relaxed))
+        } else {
+            self.suppressed_errors.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+
+    /// Check if an outbound packet fits the rate limit. Packets that don't are either dropped,
+    /// or queued to be returned by `take_admitted_outbound`, in a buffer from `pool`.
+    pub(crate) fn admit_outbound(&self, packet: &[u8], pool: &BufferPool) -> bool {
+        if !self.rate_limit.is_limited() {
+            return true;
+        }
+
+        if !self.queue_over_limit.load(Ordering::Relaxed) {
+            if self.rate_limit.try_consume(packet.len()) {
+                return true;
+            }
+            self.rate_limit_drops.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        let mut queue = self.rate_limited.lock();
+        // Don't let the packet overtake the ones already waiting
+        if queue.is_empty() && self.rate_limit.try_consume(packet.len()) {
+            return true;
+        }
+        if queue.len() < MAX_RATE_LIMITED_PACKETS {
+            queue.push_back(pool.get_copy(packet));
+        } else {
+            self.rate_limit_drops.fetch_add(1, Ordering::Relaxed);
+        }
+        false
+    }
+
+    /// Hold up to `capacity` encrypted datagrams back when the socket is congested instead of
+    /// dropping them. 0, the default, disables the queue. Shrinking it drops the newest packets
+    /// over the new capacity.
+    pub fn set_send_queue_capacity(&self, capacity: usize) {
+        self.send_queue_capacity.store(capacity, Ordering::Relaxed);
+        self.send_queue.lock().truncate(capacity);
+    }
+
+    /// Queue an encrypted datagram to send once the socket takes datagrams again. On
+    /// `QueueFull` the caller decides whether to drop the packet or wait for the queue to drain.
+    /// The device drains the queue when the connected socket of the peer turns writable, with
+    /// every peer timer run, and before sending anything new to the peer, so the packets keep
+    /// their order.
+    pub fn try_enqueue(&self, packet: &[u8]) -> Result<(), QueueFull> {
+        self.try_enqueue_with(packet, <[u8]>::to_vec)
+    }
+
+    /// Like `try_enqueue`, with the copy of `packet` in a buffer from `pool`
+    pub(crate) fn try_enqueue_pooled(
+        &self,
+        packet: &[u8],
+        pool: &BufferPool,
+    ) -> Result<(), QueueFull> {
+        self.try_enqueue_with(packet, |packet| pool.get_copy(packet))
+    }
+
+    fn try_enqueue_with(
+        &self,
+        packet: &[u8],
+        copy: impl FnOnce(&[u8]) -> Vec<u8>,
+    ) -> Result<(), QueueFull> {
+        let mut queue = self.send_queue.lock();
+        if queue.len() >= self.send_queue_capacity.load(Ordering::Relaxed) {
+            return Err(QueueFull);
+        }
+        queue.push_back(copy(packet));
+        Ok(())
+    }
+
+    /// Datagrams waiting in the send queue
+    pub fn send_queue_len(&self) -> usize {
+        self.send_queue.lock().len()
+    }
+
+    /// Pass the queued datagrams to `send` in order, until it reports congestion. The packet
+    /// that hit congestion stays first in the queue, one that failed for another reason is
+    /// dropped. The buffers of the packets that leave the queue go back to `pool`. Returns
+    /// whether the queue is now empty.
+    pub(crate) fn drain_send_queue(
+        &self,
+        pool: &BufferPool,
+        mut send: impl FnMut(&[u8]) -> io::Result<usize>,
+    ) -> bool {
+        let mut queue = self.send_queue.lock();
+        while let Some(packet) = queue.front() {
+            match send(packet) {
+                Err(err) if is_congested(&err) => return false,
+                Err(err) => {
+                    tracing::warn!(message = "Failed to send queued packet", error = ?err);
+                }
+                Ok(_) => {}
+            }
+            pool.put(queue.pop_front().unwrap());
+        }
+        true
+    }
+
+    /// Keep a handshake message or keepalive the socket refused, to try it again up to `retries`
+    /// times with `retry_held_control`. It replaces the one held before, as a newer handshake
+    /// message or keepalive makes that moot.
+    pub(crate) fn hold_control_packet(&self, packet: &[u8], retries: u32) {
+        *self.held_control.lock() = Some((packet.to_vec(), retries));
+    }
+
+    pub(crate) fn holds_control_packet(&self) -> bool {
+        self.held_control.lock().is_some()
+    }
+
+    /// Pass the held control packet to `send`, if any. It is kept while `send` reports
+    /// congestion and retries are left. Returns whether none is held now.
+    pub(crate) fn retry_held_control(&self, send: impl FnOnce(&[u8]) -> io::Result<usize>) -> bool {
+        let mut held = self.held_control.lock();
+        let Some((packet, retries)) = held.as_mut() else {
+            return true;
+        };
+        match send(packet) {
+            Err(err) if is_congested(&err) && *retries > 1 => {
+                *retries -= 1;
+                return false;
+            }
+            Err(err) => {
+                tracing::warn!(message = "Failed to send held control packet", error = ?err);
+            }
+            Ok(_) => {}
+        }
+        *held = None;
+        true
+    }
+
+    /// Queued outbound packets that now fit the rate limit
+    pub(crate) fn take_admitted_outbound(&self) -> Vec<Vec<u8>> {
+        let mut queue = self.rate_limited.lock();
+        let mut admitted = vec![];
+        while let Some(packet) = queue.front() {
+            if !self.rate_limit.try_consume(packet.len()) {
+                break;
+            }
+            admitted.extend(queue.pop_front());
+        }
+        admitted
+    }
+
     pub fn is_allowed_ip<I: Into<IpAddr>>(&self, addr: I) -> bool {
-        self.allowed_ips.read().find(addr.into()).is_some()
+        let addr = addr.into();
+        self.skips_source_check(addr) || self.allowed_ips.read().find(addr).is_some()
+    }
+
+    /// Whether `is_allowed_ip` can allow `addr` without a lookup. It is checked for every packet
+    /// received, and the only peer of a client device usually routes everything.
+    pub(crate) fn skips_source_check(&self, addr: IpAddr) -> bool {
+        let route = match addr {
+            IpAddr::V4(_) => DEFAULT_ROUTE_V4,
+            IpAddr::V6(_) => DEFAULT_ROUTE_V6,
+        };
+        self.sole_peer.load(Ordering::Relaxed)
+            && self.default_routes.load(Ordering::Relaxed) & route != 0
+    }
+
+    /// Mark the peer as the only one of its device or not, as the device does when peers are
+    /// added or removed, so `is_allowed_ip` only takes the fast path while it is. For the
+    /// benchmarks.
+    #[cfg(feature = "test-utils")]
+    pub fn set_sole_peer(&self, sole: bool) {
+        self.sole_peer.store(sole, Ordering::Relaxed);
     }
 
     pub fn allowed_ips(&self) -> Vec<AllowedIP> {
-        self.allowed_ips
-            .read()
+        allowed_ip_list(&self.allowed_ips.read())
+    }
+
+    /// Only forward the packets of this peer to destinations within `prefixes`, dropping the
+    /// rest. The allowed IPs decide which sources the peer may use, this where its traffic may
+    /// go. Empty, the default, puts no limit on the destinations.
+    pub fn set_egress_filter(&self, prefixes: &[AllowedIP]) {
+        *self.egress_filter.write() = prefixes.iter().map(|ip| (ip, ())).collect();
+    }
+
+    /// Decapsulated packets dropped for going where the egress filter doesn't allow
+    pub fn egress_drops(&self) -> u64 {
+        self.egress_drops.load(Ordering::Relaxed)
+    }
+
+    /// Check the destination of a decapsulated packet against the egress filter, counting the
+    /// packets dropped
+    pub(crate) fn admit_egress(&self, packet: &[u8]) -> bool {
+        let filter = self.egress_filter.read();
+        if filter.is_empty() {
+            return true;
+        }
+        let admitted = Tunn::dst_address(packet).map_or(false, |dst| filter.find(dst).is_some());
+        if !admitted {
+            self.egress_drops.fetch_add(1, Ordering::Relaxed);
+        }
+        admitted
+    }
+
+    fn note_default_routes(&self, allowed_ips: &AllowedIps<()>) {
+        self.default_routes
+            .store(default_routes(allowed_ips), Ordering::Relaxed);
+    }
+
+    /// Change the allowed IPs with `update`, then report the change to the callback if one is set.
+    /// Nothing changes when `update` fails.
+    fn update_allowed_ips(
+        &self,
+        update: impl FnOnce(&mut AllowedIps<()>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let cb = match self.on_allowed_ips_changed.read().clone() {
+            Some(cb) => cb,
+            None => {
+                let mut allowed_ips = self.allowed_ips.write();
+                update(&mut allowed_ips)?;
+                self.note_default_routes(&allowed_ips);
+                return Ok(());
+            }
+        };
+        let (before, after) = {
+            let mut allowed_ips = self.allowed_ips.write();
+            let before = allowed_ip_list(&allowed_ips);
+            update(&mut allowed_ips)?;
+            self.note_default_routes(&allowed_ips);
+            (before, allowed_ip_list(&allowed_ips))
+        };
+        report_allowed_ips_change(&cb, before, after);
+        Ok(())
+    }
+
+    /// Apply `add`, which adds at most `added` prefixes, unless that leaves more than the limit.
+    /// Only copies the trie to count when the sum of both could be over it.
+    fn add_within_limit(
+        &self,
+        allowed_ips: &mut AllowedIps<()>,
+        added: usize,
+        add: impl Fn(&mut AllowedIps<()>),
+    ) -> Result<(), Error> {
+        let max = self.max_allowed_ips.load(Ordering::Relaxed);
+        if allowed_ips.len().saturating_add(added) <= max {
+            add(allowed_ips);
+            return Ok(());
+        }
+        let mut updated: AllowedIps<()> = allowed_ip_list(allowed_ips)
             .iter()
-            .map(|(_, ip, cidr)| AllowedIP {
-                addr: ip,
-                cidr: cidr,
-            })
-            .collect()
+            .map(|ip| (ip, ()))
+            .collect();
+        add(&mut updated);
+        if updated.len() > max {
+            return Err(Error::TooManyAllowedIps(max));
+        }
+        *allowed_ips = updated;
+        Ok(())
     }
 
-    pub fn add_allowed_ips(&self, new_allowed_ips: &[AllowedIP]) {
-        let mut allowed_ips = self.allowed_ips.write();
+    /// Fails if the peer wouldn't take `allowed_ips` in place of its current ones
+    pub(crate) fn check_allowed_ips_limit(&self, allowed_ips: &[AllowedIP]) -> Result<(), Error> {
+        Self::check_allowed_ip_count(allowed_ips, self.max_allowed_ips.load(Ordering::Relaxed))
+    }
 
-        for AllowedIP { addr, cidr } in new_allowed_ips {
-            allowed_ips.insert(*addr, *cidr as u32, ());
+    /// Fails if `allowed_ips` holds more than `max` distinct prefixes, e.g. before creating a
+    /// peer with them
+    pub(crate) fn check_allowed_ip_count(
+        allowed_ips: &[AllowedIP],
+        max: usize,
+    ) -> Result<(), Error> {
+        if allowed_ips.len() <= max {
+            return Ok(());
+        }
+        // The list may repeat prefixes
+        let distinct: AllowedIps<()> = allowed_ips.iter().map(|ip| (ip, ())).collect();
+        if distinct.len() > max {
+            return Err(Error::TooManyAllowedIps(max));
         }
+        Ok(())
+    }
+
+    /// Most prefixes the allowed IPs may hold, `usize::MAX` for no limit. Only checked by later
+    /// changes, the current allowed IPs are kept even when more.
+    pub(crate) fn set_max_allowed_ips(&self, max: usize) {
+        self.max_allowed_ips.store(max, Ordering::Relaxed);
     }
 
-    pub fn set_allowed_ips(&self, allowed_ips: &[AllowedIP]) {
-        *self.allowed_ips.write() = allowed_ips.iter().map(|ip| (ip, ())).collect();
+    /// Number of prefixes in the allowed IPs
+    pub fn allowed_ip_count(&self) -> usize {
+        self.allowed_ips.read().len()
+    }
+
+    /// Fails with `Error::TooManyAllowedIps`, adding none of them, if the peer would end up with
+    /// more prefixes than allowed
+    pub fn add_allowed_ips(&self, new_allowed_ips: &[AllowedIP]) -> Result<(), Error> {
+        self.update_allowed_ips(|allowed_ips| {
+            self.add_within_limit(allowed_ips, new_allowed_ips.len(), |allowed_ips| {
+                for AllowedIP { addr, cidr } in new_allowed_ips {
+                    allowed_ips.insert(*addr, *cidr as u32, ());
+                }
+            })
+        })
+    }
+
+    /// Like `add_allowed_ips`, but skips prefixes already covered by an existing one, and drops
+    /// existing prefixes made redundant by a new covering prefix
+    pub fn add_allowed_ips_normalized(&self, new_allowed_ips: &[AllowedIP]) -> Result<(), Error> {
+        self.update_allowed_ips(|allowed_ips| {
+            self.add_within_limit(allowed_ips, new_allowed_ips.len(), |allowed_ips| {
+                for AllowedIP { addr, cidr } in new_allowed_ips {
+                    if allowed_ips.covers(*addr, *cidr as u32) {
+                        continue;
+                    }
+                    allowed_ips.remove_covered(*addr, *cidr as u32);
+                    allowed_ips.insert(*addr, *cidr as u32, ());
+                }
+            })
+        })
+    }
+
+    /// Replace the allowed IPs. Only the size of the new set is checked against the limit, so
+    /// shrinking a set that grew past it always works.
+    pub fn set_allowed_ips(&self, new_allowed_ips: &[AllowedIP]) -> Result<(), Error> {
+        self.check_allowed_ips_limit(new_allowed_ips)?;
+        self.update_allowed_ips(|allowed_ips| {
+            *allowed_ips = new_allowed_ips.iter().map(|ip| (ip, ())).collect();
+            Ok(())
+        })
+    }
+
+    /// Remove exactly the given prefixes, more specific prefixes they cover are kept
+    pub fn remove_allowed_ips(&self, removed: &[AllowedIP]) {
+        let _ = self.update_allowed_ips(|allowed_ips| {
+            *allowed_ips = allowed_ip_list(allowed_ips)
+                .iter()
+                .filter(|ip| !removed.contains(ip))
+                .map(|ip| (ip, ()))
+                .collect();
+            Ok(())
+        });
     }
 
     pub fn preshared_key(&self) -> Option<[u8; 32]> {
         self.preshared_key.read().clone()
     }
 
+    /// Check if a preshared key is set, without copying the key
+    pub fn has_preshared_key(&self) -> bool {
+        self.preshared_key.read().is_some()
+    }
+
     /// Be carefull using this one, as it locks tunnel
     pub fn set_preshared_key(&self, key: [u8; 32]) {
         let key = if key == [0; 32] { None } else { Some(key) };
@@ -185,9 +1367,163 @@ impl Peer {
         self.tunnel.lock().set_preshared_key(key);
     }
 
+    /// Apply all the fields of `update` together, so no other method sees a mix of old and new
+    /// values. Returns the previous values of the fields that were set, applying them undoes the
+    /// update. An endpoint absent before is returned as `None`, and can't be restored this way.
+    /// Fails with `Error::TooManyAllowedIps`, changing nothing, if the new allowed IPs are over
+    /// the limit, as with `set_allowed_ips`.
+    ///
+    /// Takes the locks in the order endpoint, allowed IPs, preshared key, tunnel. Methods taking
+    /// more than one of them must follow the same order, or release each before taking the next.
+    pub fn apply_config(&self, update: PeerUpdate) -> Result<PeerUpdate, Error> {
+        if let Some(allowed_ips) = &update.allowed_ips {
+            self.check_allowed_ips_limit(allowed_ips)?;
+        }
+
+        let mut endpoint = self.endpoint.write();
+        let mut allowed_ips = self.allowed_ips.write();
+        let mut preshared_key = self.preshared_key.write();
+        let mut tunnel = self.tunnel.lock();
+        let cb = self.on_allowed_ips_changed.read().clone();
+
+        let mut previous = PeerUpdate::default();
+        let mut allowed_ips_change = None;
+        if let Some(addr) = update.endpoint {
+            previous.endpoint = endpoint.addr;
+            self.move_endpoint(&mut endpoint, addr);
+        }
+        if let Some(new_allowed_ips) = update.allowed_ips {
+            let before = allowed_ip_list(&allowed_ips);
+            *allowed_ips = new_allowed_ips.iter().map(|ip| (ip, ())).collect();
+            self.note_default_routes(&allowed_ips);
+            allowed_ips_change = Some((before.clone(), allowed_ip_list(&allowed_ips)));
+            previous.allowed_ips = Some(before);
+        }
+        if let Some(keepalive) = update.keepalive {
+            previous.keepalive = Some(tunnel.persistent_keepalive().unwrap_or(0));
+            tunnel.set_persistent_keepalive(keepalive);
+        }
+        if let Some(key) = update.preshared_key {
+            previous.preshared_key = Some(preshared_key.unwrap_or([0; 32]));
+            let key = if key == [0; 32] { None } else { Some(key) };
+            *preshared_key = key;
+            tunnel.set_preshared_key(key);
+        }
+
+        drop((endpoint, allowed_ips, preshared_key, tunnel));
+        if let (Some(cb), Some((before, after))) = (cb, allowed_ips_change) {
+            report_allowed_ips_change(&cb, before, after);
+        }
+        Ok(previous)
+    }
+
     pub fn index(&self) -> u32 {
         self.index
     }
+
+    /// Like `Tunn::decapsulate` on the tunnel of the peer, but data packets are decrypted
+    /// before the tunnel is locked, see `Tunn::data_path`. The lock is only held to account for
+    /// them, so threads receiving from the same peer decrypt in parallel. Also tells what the
+    /// datagram did to the tunnel, see `Received`.
+    pub fn decapsulate<'a>(
+        &self,
+        src_addr: Option<IpAddr>,
+        datagram: &[u8],
+        dst: &'a mut [u8],
+    ) -> Received<'a> {
+        match Tunn::parse_incoming_packet(datagram) {
+            Ok(packet @ Packet::PacketData(_)) => self.receive_verified(packet, dst),
+            _ => {
+                let mut tun = self.tunnel.lock();
+                let res = tun.decapsulate(src_addr, datagram, dst);
+                Received::take(res, &mut tun)
+            }
+        }
+    }
+
+    /// Like `decapsulate` for a packet the rate limiter already verified, see
+    /// `Tunn::handle_verified_packet`
+    pub(crate) fn receive_verified<'a>(&self, packet: Packet, dst: &'a mut [u8]) -> Received<'a> {
+        let mut tun;
+        let res = match packet {
+            Packet::PacketData(data) => {
+                let decrypted = self.data_path.decrypt(data, dst);
+                tun = self.tunnel.lock();
+                match decrypted {
+                    Ok(decrypted) => tun.receive_decrypted(decrypted),
+                    Err(err) => TunnResult::Err(err),
+                }
+            }
+            packet => {
+                tun = self.tunnel.lock();
+                tun.handle_verified_packet(packet, dst)
+            }
+        };
+        Received::take(res, &mut tun)
+    }
+
+    /// The index the peer assigned to the current session, see `Tunn::remote_index`
+    pub fn remote_index(&self) -> Option<u32> {
+        self.tunnel.lock().remote_index()
+    }
+
+    /// Remote indices of all live sessions, starting from the current session
+    pub fn remote_session_indices(&self) -> Vec<u32> {
+        self.tunnel.lock().remote_session_indices()
+    }
+
+    /// Keepalive cadence, handshake role and largest packet learned from the traffic of the
+    /// peer, to tune its MTU and keepalive
+    pub fn observed_behavior(&self) -> ObservedBehavior {
+        self.tunnel.lock().observed_behavior()
+    }
+
+    /// Label this peer, e.g. `region:eu`, to select it later with `Device::peers_with_tag`
+    pub fn add_tag(&self, tag: &str) {
+        self.tags.write().insert(tag.to_owned());
+    }
+
+    pub fn remove_tag(&self, tag: &str) {
+        self.tags.write().remove(tag);
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.read().contains(tag)
+    }
+}
+
+/// The families `allowed_ips` routes entirely, as `DEFAULT_ROUTE_V4` and `DEFAULT_ROUTE_V6` bits
+fn default_routes(allowed_ips: &AllowedIps<()>) -> u8 {
+    let mut routes = 0;
+    if allowed_ips.covers(Ipv4Addr::UNSPECIFIED.into(), 0) {
+        routes |= DEFAULT_ROUTE_V4;
+    }
+    if allowed_ips.covers(Ipv6Addr::UNSPECIFIED.into(), 0) {
+        routes |= DEFAULT_ROUTE_V6;
+    }
+    routes
+}
+
+fn allowed_ip_list(allowed_ips: &AllowedIps<()>) -> Vec<AllowedIP> {
+    allowed_ips
+        .iter()
+        .map(|(_, addr, cidr)| AllowedIP { addr, cidr })
+        .collect()
+}
+
+/// Call `cb` with the prefixes in `after` but not `before`, and the other way around
+fn report_allowed_ips_change(
+    cb: &AllowedIpsCallback,
+    before: Vec<AllowedIP>,
+    after: Vec<AllowedIP>,
+) {
+    let before: BTreeSet<_> = before.into_iter().collect();
+    let after: BTreeSet<_> = after.into_iter().collect();
+    let added: Vec<_> = after.difference(&before).copied().collect();
+    let removed: Vec<_> = before.difference(&after).copied().collect();
+    if !added.is_empty() || !removed.is_empty() {
+        cb(&added, &removed);
+    }
 }
 
 #[cfg(test)]
@@ -199,21 +1535,769 @@ mod tests {
     // Introduced this test to prevent LLT-5351 recurring in the future:
     #[test]
     fn test_connect_endpoint() {
+        let peer = create_peer_at(Some(SocketAddr::new(IpAddr::from([1, 2, 3, 4]), 54321)));
+
+        peer.connect_endpoint(12345).unwrap();
+    }
+
+    fn create_peer() -> Peer {
+        create_peer_at(None)
+    }
+
+    fn create_peer_at(endpoint: Option<SocketAddr>) -> Peer {
         let a_secret_key = StaticSecret::random_from_rng(&mut rand::rngs::StdRng::from_entropy());
 
         let b_secret_key = StaticSecret::random_from_rng(&mut rand::rngs::StdRng::from_entropy());
         let b_public_key = PublicKey::from(&b_secret_key);
 
         let tunnel = Tunn::new(a_secret_key, b_public_key, None, None, 0, None).unwrap();
-        let peer = Peer::new(
+        Peer::new(
             tunnel,
             0,
-            Some(SocketAddr::new(IpAddr::from([1, 2, 3, 4]), 54321)),
+            endpoint,
             &[],
             None,
             Arc::new(crate::device::MakeExternalBoringtunNoop),
+            Arc::new(crate::device::transport::DirectUdp),
+        )
+    }
+
+    #[test]
+    fn test_smoothed_rtt() {
+        let peer = create_peer();
+        assert_eq!((peer.smoothed_rtt(), peer.last_rtt()), (None, None));
+
+        peer.record_rtt(Duration::from_millis(80));
+        assert_eq!(peer.smoothed_rtt(), Some(Duration::from_millis(80)));
+        peer.record_rtt(Duration::from_millis(160));
+        assert_eq!(peer.smoothed_rtt(), Some(Duration::from_millis(90)));
+        assert_eq!(peer.last_rtt(), Some(Duration::from_millis(160)));
+
+        // Converges on a steady round trip time
+        for _ in 0..64 {
+            peer.record_rtt(Duration::from_millis(20));
+        }
+        let smoothed = peer.smoothed_rtt().unwrap();
+        assert!(smoothed - Duration::from_millis(20) < Duration::from_millis(1));
+        assert_eq!(peer.last_rtt(), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_endpoint_resolution_backoff() {
+        let peer = create_peer();
+        let interval = Duration::from_secs(120);
+        let addr: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let mut now = Instant::now();
+        peer.set_endpoint_hostname(Some("peer.example:51820".to_owned()), now);
+
+        // Resolves the hostname, then schedules the next resolution by the result, here with
+        // no jitter. Returns the delay.
+        let mut resolve = |result: Result<SocketAddr, ()>| {
+            let hostname = peer.start_resolution(now).unwrap();
+            assert_eq!(peer.start_resolution(now), None);
+            peer.finish_resolution(&hostname, result);
+            assert_eq!(peer.take_resolution(now, interval, || 0.5), result.ok());
+            let due = peer.resolution.lock().as_ref().unwrap().due;
+            assert_eq!(peer.start_resolution(due - Duration::from_millis(1)), None);
+            let delay = due - now;
+            now = due;
+            delay
+        };
+
+        assert_eq!(resolve(Ok(addr)), interval);
+        // Failures back off from a short delay
+        assert_eq!(resolve(Err(())), Duration::from_secs(5));
+        assert_eq!(resolve(Err(())), Duration::from_secs(10));
+        assert_eq!(resolve(Err(())), Duration::from_secs(20));
+        for _ in 0..20 {
+            resolve(Err(()));
+        }
+        assert_eq!(resolve(Err(())), MAX_RESOLUTION_BACKOFF);
+        // A success resets the backoff
+        assert_eq!(resolve(Ok(addr)), interval);
+        assert_eq!(resolve(Err(())), Duration::from_secs(5));
+
+        // The jitter spreads the delay around the interval
+        let mut resolution = peer.resolution.lock();
+        let resolution = resolution.as_mut().unwrap();
+        resolution.schedule(true, now, interval, 0.0);
+        assert_eq!(resolution.due - now, interval / 2);
+        resolution.schedule(true, now, interval, 1.0);
+        assert_eq!(resolution.due - now, interval * 3 / 2);
+    }
+
+    #[test]
+    fn test_has_preshared_key() {
+        let peer = create_peer();
+        assert!(!peer.has_preshared_key());
+
+        peer.set_preshared_key([1; 32]);
+        assert!(peer.has_preshared_key());
+
+        // An all zero key clears the preshared key
+        peer.set_preshared_key([0; 32]);
+        assert!(!peer.has_preshared_key());
+    }
+
+    #[test]
+    fn test_allowed_ips_normalized_cover_absorbs_child() {
+        let peer = create_peer();
+        let cover: AllowedIP = "10.0.0.0/16".parse().unwrap();
+        peer.add_allowed_ips_normalized(&[cover]).unwrap();
+        peer.add_allowed_ips_normalized(&["10.0.1.0/24".parse().unwrap()])
+            .unwrap();
+
+        assert_eq!(peer.allowed_ips(), vec![cover]);
+    }
+
+    #[test]
+    fn test_allowed_ips_normalized_child_absorbed_by_cover() {
+        let peer = create_peer();
+        let other: AllowedIP = "192.168.0.0/24".parse().unwrap();
+        peer.add_allowed_ips_normalized(&[
+            "10.0.0.0/24".parse().unwrap(),
+            "10.0.1.1/32".parse().unwrap(),
+            other,
+        ])
+        .unwrap();
+        let cover: AllowedIP = "10.0.0.0/16".parse().unwrap();
+        peer.add_allowed_ips_normalized(&[cover]).unwrap();
+
+        let mut allowed_ips = peer.allowed_ips();
+        allowed_ips.sort();
+        assert_eq!(allowed_ips, vec![cover, other]);
+    }
+
+    #[test]
+    fn test_session_callbacks() {
+        let peer = create_peer();
+        let allowed_ip: AllowedIP = "10.0.0.0/24".parse().unwrap();
+        peer.add_allowed_ips(&[allowed_ip]).unwrap();
+
+        let completed = Arc::new(parking_lot::Mutex::new(vec![]));
+        let expired = Arc::new(AtomicBool::new(false));
+        {
+            let completed = Arc::clone(&completed);
+            peer.on_handshake_complete(move |ips| completed.lock().extend_from_slice(ips));
+            let expired = Arc::clone(&expired);
+            peer.on_session_expired(move || {
+                assert!(!expired.swap(true, Ordering::Relaxed));
+            });
+        }
+
+        // Expiry without a completed handshake is not reported
+        peer.session_expired();
+        assert!(!expired.load(Ordering::Relaxed));
+
+        peer.handshake_completed();
+        assert_eq!(*completed.lock(), vec![allowed_ip]);
+
+        // Expiry is reported once per session
+        peer.session_expired();
+        peer.session_expired();
+        assert!(expired.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_parse_allowed_ip_list() {
+        let list = AllowedIP::parse_list(" 10.0.0.0/24,fd00::/64 , 192.0.2.1, fd00::1").unwrap();
+        assert_eq!(
+            list,
+            [
+                "10.0.0.0/24".parse().unwrap(),
+                "fd00::/64".parse().unwrap(),
+                "192.0.2.1/32".parse::<AllowedIP>().unwrap(),
+                "fd00::1/128".parse().unwrap(),
+            ]
         );
+        assert_eq!(AllowedIP::parse_list(""), Ok(vec![]));
 
-        peer.connect_endpoint(12345).unwrap();
+        assert_eq!(
+            AllowedIP::parse_list("10.0.0.0/24, 10.0.1.0/33"),
+            Err("Allowed IP 2 (\"10.0.1.0/33\"): Invalid IP format".to_owned())
+        );
+        assert!(AllowedIP::parse_list("10.0.0.0/24,").is_err());
+    }
+
+    #[test]
+    fn test_egress_filter() {
+        let peer = create_peer();
+        let to = |dst: [u8; 4]| {
+            let mut packet = vec![0u8; 20];
+            packet[0] = 0x45;
+            packet[16..20].copy_from_slice(&dst);
+            packet
+        };
+        assert!(peer.admit_egress(&to([192, 0, 2, 1])));
+
+        peer.set_egress_filter(&["10.0.0.0/24".parse().unwrap()]);
+        assert!(peer.admit_egress(&to([10, 0, 0, 7])));
+        assert!(!peer.admit_egress(&to([10, 0, 1, 7])));
+        assert!(!peer.admit_egress(&[0x45]));
+        assert_eq!(peer.egress_drops(), 2);
+
+        peer.set_egress_filter(&[]);
+        assert!(peer.admit_egress(&to([10, 0, 1, 7])));
+        assert_eq!(peer.egress_drops(), 2);
+    }
+
+    #[test]
+    fn test_last_rx_source() {
+        let peer = create_peer();
+        assert_eq!(peer.last_rx_source(), None);
+
+        let endpoint: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let source: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        peer.set_endpoint(endpoint);
+        peer.record_rx_source(source);
+        assert_eq!(peer.last_rx_source(), Some(source));
+        assert_eq!(peer.endpoint().addr, Some(endpoint));
+    }
+
+    #[test]
+    fn test_socket_fd() {
+        let peer = create_peer();
+        assert_eq!(peer.socket_fd(), None);
+
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_endpoint(remote.local_addr().unwrap());
+        let conn = peer.connect_endpoint(0).unwrap();
+        // The peer keeps its own duplicate of the socket
+        assert!(peer.socket_fd().is_some());
+        assert_ne!(peer.socket_fd(), Some(conn.as_raw_fd()));
+
+        peer.shutdown_endpoint();
+        assert_eq!(peer.socket_fd(), None);
+    }
+
+    #[test]
+    fn test_send_queue() {
+        let peer = create_peer();
+        // Disabled by default
+        assert_eq!(peer.try_enqueue(&[0]), Err(QueueFull));
+
+        peer.set_send_queue_capacity(3);
+        let pool = BufferPool::new(2048, 4);
+        for i in 0..3 {
+            peer.try_enqueue_pooled(&[i], &pool).unwrap();
+        }
+        assert_eq!(peer.try_enqueue(&[3]), Err(QueueFull));
+        assert_eq!(peer.send_queue_len(), 3);
+
+        // Still congested after the first packet, the second stays queued
+        let mut sent = vec![];
+        assert!(!peer.drain_send_queue(&pool, |packet| {
+            if sent.is_empty() {
+                sent.push(packet[0]);
+                Ok(packet.len())
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }));
+        assert_eq!(peer.send_queue_len(), 2);
+        assert_eq!(pool.len(), 1);
+
+        assert!(peer.drain_send_queue(&pool, |packet| {
+            sent.push(packet[0]);
+            Ok(packet.len())
+        }));
+        assert_eq!(sent, [0, 1, 2]);
+        assert_eq!(peer.send_queue_len(), 0);
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn test_held_control_packet() {
+        let peer = create_peer();
+        let congested = |_: &[u8]| -> io::Result<usize> { Err(io::ErrorKind::WouldBlock.into()) };
+        assert!(peer.retry_held_control(|_| unreachable!()));
+
+        // The newer packet replaces the older one
+        peer.hold_control_packet(&[0], 2);
+        peer.hold_control_packet(&[1], 2);
+        assert!(!peer.retry_held_control(congested));
+        let mut sent = vec![];
+        assert!(peer.retry_held_control(|packet| {
+            sent.extend_from_slice(packet);
+            Ok(packet.len())
+        }));
+        assert_eq!(sent, [1]);
+        assert!(!peer.holds_control_packet());
+
+        // Dropped once out of retries
+        peer.hold_control_packet(&[2], 2);
+        assert!(!peer.retry_held_control(congested));
+        assert!(peer.retry_held_control(congested));
+        assert!(!peer.holds_control_packet());
+    }
+
+    #[test]
+    fn test_allowed_ips_changed() {
+        let peer = Arc::new(create_peer());
+        let (a, b, c): (AllowedIP, AllowedIP, AllowedIP) = (
+            "10.0.0.0/24".parse().unwrap(),
+            "10.0.1.0/24".parse().unwrap(),
+            "fd00::/64".parse().unwrap(),
+        );
+        peer.add_allowed_ips(&[a]).unwrap();
+
+        let changes = Arc::new(parking_lot::Mutex::new(vec![]));
+        {
+            let changes = Arc::clone(&changes);
+            let weak = Arc::downgrade(&peer);
+            peer.on_allowed_ips_changed(Arc::new(move |added, removed| {
+                // The new set is in place and the lock released
+                let current = weak.upgrade().unwrap().allowed_ips();
+                assert!(added.iter().all(|ip| current.contains(ip)));
+                changes.lock().push((added.to_vec(), removed.to_vec()));
+            }));
+        }
+
+        peer.add_allowed_ips(&[b]).unwrap();
+        peer.set_allowed_ips(&[b, c]).unwrap();
+        // No change, not reported
+        peer.add_allowed_ips(&[c]).unwrap();
+        peer.remove_allowed_ips(&[b]);
+        peer.apply_config(PeerUpdate {
+            allowed_ips: Some(vec![a]),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            *changes.lock(),
+            vec![
+                (vec![b], vec![]),
+                (vec![c], vec![a]),
+                (vec![], vec![b]),
+                (vec![a], vec![c]),
+            ]
+        );
+        assert_eq!(peer.allowed_ips(), vec![a]);
+    }
+
+    #[test]
+    fn test_eager_rehandshake_rate_limited() {
+        let peer = create_peer();
+        let mut dst = [0u8; KEEPALIVE_BUF_SIZE];
+        let init = peer.eager_rehandshake(&mut dst).unwrap();
+        assert_eq!(init.len(), 148);
+        // Within the interval nothing more is sent
+        assert!(peer.eager_rehandshake(&mut dst).is_none());
+        *peer.last_eager_rehandshake.lock() = Some(Instant::now() - EAGER_REHANDSHAKE_INTERVAL);
+        // The initiation sent is still in progress
+        assert!(peer.eager_rehandshake(&mut dst).is_none());
+        assert!(peer.last_eager_rehandshake.lock().unwrap().elapsed() < EAGER_REHANDSHAKE_INTERVAL);
+    }
+
+    #[test]
+    fn test_error_events_limited() {
+        let peer = create_peer();
+        for _ in 0..ERROR_EVENTS_PER_SEC {
+            assert_eq!(peer.admit_error_event(), Some(0));
+        }
+        for _ in 0..5 {
+            assert_eq!(peer.admit_error_event(), None);
+        }
+        // Refilled, the next one published counts those left out
+        peer.error_events
+            .set_rate(ERROR_EVENTS_PER_SEC, ERROR_EVENTS_PER_SEC);
+        assert_eq!(peer.admit_error_event(), Some(5));
+        assert_eq!(peer.admit_error_event(), Some(0));
+    }
+
+    #[test]
+    fn test_rate_limit_drop() {
+        let peer = create_peer();
+        let pool = BufferPool::new(2048, 4);
+        let packet = [0u8; 100];
+        assert!(peer.admit_outbound(&packet, &pool));
+
+        peer.set_rate_limit(1, 200);
+        assert!(peer.admit_outbound(&packet, &pool));
+        assert!(peer.admit_outbound(&packet, &pool));
+        assert!(!peer.admit_outbound(&packet, &pool));
+        assert_eq!(peer.rate_limit_drops(), 1);
+        assert!(peer.take_admitted_outbound().is_empty());
+    }
+
+    #[test]
+    fn test_rate_limit_queue() {
+        let peer = create_peer();
+        let pool = BufferPool::new(2048, 4);
+        peer.set_over_limit(OverLimit::Queue);
+        peer.set_rate_limit(1, 100);
+        assert!(peer.admit_outbound(&[1u8; 100], &pool));
+        assert!(!peer.admit_outbound(&[2u8; 100], &pool));
+        assert!(!peer.admit_outbound(&[3u8; 10], &pool));
+        assert_eq!(peer.rate_limit_drops(), 0);
+        assert!(peer.take_admitted_outbound().is_empty());
+
+        // Lifting the limit releases the queue in order
+        peer.set_rate_limit(0, 0);
+        assert_eq!(
+            peer.take_admitted_outbound(),
+            vec![vec![2u8; 100], vec![3u8; 10]]
+        );
+    }
+
+    /// A peer initiating a session with the returned tunnel
+    fn create_peer_with_session(endpoint: Option<SocketAddr>) -> (Peer, Tunn) {
+        let a_secret_key = StaticSecret::random_from_rng(&mut rand::rngs::StdRng::from_entropy());
+        let a_public_key = PublicKey::from(&a_secret_key);
+        let b_secret_key = StaticSecret::random_from_rng(&mut rand::rngs::StdRng::from_entropy());
+        let b_public_key = PublicKey::from(&b_secret_key);
+
+        let peer = Peer::new(
+            Tunn::new(a_secret_key, b_public_key, None, None, 0, None).unwrap(),
+            0,
+            endpoint,
+            &[],
+            None,
+            Arc::new(crate::device::MakeExternalBoringtunNoop),
+            Arc::new(crate::device::transport::DirectUdp),
+        );
+        let mut their_tun = Tunn::new(b_secret_key, a_public_key, None, None, 1, None).unwrap();
+
+        let mut buf = vec![0u8; 2048];
+        let mut dst = vec![0u8; 2048];
+        let TunnResult::WriteToNetwork(init) = peer
+            .tunnel
+            .lock()
+            .format_handshake_initiation(&mut buf, false)
+        else {
+            panic!("Expected a handshake initiation");
+        };
+        let init = init.to_vec();
+        let TunnResult::WriteToNetwork(resp) = their_tun.decapsulate(None, &init, &mut dst) else {
+            panic!("Expected a handshake response");
+        };
+        let resp = resp.to_vec();
+        let TunnResult::WriteToNetwork(keepalive) =
+            peer.tunnel.lock().decapsulate(None, &resp, &mut buf)
+        else {
+            panic!("Expected a keepalive");
+        };
+        let keepalive = keepalive.to_vec();
+        their_tun.decapsulate(None, &keepalive, &mut dst);
+
+        (peer, their_tun)
+    }
+
+    #[test]
+    fn test_cancel_handshake() {
+        let (peer, mut their_tun) = create_peer_with_session(None);
+        assert!(!peer.handshake_pending());
+        assert!(!peer.cancel_handshake());
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        let TunnResult::WriteToNetwork(init) = peer
+            .tunnel
+            .lock()
+            .format_handshake_initiation(&mut buf, true)
+        else {
+            panic!("Expected a handshake initiation");
+        };
+        let init = init.to_vec();
+        assert!(peer.handshake_pending());
+        assert!(peer.cancel_handshake());
+        assert!(!peer.handshake_pending());
+
+        // The response to the cancelled initiation completes nothing
+        let TunnResult::WriteToNetwork(resp) = their_tun.decapsulate(None, &init, &mut dst) else {
+            panic!("Expected a handshake response");
+        };
+        let resp = resp.to_vec();
+        assert!(matches!(
+            peer.tunnel.lock().decapsulate(None, &resp, &mut buf),
+            TunnResult::Err(_)
+        ));
+
+        // And the session from before still carries data
+        let TunnResult::WriteToNetwork(data) = peer.tunnel.lock().encapsulate(&[], &mut buf) else {
+            panic!("Expected a keepalive");
+        };
+        let data = data.to_vec();
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut dst),
+            TunnResult::Done
+        ));
+    }
+
+    #[test]
+    fn test_path_mtu() {
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (peer, _) = create_peer_with_session(Some(remote.local_addr().unwrap()));
+        assert_eq!(peer.path_mtu(), None);
+
+        let now = Instant::now();
+        peer.record_path_mtu(1420, now);
+        assert_eq!(peer.path_mtu_at(now), Some(1420));
+        peer.record_path_mtu(1280, now);
+        assert_eq!(peer.path_mtu_at(now), Some(1280));
+        // Up again after the path changed
+        peer.record_path_mtu(1400, now + Duration::from_secs(1));
+        assert_eq!(peer.path_mtu_at(now + Duration::from_secs(1)), Some(1400));
+        // Forgotten unless confirmed, to probe upward
+        assert_eq!(peer.path_mtu_at(now + PATH_MTU_EXPIRY * 2), None);
+
+        peer.set_endpoint("127.0.0.1:9".parse().unwrap());
+        assert_eq!(peer.path_mtu_at(now), None);
+
+        // Read from the connected socket
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            peer.set_endpoint(remote.local_addr().unwrap());
+            let conn = peer.connect_endpoint(0).unwrap();
+            crate::device::transport::set_path_mtu_discovery(&conn).unwrap();
+            peer.update_path_mtu(now).unwrap();
+            assert!(peer.path_mtu_at(now).unwrap() >= 1280);
+        }
+    }
+
+    #[test]
+    fn test_handshake_socket() {
+        let remote = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        remote
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (peer, mut their_tun) = create_peer_with_session(Some(remote.local_addr().unwrap()));
+        let primary = peer.connect_endpoint(0).unwrap();
+        let secondary = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        secondary.connect(remote.local_addr().unwrap()).unwrap();
+        let secondary_addr = secondary.local_addr().unwrap();
+        peer.set_handshake_socket(Some(socket2::Socket::from(secondary)));
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        // Data keeps to the connected endpoint
+        peer.send_keepalive_now(false).unwrap();
+        let (len, src) = remote.recv_from(&mut buf).unwrap();
+        let primary_port = primary.local_addr().unwrap().as_socket().unwrap().port();
+        assert_eq!(src.port(), primary_port);
+        assert_eq!(classify_packet(&buf[..len]), Some(MessageType::Data));
+        assert!(matches!(
+            their_tun.decapsulate(None, &buf[..len], &mut dst),
+            TunnResult::Done
+        ));
+
+        // While the initiation takes the handshake socket
+        peer.reset_session().unwrap();
+        let (len, src) = remote.recv_from(&mut buf).unwrap();
+        assert_eq!(src, secondary_addr);
+        assert_eq!(
+            classify_packet(&buf[..len]),
+            Some(MessageType::HandshakeInit)
+        );
+
+        // Not for handshakes to another address than it is connected to
+        let elsewhere = "127.0.0.1:9".parse().unwrap();
+        assert!(peer.send_handshake(&buf[..len], Some(elsewhere)).is_none());
+        peer.set_handshake_socket(None);
+        assert!(peer.send_handshake(&buf[..len], None).is_none());
+    }
+
+    #[test]
+    fn test_blackhole_detector() {
+        let (peer, mut their_tun) = create_peer_with_session(None);
+        let mut buf = vec![0u8; 2048];
+        let mut dst = vec![0u8; 2048];
+
+        let fired = Arc::new(AtomicU64::new(0));
+        {
+            let fired = Arc::clone(&fired);
+            peer.set_blackhole_detector(Duration::ZERO, move || {
+                fired.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+
+        // Idle
+        peer.check_blackhole();
+        assert_eq!(fired.load(Ordering::Relaxed), 0);
+
+        // Sending without receiving
+        peer.tunnel.lock().encapsulate(&[], &mut buf);
+        peer.check_blackhole();
+        assert_eq!(fired.load(Ordering::Relaxed), 1);
+
+        // Sending and receiving
+        peer.tunnel.lock().encapsulate(&[], &mut buf);
+        let TunnResult::WriteToNetwork(data) = their_tun.encapsulate(&[], &mut dst) else {
+            panic!("Expected a keepalive");
+        };
+        let data = data.to_vec();
+        peer.tunnel.lock().decapsulate(None, &data, &mut buf);
+        peer.check_blackhole();
+        assert_eq!(fired.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_send_keepalive_now() {
+        let receiver =
+            std::net::UdpSocket::bind(SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0)))
+                .unwrap();
+        let (peer, mut their_tun) = create_peer_with_session(Some(receiver.local_addr().unwrap()));
+        assert!(peer.send_keepalive_now(false).is_err());
+        peer.connect_endpoint(0).unwrap();
+
+        let mut buf = [0u8; 2048];
+        let mut dst = [0u8; 2048];
+        for _ in 0..2 {
+            peer.send_keepalive_now(false).unwrap();
+            let len = receiver.recv(&mut buf).unwrap();
+            assert!(matches!(
+                their_tun.decapsulate(None, &buf[..len], &mut dst),
+                TunnResult::Done
+            ));
+        }
+    }
+
+    #[test]
+    fn test_send_keepalive_without_session() {
+        let peer = create_peer();
+        let mut buf = [0u8; KEEPALIVE_BUF_SIZE];
+        assert!(matches!(
+            peer.format_keepalive(false, &mut buf),
+            Err(Error::Keepalive(WireGuardError::NoCurrentSession))
+        ));
+        let init = peer.format_keepalive(true, &mut buf).unwrap().unwrap();
+        assert!(matches!(
+            Tunn::parse_incoming_packet(init),
+            Ok(crate::noise::Packet::HandshakeInit(_))
+        ));
+    }
+
+    #[test]
+    fn test_endpoint_scope() {
+        let peer = create_peer();
+        let addr: SocketAddr = "[fe80::1]:51820".parse().unwrap();
+        peer.set_endpoint_with_scope(addr, 3);
+        let Some(SocketAddr::V6(endpoint)) = peer.endpoint().addr else {
+            panic!("Expected an IPv6 endpoint");
+        };
+        assert_eq!(endpoint.scope_id(), 3);
+        assert_eq!(
+            endpoint.ip(),
+            &"fe80::1".parse::<std::net::Ipv6Addr>().unwrap()
+        );
+
+        let scoped: SocketAddr = "[fe80::2%7]:51820".parse().unwrap();
+        peer.set_endpoint(scoped);
+        assert_eq!(peer.endpoint().addr, Some(scoped));
+        let Some(SocketAddr::V6(endpoint)) = peer.endpoint().addr else {
+            panic!("Expected an IPv6 endpoint");
+        };
+        assert_eq!(endpoint.scope_id(), 7);
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let peer = create_peer();
+        let old_endpoint: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let old_ips: Vec<AllowedIP> = vec!["10.0.0.0/24".parse().unwrap()];
+        peer.set_endpoint(old_endpoint);
+        peer.set_allowed_ips(&old_ips).unwrap();
+        peer.tunnel.lock().set_persistent_keepalive(25);
+
+        let update = PeerUpdate {
+            endpoint: Some("192.0.2.2:51820".parse().unwrap()),
+            allowed_ips: Some(vec!["10.1.0.0/16".parse().unwrap()]),
+            keepalive: Some(0),
+            preshared_key: Some([3; 32]),
+        };
+        peer.record_path_mtu(1420, Instant::now());
+        let previous = peer.apply_config(update.clone()).unwrap();
+        assert_eq!(
+            previous,
+            PeerUpdate {
+                endpoint: Some(old_endpoint),
+                allowed_ips: Some(old_ips.clone()),
+                keepalive: Some(25),
+                preshared_key: Some([0; 32]),
+            }
+        );
+        assert_eq!(peer.endpoint().addr, update.endpoint);
+        // Learned for the old endpoint
+        assert_eq!(peer.path_mtu(), None);
+        assert!(peer.is_allowed_ip(IpAddr::from([10, 1, 2, 3])));
+        assert!(!peer.is_allowed_ip(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(peer.tunnel.lock().persistent_keepalive(), None);
+        assert_eq!(peer.preshared_key(), Some([3; 32]));
+
+        // Fields not set are left alone
+        assert_eq!(
+            peer.apply_config(PeerUpdate::default()).unwrap(),
+            PeerUpdate::default()
+        );
+
+        // Nothing is applied with more allowed IPs than the limit
+        peer.set_max_allowed_ips(1);
+        let over_limit = PeerUpdate {
+            endpoint: Some(old_endpoint),
+            allowed_ips: Some(vec![
+                "10.2.0.0/16".parse().unwrap(),
+                "10.3.0.0/16".parse().unwrap(),
+            ]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            peer.apply_config(over_limit),
+            Err(Error::TooManyAllowedIps(1))
+        ));
+        assert_eq!(peer.endpoint().addr, update.endpoint);
+        assert_eq!(peer.allowed_ips(), update.allowed_ips.clone().unwrap());
+
+        // Applying the previous values undoes the update
+        assert_eq!(peer.apply_config(previous).unwrap(), update);
+        assert_eq!(peer.endpoint().addr, Some(old_endpoint));
+        assert_eq!(peer.allowed_ips(), old_ips);
+        assert_eq!(peer.tunnel.lock().persistent_keepalive(), Some(25));
+        assert!(!peer.has_preshared_key());
+    }
+
+    #[test]
+    fn test_tags() {
+        let peer = create_peer();
+        assert!(!peer.has_tag("region:eu"));
+        peer.add_tag("region:eu");
+        peer.add_tag("tier:free");
+        assert!(peer.has_tag("region:eu"));
+        peer.remove_tag("region:eu");
+        assert!(!peer.has_tag("region:eu"));
+        assert!(peer.has_tag("tier:free"));
+    }
+
+    #[test]
+    fn test_decrypt_failures() {
+        let (peer, mut their_tun) = create_peer_with_session(None);
+
+        let mut buf = vec![0u8; 2048];
+        let mut dst = vec![0u8; 2048];
+        let TunnResult::WriteToNetwork(data) = their_tun.encapsulate(&[], &mut buf) else {
+            panic!("Expected a data packet");
+        };
+        let mut corrupted = data.to_vec();
+        *corrupted.last_mut().unwrap() ^= 1;
+
+        let res = peer.tunnel.lock().decapsulate(None, &corrupted, &mut dst);
+        let TunnResult::Err(err) = res else {
+            panic!("Expected a decryption failure");
+        };
+        peer.record_decapsulate_error(&err, &corrupted);
+        assert_eq!(peer.decrypt_failures(), 1);
+
+        // Other errors are not counted
+        peer.record_decapsulate_error(&WireGuardError::DuplicateCounter, &corrupted);
+        assert_eq!(peer.decrypt_failures(), 1);
+
+        // Nor are handshakes failing to decrypt
+        let TunnResult::WriteToNetwork(init) =
+            their_tun.format_handshake_initiation(&mut buf, true)
+        else {
+            panic!("Expected a handshake initiation");
+        };
+        peer.record_decapsulate_error(&WireGuardError::InvalidAeadTag, init);
+        assert_eq!(peer.decrypt_failures(), 1);
     }
 }
@@ -0,0 +1,153 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A small least recently used map from the source address of a datagram to the peer that sent
+//! it, consulted by the listening sockets before the full lookup. See
+//! `Device::set_source_cache_size`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+
+/// Holds at most `capacity` entries, inserting past that evicts the one used longest ago. A
+/// capacity of 0 keeps nothing.
+pub struct SourceCache<V> {
+    entries: HashMap<SocketAddr, (V, u64)>,
+    /// Source of each entry, by the stamp of its last use
+    recency: BTreeMap<u64, SocketAddr>,
+    next_stamp: u64,
+    capacity: usize,
+}
+
+impl<V> SourceCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        SourceCache {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_stamp: 0,
+            capacity,
+        }
+    }
+
+    fn stamp(&mut self) -> u64 {
+        self.next_stamp += 1;
+        self.next_stamp
+    }
+
+    /// The entry for `src`, which becomes the most recently used
+    pub fn get(&mut self, src: &SocketAddr) -> Option<&V> {
+        let stamp = self.stamp();
+        let (_, used) = self.entries.get_mut(src)?;
+        self.recency.remove(used);
+        self.recency.insert(stamp, *src);
+        *used = stamp;
+        self.entries.get(src).map(|(value, _)| value)
+    }
+
+    pub fn insert(&mut self, src: SocketAddr, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&src);
+        while self.entries.len() >= self.capacity {
+            self.evict_oldest();
+        }
+        let stamp = self.stamp();
+        self.recency.insert(stamp, src);
+        self.entries.insert(src, (value, stamp));
+    }
+
+    pub fn remove(&mut self, src: &SocketAddr) -> Option<V> {
+        let (value, used) = self.entries.remove(src)?;
+        self.recency.remove(&used);
+        Some(value)
+    }
+
+    /// Drop every entry whose value matches `f`
+    pub fn remove_matching(&mut self, mut f: impl FnMut(&V) -> bool) {
+        let recency = &mut self.recency;
+        self.entries.retain(|_, (value, used)| {
+            let keep = !f(value);
+            if !keep {
+                recency.remove(used);
+            }
+            keep
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    /// Change the capacity, evicting the least recently used entries past it
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((&used, _)) = self.recency.iter().next() {
+            if let Some(src) = self.recency.remove(&used) {
+                self.entries.remove(&src);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    #[test]
+    fn test_least_recently_used_evicted() {
+        let mut cache = SourceCache::new(2);
+        cache.insert(addr(1), 1);
+        cache.insert(addr(2), 2);
+        assert_eq!(cache.get(&addr(1)), Some(&1));
+
+        cache.insert(addr(3), 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&addr(2)), None);
+        assert_eq!(cache.get(&addr(1)), Some(&1));
+        assert_eq!(cache.get(&addr(3)), Some(&3));
+
+        cache.set_capacity(1);
+        assert_eq!(cache.get(&addr(1)), None);
+        assert_eq!(cache.get(&addr(3)), Some(&3));
+
+        cache.set_capacity(0);
+        cache.insert(addr(4), 4);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_remove_matching() {
+        let mut cache = SourceCache::new(4);
+        cache.insert(addr(1), 1);
+        cache.insert(addr(2), 2);
+        cache.insert(addr(3), 1);
+        cache.remove_matching(|&v| v == 1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&addr(2)), Some(&2));
+
+        // Eviction still works on what is left
+        cache.set_capacity(0);
+        assert!(cache.is_empty());
+    }
+}
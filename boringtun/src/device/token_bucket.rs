@@ -0,0 +1,155 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+#[cfg(feature = "mock-instant")]
+use mock_instant::Instant;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(not(feature = "mock-instant"))]
+use crate::sleepyinstant::Instant;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A token bucket counting bytes, refilled at a fixed rate up to a burst size.
+/// All operations are lock free, so it can be consulted on the packet path.
+pub struct TokenBucket {
+    /// Refill rate in bytes per second, 0 when unlimited
+    rate: AtomicU64,
+    /// Maximum amount of tokens the bucket can hold
+    burst: AtomicU64,
+    tokens: AtomicU64,
+    /// Nanoseconds since `start` up to which tokens were refilled
+    refilled_until: AtomicU64,
+    start: Instant,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        TokenBucket {
+            rate: AtomicU64::new(0),
+            burst: AtomicU64::new(0),
+            tokens: AtomicU64::new(0),
+            refilled_until: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /// Limit to `bytes_per_sec`, allowing bursts of `burst` bytes. A rate of 0 removes the limit.
+    pub fn set_rate(&self, bytes_per_sec: u64, burst: u64) {
+        self.rate.store(bytes_per_sec, Ordering::Relaxed);
+        self.burst.store(burst, Ordering::Relaxed);
+        self.tokens.store(burst, Ordering::Relaxed);
+        self.refilled_until
+            .store(self.elapsed_nanos(), Ordering::Relaxed);
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.rate.load(Ordering::Relaxed) != 0
+    }
+
+    /// Take `len` tokens if available
+    pub fn try_consume(&self, len: usize) -> bool {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return true;
+        }
+
+        self.refill(rate);
+
+        self.tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                tokens.checked_sub(len as u64)
+            })
+            .is_ok()
+    }
+
+    fn refill(&self, rate: u64) {
+        let now = self.elapsed_nanos();
+        let refilled_until = self.refilled_until.load(Ordering::Relaxed);
+        let elapsed = now.saturating_sub(refilled_until) as u128;
+
+        let new_tokens = elapsed * rate as u128 / NANOS_PER_SEC;
+        if new_tokens == 0 {
+            return;
+        }
+
+        // Only advance the refill time by what the new tokens account for, so the fractional
+        // remainder is not lost when refilling often
+        let consumed = (new_tokens * NANOS_PER_SEC / rate as u128) as u64;
+        if self
+            .refilled_until
+            .compare_exchange(
+                refilled_until,
+                refilled_until + consumed,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            // Another thread refilled in the meantime
+            return;
+        }
+
+        let burst = self.burst.load(Ordering::Relaxed);
+        let _ = self
+            .tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                Some(tokens.saturating_add(new_tokens as u64).min(burst))
+            });
+    }
+
+    fn elapsed_nanos(&self) -> u64 {
+        Instant::now().duration_since(self.start).as_nanos() as u64
+    }
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited_by_default() {
+        let bucket = TokenBucket::new();
+        assert!(!bucket.is_limited());
+        assert!(bucket.try_consume(usize::MAX));
+    }
+
+    #[test]
+    fn test_burst_exhausted() {
+        let bucket = TokenBucket::new();
+        // Slow enough that no meaningful refill happens during the test
+        bucket.set_rate(1, 1000);
+        for _ in 0..10 {
+            assert!(bucket.try_consume(100));
+        }
+        assert!(!bucket.try_consume(100));
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn test_sustained_rate_throttled() {
+        use std::time::Duration;
+
+        let bucket = TokenBucket::new();
+        bucket.set_rate(10_000, 1_000);
+
+        // Offer ten times the configured rate for 10 seconds
+        let mut sent = 0;
+        for _ in 0..10_000 {
+            if bucket.try_consume(100) {
+                sent += 100;
+            }
+            mock_instant::MockClock::advance(Duration::from_millis(1));
+        }
+
+        // Burst plus 10 seconds worth of refill, with some slack
+        assert!(sent >= 100_000, "sent {}", sent);
+        assert!(sent <= 102_000, "sent {}", sent);
+    }
+}
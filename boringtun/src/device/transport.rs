@@ -0,0 +1,567 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use socket2::{Domain, Protocol, Type};
+
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
+
+use crate::device::{Error, MakeExternalBoringtun};
+
+/// Carries the datagrams of a peer's connected endpoint.
+///
+/// The socket returned by `connect` is registered with the event loop, and every datagram sent
+/// to or received from it goes through `send` and `recv`. This lets a relay (UDP over SOCKS5, QUIC
+/// datagrams, ...) wrap and unwrap its own framing. Relays must preserve datagram boundaries: each
+/// packet passed to `send` has to come out of the remote end as exactly one packet.
+///
+/// Only connected endpoints are covered. The listen sockets stay plain UDP and what the device
+/// sends from them bypasses the transport: everything sent to a peer before its endpoint is
+/// connected, which happens once a datagram from the peer arrives, and all traffic while
+/// `DeviceConfig::use_connected_socket` is off or connecting the endpoint fails.
+pub trait Transport: Send + Sync {
+    /// Open a nonblocking socket bound to the local `port` and connected towards `addr`
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        port: u16,
+        protect: &dyn MakeExternalBoringtun,
+    ) -> Result<socket2::Socket, Error>;
+
+    fn send(&self, conn: &socket2::Socket, packet: &[u8]) -> io::Result<usize>;
+
+    fn recv(&self, conn: &socket2::Socket, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize>;
+
+    /// Mark the datagrams `conn` sends with `mark` for policy routing, `SO_MARK` where the
+    /// platform has it and nothing elsewhere. The device marks its listen sockets this way too.
+    fn set_mark(&self, conn: &socket2::Socket, mark: u32) -> io::Result<()> {
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        return conn.set_mark(mark);
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        {
+            let _ = (conn, mark);
+            Ok(())
+        }
+    }
+}
+
+/// Let `socket` bind a port other sockets are bound to, with `SO_REUSEADDR`. This is how a
+/// connected peer socket shares the port of the listen socket: for each datagram the kernel
+/// picks the socket matching it best, the connected one for datagrams from its endpoint.
+pub fn set_reuse_addr(socket: &socket2::Socket) -> io::Result<()> {
+    socket.set_reuse_address(true)
+}
+
+/// Let several listen sockets bind the same port with `SO_REUSEPORT`, the kernel spreading the
+/// incoming datagrams over them by a hash of their addresses.
+///
+/// Connected sockets must not use it. A lookup finding a reuseport group takes the member picked
+/// by the hash, and on IPv6 Linux does so before looking for a better match, so a connected peer
+/// socket in the group would lose datagrams from its endpoint to the listeners and take some
+/// meant for them. `set_reuse_addr` doesn't have that problem.
+#[cfg(unix)]
+pub fn set_reuse_port(socket: &socket2::Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+/// Set the TTL of the datagrams `socket` sends, `IP_TTL` or `IPV6_UNICAST_HOPS` by the family of
+/// the address it is bound to, so it must be bound already. Besides limiting how far tunnel
+/// packets travel, this serves the Generalized TTL Security Mechanism (GTSM, RFC 5082): two
+/// directly connected hosts send with a TTL of 255 and drop anything arriving with less, which
+/// anything sent from beyond the link can't fake. Checking the TTL on receipt is up to the
+/// firewall of the other side.
+pub fn set_ttl(socket: &socket2::Socket, ttl: u8) -> io::Result<()> {
+    if is_ipv6(socket)? {
+        socket.set_unicast_hops_v6(ttl.into())
+    } else {
+        socket.set_ttl(ttl.into())
+    }
+}
+
+/// The TTL of the datagrams `socket` sends, see `set_ttl`
+pub fn ttl(socket: &socket2::Socket) -> io::Result<u8> {
+    let ttl = if is_ipv6(socket)? {
+        socket.unicast_hops_v6()?
+    } else {
+        socket.ttl()?
+    };
+    Ok(ttl as u8)
+}
+
+fn is_ipv6(socket: &socket2::Socket) -> io::Result<bool> {
+    Ok(socket
+        .local_addr()?
+        .as_socket()
+        .map_or(false, |addr| addr.is_ipv6()))
+}
+
+/// A datagram received with `recv_with_info`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecvInfo {
+    pub len: usize,
+    pub source: SocketAddr,
+    /// Index of the interface the datagram arrived on, `None` when it came without packet info
+    pub ifindex: Option<u32>,
+    /// The local address the datagram was sent to, `None` when it came without packet info
+    pub destination: Option<IpAddr>,
+    /// The datagrams dropped on the socket since `set_rxq_ovfl`, as of this one. `None` without
+    /// it, or until the kernel first drops one.
+    pub rxq_ovfl_drops: Option<u32>,
+}
+
+/// Have the kernel tell which interface and local address each datagram arrives on, with
+/// `IP_PKTINFO` or `IPV6_RECVPKTINFO` by the family of the address `socket` is bound to. Read
+/// them with `recv_with_info`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_recv_pktinfo(socket: &socket2::Socket) -> io::Result<()> {
+    if is_ipv6(socket)? {
+        setsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)
+    } else {
+        setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)
+    }
+}
+
+/// `recvmsg` from `socket` into `buf`, passing the level, type and data of each control message
+/// that came with the datagram to `on_cmsg`. `control` must be large enough for all of them,
+/// the kernel truncates the rest.
+///
+/// # Safety
+///
+/// `on_cmsg` gets the data as the kernel wrote it, unaligned, and may only read as much of it as
+/// the level and type imply.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn recvmsg_cmsgs(
+    socket: &socket2::Socket,
+    buf: &mut [MaybeUninit<u8>],
+    flags: libc::c_int,
+    control: &mut [u64],
+    mut on_cmsg: impl FnMut(libc::c_int, libc::c_int, *const libc::c_uchar),
+) -> io::Result<(usize, socket2::SockAddr)> {
+    // Safety: `try_init` hands us storage for the source address, which recvmsg fills in, and
+    // the control messages are only walked within the bounds the kernel reported
+    socket2::SockAddr::try_init(|addr, addr_len| {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_name = addr as *mut libc::c_void;
+        msg.msg_namelen = *addr_len;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(control) as _;
+
+        let n = libc::recvmsg(socket.as_raw_fd(), &mut msg, flags);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        *addr_len = msg.msg_namelen;
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            on_cmsg((*cmsg).cmsg_level, (*cmsg).cmsg_type, libc::CMSG_DATA(cmsg));
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        Ok(n as usize)
+    })
+}
+
+/// Like `recv_from`, but with `recvmsg` to also read the packet info `set_recv_pktinfo` and the
+/// drop count `set_rxq_ovfl` asked for. Without them, or for a datagram received before they
+/// were set, the fields they fill in are `None`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn recv_with_info(
+    socket: &socket2::Socket,
+    buf: &mut [MaybeUninit<u8>],
+) -> io::Result<RecvInfo> {
+    // Room for the larger in6_pktinfo and the drop count, u64 for cmsghdr alignment
+    let mut control = [0u64; 16];
+    let mut ifindex = None;
+    let mut destination = None;
+    let mut rxq_ovfl_drops = None;
+
+    // Safety: each control message is read as the type its level and type name
+    let (len, source) = unsafe {
+        recvmsg_cmsgs(socket, buf, 0, &mut control, |level, kind, data| {
+            match (level, kind) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info = std::ptr::read_unaligned(data as *const libc::in_pktinfo);
+                    ifindex = Some(info.ipi_ifindex as u32);
+                    destination = Some(IpAddr::from(info.ipi_addr.s_addr.to_ne_bytes()));
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = std::ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                    ifindex = Some(info.ipi6_ifindex);
+                    destination = Some(IpAddr::from(info.ipi6_addr.s6_addr));
+                }
+                (libc::SOL_SOCKET, libc::SO_RXQ_OVFL) => {
+                    rxq_ovfl_drops = Some(std::ptr::read_unaligned(data as *const u32));
+                }
+                _ => {}
+            }
+        })?
+    };
+
+    Ok(RecvInfo {
+        len,
+        source: source
+            .as_socket()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?,
+        ifindex,
+        destination,
+        rxq_ovfl_drops,
+    })
+}
+
+/// Have the kernel count the datagrams it drops because the receive queue of `socket` is full,
+/// with `SO_RXQ_OVFL`. The count is read with `recv_with_info`. Each socket keeps its own, so
+/// with `set_reuse_port` it tells which of the sockets sharing a port falls behind.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_rxq_ovfl(socket: &socket2::Socket) -> io::Result<()> {
+    setsockopt_int(socket, libc::SOL_SOCKET, libc::SO_RXQ_OVFL, 1)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_rxq_ovfl(_socket: &socket2::Socket) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_EE_ORIGIN_LOCAL: u8 = 1;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_EE_ORIGIN_ICMP: u8 = 2;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_EE_ORIGIN_ICMP6: u8 = 3;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn getsockopt_int(
+    socket: &socket2::Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+    match unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(value),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn setsockopt_int(
+    socket: &socket2::Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    match unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Do path MTU discovery on `socket`: send with Don't Fragment, and queue the ICMP
+/// fragmentation needed or packet too big messages received for it on the error queue, with
+/// `IP_RECVERR` or `IPV6_RECVERR`, for `take_packet_too_big`. The family is that of the address
+/// `socket` is bound to. Datagrams larger than the path MTU the kernel knows fail to send with
+/// `EMSGSIZE` from then on.
+///
+/// Fails on a socket with `SO_ZEROCOPY`, see `zerocopy`: `take_packet_too_big` would drop the
+/// completions of its sends from the error queue.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_path_mtu_discovery(socket: &socket2::Socket) -> io::Result<()> {
+    match getsockopt_int(socket, libc::SOL_SOCKET, libc::SO_ZEROCOPY) {
+        Ok(0) => {}
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Path MTU discovery does not mix with zerocopy sends",
+            ))
+        }
+        // Kernels without zerocopy
+        Err(err) if err.raw_os_error() == Some(libc::ENOPROTOOPT) => {}
+        Err(err) => return Err(err),
+    }
+    if is_ipv6(socket)? {
+        setsockopt_int(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )?;
+        setsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1)
+    } else {
+        setsockopt_int(
+            socket,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )?;
+        setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_RECVERR, 1)
+    }
+}
+
+/// The path MTU the kernel knows for the connected `socket`, with `IP_MTU` or `IPV6_MTU`. The
+/// kernel ages what it learned from ICMP, so this goes back up to the MTU of the route after a
+/// while. Saturates at `u16::MAX`, which loopback exceeds.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn path_mtu(socket: &socket2::Socket) -> io::Result<u16> {
+    let mtu = if is_ipv6(socket)? {
+        getsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_MTU)?
+    } else {
+        getsockopt_int(socket, libc::IPPROTO_IP, libc::IP_MTU)?
+    };
+    Ok(mtu.clamp(0, u16::MAX.into()) as u16)
+}
+
+/// Whether `socket` queues errors with `set_path_mtu_discovery`
+#[cfg(all(target_os = "linux", feature = "zerocopy"))]
+pub(crate) fn queues_errors(socket: &socket2::Socket) -> io::Result<bool> {
+    let recverr = if is_ipv6(socket)? {
+        getsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVERR)?
+    } else {
+        getsockopt_int(socket, libc::IPPROTO_IP, libc::IP_RECVERR)?
+    };
+    Ok(recverr != 0)
+}
+
+/// Drain the error queue of `socket`, set up with `set_path_mtu_discovery`, and return the MTU
+/// of the latest packet too big among the messages, `None` if there was none. Other errors
+/// queued are dropped, so this doesn't mix with anything else reading the error queue, like
+/// the completions of `zerocopy` sends: `set_path_mtu_discovery` and `zerocopy::set_zerocopy`
+/// refuse a socket set up for the other.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn take_packet_too_big(socket: &socket2::Socket) -> io::Result<Option<u16>> {
+    let mut mtu = None;
+    loop {
+        // Room for the extended error and the offender address, u64 for cmsghdr alignment
+        let mut control = [0u64; 16];
+        let flags = libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT;
+
+        // Safety: the extended errors are read as what their level and type name
+        let res = unsafe {
+            recvmsg_cmsgs(socket, &mut [], flags, &mut control, |level, kind, data| {
+                if (level == libc::SOL_IP && kind == libc::IP_RECVERR)
+                    || (level == libc::SOL_IPV6 && kind == libc::IPV6_RECVERR)
+                {
+                    let err = std::ptr::read_unaligned(data as *const libc::sock_extended_err);
+                    // Learned from ICMP, or from sending past what the kernel already knew
+                    let origin = matches!(
+                        err.ee_origin,
+                        SO_EE_ORIGIN_LOCAL | SO_EE_ORIGIN_ICMP | SO_EE_ORIGIN_ICMP6
+                    );
+                    if origin && err.ee_errno == libc::EMSGSIZE as u32 && err.ee_info > 0 {
+                        mtu = Some(err.ee_info.min(u16::MAX.into()) as u16);
+                    }
+                }
+            })
+        };
+        match res {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(mtu),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Bind `socket` to the VRF, the L3 master device, named `vrf_name` with `SO_BINDTODEVICE`.
+///
+/// Routes and source addresses are then looked up in the table of the VRF, from its enslaved
+/// interfaces. A fwmark still applies, but only through the rules ordered before the `l3mdev`
+/// rule, so with the default rule priorities the VRF wins. The socket no longer receives
+/// datagrams arriving on interfaces outside the VRF, unless `net.ipv4.udp_l3mdev_accept` lets
+/// sockets of the default VRF take them instead. Binding before `bind` also lets the same port
+/// be used in other VRFs.
+#[cfg(target_os = "linux")]
+pub fn bind_to_vrf(socket: &socket2::Socket, vrf_name: &str) -> io::Result<()> {
+    socket.bind_device(Some(vrf_name.as_bytes()))
+}
+
+/// Sends datagrams directly to the endpoint over UDP
+pub struct DirectUdp;
+
+impl Transport for DirectUdp {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        port: u16,
+        protect: &dyn MakeExternalBoringtun,
+    ) -> Result<socket2::Socket, Error> {
+        let udp_conn =
+            socket2::Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        set_reuse_addr(&udp_conn)?;
+        let bind_addr = match addr {
+            SocketAddr::V4(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into(),
+            // Keep the interface of link-local endpoints
+            SocketAddr::V6(addr) => {
+                SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, addr.scope_id()).into()
+            }
+        };
+        udp_conn.bind(&bind_addr)?;
+        udp_conn.set_nonblocking(true)?;
+        // fw_mark is being set inside make_external(), so no need to set it twice as in Cloudflare's repo.
+        protect.make_external(udp_conn.as_raw_fd());
+        // Also mind that all socket setup functions should be called before .connect().
+        udp_conn.connect(&addr.into())?;
+        Ok(udp_conn)
+    }
+
+    fn send(&self, conn: &socket2::Socket, packet: &[u8]) -> io::Result<usize> {
+        conn.send(packet)
+    }
+
+    fn recv(&self, conn: &socket2::Socket, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        conn.recv(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(port: u16) -> io::Result<socket2::Socket> {
+        let socket = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        set_reuse_addr(&socket)?;
+        set_reuse_port(&socket)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into())?;
+        Ok(socket)
+    }
+
+    #[test]
+    fn test_reuse_port_listeners() {
+        let first = listener(0).unwrap();
+        let port = first.local_addr().unwrap().as_socket().unwrap().port();
+        let second = listener(port).unwrap();
+        assert_eq!(
+            second.local_addr().unwrap().as_socket().unwrap().port(),
+            port
+        );
+
+        // Without the option the port is taken
+        let plain = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        let taken = plain.bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into());
+        assert!(taken.is_err());
+    }
+
+    #[test]
+    fn test_direct_udp() {
+        let endpoint = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let conn = DirectUdp
+            .connect(
+                endpoint.local_addr().unwrap(),
+                0,
+                &crate::device::MakeExternalBoringtunNoop,
+            )
+            .unwrap();
+
+        // Each send arrives as one datagram
+        assert_eq!(DirectUdp.send(&conn, b"first").unwrap(), 5);
+        assert_eq!(DirectUdp.send(&conn, b"second").unwrap(), 6);
+        let mut buf = [0u8; 64];
+        let (len, from) = endpoint.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"first");
+        assert_eq!(endpoint.recv_from(&mut buf).unwrap().0, 6);
+
+        endpoint.send_to(b"reply", from).unwrap();
+        let mut buf = [MaybeUninit::new(0u8); 64];
+        let len = loop {
+            match DirectUdp.recv(&conn, &mut buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(1))
+                }
+                res => break res.unwrap(),
+            }
+        };
+        assert_eq!(len, 5);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let v4 = listener(0).unwrap();
+        set_ttl(&v4, 255).unwrap();
+        assert_eq!(ttl(&v4).unwrap(), 255);
+        set_ttl(&v4, 3).unwrap();
+        assert_eq!(ttl(&v4).unwrap(), 3);
+
+        // Where IPv6 is available
+        let v6 = socket2::Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        if v6
+            .bind(&SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0).into())
+            .is_ok()
+        {
+            set_ttl(&v6, 255).unwrap();
+            assert_eq!(ttl(&v6).unwrap(), 255);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_recv_with_info() {
+        let receiver = listener(0).unwrap();
+        let addr = receiver.local_addr().unwrap().as_socket().unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = [MaybeUninit::new(0u8); 64];
+
+        // No packet info until asked for
+        sender.send_to(b"before", addr).unwrap();
+        let info = recv_with_info(&receiver, &mut buf).unwrap();
+        assert_eq!(info.len, 6);
+        assert_eq!(info.source, sender.local_addr().unwrap());
+        assert_eq!((info.ifindex, info.destination), (None, None));
+        assert_eq!(info.rxq_ovfl_drops, None);
+
+        set_recv_pktinfo(&receiver).unwrap();
+        sender.send_to(b"after", addr).unwrap();
+        let info = recv_with_info(&receiver, &mut buf).unwrap();
+        assert_eq!(info.len, 5);
+        let lo = unsafe { libc::if_nametoindex(b"lo\0".as_ptr() as *const libc::c_char) };
+        assert_ne!(lo, 0);
+        assert_eq!(info.ifindex, Some(lo));
+        assert_eq!(info.destination, Some(Ipv4Addr::LOCALHOST.into()));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_recv_with_rxq_ovfl() {
+        let receiver = listener(0).unwrap();
+        receiver.set_recv_buffer_size(0).unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        set_rxq_ovfl(&receiver).unwrap();
+        let addr = receiver.local_addr().unwrap().as_socket().unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = [MaybeUninit::new(0u8); 1024];
+
+        // Overflow the smallest receive queue, then drain it
+        for _ in 0..64 {
+            sender.send_to(&[0; 1024], addr).unwrap();
+        }
+        while recv_with_info(&receiver, &mut buf).is_ok() {}
+
+        // The kernel stamps the count on the datagrams queued after the drops
+        sender.send_to(b"after", addr).unwrap();
+        let info = recv_with_info(&receiver, &mut buf).unwrap();
+        assert_eq!(info.len, 5);
+        assert_eq!(info.source, sender.local_addr().unwrap());
+        assert!(info.rxq_ovfl_drops.unwrap() > 0);
+    }
+}
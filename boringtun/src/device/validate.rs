@@ -0,0 +1,335 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Checking a configuration before any of it is applied, a `DeviceConfig` or the body of a UAPI
+//! `set=1` request. See `Device::validate_config` and `Device::validate_uapi`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use super::peer::AllowedIP;
+use super::{Device, DeviceConfig};
+use crate::noise::errors::KeyError;
+use crate::noise::parse_public_key;
+use crate::serialization::KeyBytes;
+
+/// A problem with a configuration. Lines are counted from 1, in the body passed to
+/// `Device::validate_uapi`.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ConfigError {
+    #[error("n_threads must be nonzero")]
+    NoThreads,
+    #[error("Line {line}: not a key=value pair")]
+    Syntax { line: usize },
+    #[error("Line {line}: unknown key {key:?}")]
+    UnknownKey { line: usize, key: String },
+    #[error("Line {line}: invalid {key} {value:?}")]
+    InvalidValue {
+        line: usize,
+        key: String,
+        value: String,
+    },
+    #[error("Line {line}: public key: {error}")]
+    PublicKey { line: usize, error: KeyError },
+    #[error("Line {line}: same public key as the peer of line {first}")]
+    DuplicatePeer { line: usize, first: usize },
+    #[error("Line {line}: endpoint {endpoint:?} is not an address or host with a port")]
+    Endpoint { line: usize, endpoint: String },
+    #[error("Line {line}: allowed IP {value:?}: {error}")]
+    AllowedIp {
+        line: usize,
+        value: String,
+        error: String,
+    },
+    #[error("Line {line}: allowed IP {addr}/{cidr} is already routed to the peer of line {first}")]
+    AllowedIpOverlap {
+        line: usize,
+        addr: IpAddr,
+        cidr: u8,
+        first: usize,
+    },
+}
+
+impl Device {
+    /// Check `cfg` for values the device can't run with, before building one from it
+    pub fn validate_config(cfg: &DeviceConfig) -> Result<(), Vec<ConfigError>> {
+        let mut errors = vec![];
+        if cfg.n_threads == 0 {
+            errors.push(ConfigError::NoThreads);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Check the lines of a UAPI `set=1` request, those after `set=1` up to the empty one, with
+    /// the parsers `api_set` applies them with, and that no two peers share a public key or an
+    /// allowed IP. Nothing is applied, and no name is resolved. Returns all the problems found,
+    /// not just the first.
+    pub fn validate_uapi(set: &str) -> Result<(), Vec<ConfigError>> {
+        let mut errors = vec![];
+        // The line of the public key of each peer, and the peer of each network
+        let mut peers = HashMap::new();
+        let mut routes = HashMap::new();
+        // The line of the public key of the peer section the line is in
+        let mut peer = None;
+
+        for (i, cmd) in set.lines().enumerate() {
+            let line = i + 1;
+            if cmd.is_empty() {
+                break;
+            }
+            let (key, val) = match cmd.split_once('=') {
+                Some(pair) => pair,
+                None => {
+                    errors.push(ConfigError::Syntax { line });
+                    continue;
+                }
+            };
+            let invalid = || ConfigError::InvalidValue {
+                line,
+                key: key.to_owned(),
+                value: val.to_owned(),
+            };
+
+            match (key, peer) {
+                ("public_key", _) => {
+                    match parse_public_key(val) {
+                        Ok(public_key) => match peers.get(public_key.as_bytes()) {
+                            Some(&first) => errors.push(ConfigError::DuplicatePeer { line, first }),
+                            None => {
+                                peers.insert(public_key.to_bytes(), line);
+                            }
+                        },
+                        Err(error) => errors.push(ConfigError::PublicKey { line, error }),
+                    }
+                    peer = Some(line);
+                }
+                ("private_key", None) | ("preshared_key", Some(_)) => {
+                    if val.parse::<KeyBytes>().is_err() {
+                        errors.push(invalid());
+                    }
+                }
+                ("listen_port", None) => {
+                    if val.parse::<u16>().is_err() {
+                        errors.push(invalid());
+                    }
+                }
+                #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+                ("fwmark", None) => {
+                    if val.parse::<u32>().is_err() {
+                        errors.push(invalid());
+                    }
+                }
+                ("replace_peers", None)
+                | ("update_only", Some(_))
+                | ("remove", Some(_))
+                | ("replace_allowed_ips", Some(_)) => {
+                    if val.parse::<bool>().is_err() {
+                        errors.push(invalid());
+                    }
+                }
+                ("persistent_keepalive_interval", Some(_)) => {
+                    if val.parse::<u16>().is_err() {
+                        errors.push(invalid());
+                    }
+                }
+                ("protocol_version", Some(_)) => {
+                    if val.parse::<u32>() != Ok(1) {
+                        errors.push(invalid());
+                    }
+                }
+                ("endpoint", Some(_)) => {
+                    if !valid_endpoint(val) {
+                        errors.push(ConfigError::Endpoint {
+                            line,
+                            endpoint: val.to_owned(),
+                        });
+                    }
+                }
+                ("allowed_ip", Some(peer)) => match val.parse::<AllowedIP>() {
+                    Ok(AllowedIP { addr, cidr }) => {
+                        let network = (network(addr, cidr), cidr);
+                        match routes.get(&network) {
+                            Some(&first) if first != peer => {
+                                errors.push(ConfigError::AllowedIpOverlap {
+                                    line,
+                                    addr: network.0,
+                                    cidr,
+                                    first,
+                                });
+                            }
+                            Some(_) => {}
+                            None => {
+                                routes.insert(network, peer);
+                            }
+                        }
+                    }
+                    Err(error) => errors.push(ConfigError::AllowedIp {
+                        line,
+                        value: val.to_owned(),
+                        error,
+                    }),
+                },
+                _ => errors.push(ConfigError::UnknownKey {
+                    line,
+                    key: key.to_owned(),
+                }),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// An address with a port, or a host name with a port that may resolve later
+fn valid_endpoint(endpoint: &str) -> bool {
+    if endpoint.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    match endpoint.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty()
+                && !host.contains(':')
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    }
+}
+
+/// `addr` with the bits past the prefix cleared, so equal networks compare equal
+fn network(addr: IpAddr, cidr: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(cidr)).unwrap_or(0);
+            IpAddr::from((u32::from(addr) & mask).to_be_bytes())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(cidr)).unwrap_or(0);
+            IpAddr::from((u128::from(addr) & mask).to_be_bytes())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MakeExternalBoringtunNoop;
+    use crate::x25519;
+    use rand_core::OsRng;
+    use std::sync::Arc;
+
+    fn public_key() -> String {
+        let secret = x25519::StaticSecret::random_from_rng(OsRng);
+        hex::encode(x25519::PublicKey::from(&secret).as_bytes())
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let mut cfg = DeviceConfig {
+            n_threads: 2,
+            use_connected_socket: true,
+            #[cfg(target_os = "linux")]
+            use_multi_queue: false,
+            open_uapi_socket: false,
+            protect: Arc::new(MakeExternalBoringtunNoop),
+            firewall_process_inbound_callback: None,
+            firewall_process_outbound_callback: None,
+            #[cfg(target_os = "linux")]
+            uapi_fd: -1,
+        };
+        assert_eq!(Device::validate_config(&cfg), Ok(()));
+
+        cfg.n_threads = 0;
+        assert_eq!(
+            Device::validate_config(&cfg),
+            Err(vec![ConfigError::NoThreads])
+        );
+    }
+
+    #[test]
+    fn test_valid_uapi() {
+        let set = format!(
+            "private_key={}\nlisten_port=51820\nreplace_peers=true\n\
+             public_key={}\nendpoint=vpn.example.com:51820\nallowed_ip=10.0.0.0/24\n\
+             allowed_ip=fd00::/64\npersistent_keepalive_interval=25\n\
+             public_key={}\nendpoint=[fd00::1]:51820\nallowed_ip=10.0.1.1/32\n\n",
+            hex::encode([7u8; 32]),
+            public_key(),
+            public_key(),
+        );
+        assert_eq!(Device::validate_uapi(&set), Ok(()));
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let duplicate = public_key();
+        let set = format!(
+            "private_key=not a key\nlisten_port=70000\n\
+             public_key={}\nendpoint=192.0.2.1\nallowed_ip=10.0.0.0/24\n\
+             persistent_keepalive_interval=-1\n\
+             public_key={}\nallowed_ip=10.1.0.0/33\nlisten_port=51820\n\
+             public_key={}\nallowed_ip=10.0.0.1/24\ngarbage\n",
+            duplicate,
+            duplicate,
+            base64::encode([1u8; 16]),
+        );
+
+        let errors = Device::validate_uapi(&set).unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                ConfigError::InvalidValue {
+                    line: 1,
+                    key: "private_key".to_owned(),
+                    value: "not a key".to_owned()
+                },
+                ConfigError::InvalidValue {
+                    line: 2,
+                    key: "listen_port".to_owned(),
+                    value: "70000".to_owned()
+                },
+                ConfigError::Endpoint {
+                    line: 4,
+                    endpoint: "192.0.2.1".to_owned()
+                },
+                ConfigError::InvalidValue {
+                    line: 6,
+                    key: "persistent_keepalive_interval".to_owned(),
+                    value: "-1".to_owned()
+                },
+                ConfigError::DuplicatePeer { line: 7, first: 3 },
+                ConfigError::AllowedIp {
+                    line: 8,
+                    value: "10.1.0.0/33".to_owned(),
+                    error: "Invalid IP format".to_owned()
+                },
+                // Device keys only go before the first peer
+                ConfigError::UnknownKey {
+                    line: 9,
+                    key: "listen_port".to_owned()
+                },
+                ConfigError::PublicKey {
+                    line: 10,
+                    error: KeyError::WrongLength(16)
+                },
+                ConfigError::AllowedIpOverlap {
+                    line: 11,
+                    addr: "10.0.0.0".parse().unwrap(),
+                    cidr: 24,
+                    first: 3,
+                },
+                ConfigError::Syntax { line: 12 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Log lines in the format of the WireGuard Linux kernel module, so tools scraping the kernel
+//! log work against boringtun too. Peers are numbered by their index. The lines are logged at
+//! info level with the `wireguard` target, once enabled with `Device::set_wg_compat_logging`.
+
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+use super::peer::Peer;
+use super::Device;
+use crate::noise::Direction;
+
+const HANDSHAKE_INIT: u32 = 1;
+const HANDSHAKE_RESP: u32 = 2;
+const COOKIE_REPLY: u32 = 3;
+const DATA: u32 = 4;
+/// A data message with an empty payload
+const KEEPALIVE_SZ: usize = 32;
+
+impl Device {
+    /// Log handshake, keepalive and peer events like the Linux kernel module does
+    pub fn set_wg_compat_logging(&self, enabled: bool) {
+        self.wg_compat_logging.store(enabled, Ordering::Relaxed);
+    }
+
+    pub(crate) fn wg_log_sent(&self, peer: &Peer, packet: &[u8], addr: Option<SocketAddr>) {
+        // The attempts are counted whether logging or not, so they are right once enabled
+        let attempt = match message_type(packet) {
+            Some(HANDSHAKE_INIT) => peer.initiation_sent(),
+            _ => 0,
+        };
+        if self.wg_compat_logging.load(Ordering::Relaxed) {
+            let lines = message_lines(
+                &self.wg_log_iface(),
+                Direction::Sent,
+                packet,
+                peer.index(),
+                addr,
+                attempt,
+            );
+            emit(lines);
+        }
+    }
+
+    pub(crate) fn wg_log_received(&self, peer: &Peer, packet: &[u8], addr: Option<SocketAddr>) {
+        if self.wg_compat_logging.load(Ordering::Relaxed) {
+            let lines = message_lines(
+                &self.wg_log_iface(),
+                Direction::Received,
+                packet,
+                peer.index(),
+                addr,
+                0,
+            );
+            emit(lines);
+        }
+    }
+
+    pub(crate) fn wg_log_peer_created(&self, peer: &Peer) {
+        if self.wg_compat_logging.load(Ordering::Relaxed) {
+            emit(vec![format!(
+                "{}: Peer {} created",
+                self.wg_log_iface(),
+                peer.index()
+            )]);
+        }
+    }
+
+    pub(crate) fn wg_log_peer_destroyed(&self, peer: &Peer) {
+        if self.wg_compat_logging.load(Ordering::Relaxed) {
+            emit(vec![format!(
+                "{}: Peer {} ({}) destroyed",
+                self.wg_log_iface(),
+                peer.index(),
+                endpoint(peer.endpoint().addr)
+            )]);
+        }
+    }
+
+    fn wg_log_iface(&self) -> String {
+        self.iface_name().unwrap_or_default()
+    }
+}
+
+fn emit(lines: Vec<String>) {
+    for line in lines {
+        tracing::info!(target: "wireguard", "{}", line);
+    }
+}
+
+fn message_type(packet: &[u8]) -> Option<u32> {
+    packet
+        .get(..4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn endpoint(addr: Option<SocketAddr>) -> String {
+    addr.map_or_else(|| "none".to_owned(), |addr| addr.to_string())
+}
+
+/// The kernel log lines for a message sent to or received from a peer. `attempt` counts the
+/// initiations sent since the last completed handshake, including this one.
+fn message_lines(
+    iface: &str,
+    direction: Direction,
+    packet: &[u8],
+    peer_index: u32,
+    addr: Option<SocketAddr>,
+    attempt: u32,
+) -> Vec<String> {
+    let addr = endpoint(addr);
+    let peer = format!("peer {} ({})", peer_index, addr);
+    match (direction, message_type(packet)) {
+        (Direction::Sent, Some(HANDSHAKE_INIT)) => {
+            let mut lines = vec![];
+            if attempt > 1 {
+                lines.push(format!(
+                    "{}: Handshake for {} did not complete after 5 seconds, retrying (try {})",
+                    iface, peer, attempt
+                ));
+            }
+            lines.push(format!(
+                "{}: Sending handshake initiation to {}",
+                iface, peer
+            ));
+            lines
+        }
+        (Direction::Sent, Some(HANDSHAKE_RESP)) => {
+            vec![format!("{}: Sending handshake response to {}", iface, peer)]
+        }
+        (Direction::Sent, Some(DATA)) if packet.len() == KEEPALIVE_SZ => {
+            vec![format!("{}: Sending keepalive packet to {}", iface, peer)]
+        }
+        (Direction::Received, Some(HANDSHAKE_INIT)) => {
+            vec![format!(
+                "{}: Receiving handshake initiation from {}",
+                iface, peer
+            )]
+        }
+        (Direction::Received, Some(HANDSHAKE_RESP)) => {
+            vec![format!(
+                "{}: Receiving handshake response from {}",
+                iface, peer
+            )]
+        }
+        (Direction::Received, Some(COOKIE_REPLY)) => {
+            vec![format!(
+                "{}: Receiving cookie response from {}",
+                iface, addr
+            )]
+        }
+        (Direction::Received, Some(DATA)) if packet.len() == KEEPALIVE_SZ => {
+            vec![format!(
+                "{}: Receiving keepalive packet from {}",
+                iface, peer
+            )]
+        }
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(kind: u32, len: usize) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        packet[..4].copy_from_slice(&kind.to_le_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_kernel_format() {
+        let addr = Some("192.0.2.1:51820".parse().unwrap());
+        let lines = |direction, packet: &[u8], attempt| {
+            message_lines("wg0", direction, packet, 3, addr, attempt)
+        };
+
+        assert_eq!(
+            lines(Direction::Sent, &message(HANDSHAKE_INIT, 148), 1),
+            ["wg0: Sending handshake initiation to peer 3 (192.0.2.1:51820)"]
+        );
+        assert_eq!(
+            lines(Direction::Sent, &message(HANDSHAKE_INIT, 148), 2),
+            [
+                "wg0: Handshake for peer 3 (192.0.2.1:51820) did not complete after 5 seconds, \
+                 retrying (try 2)",
+                "wg0: Sending handshake initiation to peer 3 (192.0.2.1:51820)"
+            ]
+        );
+        assert_eq!(
+            lines(Direction::Received, &message(HANDSHAKE_RESP, 92), 0),
+            ["wg0: Receiving handshake response from peer 3 (192.0.2.1:51820)"]
+        );
+        assert_eq!(
+            lines(Direction::Received, &message(DATA, KEEPALIVE_SZ), 0),
+            ["wg0: Receiving keepalive packet from peer 3 (192.0.2.1:51820)"]
+        );
+        // Data packets are not logged
+        assert!(lines(Direction::Sent, &message(DATA, 100), 0).is_empty());
+    }
+}
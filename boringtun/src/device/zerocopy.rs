@@ -0,0 +1,274 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! `MSG_ZEROCOPY` sends on Linux.
+//!
+//! A zerocopy send pins the pages of the buffer instead of copying them into the kernel, so the
+//! buffer must stay alive and unchanged until the kernel reports the send as completed on the
+//! socket error queue. This is why `ZerocopySender` takes ownership of the buffers it sends, and
+//! only hands them back through `buffer` once `drain_completions` saw their completion. Pinning
+//! and tracking has a cost of its own, so only packets of at least the threshold are sent this
+//! way.
+
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::AsRawFd;
+
+use crate::device::Error;
+
+/// From `linux/errqueue.h`, missing from libc
+const SO_EE_ORIGIN_ZEROCOPY: u8 = 5;
+const SO_EE_CODE_ZEROCOPY_COPIED: u8 = 1;
+
+/// Below this size copying is cheaper than pinning the pages
+pub const DEFAULT_ZEROCOPY_THRESHOLD: usize = 1024;
+
+/// Allow `MSG_ZEROCOPY` sends on the socket, via `SO_ZEROCOPY`. Fails on a socket set up for
+/// path MTU discovery, which would drop the completions from the error queue, see
+/// `transport::take_packet_too_big`.
+pub fn set_zerocopy(sock: &socket2::Socket) -> Result<(), Error> {
+    if crate::device::transport::queues_errors(sock)? {
+        return Err(Error::SetSockOpt(
+            "Zerocopy sends do not mix with path MTU discovery".to_owned(),
+        ));
+    }
+    let enable: libc::c_int = 1;
+    match unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ZEROCOPY,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    } {
+        -1 => Err(Error::SetSockOpt(io::Error::last_os_error().to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Sends packets on a single `SO_ZEROCOPY` socket, keeping the buffers of zerocopy sends until
+/// their completion. The kernel numbers zerocopy sends per socket, so every zerocopy send on the
+/// socket must go through the same sender.
+pub struct ZerocopySender {
+    threshold: usize,
+    /// Id the kernel assigns to the next zerocopy send
+    next_id: u32,
+    /// Buffers of zerocopy sends yet to complete, with their id
+    in_flight: VecDeque<(u32, Vec<u8>)>,
+    /// Buffers free for reuse
+    free: Vec<Vec<u8>>,
+    copied: u64,
+}
+
+impl ZerocopySender {
+    pub fn new(threshold: usize) -> Self {
+        ZerocopySender {
+            threshold,
+            next_id: 0,
+            in_flight: VecDeque::new(),
+            free: vec![],
+            copied: 0,
+        }
+    }
+
+    /// An empty buffer to build the next packet in, reusing the buffers of completed sends
+    pub fn buffer(&mut self) -> Vec<u8> {
+        let mut buf = self.free.pop().unwrap_or_default();
+        buf.clear();
+        buf
+    }
+
+    /// Send `packet`, with `MSG_ZEROCOPY` if it is at least the threshold
+    pub fn send(&mut self, sock: &socket2::Socket, packet: Vec<u8>) -> io::Result<usize> {
+        if packet.len() < self.threshold {
+            let res = sock.send(&packet);
+            self.free.push(packet);
+            return res;
+        }
+
+        let n = unsafe {
+            libc::send(
+                sock.as_raw_fd(),
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                libc::MSG_ZEROCOPY,
+            )
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            // Out of memory to pin pages for, send a copy instead
+            let res = match err.raw_os_error() {
+                Some(libc::ENOBUFS) => sock.send(&packet),
+                _ => Err(err),
+            };
+            self.free.push(packet);
+            return res;
+        }
+
+        self.in_flight.push_back((self.next_id, packet));
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(n as usize)
+    }
+
+    /// Read the completion notifications waiting on the socket error queue, freeing the buffers
+    /// of the completed sends. Returns the number of buffers freed. Other errors queued on the
+    /// socket are consumed and ignored.
+    pub fn drain_completions(&mut self, sock: &socket2::Socket) -> io::Result<usize> {
+        let mut freed = 0;
+        loop {
+            match recv_completion(sock) {
+                Ok(Some((lo, hi, copied))) => {
+                    if copied {
+                        self.copied += u64::from(hi.wrapping_sub(lo)) + 1;
+                    }
+                    freed += self.release(lo, hi);
+                }
+                Ok(None) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(freed),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Number of zerocopy sends whose buffer is still held
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Completed sends the kernel copied after all, e.g. over loopback
+    pub fn copied(&self) -> u64 {
+        self.copied
+    }
+
+    /// Free the buffers of the sends with ids in `lo..=hi`
+    fn release(&mut self, lo: u32, hi: u32) -> usize {
+        let span = hi.wrapping_sub(lo);
+        let before = self.in_flight.len();
+        // Completions are usually in order, but the kernel does not promise it
+        let mut still_in_flight = VecDeque::with_capacity(before);
+        for (id, buf) in self.in_flight.drain(..) {
+            if id.wrapping_sub(lo) <= span {
+                self.free.push(buf);
+            } else {
+                still_in_flight.push_back((id, buf));
+            }
+        }
+        self.in_flight = still_in_flight;
+        before - self.in_flight.len()
+    }
+}
+
+impl Default for ZerocopySender {
+    fn default() -> Self {
+        Self::new(DEFAULT_ZEROCOPY_THRESHOLD)
+    }
+}
+
+/// Read one message from the socket error queue. Returns the range of completed send ids and
+/// whether the kernel copied them, or `None` for other errors.
+fn recv_completion(sock: &socket2::Socket) -> io::Result<Option<(u32, u32, bool)>> {
+    // Room for the extended error and the offender address, u64 for cmsghdr alignment
+    let mut control = [0u64; 16];
+
+    // Safety: the control messages are only read within the bounds the kernel reported
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        if libc::recvmsg(
+            sock.as_raw_fd(),
+            &mut msg,
+            libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let level = (*cmsg).cmsg_level;
+            let kind = (*cmsg).cmsg_type;
+            if (level == libc::SOL_IP && kind == libc::IP_RECVERR)
+                || (level == libc::SOL_IPV6 && kind == libc::IPV6_RECVERR)
+            {
+                let err = std::ptr::read_unaligned(
+                    libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err
+                );
+                if err.ee_errno == 0 && err.ee_origin == SO_EE_ORIGIN_ZEROCOPY {
+                    let copied = err.ee_code & SO_EE_CODE_ZEROCOPY_COPIED != 0;
+                    return Ok(Some((err.ee_info, err.ee_data, copied)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::{Domain, Protocol, Type};
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    #[test]
+    fn test_exclusive_with_path_mtu_discovery() {
+        use crate::device::transport::set_path_mtu_discovery;
+
+        let socket = || {
+            let socket =
+                socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+            socket
+                .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+                .unwrap();
+            socket
+        };
+        let zerocopy = socket();
+        set_zerocopy(&zerocopy).unwrap();
+        assert!(set_path_mtu_discovery(&zerocopy).is_err());
+
+        let discovering = socket();
+        set_path_mtu_discovery(&discovering).unwrap();
+        assert!(set_zerocopy(&discovering).is_err());
+    }
+
+    #[test]
+    fn test_zerocopy_buffers_released() {
+        let receiver =
+            socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        receiver
+            .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        let sender = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        sender.connect(&receiver.local_addr().unwrap()).unwrap();
+        set_zerocopy(&sender).unwrap();
+
+        let mut zerocopy = ZerocopySender::new(1000);
+        let mut small = zerocopy.buffer();
+        small.resize(100, 1);
+        assert_eq!(zerocopy.send(&sender, small).unwrap(), 100);
+        assert_eq!(zerocopy.in_flight(), 0);
+
+        for _ in 0..4 {
+            let mut large = zerocopy.buffer();
+            large.resize(1200, 2);
+            assert_eq!(zerocopy.send(&sender, large).unwrap(), 1200);
+        }
+        assert_eq!(zerocopy.in_flight(), 4);
+
+        // Loopback completes right away, by copying
+        let mut freed = 0;
+        for _ in 0..100 {
+            freed += zerocopy.drain_completions(&sender).unwrap();
+            if zerocopy.in_flight() == 0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(zerocopy.in_flight(), 0);
+        assert_eq!(freed, 4);
+        assert_eq!(zerocopy.copied(), 4);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! An `Option<Arc<T>>` that readers load without taking a lock, in the manner of the
+//! `arc-swap` crate. The sessions of a tunnel live in these for its data path, see `DataPath`.
+//!
+//! Built with `--cfg loom`, the atomics and the lock are those of `loom`, whose model checker
+//! runs the tests below under every interleaving.
+
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::ptr;
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[cfg(not(loom))]
+use parking_lot::Mutex;
+
+/// `loom::sync::Mutex` with the interface of `parking_lot::Mutex`
+#[cfg(loom)]
+struct Mutex<T>(loom::sync::Mutex<T>);
+
+#[cfg(loom)]
+impl<T> Mutex<T> {
+    fn new(value: T) -> Self {
+        Mutex(loom::sync::Mutex::new(value))
+    }
+
+    fn lock(&self) -> loom::sync::MutexGuard<'_, T> {
+        self.0.lock().unwrap()
+    }
+}
+
+/// Readers count themselves in while they pick up a reference, so a store knows whether the
+/// value it replaced may still be in the middle of being picked up. Such a value is retired
+/// rather than waited for, and dropped once no reader is in: by the store itself when none is,
+/// or else by the last reader leaving, so a steady stream of readers never holds up a store
+/// and a retired value doesn't outlive the readers that might hold it.
+pub(crate) struct ArcCell<T> {
+    /// From `Arc::into_raw`, the cell holds one strong reference. Null for `None`.
+    ptr: AtomicPtr<T>,
+    /// Readers between loading `ptr` and taking their own strong reference
+    readers: AtomicUsize,
+    retired: Mutex<Vec<Arc<T>>>,
+    /// Whether `retired` holds anything, so readers only take its lock when it does
+    has_retired: AtomicBool,
+}
+
+impl<T> Default for ArcCell<T> {
+    fn default() -> Self {
+        ArcCell {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            readers: AtomicUsize::new(0),
+            retired: Mutex::new(Vec::new()),
+            has_retired: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<T> ArcCell<T> {
+    pub(crate) fn load(&self) -> Option<Arc<T>> {
+        // All SeqCst: a store that reads no readers after its swap must be ordered after every
+        // reader that could have loaded the old pointer
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let ptr = self.ptr.load(Ordering::SeqCst);
+        let value = if ptr.is_null() {
+            None
+        } else {
+            // Safety: `ptr` came from `Arc::into_raw`, and a store replacing it doesn't drop
+            // its reference while this reader is counted in
+            unsafe {
+                Arc::increment_strong_count(ptr);
+                Some(Arc::from_raw(ptr))
+            }
+        };
+        if self.readers.fetch_sub(1, Ordering::SeqCst) == 1
+            && self.has_retired.load(Ordering::SeqCst)
+        {
+            self.reclaim();
+        }
+        value
+    }
+
+    /// Whether the cell holds a value, without picking up a reference like `load`
+    pub(crate) fn is_some(&self) -> bool {
+        !self.ptr.load(Ordering::SeqCst).is_null()
+    }
+
+    /// Drop the retired values if no reader is in. Any reader that could still be picking one
+    /// up was counted in before it was retired, under the lock, and is then still counted.
+    #[cold]
+    fn reclaim(&self) {
+        let mut retired = self.retired.lock();
+        if self.readers.load(Ordering::SeqCst) == 0 {
+            retired.clear();
+            self.has_retired.store(false, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn store(&self, value: Option<Arc<T>>) {
+        let new = value.map_or(ptr::null_mut(), |value| Arc::into_raw(value) as *mut T);
+        let old = self.ptr.swap(new, Ordering::SeqCst);
+        let mut retired = self.retired.lock();
+        if !old.is_null() {
+            // Safety: the reference the cell held, which nothing else releases
+            retired.push(unsafe { Arc::from_raw(old) });
+            // Before checking the readers, so the last one leaving after sees it
+            self.has_retired.store(true, Ordering::SeqCst);
+        }
+        if self.readers.load(Ordering::SeqCst) == 0 {
+            retired.clear();
+            self.has_retired.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<T> Drop for ArcCell<T> {
+    fn drop(&mut self) {
+        let ptr = self.ptr.load(Ordering::SeqCst);
+        if !ptr.is_null() {
+            // Safety: the reference the cell held, no reader can be in during drop
+            drop(unsafe { Arc::from_raw(ptr) });
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_store() {
+        let cell = ArcCell::default();
+        assert!(cell.load().is_none());
+        assert!(!cell.is_some());
+
+        let first = Arc::new(1);
+        cell.store(Some(Arc::clone(&first)));
+        assert!(cell.is_some());
+        assert_eq!(cell.load().as_deref(), Some(&1));
+        assert_eq!(Arc::strong_count(&first), 2);
+
+        cell.store(Some(Arc::new(2)));
+        assert_eq!(cell.load().as_deref(), Some(&2));
+        // No reader was in, so the old value is released right away
+        assert_eq!(Arc::strong_count(&first), 1);
+
+        cell.store(None);
+        assert!(cell.load().is_none());
+        assert!(cell.retired.lock().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_readers() {
+        let cell = Arc::new(ArcCell::default());
+        let values: Vec<_> = (0..100).map(Arc::new).collect();
+        cell.store(Some(Arc::clone(&values[0])));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cell = Arc::clone(&cell);
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        let value = cell.load().unwrap();
+                        assert!(*value < 100);
+                    }
+                })
+            })
+            .collect();
+        for value in &values[1..] {
+            cell.store(Some(Arc::clone(value)));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // Whatever was retired went with the last reader leaving
+        assert!(!cell.has_retired.load(Ordering::SeqCst));
+        assert!(values[..99]
+            .iter()
+            .all(|value| Arc::strong_count(value) == 1));
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    #[test]
+    fn test_retired_dropped_by_last_reader() {
+        loom::model(|| {
+            let cell = Arc::new(ArcCell::default());
+            let first = Arc::new(1);
+            cell.store(Some(Arc::clone(&first)));
+
+            let reader = {
+                let cell = Arc::clone(&cell);
+                loom::thread::spawn(move || {
+                    if let Some(value) = cell.load() {
+                        assert_eq!(*value, 1);
+                    }
+                })
+            };
+            cell.store(None);
+            reader.join().unwrap();
+
+            // Released by the store or by the reader, whichever left last, with no store after
+            assert_eq!(Arc::strong_count(&first), 1);
+            assert!(cell.retired.lock().is_empty());
+        });
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum WireGuardError {
     DestinationBufferTooSmall,
     IncorrectPacketLength,
@@ -20,4 +20,32 @@ pub enum WireGuardError {
     LockFailed,
     ConnectionExpired,
     UnderLoad,
+    /// The random number generator failed to supply an ephemeral key
+    EntropyFailure,
+    /// An authenticated initiation arrived within the minimum handshake interval of the last
+    /// one accepted, see `Tunn::set_min_handshake_interval`
+    InitiationTooSoon,
 }
+
+/// Reasons a public key string is rejected by `parse_public_key`
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyError {
+    /// Decoded to the given number of bytes instead of 32
+    WrongLength(usize),
+    /// Neither valid hex nor valid base64
+    BadEncoding,
+    /// A low order point, no session with it would be secure
+    InsecureKey,
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::WrongLength(len) => write!(f, "wrong key length: {} bytes, expected 32", len),
+            KeyError::BadEncoding => write!(f, "bad key encoding: expected hex or base64"),
+            KeyError::InsecureKey => write!(f, "insecure key: low order point"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
@@ -1,7 +1,8 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use super::{HandshakeInit, HandshakeResponse, PacketCookieReply};
+use super::timers::{Clock, SystemClock};
+use super::{os_rng, HandshakeInit, HandshakeResponse, PacketCookieReply, SharedRng};
 use crate::noise::errors::WireGuardError;
 use crate::noise::session::Session;
 #[cfg(not(feature = "mock-instant"))]
@@ -11,10 +12,12 @@ use aead::{Aead, Payload};
 use blake2::digest::{FixedOutput, KeyInit};
 use blake2::{Blake2s256, Blake2sMac, Digest};
 use chacha20poly1305::XChaCha20Poly1305;
-use rand_core::OsRng;
+use rand_core::{CryptoRng, RngCore};
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use std::convert::TryInto;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
+use zeroize::Zeroize;
 
 #[cfg(feature = "mock-instant")]
 use mock_instant::Instant;
@@ -80,6 +83,17 @@ pub(crate) fn b2s_keyed_mac_16_2(key: &[u8], data1: &[u8], data2: &[u8]) -> [u8;
     hmac.finalize_fixed().into()
 }
 
+/// The key of the MAC1 of messages to the holder of `public_key`, HASH(LABEL_MAC1 || public_key)
+pub(crate) fn mac1_key(public_key: &x25519::PublicKey) -> [u8; KEY_LEN] {
+    b2s_hash(LABEL_MAC1, public_key.as_bytes())
+}
+
+/// MAC1 of the handshake message `msg` with the `mac1_key` of its recipient. `msg` includes the
+/// MAC fields, which aren't covered.
+pub(crate) fn keyed_mac1(key: &[u8], msg: &[u8]) -> [u8; 16] {
+    b2s_keyed_mac_16(key, &msg[..msg.len().saturating_sub(32)])
+}
+
 pub(crate) fn b2s_mac_24(key: &[u8], data1: &[u8]) -> [u8; 24] {
     let mut hmac = Blake2sMac::new_from_slice(key).unwrap();
     blake2::digest::Update::update(&mut hmac, data1);
@@ -262,7 +276,8 @@ struct HandshakeInitSentState {
     hash: [u8; KEY_LEN],
     chaining_key: [u8; KEY_LEN],
     ephemeral_private: x25519::ReusableSecret,
-    time_sent: Instant,
+    /// On `Handshake::clock`
+    time_sent: Duration,
 }
 
 impl std::fmt::Debug for HandshakeInitSentState {
@@ -294,6 +309,25 @@ enum HandshakeState {
     Expired,
 }
 
+impl HandshakeState {
+    /// Overwrite the hash and chaining key of a handshake in flight, the ephemeral secret wipes
+    /// itself as it is dropped
+    fn zeroize(&mut self) {
+        match self {
+            HandshakeState::InitSent(HandshakeInitSentState {
+                hash, chaining_key, ..
+            })
+            | HandshakeState::InitReceived {
+                hash, chaining_key, ..
+            } => {
+                hash.zeroize();
+                chaining_key.zeroize();
+            }
+            HandshakeState::None | HandshakeState::Expired => {}
+        }
+    }
+}
+
 pub struct Handshake {
     params: NoiseParams,
     /// Index of the next session
@@ -308,6 +342,12 @@ pub struct Handshake {
     // TODO: make TimeStamper a singleton
     stamper: TimeStamper,
     pub(super) last_rtt: Option<u32>,
+    /// The round trip of the last handshake we initiated, until taken by `Tunn::take_rtt_sample`
+    pub(super) rtt_sample: Option<Duration>,
+    /// Source of the ephemeral keys
+    rng: SharedRng,
+    /// Times the initiations, see `Tunn::set_clock`
+    clock: Arc<dyn Clock>,
 }
 
 #[derive(Default)]
@@ -378,7 +418,7 @@ impl NoiseParams {
     ) -> Result<NoiseParams, WireGuardError> {
         let static_shared = static_private.diffie_hellman(&peer_static_public);
 
-        let initial_sending_mac_key = b2s_hash(LABEL_MAC1, peer_static_public.as_bytes());
+        let initial_sending_mac_key = mac1_key(&peer_static_public);
 
         Ok(NoiseParams {
             static_public,
@@ -432,6 +472,9 @@ impl Handshake {
             stamper: TimeStamper::new(),
             cookies: Default::default(),
             last_rtt: None,
+            rtt_sample: None,
+            rng: os_rng(),
+            clock: Arc::new(SystemClock::default()),
         })
     }
 
@@ -439,9 +482,25 @@ impl Handshake {
         !matches!(self.state, HandshakeState::None | HandshakeState::Expired)
     }
 
-    pub(crate) fn timer(&self) -> Option<Instant> {
+    /// Drop the initiations of ours awaiting a response and their ephemeral keys, so a late
+    /// response completes nothing. Returns whether there were any.
+    pub(crate) fn cancel_initiation(&mut self) -> bool {
+        let mut cancelled = false;
+        for state in [&mut self.state, &mut self.previous] {
+            if matches!(state, HandshakeState::InitSent(_)) {
+                *state = HandshakeState::None;
+                cancelled = true;
+            }
+        }
+        cancelled
+    }
+
+    /// Time since we sent the initiation awaiting a response, if any
+    pub(crate) fn time_since_init_sent(&self) -> Option<Duration> {
         match self.state {
-            HandshakeState::InitSent(HandshakeInitSentState { time_sent, .. }) => Some(time_sent),
+            HandshakeState::InitSent(HandshakeInitSentState { time_sent, .. }) => {
+                Some(self.clock.now().saturating_sub(time_sent))
+            }
             _ => None,
         }
     }
@@ -463,6 +522,18 @@ impl Handshake {
         self.cookies.write_cookie = None;
     }
 
+    /// Forget the handshakes in flight and the cookie. The timestamp of the last initiation
+    /// received is kept, so old initiations can't be replayed.
+    pub(crate) fn reset(&mut self) {
+        self.previous.zeroize();
+        self.state.zeroize();
+        self.previous = HandshakeState::None;
+        self.state = HandshakeState::None;
+        self.cookies = Default::default();
+        self.last_rtt = None;
+        self.rtt_sample = None;
+    }
+
     // The index used is 24 bits for peer index, allowing for 16M active peers per server and 8 bits for cyclic session index
     fn inc_index(&mut self) -> u32 {
         let index = self.next_index;
@@ -479,14 +550,46 @@ impl Handshake {
         self.params.set_static_private(private_key, public_key)
     }
 
+    /// A new ephemeral secret. Fails, instead of panicking in the crypto library, when the
+    /// generator can't supply the bytes, so the handshake can be tried again later.
+    fn new_ephemeral(&self) -> Result<x25519::ReusableSecret, WireGuardError> {
+        let mut drawn = Predrawn([0u8; 32]);
+        self.rng
+            .lock()
+            .try_fill_bytes(&mut drawn.0)
+            .map_err(|_| WireGuardError::EntropyFailure)?;
+        Ok(x25519::ReusableSecret::random_from_rng(&mut drawn))
+    }
+
+    pub(crate) fn set_rng(&mut self, rng: SharedRng) {
+        self.rng = rng;
+    }
+
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub(crate) fn static_public(&self) -> x25519::PublicKey {
+        self.params.static_public
+    }
+
+    #[cfg(feature = "test-utils")]
+    pub(crate) fn static_private(&self) -> &x25519::StaticSecret {
+        &self.params.static_private
+    }
+
     pub(crate) fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
         self.params.preshared_key = preshared_key;
     }
 
+    /// Authenticate an initiation and respond to it. A `too_soon` initiation is authenticated
+    /// and its timestamp consumed, so it can't be replayed later, but then rejected with
+    /// `InitiationTooSoon` instead of changing any state.
     pub(super) fn receive_handshake_initialization<'a>(
         &mut self,
         packet: HandshakeInit,
         dst: &'a mut [u8],
+        too_soon: bool,
     ) -> Result<(&'a mut [u8], Session), WireGuardError> {
         // initiator.chaining_key = HASH(CONSTRUCTION)
         let mut chaining_key = INITIAL_CHAIN_KEY;
@@ -550,6 +653,9 @@ impl Handshake {
             return Err(WireGuardError::WrongTai64nTimestamp);
         }
         self.last_handshake_timestamp = timestamp;
+        if too_soon {
+            return Err(WireGuardError::InitiationTooSoon);
+        }
 
         // initiator.hash = HASH(initiator.hash || msg.encrypted_timestamp)
         hash = b2s_hash(&hash, packet.encrypted_timestamp);
@@ -638,8 +744,9 @@ impl Handshake {
         let temp2 = b2s_hmac(&temp1, &[0x01]);
         let temp3 = b2s_hmac2(&temp1, &temp2, &[0x02]);
 
-        let rtt_time = Instant::now().duration_since(state.time_sent);
+        let rtt_time = self.clock.now().saturating_sub(state.time_sent);
         self.last_rtt = Some(rtt_time.as_millis() as u32);
+        self.rtt_sample = Some(rtt_time);
 
         if is_previous {
             self.previous = HandshakeState::None;
@@ -693,7 +800,7 @@ impl Handshake {
         let mac2_off = dst.len() - 16;
 
         // msg.mac1 = MAC(HASH(LABEL_MAC1 || responder.static_public), msg[0:offsetof(msg.mac1)])
-        let msg_mac1 = b2s_keyed_mac_16(&self.params.sending_mac1_key, &dst[..mac1_off]);
+        let msg_mac1 = keyed_mac1(&self.params.sending_mac1_key, dst);
 
         dst[mac1_off..mac2_off].copy_from_slice(&msg_mac1[..]);
 
@@ -719,6 +826,9 @@ impl Handshake {
             return Err(WireGuardError::DestinationBufferTooSmall);
         }
 
+        // initiator.ephemeral_private = DH_GENERATE()
+        let ephemeral_private = self.new_ephemeral()?;
+
         let (message_type, rest) = dst.split_at_mut(4);
         let (sender_index, rest) = rest.split_at_mut(4);
         let (unencrypted_ephemeral, rest) = rest.split_at_mut(32);
@@ -732,8 +842,6 @@ impl Handshake {
         // initiator.hash = HASH(HASH(initiator.chaining_key || IDENTIFIER) || responder.static_public)
         let mut hash = INITIAL_CHAIN_HASH;
         hash = b2s_hash(&hash, self.params.peer_static_public.as_bytes());
-        // initiator.ephemeral_private = DH_GENERATE()
-        let ephemeral_private = x25519::ReusableSecret::random_from_rng(OsRng);
         // msg.message_type = 1
         // msg.reserved_zero = { 0, 0, 0 }
         message_type.copy_from_slice(&super::HANDSHAKE_INIT.to_le_bytes());
@@ -776,7 +884,7 @@ impl Handshake {
         // initiator.hash = HASH(initiator.hash || msg.encrypted_timestamp)
         hash = b2s_hash(&hash, encrypted_timestamp);
 
-        let time_now = Instant::now();
+        let time_now = self.clock.now();
         self.previous = std::mem::replace(
             &mut self.state,
             HandshakeState::InitSent(HandshakeInitSentState {
@@ -799,6 +907,9 @@ impl Handshake {
             return Err(WireGuardError::DestinationBufferTooSmall);
         }
 
+        // responder.ephemeral_private = DH_GENERATE()
+        let ephemeral_private = self.new_ephemeral()?;
+
         let state = std::mem::replace(&mut self.state, HandshakeState::None);
         let (mut chaining_key, mut hash, peer_ephemeral_public, peer_index) = match state {
             HandshakeState::InitReceived {
@@ -818,8 +929,6 @@ impl Handshake {
         let (unencrypted_ephemeral, rest) = rest.split_at_mut(32);
         let (encrypted_nothing, _) = rest.split_at_mut(16);
 
-        // responder.ephemeral_private = DH_GENERATE()
-        let ephemeral_private = x25519::ReusableSecret::random_from_rng(OsRng);
         let local_index = self.inc_index();
         // msg.message_type = 2
         // msg.reserved_zero = { 0, 0, 0 }
@@ -885,6 +994,33 @@ impl Handshake {
     }
 }
 
+/// Hands out the bytes of a secret drawn beforehand, so a failure to draw them can be handled.
+/// The bytes are wiped once handed out.
+struct Predrawn([u8; 32]);
+
+impl RngCore for Predrawn {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let n = dest.len().min(self.0.len());
+        dest[..n].copy_from_slice(&self.0[..n]);
+        self.0[..n].fill(0);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for Predrawn {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -44,9 +44,9 @@ mod tests {
         }
 
         fn assert_tx_rx(&self, tx_bytes: usize, rx_bytes: usize) {
-            let (_, tx, rx, _, _) = self.tunnel.lock().stats();
-            assert_eq!(tx, tx_bytes);
-            assert_eq!(rx, rx_bytes);
+            let stats = self.tunnel.lock().stats();
+            assert_eq!(stats.tx_bytes, tx_bytes);
+            assert_eq!(stats.rx_bytes, rx_bytes);
         }
     }
 
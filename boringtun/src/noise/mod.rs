@@ -6,24 +6,69 @@ pub mod handshake;
 pub mod rate_limiter;
 pub mod safe_duration;
 
+mod arc_cell;
 #[cfg(test)]
 mod integration_tests;
 mod session;
 mod timers;
 
-use crate::noise::errors::WireGuardError;
+use crate::noise::arc_cell::ArcCell;
+use crate::noise::errors::{KeyError, WireGuardError};
 use crate::noise::handshake::Handshake;
-use crate::noise::rate_limiter::RateLimiter;
+use crate::noise::rate_limiter::{HalfOpenHandshake, RateLimiter};
+use crate::noise::safe_duration::SafeDuration;
 use crate::noise::session::message_data_len;
 use crate::noise::timers::{TimerName, Timers};
 use crate::x25519;
 
+#[cfg(feature = "test-utils")]
+pub use timers::MockClock;
+pub use timers::{Clock, SystemClock};
+
+use parking_lot::Mutex;
+use rand_core::{CryptoRng, OsRng, RngCore};
 use std::collections::VecDeque;
 use std::convert::{TryFrom, TryInto};
+use std::io::IoSliceMut;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 use std::time::Duration;
 
+/// Parse a peer public key from hex or base64, rejecting keys no secure session can be made with
+pub fn parse_public_key(s: &str) -> Result<x25519::PublicKey, KeyError> {
+    let s = s.trim();
+    let bytes = if s.len() == 64 {
+        hex::decode(s).map_err(|_| KeyError::BadEncoding)?
+    } else {
+        base64::decode(s).map_err(|_| KeyError::BadEncoding)?
+    };
+    let bytes: [u8; 32] = bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| KeyError::WrongLength(bytes.len()))?;
+
+    let public_key = x25519::PublicKey::from(bytes);
+    // Any secret multiplied with a low order point gives the all zero shared secret
+    let probe = x25519::ReusableSecret::random_from_rng(OsRng);
+    if !probe.diffie_hellman(&public_key).was_contributive() {
+        return Err(KeyError::InsecureKey);
+    }
+    Ok(public_key)
+}
+
+/// A cryptographically secure random number generator
+pub trait EntropySource: RngCore + CryptoRng + Send {}
+
+impl<T: RngCore + CryptoRng + Send> EntropySource for T {}
+
+/// Where the noise layer draws the ephemeral keys of its handshakes and the secrets of its
+/// cookies from, `OsRng` unless replaced with `Tunn::set_rng` and `RateLimiter::with_rng`
+pub type SharedRng = Arc<Mutex<dyn EntropySource>>;
+
+pub(crate) fn os_rng() -> SharedRng {
+    Arc::new(Mutex::new(OsRng))
+}
+
 /// The default value to use for rate limiting, when no other rate limiter is defined
 const PEER_HANDSHAKE_RATE_LIMIT: u64 = 10;
 
@@ -42,6 +87,10 @@ const IPV6_IP_SZ: usize = 16;
 const IP_LEN_SZ: usize = 2;
 
 const MAX_QUEUE_DEPTH: usize = 256;
+
+/// Initiations from a peer accepted at most this often by default, 50 per second like the
+/// kernel module. Far below the pace of retries and rekeys, only a flood comes this close.
+const DEFAULT_MIN_HANDSHAKE_INTERVAL: Duration = Duration::from_millis(20);
 /// number of sessions in the ring, better keep a PoT
 const N_SESSIONS: usize = 8;
 
@@ -54,6 +103,86 @@ pub enum TunnResult<'a> {
     WriteToTunnelV6(&'a mut [u8], Ipv6Addr),
 }
 
+/// The transport keys of a session, see `Tunn::dump_keys`
+#[cfg(feature = "debug_keys")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyDump {
+    pub local_index: u32,
+    pub remote_index: u32,
+    /// Hex of the key our data messages are encrypted with
+    pub send_key: String,
+    /// Hex of the key the data messages of the peer are encrypted with
+    pub recv_key: String,
+    /// Counter of the next data message sent
+    pub send_nonce: u64,
+    /// One past the highest counter received
+    pub recv_nonce: u64,
+}
+
+#[cfg(feature = "debug_keys")]
+impl std::fmt::Display for KeyDump {
+    /// One `name=value` line per field, indices in hex
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "local_index={:08x}", self.local_index)?;
+        writeln!(f, "remote_index={:08x}", self.remote_index)?;
+        writeln!(f, "send_key={}", self.send_key)?;
+        writeln!(f, "recv_key={}", self.recv_key)?;
+        writeln!(f, "send_nonce={}", self.send_nonce)?;
+        writeln!(f, "recv_nonce={}", self.recv_nonce)
+    }
+}
+
+/// The keys and counters of an established session, to build a `Tunn` from without a
+/// handshake, see `Tunn::from_raw_session` and `Tunn::export_state`
+#[cfg(feature = "test-utils")]
+pub struct RawSession {
+    pub static_private: x25519::StaticSecret,
+    pub peer_static_public: x25519::PublicKey,
+    /// The index the data messages of the peer carry, its upper 24 bits are the index of the
+    /// tunnel as for `Tunn::new`
+    pub local_index: u32,
+    /// The index our data messages carry
+    pub remote_index: u32,
+    pub send_key: [u8; 32],
+    pub recv_key: [u8; 32],
+    /// Counter of the next data message sent
+    pub send_nonce: u64,
+    /// One past the highest counter received
+    pub recv_nonce: u64,
+    /// The counters of the replay window already received, bit `c % 64` of word `c / 64 % 16`
+    /// for counter `c`
+    pub replay_bitmap: [u64; 16],
+    /// We sent the initiation of the session, and so are the one to rotate its keys
+    pub is_initiator: bool,
+}
+
+/// The counters of a tunnel, see `Tunn::stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct TunnStats {
+    /// Time since the last handshake, `None` without a session
+    pub time_since_handshake: Option<Duration>,
+    /// Bytes of the messages sent
+    pub tx_bytes: usize,
+    /// Bytes of the messages received
+    pub rx_bytes: usize,
+    /// Estimated packet loss, from 0 to 1
+    pub estimated_loss: f32,
+    /// Round trip time of the last handshake we initiated in milliseconds
+    pub rtt_ms: Option<u32>,
+    /// Key epoch of the current session, see `Tunn::key_epoch`
+    pub key_epoch: u64,
+}
+
+/// What `Tunn::decapsulate_into` did with a datagram
+#[derive(Debug)]
+pub enum DecapsulatedInto<'a> {
+    /// An IP packet of this length from this source was written over the slices, in order
+    Packet(usize, IpAddr),
+    /// Anything else, as `decapsulate` returns it with the first slice as `dst`
+    Other(TunnResult<'a>),
+}
+
 impl<'a> From<WireGuardError> for TunnResult<'a> {
     fn from(err: WireGuardError) -> TunnResult<'a> {
         TunnResult::Err(err)
@@ -64,8 +193,12 @@ impl<'a> From<WireGuardError> for TunnResult<'a> {
 pub struct Tunn {
     /// The handshake currently in progress
     handshake: handshake::Handshake,
-    /// The N_SESSIONS most recent sessions, index is session id modulo N_SESSIONS
-    sessions: [Option<session::Session>; N_SESSIONS],
+    /// The N_SESSIONS most recent sessions, index is session id modulo N_SESSIONS. Mirrored
+    /// in `data_path`, see `store_session`.
+    sessions: [Option<Arc<session::Session>>; N_SESSIONS],
+    data_path: Arc<DataPath>,
+    /// Accounts for the sessions we responded with, until the initiator confirms them
+    half_open: [Option<HalfOpenHandshake>; N_SESSIONS],
     /// Index of most recently used session
     current: usize,
     /// Queue to store blocked packets
@@ -75,23 +208,82 @@ pub struct Tunn {
     tx_bytes: usize,
     rx_bytes: usize,
     rate_limiter: Arc<RateLimiter>,
+    /// A session was established since the last call to `take_handshake_completed`
+    handshake_completed: bool,
+    /// A packet authenticated since the last call to `take_authenticated`
+    authenticated: bool,
+    /// Keep a copy of handshake messages, see `set_debug_capture`
+    debug_capture: bool,
+    /// See `set_initiation_allowed`
+    initiation_allowed: bool,
+    /// Handshakes completed, the epoch of the latest session
+    key_rotations: u64,
+    /// See `set_response_delay`
+    #[cfg(feature = "test-utils")]
+    response_delay: Duration,
+    /// Handshake responses held back by `response_delay`, with the tunnel time they are due
+    #[cfg(feature = "test-utils")]
+    delayed_responses: VecDeque<(SafeDuration, Vec<u8>)>,
+    last_handshake_bytes: Option<(Direction, Vec<u8>)>,
+    observed: ObservedBehavior,
+    /// Tunnel time of the last keepalive received
+    last_keepalive_received: Option<SafeDuration>,
+    /// See `set_min_handshake_interval`
+    min_handshake_interval: Duration,
+    /// Tunnel time of the last initiation that set up a session
+    last_initiation_accepted: Option<SafeDuration>,
+    /// Initiations dropped for following the last one too closely
+    flapping_initiations: u64,
+    /// Plaintext of packets `decapsulate_into` scatters over several slices
+    scatter_buf: Vec<u8>,
 
     pub peer_static_public: x25519_dalek::PublicKey,
 }
 
-type MessageType = u32;
-const HANDSHAKE_INIT: MessageType = 1;
-const HANDSHAKE_RESP: MessageType = 2;
-const COOKIE_REPLY: MessageType = 3;
-const DATA: MessageType = 4;
+const HANDSHAKE_INIT: u32 = 1;
+const HANDSHAKE_RESP: u32 = 2;
+const COOKIE_REPLY: u32 = 3;
+const DATA: u32 = 4;
+
+/// The four kinds of WireGuard messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum MessageType {
+    HandshakeInit = HANDSHAKE_INIT,
+    HandshakeResponse = HANDSHAKE_RESP,
+    CookieReply = COOKIE_REPLY,
+    Data = DATA,
+}
+
+/// The kind of WireGuard message in `buf`, from its type field and length, without decrypting
+/// anything. `None` if `buf` can't be a WireGuard message, e.g. is too short for its type or has
+/// the reserved bytes set. Exactly the datagrams `decapsulate` would go on to process are
+/// classified.
+pub fn classify_packet(buf: &[u8]) -> Option<MessageType> {
+    match Tunn::parse_incoming_packet(buf).ok()? {
+        Packet::HandshakeInit(_) => Some(MessageType::HandshakeInit),
+        Packet::HandshakeResponse(_) => Some(MessageType::HandshakeResponse),
+        Packet::PacketCookieReply(_) => Some(MessageType::CookieReply),
+        Packet::PacketData(_) => Some(MessageType::Data),
+    }
+}
+
+/// The MAC1 of the handshake message `msg` sent to the holder of `public_key`, computed the
+/// way `Tunn` does, to check or produce handshakes before they reach a `Tunn`. `msg` is the
+/// whole message, its trailing MAC1 and MAC2 fields are not covered.
+pub fn compute_mac1(public_key: &x25519::PublicKey, msg: &[u8]) -> [u8; 16] {
+    handshake::keyed_mac1(&handshake::mac1_key(public_key), msg)
+}
 
 const HANDSHAKE_INIT_SZ: usize = 148;
 const HANDSHAKE_RESP_SZ: usize = 92;
 const COOKIE_REPLY_SZ: usize = 64;
-const DATA_OVERHEAD_SZ: usize = 32;
+pub(crate) const DATA_OVERHEAD_SZ: usize = 32;
 
 #[derive(Debug)]
 pub struct HandshakeInit<'a> {
+    /// The whole message
+    raw: &'a [u8],
     sender_idx: u32,
     unencrypted_ephemeral: &'a [u8; 32],
     encrypted_static: &'a [u8],
@@ -100,6 +292,8 @@ pub struct HandshakeInit<'a> {
 
 #[derive(Debug)]
 pub struct HandshakeResponse<'a> {
+    /// The whole message
+    raw: &'a [u8],
     sender_idx: u32,
     pub receiver_idx: u32,
     unencrypted_ephemeral: &'a [u8; 32],
@@ -120,6 +314,77 @@ pub struct PacketData<'a> {
     encrypted_encapsulated_packet: &'a [u8],
 }
 
+/// The sessions of a tunnel, shared with the threads receiving its data packets so they
+/// decrypt without the lock on the tunnel, see `Tunn::data_path`. The tunnel swaps sessions in
+/// and out as handshakes complete and sessions expire. Each session keeps its replay window
+/// behind a lock of its own, so that stays correct across threads.
+#[derive(Default)]
+pub(crate) struct DataPath {
+    sessions: [ArcCell<session::Session>; N_SESSIONS],
+    /// See `Tunn::on_replay_reject`
+    on_replay_reject: parking_lot::RwLock<Option<session::ReplayRejectCallback>>,
+}
+
+/// A data packet decrypted by `DataPath::decrypt`, for `Tunn::receive_decrypted`
+pub(crate) struct Decrypted<'a> {
+    receiver_idx: u32,
+    packet: &'a mut [u8],
+}
+
+impl DataPath {
+    /// Decrypt the data packet `packet` into `dst` with the session it is for, checking and
+    /// marking its counter in the replay window. Pass the result to `Tunn::receive_decrypted`
+    /// to finish receiving it.
+    pub(crate) fn decrypt<'a>(
+        &self,
+        packet: PacketData,
+        dst: &'a mut [u8],
+    ) -> Result<Decrypted<'a>, WireGuardError> {
+        let receiver_idx = packet.receiver_idx;
+        let session = self.sessions[receiver_idx as usize % N_SESSIONS].load();
+        let session = session.ok_or_else(|| {
+            tracing::trace!(
+                message = "No current session available",
+                remote_idx = receiver_idx
+            );
+            WireGuardError::NoCurrentSession
+        })?;
+        let packet = session.receive_packet_data(packet, dst, &self.on_replay_reject)?;
+        Ok(Decrypted {
+            receiver_idx,
+            packet,
+        })
+    }
+}
+
+/// Whether a message was sent to or received from the peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// What the traffic of a peer tells about its implementation and configuration, learned
+/// passively from the packets it sends
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ObservedBehavior {
+    /// Time between the last two keepalives received, `None` until two were seen
+    pub keepalive_interval: Option<Duration>,
+    /// Handshakes the peer initiated
+    pub initiations_received: u64,
+    /// Handshakes the peer completed by responding to ours
+    pub responses_received: u64,
+    /// Largest decrypted IP packet, a hint of the MTU on the other side
+    pub max_inner_packet: usize,
+}
+
+impl ObservedBehavior {
+    /// The peer completed handshakes, but never initiated one
+    pub fn always_responds(&self) -> bool {
+        self.initiations_received == 0 && self.responses_received > 0
+    }
+}
+
 /// Describes a packet from network
 #[derive(Debug)]
 pub enum Packet<'a> {
@@ -145,6 +410,7 @@ impl Tunn {
 
         Ok(match (packet_type, src.len()) {
             (HANDSHAKE_INIT, HANDSHAKE_INIT_SZ) => Packet::HandshakeInit(HandshakeInit {
+                raw: src,
                 sender_idx: u32::from_le_bytes(src[4..8].try_into().unwrap()),
                 unencrypted_ephemeral: <&[u8; 32] as TryFrom<&[u8]>>::try_from(&src[8..40])
                     .expect("length already checked above"),
@@ -152,6 +418,7 @@ impl Tunn {
                 encrypted_timestamp: &src[88..116],
             }),
             (HANDSHAKE_RESP, HANDSHAKE_RESP_SZ) => Packet::HandshakeResponse(HandshakeResponse {
+                raw: src,
                 sender_idx: u32::from_le_bytes(src[4..8].try_into().unwrap()),
                 receiver_idx: u32::from_le_bytes(src[8..12].try_into().unwrap()),
                 unencrypted_ephemeral: <&[u8; 32] as TryFrom<&[u8]>>::try_from(&src[12..44])
@@ -176,6 +443,24 @@ impl Tunn {
         self.handshake.is_expired()
     }
 
+    /// Whether we sent a handshake initiation that is yet to be answered
+    pub fn handshake_pending(&self) -> bool {
+        self.handshake.time_since_init_sent().is_some()
+    }
+
+    /// Abort the handshake we initiated, if any: its ephemeral key is dropped, so a response
+    /// arriving later is rejected, and no more initiations are retried for it. Established
+    /// sessions are left alone, as are handshakes the peer initiated. The timers start no new
+    /// handshake, for a rekey or a persistent keepalive, until a data packet is sent or received
+    /// again. Returns whether one was pending.
+    pub fn cancel_handshake(&mut self) -> bool {
+        let cancelled = self.handshake.cancel_initiation();
+        if cancelled {
+            self.timers.cancel_initiations();
+        }
+        cancelled
+    }
+
     pub fn dst_address(packet: &[u8]) -> Option<IpAddr> {
         if packet.is_empty() {
             return None;
@@ -222,9 +507,27 @@ impl Tunn {
             )
             .map_err(|_| "Invalid parameters")?,
             sessions: Default::default(),
+            data_path: Default::default(),
+            half_open: Default::default(),
             current: Default::default(),
             tx_bytes: Default::default(),
             rx_bytes: Default::default(),
+            handshake_completed: false,
+            authenticated: false,
+            debug_capture: false,
+            initiation_allowed: true,
+            key_rotations: 0,
+            #[cfg(feature = "test-utils")]
+            response_delay: Duration::ZERO,
+            #[cfg(feature = "test-utils")]
+            delayed_responses: VecDeque::new(),
+            last_handshake_bytes: None,
+            observed: Default::default(),
+            last_keepalive_received: None,
+            min_handshake_interval: DEFAULT_MIN_HANDSHAKE_INTERVAL,
+            last_initiation_accepted: None,
+            flapping_initiations: 0,
+            scatter_buf: Vec::new(),
 
             packet_queue: VecDeque::new(),
             timers: Timers::new(persistent_keepalive, rate_limiter.is_none()),
@@ -250,20 +553,128 @@ impl Tunn {
         });
         self.handshake
             .set_static_private(static_private, static_public)?;
-        for s in &mut self.sessions {
-            *s = None;
+        self.clear_sessions();
+        Ok(())
+    }
+
+    /// Drop all session state, as if the peer were just added, and start over with a new
+    /// handshake: returns the initiation to send, or `Done` if initiations are not allowed.
+    ///
+    /// The sessions, the handshakes in flight, the cookie, queued packets, timers and byte
+    /// counters all go. The hashes and chaining keys of the handshakes are overwritten, and the
+    /// ephemeral secrets wipe themselves as they are dropped. The keys and the persistent
+    /// keepalive are kept, and so is `key_epoch`, which moves on with the next session so it
+    /// can't be mistaken for the old one.
+    pub fn reset<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a> {
+        self.handshake.reset();
+        self.clear_all();
+        self.tx_bytes = 0;
+        self.rx_bytes = 0;
+        self.handshake_completed = false;
+        self.authenticated = false;
+        self.last_keepalive_received = None;
+        self.last_initiation_accepted = None;
+        self.last_handshake_bytes = None;
+        #[cfg(feature = "test-utils")]
+        self.delayed_responses.clear();
+        self.format_handshake_initiation(dst, false)
+    }
+
+    /// Draw all randomness of this tunnel from `rng`, for embedders that must use an approved
+    /// generator. Covers the ephemeral keys, and the cookie secrets when the tunnel has its own
+    /// rate limiter. A shared rate limiter has to be given the generator with
+    /// `RateLimiter::with_rng`. Fails with `EntropyFailure`, keeping the generator in use, when
+    /// `rng` can't supply the new cookie secrets.
+    pub fn set_rng(&mut self, rng: SharedRng) -> Result<(), WireGuardError> {
+        if self.timers.should_reset_rr {
+            self.rate_limiter = Arc::new(RateLimiter::with_rng(
+                &self.handshake.static_public(),
+                PEER_HANDSHAKE_RATE_LIMIT,
+                &rng,
+            )?);
         }
+        self.handshake.set_rng(rng);
         Ok(())
     }
 
+    /// Read the time for the timers of this tunnel from `clock`, `SystemClock` by default,
+    /// so a test can drive rekeys, keepalives and expiry with a `MockClock` instead of
+    /// sleeping. Times taken so far carry over. The timestamps of the initiations still follow
+    /// the system time, as the protocol wants, and so does the rate limiter.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.handshake.set_clock(Arc::clone(&clock));
+        self.timers.set_clock(clock);
+    }
+
     /// Update the preshared key and clear sessions
     pub fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
         self.handshake.set_preshared_key(preshared_key);
-        for s in &mut self.sessions {
-            *s = None;
+        self.clear_sessions();
+    }
+
+    /// Put `session` in `slot` of the ring, and of the data path
+    fn store_session(&mut self, slot: usize, session: Option<session::Session>) {
+        let session = session.map(Arc::new);
+        self.data_path.sessions[slot].store(session.clone());
+        self.sessions[slot] = session;
+    }
+
+    fn clear_sessions(&mut self) {
+        for slot in 0..N_SESSIONS {
+            self.store_session(slot, None);
         }
     }
 
+    /// The sessions of this tunnel for decrypting its data packets from other threads, without
+    /// the lock on the tunnel around the decryption: `DataPath::decrypt`, then
+    /// `Tunn::receive_decrypted` to account for the packet. `Peer::decapsulate` does this.
+    pub(crate) fn data_path(&self) -> Arc<DataPath> {
+        Arc::clone(&self.data_path)
+    }
+
+    /// A tunnel whose current session has the keys and counters of `raw`, as if a handshake just
+    /// established it, to test the migration of sessions between processes, see
+    /// `export_state`. Fails if `send_nonce` doesn't fit the counter of the target, 32 bits
+    /// wide on 32-bit targets. Only built with the `test-utils` feature.
+    ///
+    /// **Insecure if misused**: reusing the keys of a session that is still live elsewhere
+    /// repeats nonces, which reveals the traffic and lets it be forged, and a replay window
+    /// behind the real one accepts replayed packets.
+    #[cfg(feature = "test-utils")]
+    pub fn from_raw_session(raw: RawSession) -> Result<Self, &'static str> {
+        let mut session = session::Session::from_raw(&raw)?;
+        let mut tunn = Tunn::new(
+            raw.static_private,
+            raw.peer_static_public,
+            None,
+            None,
+            raw.local_index >> 8,
+            None,
+        )?;
+        let index = session.local_index();
+        tunn.key_rotations += 1;
+        session.epoch = tunn.key_rotations;
+        tunn.store_session(index % N_SESSIONS, Some(session));
+        tunn.current = index;
+        tunn.timer_tick_session_established(raw.is_initiator, index);
+        Ok(tunn)
+    }
+
+    /// The keys and counters of the current session, to build a tunnel continuing it with
+    /// `from_raw_session`. `None` without a session. Only built with the `test-utils` feature.
+    ///
+    /// **Insecure if misused**: see `from_raw_session`, this tunnel must not send on the
+    /// session once the state is imported elsewhere.
+    #[cfg(feature = "test-utils")]
+    pub fn export_state(&self) -> Option<RawSession> {
+        let session = self.sessions[self.current % N_SESSIONS].as_ref()?;
+        Some(session.to_raw(
+            self.handshake.static_private().clone(),
+            self.peer_static_public,
+            self.timers.is_initiator(),
+        ))
+    }
+
     /// Encapsulate a single packet from the tunnel interface.
     /// Returns TunnResult.
     ///
@@ -295,6 +706,36 @@ impl Tunn {
         self.format_handshake_initiation(dst, false)
     }
 
+    /// Like `encapsulate`, but the message is written at `offset` into `dst`, leaving the bytes
+    /// before it alone for the caller to fill with the outer IP and UDP headers. The result
+    /// covers just the WireGuard message, from `offset` on. Takes `&mut self` like
+    /// `encapsulate`, as sending moves the counters and timers. Returns
+    /// `WireGuardError::DestinationBufferTooSmall` if `offset` is past the end of `dst`.
+    ///
+    /// # Panics
+    /// Panics if the space after `offset` is too small, as with `encapsulate`.
+    pub fn encapsulate_at<'a>(
+        &mut self,
+        src: &[u8],
+        dst: &'a mut [u8],
+        offset: usize,
+    ) -> TunnResult<'a> {
+        match dst.get_mut(offset..) {
+            Some(dst) => self.encapsulate(src, dst),
+            None => TunnResult::Err(WireGuardError::DestinationBufferTooSmall),
+        }
+    }
+
+    /// Format an empty data packet for the current session right away, regardless of the
+    /// persistent keepalive timer, e.g. to refresh NAT mappings. Uses up a counter like any data
+    /// packet. Fails if there is no session.
+    pub fn send_keepalive<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a> {
+        if self.sessions[self.current % N_SESSIONS].is_none() {
+            return TunnResult::Err(WireGuardError::NoCurrentSession);
+        }
+        self.encapsulate(&[], dst)
+    }
+
     /// Receives a UDP datagram from the network and parses it.
     /// Returns TunnResult.
     ///
@@ -308,6 +749,10 @@ impl Tunn {
         dst: &'a mut [u8],
     ) -> TunnResult<'a> {
         if datagram.is_empty() {
+            #[cfg(feature = "test-utils")]
+            if self.delayed_response_due() {
+                return TunnResult::WriteToNetwork(self.release_delayed_response(dst));
+            }
             // Indicates a repeated call
             return self.send_queued_packet(dst);
         }
@@ -330,6 +775,72 @@ impl Tunn {
         self.handle_verified_packet(packet, dst)
     }
 
+    /// Like `decapsulate`, but a data packet is decrypted over the `dst` slices, e.g. buffers
+    /// registered for vectored I/O. A packet fitting the first slice is decrypted in place there,
+    /// with no copy `decapsulate` doesn't make. The AEAD only works on contiguous memory, so a
+    /// longer one is decrypted into a buffer of the tunnel and copied over the slices once its
+    /// tag verified: the slices never hold unauthenticated plaintext, at the cost of that copy.
+    /// A first slice as large as the MTU keeps every packet on the path without it. Other
+    /// messages use the first slice as the `dst` of `decapsulate`.
+    ///
+    /// Returns `WireGuardError::DestinationBufferTooSmall` when the slices together are too
+    /// small for the packet, or the first one for the response to a handshake message.
+    pub fn decapsulate_into<'a>(
+        &mut self,
+        src_addr: Option<IpAddr>,
+        datagram: &[u8],
+        dst: &'a mut [IoSliceMut<'_>],
+    ) -> DecapsulatedInto<'a> {
+        let (ct_len, min_first) = match Tunn::parse_incoming_packet(datagram) {
+            Ok(Packet::PacketData(packet)) => (packet.encrypted_encapsulated_packet.len(), 0),
+            Ok(_) => (0, HANDSHAKE_RESP_SZ),
+            // Left to `decapsulate` to report
+            Err(_) => (0, 0),
+        };
+        let first_len = dst.first().map_or(0, |first| first.len());
+        let too_small =
+            || DecapsulatedInto::Other(TunnResult::Err(WireGuardError::DestinationBufferTooSmall));
+        if first_len < min_first {
+            return too_small();
+        }
+
+        if ct_len <= first_len {
+            return match self.decapsulate(src_addr, datagram, &mut dst[0]) {
+                TunnResult::WriteToTunnelV4(packet, addr) => {
+                    DecapsulatedInto::Packet(packet.len(), addr.into())
+                }
+                TunnResult::WriteToTunnelV6(packet, addr) => {
+                    DecapsulatedInto::Packet(packet.len(), addr.into())
+                }
+                res => DecapsulatedInto::Other(res),
+            };
+        }
+
+        if dst.iter().map(|slice| slice.len()).sum::<usize>() < ct_len {
+            return too_small();
+        }
+
+        let mut scatter_buf = std::mem::take(&mut self.scatter_buf);
+        if scatter_buf.len() < ct_len {
+            scatter_buf.resize(ct_len, 0);
+        }
+        let res = match self.decapsulate(src_addr, datagram, &mut scatter_buf) {
+            TunnResult::WriteToTunnelV4(packet, addr) => {
+                scatter(packet, dst);
+                DecapsulatedInto::Packet(packet.len(), addr.into())
+            }
+            TunnResult::WriteToTunnelV6(packet, addr) => {
+                scatter(packet, dst);
+                DecapsulatedInto::Packet(packet.len(), addr.into())
+            }
+            TunnResult::Err(err) => DecapsulatedInto::Other(TunnResult::Err(err)),
+            // Data packets only ever lead to a packet for the tunnel or nothing
+            _ => DecapsulatedInto::Other(TunnResult::Done),
+        };
+        self.scatter_buf = scatter_buf;
+        res
+    }
+
     pub(crate) fn handle_verified_packet<'a>(
         &mut self,
         packet: Packet,
@@ -349,30 +860,64 @@ impl Tunn {
         p: HandshakeInit,
         dst: &'a mut [u8],
     ) -> Result<TunnResult<'a>, WireGuardError> {
+        self.capture_handshake(Direction::Received, p.raw);
         tracing::debug!(
             message = "Received handshake_initiation",
             remote_idx = p.sender_idx
         );
 
-        let (packet, session) = self.handshake.receive_handshake_initialization(p, dst)?;
+        let now = self.timers.elapsed();
+        let too_soon = self.last_initiation_accepted.map_or(false, |last| {
+            now.checked_sub(last).unwrap_or_default() < self.min_handshake_interval
+        });
+        let remote_idx = p.sender_idx;
+
+        let (packet, mut session) = self
+            .handshake
+            .receive_handshake_initialization(p, dst, too_soon)
+            .map_err(|err| {
+                if let WireGuardError::InitiationTooSoon = err {
+                    self.flapping_initiations += 1;
+                    tracing::debug!(
+                        message = "Dropping handshake_initiation, too soon after the last one",
+                        remote_idx
+                    );
+                }
+                err
+            })?;
+        self.last_initiation_accepted = Some(now);
+        self.authenticated = true;
 
         // We received a valid handshake initialization
         // Increase the rx_bytes accordingly
         self.rx_bytes += HANDSHAKE_INIT_SZ;
+        self.observed.initiations_received += 1;
+        self.key_rotations += 1;
+        session.epoch = self.key_rotations;
 
         // Store new session in ring buffer
         let index = session.local_index();
-        self.sessions[index % N_SESSIONS] = Some(session);
+        self.store_session(index % N_SESSIONS, Some(session));
+        self.half_open[index % N_SESSIONS] = Some(self.rate_limiter.half_open_handshake());
 
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick(TimerName::TimeLastPacketSent);
         self.timer_tick_session_established(false, index); // New session established, we are not the initiator
 
         tracing::debug!(message = "Sending handshake_response", local_idx = index);
+        self.capture_handshake(Direction::Sent, packet);
 
         // We are ready to send a Handshake response
         // Increase the tx_bytes accordingly
         self.tx_bytes += packet.len();
+
+        #[cfg(feature = "test-utils")]
+        if !self.response_delay.is_zero() {
+            let due = self.timers.elapsed() + self.response_delay.into();
+            self.delayed_responses.push_back((due, packet.to_vec()));
+            return Ok(TunnResult::Done);
+        }
+
         Ok(TunnResult::WriteToNetwork(packet))
     }
 
@@ -381,22 +926,28 @@ impl Tunn {
         p: HandshakeResponse,
         dst: &'a mut [u8],
     ) -> Result<TunnResult<'a>, WireGuardError> {
+        self.capture_handshake(Direction::Received, p.raw);
         tracing::debug!(
             message = "Received handshake_response",
             local_idx = p.receiver_idx,
             remote_idx = p.sender_idx
         );
 
-        let session = self.handshake.receive_handshake_response(p)?;
+        let mut session = self.handshake.receive_handshake_response(p)?;
+        self.authenticated = true;
         // We received a valid handshake response
         // Increase the rx_bytes accordingly
         self.rx_bytes += HANDSHAKE_RESP_SZ;
+        self.observed.responses_received += 1;
+        self.key_rotations += 1;
+        session.epoch = self.key_rotations;
 
         let keepalive_packet = session.format_packet_data(&[], dst);
         // Store new session in ring buffer
         let l_idx = session.local_index();
         let index = l_idx % N_SESSIONS;
-        self.sessions[index] = Some(session);
+        self.store_session(index, Some(session));
+        self.half_open[index] = None;
 
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick_session_established(true, index); // New session established, we are the initiator
@@ -453,24 +1004,28 @@ impl Tunn {
         packet: PacketData,
         dst: &'a mut [u8],
     ) -> Result<TunnResult<'a>, WireGuardError> {
-        let r_idx = packet.receiver_idx as usize;
-        let idx = r_idx % N_SESSIONS;
-
-        // Get the (probably) right session
-        let decapsulated_packet = {
-            let session = self.sessions[idx].as_ref();
-            let session = session.ok_or_else(|| {
-                tracing::trace!(message = "No current session available", remote_idx = r_idx);
-                WireGuardError::NoCurrentSession
-            })?;
-            session.receive_packet_data(packet, dst)?
-        };
+        let decrypted = self.data_path.decrypt(packet, dst)?;
+        Ok(self.receive_decrypted(decrypted))
+    }
 
-        self.set_current_session(r_idx);
+    /// Account for a data packet decrypted with the `data_path` of this tunnel, as `decapsulate`
+    /// would have, and return it like `decapsulate` does
+    pub(crate) fn receive_decrypted<'a>(&mut self, decrypted: Decrypted<'a>) -> TunnResult<'a> {
+        let r_idx = decrypted.receiver_idx as usize;
+        let idx = r_idx % N_SESSIONS;
+        self.authenticated = true;
+
+        // A handshake may have replaced the session since it decrypted the packet
+        let session_index = self.sessions[idx].as_ref().map(|s| s.receiving_index);
+        if session_index == Some(decrypted.receiver_idx) {
+            // The initiator used the session, so it is no longer half open
+            self.half_open[idx] = None;
+            self.set_current_session(r_idx);
+        }
 
         self.timer_tick(TimerName::TimeLastPacketReceived);
 
-        Ok(self.validate_decapsulated_packet(decapsulated_packet))
+        self.validate_decapsulated_packet(decrypted.packet)
     }
 
     /// Formats a new handshake initiation message and store it in dst. If force_resend is true will send
@@ -480,7 +1035,7 @@ impl Tunn {
         dst: &'a mut [u8],
         force_resend: bool,
     ) -> TunnResult<'a> {
-        if self.handshake.is_in_progress() && !force_resend {
+        if !self.initiation_allowed || (self.handshake.is_in_progress() && !force_resend) {
             return TunnResult::Done;
         }
 
@@ -493,6 +1048,7 @@ impl Tunn {
         match self.handshake.format_handshake_initiation(dst) {
             Ok(packet) => {
                 tracing::debug!("Sending handshake_initiation");
+                self.capture_handshake(Direction::Sent, packet);
 
                 if starting_new_handshake {
                     self.timer_tick(TimerName::TimeLastHandshakeStarted);
@@ -506,14 +1062,63 @@ impl Tunn {
         }
     }
 
+    /// Decrypts a data packet with the keys of its session, leaving the tunnel as it was: the
+    /// packet is not checked against the replay window nor recorded in it, and neither the
+    /// counters nor the timers move. So the same packet can be read again and again. Anything
+    /// but a data message gives `TunnResult::Done`.
+    ///
+    /// This is a diagnostic tool, e.g. for a proxy inspecting the traffic of a tunnel it shares
+    /// keys with. Using it in place of `decapsulate` on the live path of a tunnel would accept
+    /// replayed packets, and let a peer's session go stale.
+    pub fn decapsulate_peek<'a>(&self, packet: &[u8], dst: &'a mut [u8]) -> TunnResult<'a> {
+        let packet = match Tunn::parse_incoming_packet(packet) {
+            Ok(Packet::PacketData(packet)) => packet,
+            Ok(_) => return TunnResult::Done,
+            Err(e) => return TunnResult::Err(e),
+        };
+        let r_idx = packet.receiver_idx as usize;
+        let session = match self.sessions[r_idx % N_SESSIONS].as_ref() {
+            Some(session) => session,
+            None => return TunnResult::Err(WireGuardError::NoCurrentSession),
+        };
+        match session.peek_packet_data(packet, dst) {
+            Ok(decapsulated_packet) => Tunn::parse_decapsulated_packet(decapsulated_packet),
+            Err(e) => TunnResult::Err(e),
+        }
+    }
+
     /// Check if an IP packet is v4 or v6, truncate to the length indicated by the length field
     /// Returns the truncated packet and the source IP as TunnResult
     fn validate_decapsulated_packet<'a>(&mut self, packet: &'a mut [u8]) -> TunnResult<'a> {
-        let (computed_len, src_ip_address) = match packet.len() {
-            0 => {
+        match Tunn::parse_decapsulated_packet(packet) {
+            TunnResult::Done => {
                 self.rx_bytes += message_data_len(0);
-                return TunnResult::Done; // This is keepalive, and not an error
+                self.observe_keepalive();
+                TunnResult::Done
+            }
+            TunnResult::WriteToTunnelV4(packet, addr) => {
+                self.observe_data_packet(packet.len());
+                TunnResult::WriteToTunnelV4(packet, addr)
             }
+            TunnResult::WriteToTunnelV6(packet, addr) => {
+                self.observe_data_packet(packet.len());
+                TunnResult::WriteToTunnelV6(packet, addr)
+            }
+            res => res,
+        }
+    }
+
+    fn observe_data_packet(&mut self, len: usize) {
+        self.timer_tick(TimerName::TimeLastDataPacketReceived);
+        self.rx_bytes += message_data_len(len);
+        self.observed.max_inner_packet = self.observed.max_inner_packet.max(len);
+    }
+
+    /// The IP packet in a decrypted data message, truncated to the length of its header, and
+    /// its source. `TunnResult::Done` for a keepalive.
+    fn parse_decapsulated_packet(packet: &mut [u8]) -> TunnResult<'_> {
+        let (computed_len, src_ip_address) = match packet.len() {
+            0 => return TunnResult::Done, // This is keepalive, and not an error
             _ if packet[0] >> 4 == 4 && packet.len() >= IPV4_MIN_HEADER_SIZE => {
                 let len_bytes: [u8; IP_LEN_SZ] = packet[IPV4_LEN_OFF..IPV4_LEN_OFF + IP_LEN_SZ]
                     .try_into()
@@ -547,9 +1152,6 @@ impl Tunn {
             return TunnResult::Err(WireGuardError::InvalidPacket);
         }
 
-        self.timer_tick(TimerName::TimeLastDataPacketReceived);
-        self.rx_bytes += message_data_len(computed_len);
-
         match src_ip_address {
             IpAddr::V4(addr) => TunnResult::WriteToTunnelV4(&mut packet[..computed_len], addr),
             IpAddr::V6(addr) => TunnResult::WriteToTunnelV6(&mut packet[..computed_len], addr),
@@ -620,23 +1222,208 @@ impl Tunn {
         }
     }
 
-    /// Return stats from the tunnel:
-    /// * Time since last handshake in seconds
-    /// * Data bytes sent
-    /// * Data bytes received
-    pub fn stats(&self) -> (Option<Duration>, usize, usize, f32, Option<u32>) {
-        let time = self.time_since_last_handshake();
-        let tx_bytes = self.tx_bytes;
-        let rx_bytes = self.rx_bytes;
-        let loss = self.estimate_loss();
-        let rtt = self.handshake.last_rtt;
+    /// Whether this side initiates handshakes, allowed by default. When not allowed, it never
+    /// sends a handshake initiation, neither for packets to send nor from the timers. Initiations
+    /// from the peer are still answered, and packets sent meanwhile are queued until the peer
+    /// establishes a session.
+    pub fn set_initiation_allowed(&mut self, allowed: bool) {
+        self.initiation_allowed = allowed;
+    }
+
+    pub fn initiation_allowed(&self) -> bool {
+        self.initiation_allowed
+    }
+
+    /// Hold handshake responses back for `delay`, as if the peer were far away, to test how it
+    /// retries. A held response is returned by the first call to `update_timers`, or to
+    /// `decapsulate` with an empty datagram, once it is due. Zero, the default, sends right away.
+    #[cfg(feature = "test-utils")]
+    pub fn set_response_delay(&mut self, delay: Duration) {
+        self.response_delay = delay;
+    }
+
+    #[cfg(feature = "test-utils")]
+    fn delayed_response_due(&self) -> bool {
+        self.delayed_responses
+            .front()
+            .map_or(false, |(due, _)| *due <= self.timers.elapsed())
+    }
+
+    /// Copy the oldest held response to `dst`, it must be due
+    #[cfg(feature = "test-utils")]
+    fn release_delayed_response<'a>(&mut self, dst: &'a mut [u8]) -> &'a mut [u8] {
+        let (_, packet) = self.delayed_responses.pop_front().unwrap();
+        let dst = &mut dst[..packet.len()];
+        dst.copy_from_slice(&packet);
+        dst
+    }
+
+    /// Keep a copy of the last handshake message sent or received, for comparing against a
+    /// packet capture when debugging interop. Off by default. The copies contain handshake
+    /// material that could aid analysis of the exchange, only enable this in a lab.
+    pub fn set_debug_capture(&mut self, enabled: bool) {
+        self.debug_capture = enabled;
+        if !enabled {
+            self.last_handshake_bytes = None;
+        }
+    }
+
+    /// The last handshake message captured while `set_debug_capture` is enabled
+    pub fn last_handshake_bytes(&self) -> Option<(Direction, Vec<u8>)> {
+        self.last_handshake_bytes.clone()
+    }
+
+    /// Bytes a data message adds to the packet it carries, padding aside: the message type,
+    /// reserved bytes, receiver index and counter, 16 bytes, and the 16 byte Poly1305 tag
+    pub fn transport_overhead() -> usize {
+        message_data_len(0)
+    }
+
+    /// Padding added to a packet of `len` bytes before it is encrypted. The specification pads
+    /// to a multiple of 16, but packets are sent unpadded, so this is always 0.
+    pub fn padding_for(_len: usize) -> usize {
+        0
+    }
+
+    /// Size of the data message carrying a packet of `len` bytes, without the UDP and IP headers
+    pub fn encapsulated_len(len: usize) -> usize {
+        message_data_len(len + Self::padding_for(len))
+    }
+
+    /// Check if a packet authenticated as from the peer since the last call, and reset the
+    /// check: an initiation or response of a handshake, or a data packet that decrypted, even if
+    /// what it carried was then dropped. Only such packets may move the endpoint of the peer.
+    /// Cookie replies don't count, they only prove to answer our last message and could be
+    /// replayed, nor does anything failing before its keys are checked, like a packet held back
+    /// by the rate limiter.
+    pub fn take_authenticated(&mut self) -> bool {
+        std::mem::take(&mut self.authenticated)
+    }
+
+    /// Accept initiations from the peer at most once per `interval`, rejecting those arriving
+    /// sooner with `InitiationTooSoon` once authenticated, without setting up a session for
+    /// them, so a flapping peer can't keep us busy with handshakes. Initiations failing to
+    /// authenticate, e.g. replays, fail as they would otherwise and aren't counted as flapping.
+    /// 20 milliseconds by default. Unlike the rate limiter shared by the peers of a device this
+    /// only ever holds back this peer.
+    pub fn set_min_handshake_interval(&mut self, interval: Duration) {
+        self.min_handshake_interval = interval;
+    }
+
+    /// Authenticated initiations rejected for arriving within the minimum handshake interval
+    pub fn flapping_initiations(&self) -> u64 {
+        self.flapping_initiations
+    }
+
+    /// Call `cb` with the counter of each data packet dropped by the replay window, for having
+    /// been received already or being too old for it. Only authentic packets are reported: the
+    /// window is checked before decryption too, and with a callback set a packet it rejects then
+    /// is decrypted before being reported. Nothing is read on the receive path until a packet is
+    /// rejected.
+    pub fn on_replay_reject(&self, cb: impl Fn(u64) + Send + Sync + 'static) {
+        *self.data_path.on_replay_reject.write() = Some(Box::new(cb));
+    }
+
+    /// What the traffic of the peer revealed so far
+    pub fn observed_behavior(&self) -> ObservedBehavior {
+        self.observed
+    }
+
+    fn observe_keepalive(&mut self) {
+        let now = self.timers[TimerName::TimeCurrent];
+        if let Some(last) = self.last_keepalive_received.replace(now) {
+            self.observed.keepalive_interval = Some((now - last).into());
+        }
+    }
+
+    fn capture_handshake(&mut self, direction: Direction, message: &[u8]) {
+        if self.debug_capture {
+            self.last_handshake_bytes = Some((direction, message.to_vec()));
+        }
+    }
+
+    /// Local indices of all live sessions, starting from the current session
+    pub fn session_indices(&self) -> Vec<u32> {
+        (0..N_SESSIONS)
+            .filter_map(|i| self.sessions[(self.current.wrapping_sub(i)) % N_SESSIONS].as_ref())
+            .map(|session| session.local_index() as u32)
+            .collect()
+    }
+
+    /// Whether `receiver_index`, as carried by a data packet, belongs to one of the live sessions
+    /// of this tunnel, the current one or those kept while keys rotate. Only compares indices,
+    /// so a dispatcher can find the tunnel of a packet without trying to decrypt it.
+    pub fn accepts_index(&self, receiver_index: u32) -> bool {
+        self.sessions[receiver_index as usize % N_SESSIONS]
+            .as_ref()
+            .map_or(false, |session| {
+                session.local_index() as u32 == receiver_index
+            })
+    }
+
+    /// The index the peer assigned to the current session, which our data messages carry
+    pub fn remote_index(&self) -> Option<u32> {
+        self.sessions[self.current % N_SESSIONS]
+            .as_ref()
+            .map(|session| session.remote_index())
+    }
+
+    /// Remote indices of all live sessions, starting from the current session
+    pub fn remote_session_indices(&self) -> Vec<u32> {
+        (0..N_SESSIONS)
+            .filter_map(|i| self.sessions[(self.current.wrapping_sub(i)) % N_SESSIONS].as_ref())
+            .map(|session| session.remote_index())
+            .collect()
+    }
+
+    /// The transport keys and counters of the current session, to compare the derived keys
+    /// against the reference implementation when an interop test fails.
+    ///
+    /// **Catastrophically insecure**: anyone seeing the dump can decrypt and forge the traffic
+    /// of the session. Only built with the `debug_keys` feature, which must never be enabled in
+    /// production.
+    #[cfg(feature = "debug_keys")]
+    pub fn dump_keys(&self) -> Option<KeyDump> {
+        self.sessions[self.current % N_SESSIONS]
+            .as_ref()
+            .map(|session| session.dump_keys())
+    }
+
+    /// Counts the handshakes that established a session. The epoch of the session currently
+    /// used to send, 0 before the first one. Epochs go up by one per rotation of the keys and
+    /// never repeat for a tunnel.
+    pub fn key_epoch(&self) -> u64 {
+        self.sessions[self.current % N_SESSIONS]
+            .as_ref()
+            .map_or(0, |session| session.epoch)
+    }
+
+    /// Return stats from the tunnel
+    pub fn stats(&self) -> TunnStats {
+        TunnStats {
+            time_since_handshake: self.time_since_last_handshake(),
+            tx_bytes: self.tx_bytes,
+            rx_bytes: self.rx_bytes,
+            estimated_loss: self.estimate_loss(),
+            rtt_ms: self.handshake.last_rtt,
+            key_epoch: self.key_epoch(),
+        }
+    }
+}
 
-        (time, tx_bytes, rx_bytes, loss, rtt)
+/// Copy `packet` over the `dst` slices, in order
+fn scatter(mut packet: &[u8], dst: &mut [IoSliceMut<'_>]) {
+    for slice in dst.iter_mut() {
+        let n = slice.len().min(packet.len());
+        slice[..n].copy_from_slice(&packet[..n]);
+        packet = &packet[n..];
     }
 }
 
 #[cfg(test)]
 mod tests {
+    #[cfg(any(feature = "mock-instant", feature = "test-utils"))]
+    use crate::noise::timers::REJECT_AFTER_TIME;
     #[cfg(feature = "mock-instant")]
     use crate::noise::timers::{REKEY_AFTER_TIME, REKEY_TIMEOUT};
 
@@ -773,64 +1560,992 @@ mod tests {
     }
 
     #[test]
-    fn full_handshake_plus_timers() {
-        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
-        // Time has not yet advanced so their is nothing to do
-        assert!(matches!(my_tun.update_timers(&mut []), TunnResult::Done));
-        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
+    fn handshake_completed_reported_once() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let init = create_handshake_init(&mut my_tun);
+        assert!(!my_tun.take_handshake_completed());
+        let resp = create_handshake_response(&mut their_tun, &init);
+        parse_handshake_resp(&mut my_tun, &resp);
+
+        assert!(my_tun.take_handshake_completed());
+        assert!(!my_tun.take_handshake_completed());
     }
 
     #[test]
-    #[cfg(feature = "mock-instant")]
-    fn new_handshake_after_two_mins() {
-        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
-        let mut my_dst = [0u8; 1024];
+    fn authenticated_only_by_verified_packets() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let mut dst = vec![0u8; 2048];
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+        assert!(their_tun.take_authenticated());
+        assert!(!their_tun.take_authenticated());
 
-        // Advance time 1 second and "send" 1 packet so that we send a handshake
-        // after the timeout
-        mock_instant::MockClock::advance(Duration::from_secs(1));
-        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
+        // A replayed initiation fails on its timestamp
         assert!(matches!(
-            my_tun.update_timers(&mut my_dst),
-            TunnResult::Done
+            their_tun.decapsulate(None, &init, &mut dst),
+            TunnResult::Err(WireGuardError::WrongTai64nTimestamp)
         ));
-        let sent_packet_buf = create_ipv4_udp_packet();
-        let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
-        assert!(matches!(data, TunnResult::WriteToNetwork(_)));
+        assert!(!their_tun.take_authenticated());
 
-        //Advance to timeout
-        mock_instant::MockClock::advance(REKEY_AFTER_TIME.into());
-        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
-        update_timer_results_in_handshake(&mut my_tun);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        assert!(my_tun.take_authenticated());
+        // A replayed response finds no initiation in flight
+        assert!(matches!(
+            my_tun.decapsulate(None, &resp, &mut dst),
+            TunnResult::Err(_)
+        ));
+        assert!(!my_tun.take_authenticated());
+
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert!(their_tun.take_authenticated());
+        let mut forged = keepalive.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            their_tun.decapsulate(None, &forged, &mut dst),
+            TunnResult::Err(_)
+        ));
+        assert!(!their_tun.take_authenticated());
     }
 
     #[test]
-    #[cfg(feature = "mock-instant")]
-    fn handshake_no_resp_rekey_timeout() {
-        let (mut my_tun, _their_tun) = create_two_tuns();
-
+    #[cfg(feature = "debug_keys")]
+    fn dump_keys_match_peer() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        assert_eq!(my_tun.dump_keys(), None);
         let init = create_handshake_init(&mut my_tun);
-        let packet = Tunn::parse_incoming_packet(&init).unwrap();
-        assert!(matches!(packet, Packet::HandshakeInit(_)));
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
 
-        mock_instant::MockClock::advance(REKEY_TIMEOUT.into());
-        update_timer_results_in_handshake(&mut my_tun)
+        let mine = my_tun.dump_keys().unwrap();
+        let theirs = their_tun.dump_keys().unwrap();
+        assert_eq!(mine.send_key, theirs.recv_key);
+        assert_eq!(mine.recv_key, theirs.send_key);
+        assert_eq!(mine.local_index, theirs.remote_index);
+        assert_eq!(mine.remote_index, theirs.local_index);
+        // The keepalive used the first counter
+        assert_eq!((mine.send_nonce, theirs.recv_nonce), (1, 1));
+        assert!(mine
+            .to_string()
+            .contains(&format!("send_key={}\n", mine.send_key)));
     }
 
     #[test]
-    fn one_ip_packet() {
-        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
-        let mut my_dst = [0u8; 1024];
-        let mut their_dst = [0u8; 1024];
+    fn forced_rekey_advances_key_epoch() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        assert_eq!(my_tun.key_epoch(), 0);
+        assert_eq!(my_tun.time_since_key_rotation(), None);
 
-        let sent_packet_buf = create_ipv4_udp_packet();
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert_eq!(my_tun.key_epoch(), 1);
+        assert_eq!(their_tun.key_epoch(), 1);
+        assert!(my_tun.time_since_key_rotation().is_some());
 
-        let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
-        assert!(matches!(data, TunnResult::WriteToNetwork(_)));
-        let data = if let TunnResult::WriteToNetwork(sent) = data {
-            sent
-        } else {
-            unreachable!();
+        // The rekey follows right away
+        their_tun.set_min_handshake_interval(Duration::ZERO);
+        let mut dst = vec![0u8; 2048];
+        let init = match my_tun.format_handshake_initiation(&mut dst, true) {
+            TunnResult::WriteToNetwork(init) => init.to_vec(),
+            _ => unreachable!(),
+        };
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert_eq!(my_tun.key_epoch(), 2);
+        assert_eq!(their_tun.key_epoch(), 2);
+        assert_eq!(my_tun.stats().key_epoch, 2);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        their_tun.set_min_handshake_interval(Duration::ZERO);
+        let mut dst = vec![0u8; 2048];
+        assert_eq!(my_tun.key_epoch(), 1);
+
+        let old_data = match their_tun.encapsulate(&create_ipv4_udp_packet(), &mut dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        let init = match my_tun.reset(&mut dst) {
+            TunnResult::WriteToNetwork(init) => init.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert_eq!(my_tun.stats().tx_bytes, init.len());
+        assert!(my_tun.time_since_last_handshake().is_none());
+
+        // Nothing is left of the old session
+        assert!(matches!(
+            my_tun.decapsulate(None, &old_data, &mut dst),
+            TunnResult::Err(_)
+        ));
+        assert!(matches!(
+            my_tun.encapsulate(&create_ipv4_udp_packet(), &mut dst),
+            TunnResult::Done
+        ));
+
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert_eq!(my_tun.key_epoch(), 2);
+    }
+
+    /// Counts its uses, so a test can tell it was the generator used
+    struct CountingRng {
+        inner: rand::rngs::StdRng,
+        uses: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl RngCore for CountingRng {
+        fn next_u32(&mut self) -> u32 {
+            self.uses.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.inner.next_u32()
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.uses.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.inner.next_u64()
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            self.uses.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.inner.fill_bytes(dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.uses.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.inner.try_fill_bytes(dest)
+        }
+    }
+
+    impl CryptoRng for CountingRng {}
+
+    #[test]
+    fn injected_rng() {
+        use rand::SeedableRng;
+
+        // Keys and generators all seeded, so every run draws the same
+        let mut keys = rand::rngs::StdRng::from_seed([0; 32]);
+        let my_secret_key = x25519_dalek::StaticSecret::random_from_rng(&mut keys);
+        let my_public_key = x25519_dalek::PublicKey::from(&my_secret_key);
+        let their_secret_key = x25519_dalek::StaticSecret::random_from_rng(&mut keys);
+        let their_public_key = x25519_dalek::PublicKey::from(&their_secret_key);
+        let mut my_tun = Tunn::new(my_secret_key, their_public_key, None, None, 1, None).unwrap();
+        let mut their_tun =
+            Tunn::new(their_secret_key, my_public_key, None, None, 2, None).unwrap();
+        let uses = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        for (tun, seed) in [(&mut my_tun, 1u8), (&mut their_tun, 2u8)] {
+            tun.set_rng(Arc::new(Mutex::new(CountingRng {
+                inner: rand::rngs::StdRng::from_seed([seed; 32]),
+                uses: Arc::clone(&uses),
+            })))
+            .unwrap();
+        }
+        // The two cookie secrets of both rate limiters
+        let after_setup = uses.load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(after_setup, 4);
+
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert_eq!(my_tun.key_epoch(), 1);
+        // Both ephemeral keys
+        assert_eq!(
+            uses.load(std::sync::atomic::Ordering::Relaxed),
+            after_setup + 2
+        );
+    }
+
+    /// Never has entropy to give
+    struct FailingRng;
+
+    impl RngCore for FailingRng {
+        fn next_u32(&mut self) -> u32 {
+            panic!("Only try_fill_bytes may be used")
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            panic!("Only try_fill_bytes may be used")
+        }
+
+        fn fill_bytes(&mut self, _dest: &mut [u8]) {
+            panic!("Only try_fill_bytes may be used")
+        }
+
+        fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            let code = std::num::NonZeroU32::new(rand_core::Error::CUSTOM_START).unwrap();
+            Err(code.into())
+        }
+    }
+
+    impl CryptoRng for FailingRng {}
+
+    #[test]
+    fn entropy_failure_skips_handshake() {
+        let my_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+        let my_public_key = x25519_dalek::PublicKey::from(&my_secret_key);
+        let their_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+        let their_public_key = x25519_dalek::PublicKey::from(&their_secret_key);
+        // Given a rate limiter the tunnels don't draw new cookie secrets from the RNG
+        let tunnel = |secret_key, peer_public_key, index| {
+            let public_key = x25519_dalek::PublicKey::from(&secret_key);
+            let rate_limiter = Arc::new(RateLimiter::new(&public_key, 10));
+            let mut tun = Tunn::new(
+                secret_key,
+                peer_public_key,
+                None,
+                None,
+                index,
+                Some(rate_limiter),
+            )
+            .unwrap();
+            tun.set_rng(Arc::new(Mutex::new(FailingRng))).unwrap();
+            tun
+        };
+        let mut my_tun = tunnel(my_secret_key, their_public_key, 1);
+        let mut their_tun = tunnel(their_secret_key, my_public_key, 2);
+        let mut dst = vec![0u8; 2048];
+
+        assert!(matches!(
+            my_tun.format_handshake_initiation(&mut dst, false),
+            TunnResult::Err(WireGuardError::EntropyFailure)
+        ));
+        assert!(matches!(
+            my_tun.encapsulate(&create_ipv4_udp_packet(), &mut dst),
+            TunnResult::Err(WireGuardError::EntropyFailure)
+        ));
+        assert!(!my_tun.handshake.is_in_progress());
+
+        // The next attempt goes through once entropy is back, but the responder can't answer
+        my_tun.set_rng(os_rng()).unwrap();
+        let init = create_handshake_init(&mut my_tun);
+        assert!(matches!(
+            their_tun.decapsulate(None, &init, &mut dst),
+            TunnResult::Err(WireGuardError::EntropyFailure)
+        ));
+        assert_eq!(their_tun.key_epoch(), 0);
+
+        // Nor can a tunnel with its own rate limiter draw its cookie secrets, it keeps the
+        // generator it had
+        let (mut tun, _) = create_two_tuns();
+        assert!(matches!(
+            tun.set_rng(Arc::new(Mutex::new(FailingRng))),
+            Err(WireGuardError::EntropyFailure)
+        ));
+        create_handshake_init(&mut tun);
+    }
+
+    #[test]
+    fn initiation_not_allowed() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        my_tun.set_initiation_allowed(false);
+        let mut dst = vec![0u8; 2048];
+
+        let sent_packet_buf = create_ipv4_udp_packet();
+        assert!(matches!(
+            my_tun.encapsulate(&sent_packet_buf, &mut dst),
+            TunnResult::Done
+        ));
+        my_tun.set_persistent_keepalive(1);
+        assert!(matches!(my_tun.update_timers(&mut dst), TunnResult::Done));
+
+        // The peer can still establish a session, which delivers the queued packet
+        let init = create_handshake_init(&mut their_tun);
+        let resp = create_handshake_response(&mut my_tun, &init);
+        let keepalive = parse_handshake_resp(&mut their_tun, &resp);
+        parse_keepalive(&mut my_tun, &keepalive);
+        match my_tun.decapsulate(None, &[], &mut dst) {
+            TunnResult::WriteToNetwork(data) => {
+                let data = data.to_vec();
+                let mut recv_buf = vec![0u8; 2048];
+                assert!(matches!(
+                    their_tun.decapsulate(None, &data, &mut recv_buf),
+                    TunnResult::WriteToTunnelV4(..)
+                ));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn decapsulate_into_slices() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let sent_packet_buf = create_ipv4_udp_packet();
+        let mut dst = vec![0u8; 2048];
+        let data = match my_tun.encapsulate(&sent_packet_buf, &mut dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => unreachable!(),
+        };
+
+        // A tampered packet leaves the slices untouched
+        let mut tampered = data.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let (mut a, mut b, mut c) = ([0u8; 10], [0u8; 20], [0u8; 2048]);
+        let mut slices = [
+            IoSliceMut::new(&mut a),
+            IoSliceMut::new(&mut b),
+            IoSliceMut::new(&mut c),
+        ];
+        let res = their_tun.decapsulate_into(None, &tampered, &mut slices);
+        assert!(matches!(
+            res,
+            DecapsulatedInto::Other(TunnResult::Err(WireGuardError::InvalidAeadTag))
+        ));
+        assert!(slices.iter().all(|slice| slice.iter().all(|&b| b == 0)));
+
+        let res = their_tun.decapsulate_into(None, &data, &mut slices);
+        let len = match res {
+            DecapsulatedInto::Packet(len, _) => len,
+            _ => unreachable!(),
+        };
+        assert_eq!(len, sent_packet_buf.len());
+        let received: Vec<u8> = slices.iter().flat_map(|slice| slice.to_vec()).collect();
+        assert_eq!(&received[..len], &sent_packet_buf[..]);
+
+        // A packet fitting the first slice is decrypted right there
+        let data = match my_tun.encapsulate(&sent_packet_buf, &mut dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => unreachable!(),
+        };
+        let mut buf = [0u8; 2048];
+        let mut slices = [IoSliceMut::new(&mut buf)];
+        let res = their_tun.decapsulate_into(None, &data, &mut slices);
+        assert!(matches!(res, DecapsulatedInto::Packet(len, _) if len == sent_packet_buf.len()));
+        assert_eq!(&buf[..sent_packet_buf.len()], &sent_packet_buf[..]);
+
+        // Slices too small for the packet, or the first one for a handshake response
+        let data = match my_tun.encapsulate(&sent_packet_buf, &mut dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => unreachable!(),
+        };
+        let (mut a, mut b) = ([0u8; 10], [0u8; 10]);
+        let mut slices = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+        let res = their_tun.decapsulate_into(None, &data, &mut slices);
+        assert!(matches!(
+            res,
+            DecapsulatedInto::Other(TunnResult::Err(WireGuardError::DestinationBufferTooSmall))
+        ));
+        let init = create_handshake_init(&mut my_tun);
+        let res = their_tun.decapsulate_into(None, &init, &mut slices);
+        assert!(matches!(
+            res,
+            DecapsulatedInto::Other(TunnResult::Err(WireGuardError::DestinationBufferTooSmall))
+        ));
+    }
+
+    #[test]
+    fn debug_capture_handshake_bytes() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        create_handshake_init(&mut my_tun);
+        assert_eq!(my_tun.last_handshake_bytes(), None);
+
+        my_tun.set_debug_capture(true);
+        their_tun.set_debug_capture(true);
+        let init = create_handshake_init(&mut my_tun);
+        assert_eq!(
+            my_tun.last_handshake_bytes(),
+            Some((Direction::Sent, init.clone()))
+        );
+
+        let resp = create_handshake_response(&mut their_tun, &init);
+        assert_eq!(
+            their_tun.last_handshake_bytes(),
+            Some((Direction::Sent, resp.clone()))
+        );
+        parse_handshake_resp(&mut my_tun, &resp);
+        assert_eq!(
+            my_tun.last_handshake_bytes(),
+            Some((Direction::Received, resp))
+        );
+
+        my_tun.set_debug_capture(false);
+        assert_eq!(my_tun.last_handshake_bytes(), None);
+    }
+
+    #[test]
+    fn parse_public_key_formats() {
+        let public_key =
+            x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::random_from_rng(OsRng));
+        let hex_key = hex::encode(public_key.as_bytes());
+        let base64_key = base64::encode(public_key.as_bytes());
+        assert_eq!(parse_public_key(&hex_key), Ok(public_key));
+        assert_eq!(parse_public_key(&base64_key), Ok(public_key));
+        assert_eq!(
+            parse_public_key(base64_key.trim_end_matches('=')),
+            Ok(public_key)
+        );
+    }
+
+    #[test]
+    fn classify_packet_by_type_and_length() {
+        let message = |message_type: u32, len: usize| {
+            let mut buf = vec![0u8; len];
+            buf[..4].copy_from_slice(&message_type.to_le_bytes());
+            buf
+        };
+        let minimal = [
+            (MessageType::HandshakeInit, HANDSHAKE_INIT_SZ),
+            (MessageType::HandshakeResponse, HANDSHAKE_RESP_SZ),
+            (MessageType::CookieReply, COOKIE_REPLY_SZ),
+            (MessageType::Data, DATA_OVERHEAD_SZ),
+        ];
+        for (message_type, len) in minimal {
+            let buf = message(message_type as u32, len);
+            assert_eq!(classify_packet(&buf), Some(message_type));
+            assert_eq!(classify_packet(&buf[..len - 1]), None);
+        }
+        assert_eq!(
+            classify_packet(&message(DATA, 1500)),
+            Some(MessageType::Data)
+        );
+
+        assert_eq!(classify_packet(&[]), None);
+        assert_eq!(classify_packet(&[1]), None);
+        assert_eq!(classify_packet(&message(5, 148)), None);
+        // The reserved bytes must be zero
+        let mut reserved = message(HANDSHAKE_INIT, HANDSHAKE_INIT_SZ);
+        reserved[1] = 1;
+        assert_eq!(classify_packet(&reserved), None);
+    }
+
+    #[test]
+    fn parse_public_key_rejects() {
+        assert_eq!(
+            parse_public_key(&base64::encode([7u8; 31])),
+            Err(KeyError::WrongLength(31))
+        );
+        assert_eq!(
+            parse_public_key(&"g".repeat(64)),
+            Err(KeyError::BadEncoding)
+        );
+        assert_eq!(parse_public_key("not a key!"), Err(KeyError::BadEncoding));
+
+        let zero = base64::encode([0u8; 32]);
+        assert_eq!(parse_public_key(&zero), Err(KeyError::InsecureKey));
+        // A point of order 8
+        let low_order = "e0eb7a7c3b41b8ae1656e3faf19fc46ada098deb9c32b1fd866205165f49b800";
+        assert_eq!(parse_public_key(low_order), Err(KeyError::InsecureKey));
+    }
+
+    #[test]
+    fn encapsulated_len_matches_overhead() {
+        let (mut my_tun, _their_tun) = create_two_tuns_and_handshake();
+        assert_eq!(Tunn::transport_overhead(), 32);
+        let mut dst = vec![0u8; 2048];
+        for len in [0, 1, 15, 16, 17, 100, 1420] {
+            let expected = len + Tunn::transport_overhead() + Tunn::padding_for(len);
+            assert_eq!(Tunn::encapsulated_len(len), expected);
+            match my_tun.encapsulate(&vec![0x45; len], &mut dst) {
+                TunnResult::WriteToNetwork(packet) => assert_eq!(packet.len(), expected),
+                _ => panic!("Expected a data packet"),
+            }
+        }
+    }
+
+    #[test]
+    fn min_handshake_interval_drops_flapping() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        their_tun.set_min_handshake_interval(Duration::from_secs(10));
+        let first = create_handshake_init(&mut my_tun);
+        let mut dst = vec![0u8; 2048];
+        let second = match my_tun.format_handshake_initiation(&mut dst, true) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+
+        create_handshake_response(&mut their_tun, &first);
+        assert!(matches!(
+            their_tun.decapsulate(None, &second, &mut dst),
+            TunnResult::Err(WireGuardError::InitiationTooSoon)
+        ));
+        assert_eq!(their_tun.flapping_initiations(), 1);
+        assert_eq!(their_tun.observed_behavior().initiations_received, 1);
+
+        // Replays are rejected as such, even of the one rejected as too soon
+        for replay in [&first, &second] {
+            assert!(matches!(
+                their_tun.decapsulate(None, replay, &mut dst),
+                TunnResult::Err(WireGuardError::WrongTai64nTimestamp)
+            ));
+        }
+        assert_eq!(their_tun.flapping_initiations(), 1);
+    }
+
+    #[test]
+    fn compute_mac1_matches_handshakes() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+
+        let mac1 = |msg: &[u8]| <[u8; 16]>::try_from(&msg[msg.len() - 32..msg.len() - 16]).unwrap();
+        assert_eq!(
+            compute_mac1(&my_tun.peer_static_public(), &init),
+            mac1(&init)
+        );
+        assert_eq!(
+            compute_mac1(&their_tun.peer_static_public(), &resp),
+            mac1(&resp)
+        );
+        assert_ne!(
+            compute_mac1(&their_tun.peer_static_public(), &init),
+            mac1(&init)
+        );
+    }
+
+    #[test]
+    fn bad_mac1_gets_no_response() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let mut init = create_handshake_init(&mut my_tun);
+        let mac1_start = init.len() - 32;
+        init[mac1_start] ^= 1;
+
+        let mut dst = vec![0u8; 2048];
+        assert!(matches!(
+            their_tun.decapsulate(None, &init, &mut dst),
+            TunnResult::Err(WireGuardError::InvalidMac)
+        ));
+        assert_eq!(their_tun.rate_limiter.suppressed_responses(), 1);
+
+        their_tun.rate_limiter.set_require_mac1(false);
+        let resp = their_tun.decapsulate(None, &init, &mut dst);
+        assert!(matches!(resp, TunnResult::WriteToNetwork(_)));
+        assert_eq!(their_tun.rate_limiter.suppressed_responses(), 1);
+    }
+
+    #[test]
+    fn half_open_limit_requires_cookies() {
+        let my_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+        let my_public_key = x25519_dalek::PublicKey::from(&my_secret_key);
+        let rate_limiter = Arc::new(RateLimiter::new(&my_public_key, 1000));
+        rate_limiter.set_half_open_limit(Some(3));
+        let addr = Some(IpAddr::from([192, 0, 2, 1]));
+
+        let mut pairs = vec![];
+        for i in 0..5 {
+            let their_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+            let their_public_key = x25519_dalek::PublicKey::from(&their_secret_key);
+            let my_tun = Tunn::new(
+                my_secret_key.clone(),
+                their_public_key,
+                None,
+                None,
+                i,
+                Some(Arc::clone(&rate_limiter)),
+            )
+            .unwrap();
+            let their_tun =
+                Tunn::new(their_secret_key, my_public_key, None, None, 100 + i, None).unwrap();
+            pairs.push((my_tun, their_tun));
+        }
+
+        let mut dst = vec![0u8; 2048];
+        let mut responses = vec![];
+        for (i, (my_tun, their_tun)) in pairs.iter_mut().enumerate() {
+            let init = create_handshake_init(their_tun);
+            match my_tun.decapsulate(addr, &init, &mut dst) {
+                TunnResult::WriteToNetwork(packet) if i < 3 => {
+                    assert!(matches!(
+                        Tunn::parse_incoming_packet(packet),
+                        Ok(Packet::HandshakeResponse(_))
+                    ));
+                    responses.push(packet.to_vec());
+                }
+                TunnResult::WriteToNetwork(packet) => {
+                    assert!(matches!(
+                        Tunn::parse_incoming_packet(packet),
+                        Ok(Packet::PacketCookieReply(_))
+                    ));
+                }
+                _ => panic!("Expected a handshake response or a cookie reply"),
+            }
+        }
+        assert_eq!(rate_limiter.half_open(), 3);
+
+        // Confirming a session frees its entry
+        let keepalive = parse_handshake_resp(&mut pairs[0].1, &responses[0]);
+        parse_keepalive(&mut pairs[0].0, &keepalive);
+        assert_eq!(rate_limiter.half_open(), 2);
+
+        pairs.truncate(1);
+        assert_eq!(rate_limiter.half_open(), 0);
+    }
+
+    #[test]
+    fn send_keepalive_needs_session() {
+        let (mut my_tun, _their_tun) = create_two_tuns();
+        let mut my_dst = [0u8; 1024];
+        assert!(matches!(
+            my_tun.send_keepalive(&mut my_dst),
+            TunnResult::Err(WireGuardError::NoCurrentSession)
+        ));
+    }
+
+    #[test]
+    fn send_keepalive_uses_new_counters() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+
+        let first = match my_tun.send_keepalive(&mut my_dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+        let second = match my_tun.send_keepalive(&mut my_dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+        assert_ne!(first[8..16], second[8..16]);
+        for keepalive in [first, second] {
+            assert!(matches!(
+                their_tun.decapsulate(None, &keepalive, &mut their_dst),
+                TunnResult::Done
+            ));
+        }
+    }
+
+    #[test]
+    fn full_handshake_plus_timers() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        // Time has not yet advanced so their is nothing to do
+        assert!(matches!(my_tun.update_timers(&mut []), TunnResult::Done));
+        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn rtt_sample_of_initiated_handshake() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+        mock_instant::MockClock::advance(Duration::from_millis(30));
+        parse_handshake_resp(&mut my_tun, &resp);
+
+        assert_eq!(my_tun.take_rtt_sample(), Some(Duration::from_millis(30)));
+        assert_eq!(my_tun.take_rtt_sample(), None);
+        // The responder measures nothing
+        assert_eq!(their_tun.take_rtt_sample(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn new_handshake_after_two_mins() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+
+        // Advance time 1 second and "send" 1 packet so that we send a handshake
+        // after the timeout
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
+        assert!(matches!(
+            my_tun.update_timers(&mut my_dst),
+            TunnResult::Done
+        ));
+        let sent_packet_buf = create_ipv4_udp_packet();
+        let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
+        assert!(matches!(data, TunnResult::WriteToNetwork(_)));
+
+        //Advance to timeout
+        mock_instant::MockClock::advance(REKEY_AFTER_TIME.into());
+        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
+        update_timer_results_in_handshake(&mut my_tun);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn cancelled_rekey_waits_for_data() {
+        let (mut my_tun, _their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let sent_packet_buf = create_ipv4_udp_packet();
+
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        assert!(matches!(
+            my_tun.update_timers(&mut my_dst),
+            TunnResult::Done
+        ));
+        let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
+        assert!(matches!(data, TunnResult::WriteToNetwork(_)));
+        mock_instant::MockClock::advance(REKEY_AFTER_TIME.into());
+        update_timer_results_in_handshake(&mut my_tun);
+        assert!(my_tun.cancel_handshake());
+
+        // Neither retried nor started again by the trigger that started it
+        for _ in 0..2 * Duration::from(REKEY_TIMEOUT).as_secs() {
+            mock_instant::MockClock::advance(Duration::from_secs(1));
+            assert!(matches!(
+                my_tun.update_timers(&mut my_dst),
+                TunnResult::Done
+            ));
+        }
+
+        // Sending data again does
+        let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
+        assert!(matches!(data, TunnResult::WriteToNetwork(_)));
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        update_timer_results_in_handshake(&mut my_tun);
+    }
+
+    #[test]
+    #[cfg(all(feature = "mock-instant", feature = "test-utils"))]
+    fn delayed_response_completes_after_retry() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let mut dst = vec![0u8; 2048];
+        their_tun.set_response_delay(Duration::from_secs(6));
+
+        let init = create_handshake_init(&mut my_tun);
+        assert!(matches!(
+            their_tun.decapsulate(None, &init, &mut dst),
+            TunnResult::Done
+        ));
+        assert!(matches!(
+            their_tun.update_timers(&mut dst),
+            TunnResult::Done
+        ));
+
+        // No response within REKEY_TIMEOUT, the initiator retries
+        mock_instant::MockClock::advance(Duration::from_millis(5500));
+        let retry = match my_tun.update_timers(&mut dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected a retried initiation"),
+        };
+        their_tun.set_response_delay(Duration::from_secs(2));
+        assert!(matches!(
+            their_tun.decapsulate(None, &retry, &mut dst),
+            TunnResult::Done
+        ));
+
+        // The response to the first initiation comes too late to be accepted
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        let stale = match their_tun.update_timers(&mut dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected the first response"),
+        };
+        assert!(matches!(
+            my_tun.decapsulate(None, &stale, &mut dst),
+            TunnResult::Err(_)
+        ));
+
+        // The response to the retry completes the handshake
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        assert!(matches!(
+            their_tun.decapsulate(None, &[], &mut dst),
+            TunnResult::Done
+        ));
+        mock_instant::MockClock::advance(Duration::from_millis(500));
+        let response = match their_tun.decapsulate(None, &[], &mut dst) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("Expected the second response"),
+        };
+        let keepalive = parse_handshake_resp(&mut my_tun, &response);
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert_eq!(my_tun.key_epoch(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn handshake_no_resp_rekey_timeout() {
+        let (mut my_tun, _their_tun) = create_two_tuns();
+
+        let init = create_handshake_init(&mut my_tun);
+        let packet = Tunn::parse_incoming_packet(&init).unwrap();
+        assert!(matches!(packet, Packet::HandshakeInit(_)));
+
+        mock_instant::MockClock::advance(REKEY_TIMEOUT.into());
+        update_timer_results_in_handshake(&mut my_tun)
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn connection_expires_without_new_keys() {
+        let (mut my_tun, _their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let expiry = Duration::from(REJECT_AFTER_TIME * 3).as_secs();
+
+        let mut expired = false;
+        for _ in 0..=expiry {
+            mock_instant::MockClock::advance(Duration::from_secs(1));
+            if let TunnResult::Err(WireGuardError::ConnectionExpired) =
+                my_tun.update_timers(&mut my_dst)
+            {
+                expired = true;
+                break;
+            }
+        }
+        assert!(expired);
+        assert!(my_tun.time_since_last_handshake().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "test-utils")]
+    fn injected_clock_drives_timers() {
+        let (mut my_tun, _their_tun) = create_two_tuns_and_handshake();
+        let clock = Arc::new(MockClock::default());
+        my_tun.set_clock(clock.clone());
+        let mut my_dst = [0u8; 1024];
+
+        // The time so far carries over, then only moves with the clock
+        let since_handshake = my_tun.time_since_last_handshake().unwrap();
+        assert_eq!(my_tun.time_since_last_handshake(), Some(since_handshake));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            my_tun.time_since_last_handshake(),
+            Some(since_handshake + Duration::from_secs(1))
+        );
+
+        let expiry = Duration::from(REJECT_AFTER_TIME * 3).as_secs();
+        let mut expired = false;
+        for _ in 0..expiry {
+            clock.advance(Duration::from_secs(1));
+            if let TunnResult::Err(WireGuardError::ConnectionExpired) =
+                my_tun.update_timers(&mut my_dst)
+            {
+                expired = true;
+                break;
+            }
+        }
+        assert!(expired);
+        assert!(my_tun.time_since_last_handshake().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn clock_jump_is_clamped() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+        let data = their_tun.encapsulate(&create_ipv4_udp_packet(), &mut their_dst);
+        let data = match data {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            unexpected => panic!("Expected WriteToNetwork, got {:?}", unexpected),
+        };
+        assert!(matches!(
+            my_tun.decapsulate(None, &data, &mut my_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+
+        // A jump past REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT, which the initiator's
+        // receive trigger counts as only MAX_TIMER_STEP, while the handshake age is the real one
+        mock_instant::MockClock::advance(Duration::from_secs(170));
+        if let TunnResult::WriteToNetwork(packet) = my_tun.update_timers(&mut my_dst) {
+            assert!(!matches!(
+                Tunn::parse_incoming_packet(packet),
+                Ok(Packet::HandshakeInit(_))
+            ));
+        }
+        assert!(my_tun.time_since_last_handshake().unwrap() >= Duration::from_secs(170));
+
+        // Suspended for far longer than a connection lives without new keys: it expires
+        mock_instant::MockClock::advance(Duration::from_secs(3600));
+        assert!(matches!(
+            my_tun.update_timers(&mut my_dst),
+            TunnResult::Err(WireGuardError::ConnectionExpired)
+        ));
+        assert!(my_tun.time_since_last_handshake().is_none());
+    }
+
+    #[test]
+    fn persistent_keepalive_zero_disables() {
+        let (mut my_tun, _their_tun) = create_two_tuns();
+        my_tun.set_persistent_keepalive(25);
+        assert_eq!(my_tun.persistent_keepalive(), Some(25));
+        my_tun.set_persistent_keepalive(0);
+        assert_eq!(my_tun.persistent_keepalive(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn persistent_keepalive_zero_halts_keepalives() {
+        let (mut my_tun, _their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+
+        my_tun.set_persistent_keepalive(1);
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        assert!(matches!(
+            my_tun.update_timers(&mut my_dst),
+            TunnResult::WriteToNetwork(_)
+        ));
+
+        my_tun.set_persistent_keepalive(0);
+        for _ in 0..5 {
+            mock_instant::MockClock::advance(Duration::from_secs(1));
+            assert!(matches!(
+                my_tun.update_timers(&mut my_dst),
+                TunnResult::Done
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn adaptive_keepalive_skipped_while_busy() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+        let packet = create_ipv4_udp_packet();
+        my_tun.set_persistent_keepalive(2);
+        my_tun.set_adaptive_keepalive(true);
+
+        mock_instant::MockClock::advance(Duration::from_secs(2));
+        assert!(matches!(
+            my_tun.update_timers(&mut my_dst),
+            TunnResult::WriteToNetwork(_)
+        ));
+
+        // Sending keeps the keepalives away
+        for _ in 0..4 {
+            mock_instant::MockClock::advance(Duration::from_secs(1));
+            assert!(matches!(
+                my_tun.update_timers(&mut my_dst),
+                TunnResult::Done
+            ));
+            assert!(matches!(
+                my_tun.encapsulate(&packet, &mut my_dst),
+                TunnResult::WriteToNetwork(_)
+            ));
+        }
+
+        // So does receiving
+        for _ in 0..4 {
+            mock_instant::MockClock::advance(Duration::from_secs(1));
+            assert!(matches!(
+                my_tun.update_timers(&mut my_dst),
+                TunnResult::Done
+            ));
+            let data = match their_tun.encapsulate(&packet, &mut their_dst) {
+                TunnResult::WriteToNetwork(data) => data,
+                _ => unreachable!(),
+            };
+            assert!(matches!(
+                my_tun.decapsulate(None, data, &mut my_dst),
+                TunnResult::WriteToTunnelV4(..)
+            ));
+        }
+
+        mock_instant::MockClock::advance(Duration::from_secs(2));
+        let keepalive = match my_tun.update_timers(&mut my_dst) {
+            TunnResult::WriteToNetwork(keepalive) => keepalive.to_vec(),
+            _ => unreachable!(),
+        };
+        assert_eq!(keepalive.len(), DATA_OVERHEAD_SZ);
+    }
+
+    #[test]
+    fn one_ip_packet() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+
+        let sent_packet_buf = create_ipv4_udp_packet();
+
+        let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
+        assert!(matches!(data, TunnResult::WriteToNetwork(_)));
+        let data = if let TunnResult::WriteToNetwork(sent) = data {
+            sent
+        } else {
+            unreachable!();
         };
 
         let data = their_tun.decapsulate(None, data, &mut their_dst);
@@ -842,4 +2557,376 @@ mod tests {
         };
         assert_eq!(sent_packet_buf, recv_packet_buf);
     }
+
+    #[test]
+    fn encapsulate_at_leaves_headroom() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let packet = create_ipv4_udp_packet();
+        let headroom = 28;
+        let mut dst = vec![0xaau8; 2048];
+
+        let message = match my_tun.encapsulate_at(&packet, &mut dst, headroom) {
+            TunnResult::WriteToNetwork(message) => message.to_vec(),
+            _ => unreachable!(),
+        };
+        assert_eq!(message.len(), Tunn::encapsulated_len(packet.len()));
+        assert!(dst[..headroom].iter().all(|&b| b == 0xaa));
+        assert_eq!(&dst[headroom..headroom + message.len()], &message[..]);
+
+        let mut their_dst = [0u8; 2048];
+        match their_tun.decapsulate(None, &message, &mut their_dst) {
+            TunnResult::WriteToTunnelV4(received, _) => assert_eq!(received, &packet[..]),
+            _ => unreachable!(),
+        }
+
+        // Nothing is sent with the offset past the buffer
+        let tx_bytes = my_tun.tx_bytes;
+        assert!(matches!(
+            my_tun.encapsulate_at(&packet, &mut dst[..16], 17),
+            TunnResult::Err(WireGuardError::DestinationBufferTooSmall)
+        ));
+        assert_eq!(my_tun.tx_bytes, tx_bytes);
+    }
+
+    #[test]
+    fn decapsulate_peek_leaves_replay_window() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+        let sent_packet_buf = create_ipv4_udp_packet();
+        let data = match my_tun.encapsulate(&sent_packet_buf, &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => unreachable!(),
+        };
+        let rx_bytes = their_tun.stats().rx_bytes;
+
+        for _ in 0..2 {
+            match their_tun.decapsulate_peek(&data, &mut their_dst) {
+                TunnResult::WriteToTunnelV4(packet, _) => assert_eq!(packet, &sent_packet_buf[..]),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(their_tun.stats().rx_bytes, rx_bytes);
+
+        // The real receiver still takes it, and only once
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::Err(WireGuardError::DuplicateCounter)
+        ));
+        assert!(matches!(
+            their_tun.decapsulate_peek(&data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+    }
+
+    #[test]
+    fn session_indices_include_previous_session() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let first = my_tun.session_indices();
+        assert_eq!(first.len(), 1);
+
+        // A handshake in the other direction establishes a second session
+        let init = create_handshake_init(&mut their_tun);
+        let resp = create_handshake_response(&mut my_tun, &init);
+        let keepalive = parse_handshake_resp(&mut their_tun, &resp);
+        parse_keepalive(&mut my_tun, &keepalive);
+
+        let indices = my_tun.session_indices();
+        assert_eq!(indices.len(), 2);
+        assert!(indices.contains(&first[0]));
+    }
+
+    #[test]
+    fn accepts_index_of_live_sessions() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let previous = my_tun.session_indices()[0];
+        assert!(my_tun.accepts_index(previous));
+
+        let init = create_handshake_init(&mut their_tun);
+        let resp = create_handshake_response(&mut my_tun, &init);
+        let keepalive = parse_handshake_resp(&mut their_tun, &resp);
+        parse_keepalive(&mut my_tun, &keepalive);
+
+        let current = my_tun.session_indices()[0];
+        assert_ne!(current, previous);
+        assert!(my_tun.accepts_index(current));
+        // The previous session still takes packets sent before the peer switched keys
+        assert!(my_tun.accepts_index(previous));
+
+        // Same slot of the ring, but no session with that index
+        assert!(!my_tun.accepts_index(current.wrapping_add(N_SESSIONS as u32)));
+        assert!(!my_tun.accepts_index(their_tun.session_indices()[0]));
+    }
+
+    #[test]
+    #[cfg(feature = "test-utils")]
+    fn tunn_from_raw_session() {
+        let secrets = [(); 2].map(|_| x25519::StaticSecret::random_from_rng(OsRng));
+        let keys = [[1u8; 32], [2u8; 32]];
+        let indices = [(7 << 8) | 1, (9 << 8) | 2];
+        // Both ends of one session, the second saw counter 100 already
+        let raw = |me: usize, recv_nonce: u64, replay_bitmap: [u64; 16]| RawSession {
+            static_private: secrets[me].clone(),
+            peer_static_public: x25519::PublicKey::from(&secrets[1 - me]),
+            local_index: indices[me],
+            remote_index: indices[1 - me],
+            send_key: keys[me],
+            recv_key: keys[1 - me],
+            send_nonce: 100,
+            recv_nonce,
+            replay_bitmap,
+            is_initiator: me == 0,
+        };
+        let mut seen = [0u64; 16];
+        seen[1] = 1 << 36;
+        let mut my_tun = Tunn::from_raw_session(raw(0, 0, [0; 16])).unwrap();
+        let mut their_tun = Tunn::from_raw_session(raw(1, 101, seen)).unwrap();
+        assert_eq!(my_tun.key_epoch(), 1);
+        assert!(their_tun.accepts_index(indices[1]));
+
+        let mut my_dst = [0u8; 2048];
+        let mut their_dst = [0u8; 2048];
+        let packet = create_ipv4_udp_packet();
+        let mut send = |tun: &mut Tunn| match tun.encapsulate(&packet, &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+
+        let replayed = send(&mut my_tun);
+        assert_eq!(replayed[8..16], 100u64.to_le_bytes());
+        assert!(matches!(
+            their_tun.decapsulate(None, &replayed, &mut their_dst),
+            TunnResult::Err(WireGuardError::DuplicateCounter)
+        ));
+        let data = send(&mut my_tun);
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+
+        let data = send(&mut their_tun);
+        assert!(matches!(
+            my_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "test-utils")]
+    fn tunn_export_state_round_trip() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        assert!(create_two_tuns().0.export_state().is_none());
+
+        let mut my_dst = [0u8; 2048];
+        let mut their_dst = [0u8; 2048];
+        let packet = create_ipv4_udp_packet();
+        let mut send = |tun: &mut Tunn| match tun.encapsulate(&packet, &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        let seen = send(&mut their_tun);
+        assert!(matches!(
+            my_tun.decapsulate(None, &seen, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        send(&mut my_tun);
+
+        // The imported tunnel continues the session where the exported one left it
+        let raw = my_tun.export_state().unwrap();
+        assert_eq!(raw.send_nonce, 2);
+        assert!(raw.is_initiator);
+        let mut imported = Tunn::from_raw_session(raw).unwrap();
+        assert!(matches!(
+            imported.decapsulate(None, &seen, &mut their_dst),
+            TunnResult::Err(WireGuardError::DuplicateCounter)
+        ));
+        let data = send(&mut imported);
+        assert_eq!(data[8..16], 2u64.to_le_bytes());
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        let data = send(&mut their_tun);
+        assert!(matches!(
+            imported.decapsulate(None, &data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+    }
+
+    #[test]
+    fn replay_reject_reported_once() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let rejected = Arc::new(Mutex::new(vec![]));
+        let reported = Arc::clone(&rejected);
+        their_tun.on_replay_reject(move |counter| reported.lock().push(counter));
+
+        let mut my_dst = [0u8; 2048];
+        let mut their_dst = [0u8; 2048];
+        let data = match my_tun.encapsulate(&create_ipv4_udp_packet(), &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        let counter = u64::from_le_bytes(data[8..16].try_into().unwrap());
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        assert!(rejected.lock().is_empty());
+
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::Err(WireGuardError::DuplicateCounter)
+        ));
+        assert_eq!(*rejected.lock(), [counter]);
+
+        // A forged packet reusing the counter is dropped by the window all the same, unreported
+        let mut forged = data.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            their_tun.decapsulate(None, &forged, &mut their_dst),
+            TunnResult::Err(WireGuardError::DuplicateCounter)
+        ));
+        assert_eq!(*rejected.lock(), [counter]);
+    }
+
+    #[test]
+    fn data_path_decrypts_in_parallel() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let data_path = their_tun.data_path();
+        let mut my_dst = [0u8; 2048];
+        let packets: Vec<Vec<u8>> = (0..64)
+            .map(
+                |_| match my_tun.encapsulate(&create_ipv4_udp_packet(), &mut my_dst) {
+                    TunnResult::WriteToNetwork(data) => data.to_vec(),
+                    _ => panic!("Expected a data packet"),
+                },
+            )
+            .collect();
+
+        // Each thread decrypts its share without the tunnel
+        let decrypted: Vec<usize> = std::thread::scope(|scope| {
+            let threads: Vec<_> = packets
+                .chunks(16)
+                .map(|chunk| {
+                    let data_path = &data_path;
+                    scope.spawn(move || {
+                        let mut dst = [0u8; 2048];
+                        chunk
+                            .iter()
+                            .filter(|data| {
+                                let Ok(Packet::PacketData(packet)) =
+                                    Tunn::parse_incoming_packet(data)
+                                else {
+                                    panic!("Expected a data packet");
+                                };
+                                data_path.decrypt(packet, &mut dst).is_ok()
+                            })
+                            .count()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(decrypted, [16; 4]);
+
+        // Accounted for by the tunnel afterwards, and replays are still caught
+        let mut their_dst = [0u8; 2048];
+        let Ok(Packet::PacketData(packet)) = Tunn::parse_incoming_packet(&packets[0]) else {
+            panic!("Expected a data packet");
+        };
+        assert!(matches!(
+            data_path.decrypt(packet, &mut their_dst),
+            Err(WireGuardError::DuplicateCounter)
+        ));
+        let data = match my_tun.encapsulate(&create_ipv4_udp_packet(), &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        let Ok(Packet::PacketData(packet)) = Tunn::parse_incoming_packet(&data) else {
+            panic!("Expected a data packet");
+        };
+        let decrypted = data_path.decrypt(packet, &mut their_dst).unwrap();
+        assert!(matches!(
+            their_tun.receive_decrypted(decrypted),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+
+        // Sessions the tunnel drops are gone from the data path too
+        their_tun.reset(&mut their_dst);
+        let Ok(Packet::PacketData(packet)) = Tunn::parse_incoming_packet(&data) else {
+            panic!("Expected a data packet");
+        };
+        assert!(matches!(
+            data_path.decrypt(packet, &mut their_dst),
+            Err(WireGuardError::NoCurrentSession)
+        ));
+    }
+
+    #[test]
+    fn observed_behavior_from_traffic() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mine = my_tun.observed_behavior();
+        assert_eq!(mine.responses_received, 1);
+        assert_eq!(mine.initiations_received, 0);
+        assert!(mine.always_responds());
+        assert!(!their_tun.observed_behavior().always_responds());
+        assert_eq!(their_tun.observed_behavior().initiations_received, 1);
+        // Only the keepalive confirming the session was seen so far
+        assert_eq!(their_tun.observed_behavior().keepalive_interval, None);
+
+        let mut my_dst = [0u8; 2048];
+        let mut their_dst = [0u8; 2048];
+        let packet = create_ipv4_udp_packet();
+        let data = match my_tun.encapsulate(&packet, &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => panic!("Expected a data packet"),
+        };
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        assert_eq!(their_tun.observed_behavior().max_inner_packet, packet.len());
+
+        let keepalive = match my_tun.send_keepalive(&mut my_dst) {
+            TunnResult::WriteToNetwork(keepalive) => keepalive.to_vec(),
+            _ => panic!("Expected a keepalive"),
+        };
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert!(their_tun.observed_behavior().keepalive_interval.is_some());
+    }
+
+    #[test]
+    fn remote_index_matches_peer_local_index() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        assert_eq!(my_tun.remote_index(), None);
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert_eq!(
+            my_tun.remote_index(),
+            their_tun.session_indices().first().copied()
+        );
+        assert_eq!(
+            their_tun.remote_index(),
+            my_tun.session_indices().first().copied()
+        );
+
+        // During the rekey overlap both remote indices are known
+        let init = create_handshake_init(&mut their_tun);
+        let resp = create_handshake_response(&mut my_tun, &init);
+        let keepalive = parse_handshake_resp(&mut their_tun, &resp);
+        parse_keepalive(&mut my_tun, &keepalive);
+
+        let mut remote = my_tun.remote_session_indices();
+        let mut local = their_tun.session_indices();
+        remote.sort_unstable();
+        local.sort_unstable();
+        assert_eq!(remote.len(), 2);
+        assert_eq!(remote, local);
+    }
 }
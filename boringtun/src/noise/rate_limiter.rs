@@ -1,11 +1,16 @@
-use super::handshake::{b2s_hash, b2s_keyed_mac_16, b2s_keyed_mac_16_2, b2s_mac_24};
-use crate::noise::handshake::{LABEL_COOKIE, LABEL_MAC1};
-use crate::noise::{HandshakeInit, HandshakeResponse, Packet, Tunn, TunnResult, WireGuardError};
+use super::handshake::{b2s_hash, b2s_keyed_mac_16_2, b2s_mac_24, keyed_mac1, mac1_key};
+use crate::noise::handshake::LABEL_COOKIE;
+use crate::noise::{
+    os_rng, HandshakeInit, HandshakeResponse, Packet, SharedRng, Tunn, TunnResult, WireGuardError,
+};
 
 #[cfg(feature = "mock-instant")]
 use mock_instant::Instant;
+use std::convert::TryFrom;
 use std::net::IpAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(not(feature = "mock-instant"))]
 use crate::sleepyinstant::Instant;
@@ -14,20 +19,24 @@ use aead::generic_array::GenericArray;
 use aead::{AeadInPlace, KeyInit};
 use chacha20poly1305::{Key, XChaCha20Poly1305};
 use parking_lot::Mutex;
-use rand_core::{OsRng, RngCore};
 use ring::constant_time::verify_slices_are_equal;
 
-const COOKIE_REFRESH: u64 = 128; // Use 128 and not 120 so the compiler can optimize out the division
+/// Seconds a cookie stays valid by default, the two minutes of the WireGuard specification
+const COOKIE_REFRESH: u64 = 120;
 const COOKIE_SIZE: usize = 16;
 const COOKIE_NONCE_SIZE: usize = 24;
 
 /// How often should reset count in seconds
 const RESET_PERIOD: u64 = 1;
+/// Seconds over which `drop_rate` is averaged
+const DROP_WINDOW: usize = 5;
 
 type Cookie = [u8; COOKIE_SIZE];
 
 /// There are two places where WireGuard requires "randomness" for cookies
-/// * The 24 byte nonce in the cookie massage - here the only goal is to avoid nonce reuse
+/// * The 24 byte nonce in the cookie massage - here the only goal is to avoid nonce reuse. Each
+///   reply takes the next value of a counter, keyed through a MAC with a random secret, so no
+///   two replies share a nonce and the nonces can't be predicted.
 /// * A secret value that changes every two minutes
 /// Because the main goal of the cookie is simply for a party to prove ownership of an IP address
 /// we can relax the randomness definition a bit, in order to avoid locking, because using less
@@ -37,8 +46,8 @@ type Cookie = [u8; COOKIE_SIZE];
 pub struct RateLimiter {
     /// The key we use to derive the nonce
     nonce_key: [u8; 32],
-    /// The key we use to derive the cookie
-    secret_key: [u8; 16],
+    /// The key we use to derive the cookie, see `with_cookie_secret`
+    secret_key: [u8; 32],
     start_time: Instant,
     /// A single 64 bit counter (should suffice for many years)
     nonce_ctr: AtomicU64,
@@ -49,29 +58,101 @@ pub struct RateLimiter {
     count: AtomicU64,
     /// The time last reset was performed on this rate limiter
     last_reset: Mutex<Instant>,
+    /// Sessions set up in response to an initiation, not yet confirmed by the initiator
+    half_open: AtomicUsize,
+    /// Past this many half open handshakes, initiations must carry a valid cookie
+    half_open_limit: AtomicUsize,
+    /// How often the cookie secret changes, see `set_cookie_ttl`
+    cookie_epochs: Mutex<CookieEpochs>,
+    /// Drop handshake messages failing MAC1 instead of answering them
+    require_mac1: AtomicBool,
+    /// Initiations left without a handshake response for failing MAC1 or MAC2
+    suppressed_responses: AtomicU64,
+    /// Handshake messages refused for the load, by second, see `drop_rate`
+    drop_buckets: [AtomicU64; DROP_WINDOW],
+    /// The second since `start_time` each of `drop_buckets` counts
+    drop_bucket_secs: [AtomicU64; DROP_WINDOW],
+}
+
+/// Counts the changes of the cookie secret from when the TTL was last set, so a new TTL doesn't
+/// bring back the secrets of past epochs
+#[derive(Default)]
+struct CookieEpochs {
+    /// Milliseconds between changes
+    ttl: u64,
+    /// Milliseconds since `RateLimiter::start_time` the TTL was set at
+    anchor: u64,
+    /// The epoch at `anchor`
+    anchor_epoch: u64,
+}
+
+/// Memory held by a session answered with a handshake response
+pub const HALF_OPEN_HANDSHAKE_SIZE: usize = std::mem::size_of::<super::session::Session>();
+
+/// Accounts for a half open handshake until dropped
+pub(crate) struct HalfOpenHandshake(Arc<RateLimiter>);
+
+impl Drop for HalfOpenHandshake {
+    fn drop(&mut self) {
+        self.0.half_open.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl RateLimiter {
     pub fn new(public_key: &crate::x25519::PublicKey, limit: u64) -> Self {
-        let mut secret_key = [0u8; 16];
-        OsRng.fill_bytes(&mut secret_key);
-        RateLimiter {
-            nonce_key: Self::rand_bytes(),
+        Self::with_rng(public_key, limit, &os_rng()).expect("OsRng failed")
+    }
+
+    /// Like `new`, with the cookie secrets drawn from `rng`. Fails with `EntropyFailure` when
+    /// the generator can't supply them.
+    pub fn with_rng(
+        public_key: &crate::x25519::PublicKey,
+        limit: u64,
+        rng: &SharedRng,
+    ) -> Result<Self, WireGuardError> {
+        let mut secret_key = [0u8; 32];
+        let mut nonce_key = [0u8; 32];
+        {
+            let mut rng = rng.lock();
+            rng.try_fill_bytes(&mut secret_key)
+                .and_then(|_| rng.try_fill_bytes(&mut nonce_key))
+                .map_err(|_| WireGuardError::EntropyFailure)?;
+        }
+        Ok(RateLimiter {
+            nonce_key,
             secret_key,
             start_time: Instant::now(),
             nonce_ctr: AtomicU64::new(0),
-            mac1_key: b2s_hash(LABEL_MAC1, public_key.as_bytes()),
+            mac1_key: mac1_key(public_key),
             cookie_key: b2s_hash(LABEL_COOKIE, public_key.as_bytes()).into(),
             limit,
             count: AtomicU64::new(0),
             last_reset: Mutex::new(Instant::now()),
-        }
+            half_open: AtomicUsize::new(0),
+            half_open_limit: AtomicUsize::new(usize::MAX),
+            cookie_epochs: Mutex::new(CookieEpochs {
+                ttl: COOKIE_REFRESH * 1000,
+                ..Default::default()
+            }),
+            require_mac1: AtomicBool::new(true),
+            suppressed_responses: AtomicU64::new(0),
+            drop_buckets: Default::default(),
+            drop_bucket_secs: Default::default(),
+        })
     }
 
-    fn rand_bytes() -> [u8; 32] {
-        let mut key = [0u8; 32];
-        OsRng.fill_bytes(&mut key);
-        key
+    /// Like `new`, with `secret` in place of the random cookie secret, so tests can compute
+    /// valid cookies and MAC2 values. A known secret lets anyone forge cookies, which defeats
+    /// the DoS protection: test use only.
+    #[cfg(feature = "test-utils")]
+    pub fn with_cookie_secret(
+        public_key: &crate::x25519::PublicKey,
+        limit: u64,
+        secret: [u8; 32],
+    ) -> Self {
+        let mut limiter = Self::new(public_key, limit);
+        limiter.secret_key = secret;
+        limiter
     }
 
     /// Reset packet count (ideally should be called with a period of 1 second)
@@ -85,6 +166,95 @@ impl RateLimiter {
         }
     }
 
+    /// Require cookies from initiators once `limit` handshakes are half open, bounding the memory
+    /// an initiation flood can make us hold. `None` removes the limit.
+    pub fn set_half_open_limit(&self, limit: Option<usize>) {
+        self.half_open_limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    /// Whether handshake messages must carry a valid MAC1, true by default. Checking it before
+    /// answering means only senders knowing our public key get a response, so spoofed
+    /// initiations can't turn us into an amplifier towards their victim.
+    pub fn set_require_mac1(&self, required: bool) {
+        self.require_mac1.store(required, Ordering::Relaxed);
+    }
+
+    /// Initiations dropped for a bad MAC1, or answered with a cookie reply for a bad MAC2,
+    /// instead of with a handshake response
+    pub fn suppressed_responses(&self) -> u64 {
+        self.suppressed_responses.load(Ordering::Relaxed)
+    }
+
+    /// Handshake messages refused per second for the load, answered with a cookie reply or
+    /// dropped, averaged over the last 5 seconds
+    pub fn drop_rate(&self) -> f64 {
+        let now = self.elapsed_secs();
+        let drops: u64 = (0..DROP_WINDOW)
+            .filter(|&i| {
+                now.saturating_sub(self.drop_bucket_secs[i].load(Ordering::Relaxed))
+                    < DROP_WINDOW as u64
+            })
+            .map(|i| self.drop_buckets[i].load(Ordering::Relaxed))
+            .sum();
+        drops as f64 / DROP_WINDOW as f64
+    }
+
+    fn record_load_drop(&self) {
+        // Like the count, racing threads may lose a drop or two when the second turns
+        let now = self.elapsed_secs();
+        let i = (now % DROP_WINDOW as u64) as usize;
+        if self.drop_bucket_secs[i].swap(now, Ordering::Relaxed) != now {
+            self.drop_buckets[i].store(0, Ordering::Relaxed);
+        }
+        self.drop_buckets[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn elapsed_secs(&self) -> u64 {
+        Instant::now().duration_since(self.start_time).as_secs()
+    }
+
+    /// Handshakes answered with a response, that the initiator has yet to confirm
+    pub fn half_open(&self) -> usize {
+        self.half_open.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn half_open_handshake(self: &Arc<Self>) -> HalfOpenHandshake {
+        self.half_open.fetch_add(1, Ordering::Relaxed);
+        HalfOpenHandshake(Arc::clone(self))
+    }
+
+    fn half_open_exceeded(&self) -> bool {
+        self.half_open.load(Ordering::Relaxed) >= self.half_open_limit.load(Ordering::Relaxed)
+    }
+
+    /// How long the cookies handed out stay valid, 120 seconds by default as in the
+    /// specification. Initiators forget a cookie after 120 seconds whatever this is, so a
+    /// longer TTL only helps initiators of other implementations. The cookie of the moment stays
+    /// valid for a whole new TTL, the epochs counting on from it.
+    pub fn set_cookie_ttl(&self, ttl: Duration) {
+        let millis = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX).max(1);
+        let now = self.elapsed_millis();
+        let mut epochs = self.cookie_epochs.lock();
+        epochs.anchor_epoch = Self::epoch_at(&epochs, now);
+        epochs.anchor = now;
+        epochs.ttl = millis;
+    }
+
+    /// The number of cookie secret rotations since this rate limiter was created
+    pub fn current_cookie_epoch(&self) -> u64 {
+        let now = self.elapsed_millis();
+        Self::epoch_at(&self.cookie_epochs.lock(), now)
+    }
+
+    fn epoch_at(epochs: &CookieEpochs, now: u64) -> u64 {
+        epochs.anchor_epoch + now.saturating_sub(epochs.anchor) / epochs.ttl
+    }
+
+    fn elapsed_millis(&self) -> u64 {
+        Instant::now().duration_since(self.start_time).as_millis() as u64
+    }
+
     /// Compute the correct cookie value based on the current secret value and the source IP
     fn current_cookie(&self, addr: IpAddr) -> Cookie {
         let mut addr_bytes = [0u8; 16];
@@ -96,7 +266,7 @@ impl RateLimiter {
 
         // The current cookie for a given IP is the MAC(responder.changing_secret_every_two_minutes, initiator.ip_address)
         // First we derive the secret from the current time, the value of cur_counter would change with time.
-        let cur_counter = Instant::now().duration_since(self.start_time).as_secs() / COOKIE_REFRESH;
+        let cur_counter = self.current_cookie_epoch();
 
         // Next we derive the cookie
         b2s_keyed_mac_16_2(&self.secret_key, &cur_counter.to_le_bytes(), &addr_bytes)
@@ -165,13 +335,25 @@ impl RateLimiter {
             let (msg, macs) = src.split_at(src.len() - 32);
             let (mac1, mac2) = macs.split_at(16);
 
-            let computed_mac1 = b2s_keyed_mac_16(&self.mac1_key, msg);
-            verify_slices_are_equal(&computed_mac1[..16], mac1)
-                .map_err(|_| TunnResult::Err(WireGuardError::InvalidMac))?;
+            let is_init = matches!(packet, Packet::HandshakeInit(_));
+            let computed_mac1 = keyed_mac1(&self.mac1_key, src);
+            if verify_slices_are_equal(&computed_mac1[..16], mac1).is_err()
+                && self.require_mac1.load(Ordering::Relaxed)
+            {
+                if is_init {
+                    self.suppressed_responses.fetch_add(1, Ordering::Relaxed);
+                }
+                return Err(TunnResult::Err(WireGuardError::InvalidMac));
+            }
 
-            if self.is_under_load() {
+            // Only initiations make us hold state
+            let over_memory = is_init && self.half_open_exceeded();
+            if self.is_under_load() || over_memory {
                 let addr = match src_addr {
-                    None => return Err(TunnResult::Err(WireGuardError::UnderLoad)),
+                    None => {
+                        self.record_load_drop();
+                        return Err(TunnResult::Err(WireGuardError::UnderLoad));
+                    }
                     Some(addr) => addr,
                 };
 
@@ -180,6 +362,10 @@ impl RateLimiter {
                 let computed_mac2 = b2s_keyed_mac_16_2(&cookie, msg, mac1);
 
                 if verify_slices_are_equal(&computed_mac2[..16], mac2).is_err() {
+                    if is_init {
+                        self.suppressed_responses.fetch_add(1, Ordering::Relaxed);
+                    }
+                    self.record_load_drop();
                     let cookie_packet = self
                         .format_cookie_reply(sender_idx, cookie, mac1, dst)
                         .map_err(TunnResult::Err)?;
@@ -191,3 +377,110 @@ impl RateLimiter {
         Ok(packet)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_epoch_starts_at_zero() {
+        let public_key = crate::x25519::PublicKey::from([1u8; 32]);
+        assert_eq!(RateLimiter::new(&public_key, 10).current_cookie_epoch(), 0);
+    }
+
+    #[test]
+    fn test_cookie_reply_nonces_differ() {
+        let public_key = crate::x25519::PublicKey::from([1u8; 32]);
+        let rate_limiter = RateLimiter::new(&public_key, 10);
+        let cookie = rate_limiter.current_cookie(IpAddr::from([192, 0, 2, 1]));
+        let mac1 = [3u8; 16];
+
+        let mut first = [0u8; super::super::COOKIE_REPLY_SZ];
+        let mut second = [0u8; super::super::COOKIE_REPLY_SZ];
+        rate_limiter
+            .format_cookie_reply(1, cookie, &mac1, &mut first)
+            .unwrap();
+        rate_limiter
+            .format_cookie_reply(1, cookie, &mac1, &mut second)
+            .unwrap();
+        assert_ne!(first[8..32], second[8..32]);
+        // So the same cookie encrypts differently
+        assert_ne!(first[32..], second[32..]);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn test_cookie_ttl() {
+        let public_key = crate::x25519::PublicKey::from([1u8; 32]);
+        let rate_limiter = RateLimiter::new(&public_key, 10);
+        rate_limiter.set_cookie_ttl(Duration::from_secs(10));
+        let epoch = rate_limiter.current_cookie_epoch();
+
+        mock_instant::MockClock::advance(Duration::from_secs(10));
+        assert_eq!(rate_limiter.current_cookie_epoch(), epoch + 1);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn test_cookie_ttl_change_keeps_epochs() {
+        let public_key = crate::x25519::PublicKey::from([1u8; 32]);
+        let rate_limiter = RateLimiter::new(&public_key, 10);
+        let addr = IpAddr::from([192, 0, 2, 1]);
+        let first = rate_limiter.current_cookie(addr);
+        mock_instant::MockClock::advance(Duration::from_secs(COOKIE_REFRESH * 2 + 60));
+        let epoch = rate_limiter.current_cookie_epoch();
+        let issued = rate_limiter.current_cookie(addr);
+
+        // A longer TTL neither brings back the first cookie nor drops the one just issued
+        rate_limiter.set_cookie_ttl(Duration::from_secs(COOKIE_REFRESH * 10));
+        assert_eq!(rate_limiter.current_cookie_epoch(), epoch);
+        assert_eq!(rate_limiter.current_cookie(addr), issued);
+        assert_ne!(rate_limiter.current_cookie(addr), first);
+
+        // A shorter one doesn't jump ahead
+        rate_limiter.set_cookie_ttl(Duration::from_secs(10));
+        assert_eq!(rate_limiter.current_cookie(addr), issued);
+        mock_instant::MockClock::advance(Duration::from_secs(10));
+        assert_eq!(rate_limiter.current_cookie_epoch(), epoch + 1);
+        assert_ne!(rate_limiter.current_cookie(addr), issued);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn test_cookie_epoch_rotates() {
+        let public_key = crate::x25519::PublicKey::from([1u8; 32]);
+        let rate_limiter = RateLimiter::new(&public_key, 10);
+        let epoch = rate_limiter.current_cookie_epoch();
+        let cookie = rate_limiter.current_cookie(IpAddr::from([192, 0, 2, 1]));
+
+        mock_instant::MockClock::advance(Duration::from_secs(COOKIE_REFRESH - 1));
+        assert_eq!(rate_limiter.current_cookie_epoch(), epoch);
+        assert_eq!(
+            rate_limiter.current_cookie(IpAddr::from([192, 0, 2, 1])),
+            cookie
+        );
+
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        assert_eq!(rate_limiter.current_cookie_epoch(), epoch + 1);
+        assert_ne!(
+            rate_limiter.current_cookie(IpAddr::from([192, 0, 2, 1])),
+            cookie
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "test-utils")]
+    fn test_pinned_cookie_secret() {
+        let public_key = crate::x25519::PublicKey::from([1u8; 32]);
+        let rate_limiter = RateLimiter::with_cookie_secret(&public_key, 10, [7u8; 32]);
+
+        let mut addr_bytes = [0u8; 16];
+        addr_bytes[..4].copy_from_slice(&[192, 0, 2, 1]);
+        let epoch = rate_limiter.current_cookie_epoch();
+        let expected = b2s_keyed_mac_16_2(&[7u8; 32], &epoch.to_le_bytes(), &addr_bytes);
+        assert_eq!(
+            rate_limiter.current_cookie(IpAddr::from([192, 0, 2, 1])),
+            expected
+        );
+    }
+}
@@ -3,7 +3,7 @@
 
 use super::PacketData;
 use crate::noise::errors::WireGuardError;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -14,6 +14,11 @@ pub struct Session {
     sender: LessSafeKey,
     sending_key_counter: AtomicUsize,
     receiving_key_counter: Mutex<ReceivingKeyCounterValidator>,
+    /// Number of the handshake that established this session, counted per tunnel from 1
+    pub(super) epoch: u64,
+    /// The receiving and sending keys, see `Tunn::dump_keys` and `Tunn::export_state`
+    #[cfg(any(feature = "debug_keys", feature = "test-utils"))]
+    raw_keys: ([u8; 32], [u8; 32]),
 }
 
 impl std::fmt::Debug for Session {
@@ -167,6 +172,66 @@ impl Session {
             sender: LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &sending_key).unwrap()),
             sending_key_counter: AtomicUsize::new(0),
             receiving_key_counter: Mutex::new(Default::default()),
+            epoch: 0,
+            #[cfg(any(feature = "debug_keys", feature = "test-utils"))]
+            raw_keys: (receiving_key, sending_key),
+        }
+    }
+
+    /// A session with the given keys and counters, see `Tunn::from_raw_session`
+    #[cfg(feature = "test-utils")]
+    pub(super) fn from_raw(raw: &super::RawSession) -> Result<Session, &'static str> {
+        let send_nonce: usize = std::convert::TryFrom::try_from(raw.send_nonce)
+            .map_err(|_| "send_nonce out of range")?;
+        let session = Session::new(
+            raw.local_index,
+            raw.remote_index,
+            raw.recv_key,
+            raw.send_key,
+        );
+        session
+            .sending_key_counter
+            .store(send_nonce, Ordering::Relaxed);
+        let mut validator = session.receiving_key_counter.lock();
+        validator.next = raw.recv_nonce;
+        validator.bitmap = raw.replay_bitmap;
+        drop(validator);
+        Ok(session)
+    }
+
+    /// The keys and counters of the session, for the tunnel with the given keys, see
+    /// `Tunn::export_state`
+    #[cfg(feature = "test-utils")]
+    pub(super) fn to_raw(
+        &self,
+        static_private: crate::x25519::StaticSecret,
+        peer_static_public: crate::x25519::PublicKey,
+        is_initiator: bool,
+    ) -> super::RawSession {
+        let validator = self.receiving_key_counter.lock();
+        super::RawSession {
+            static_private,
+            peer_static_public,
+            local_index: self.receiving_index,
+            remote_index: self.sending_index,
+            send_key: self.raw_keys.1,
+            recv_key: self.raw_keys.0,
+            send_nonce: self.sending_key_counter.load(Ordering::Relaxed) as u64,
+            recv_nonce: validator.next,
+            replay_bitmap: validator.bitmap,
+            is_initiator,
+        }
+    }
+
+    #[cfg(feature = "debug_keys")]
+    pub(super) fn dump_keys(&self) -> super::KeyDump {
+        super::KeyDump {
+            local_index: self.receiving_index,
+            remote_index: self.sending_index,
+            send_key: hex::encode(self.raw_keys.1),
+            recv_key: hex::encode(self.raw_keys.0),
+            send_nonce: self.sending_key_counter.load(Ordering::Relaxed) as u64,
+            recv_nonce: self.receiving_key_counter.lock().next,
         }
     }
 
@@ -174,6 +239,11 @@ impl Session {
         self.receiving_index as usize
     }
 
+    /// The index the peer assigned, sent in our data messages
+    pub(super) fn remote_index(&self) -> u32 {
+        self.sending_index
+    }
+
     /// Returns true if receiving counter is good to use
     fn receiving_counter_quick_check(&self, counter: u64) -> Result<(), WireGuardError> {
         let counter_validator = self.receiving_key_counter.lock();
@@ -232,11 +302,13 @@ impl Session {
     /// packet - a data packet we received from the network
     /// dst - pre-allocated space to hold the encapsulated IP packet, to send to the interface
     ///       dst will always take less space than src
+    /// on_replay_reject - called with the counter if the replay window drops the packet
     /// return the size of the encapsulated packet on success
     pub(super) fn receive_packet_data<'a>(
         &self,
         packet: PacketData,
         dst: &'a mut [u8],
+        on_replay_reject: &RwLock<Option<ReplayRejectCallback>>,
     ) -> Result<&'a mut [u8], WireGuardError> {
         let ct_len = packet.encrypted_encapsulated_packet.len();
         if dst.len() < ct_len {
@@ -247,26 +319,61 @@ impl Session {
             return Err(WireGuardError::WrongIndex);
         }
         // Don't reuse counters, in case this is a replay attack we want to quickly check the counter without running expensive decryption
-        self.receiving_counter_quick_check(packet.counter)?;
+        if let Err(err) = self.receiving_counter_quick_check(packet.counter) {
+            // Only an authentic packet is reported, which takes the decryption spared otherwise
+            report_replay(on_replay_reject, packet.counter, || {
+                self.open(packet.counter, packet.encrypted_encapsulated_packet, dst)
+                    .is_ok()
+            });
+            return Err(err);
+        }
 
-        let ret = {
-            let mut nonce = [0u8; 12];
-            nonce[4..12].copy_from_slice(&packet.counter.to_le_bytes());
-            dst[..ct_len].copy_from_slice(packet.encrypted_encapsulated_packet);
-            self.receiver
-                .open_in_place(
-                    Nonce::assume_unique_for_key(nonce),
-                    Aad::from(&[]),
-                    &mut dst[..ct_len],
-                )
-                .map_err(|_| WireGuardError::InvalidAeadTag)?
-        };
+        let ret = self.open(packet.counter, packet.encrypted_encapsulated_packet, dst)?;
 
         // After decryption is done, check counter again, and mark as received
-        self.receiving_counter_mark(packet.counter)?;
+        if let Err(err) = self.receiving_counter_mark(packet.counter) {
+            report_replay(on_replay_reject, packet.counter, || true);
+            return Err(err);
+        }
         Ok(ret)
     }
 
+    /// Decrypts like `receive_packet_data`, but neither checks the counter against the replay
+    /// window nor marks it as received
+    pub(super) fn peek_packet_data<'a>(
+        &self,
+        packet: PacketData,
+        dst: &'a mut [u8],
+    ) -> Result<&'a mut [u8], WireGuardError> {
+        let ct_len = packet.encrypted_encapsulated_packet.len();
+        if dst.len() < ct_len {
+            panic!("The destination buffer is too small");
+        }
+        if packet.receiver_idx != self.receiving_index {
+            return Err(WireGuardError::WrongIndex);
+        }
+        self.open(packet.counter, packet.encrypted_encapsulated_packet, dst)
+    }
+
+    /// Decrypt `ciphertext`, sent with `counter`, into `dst`
+    fn open<'a>(
+        &self,
+        counter: u64,
+        ciphertext: &[u8],
+        dst: &'a mut [u8],
+    ) -> Result<&'a mut [u8], WireGuardError> {
+        let mut nonce = [0u8; 12];
+        nonce[4..12].copy_from_slice(&counter.to_le_bytes());
+        dst[..ciphertext.len()].copy_from_slice(ciphertext);
+        self.receiver
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&[]),
+                &mut dst[..ciphertext.len()],
+            )
+            .map_err(|_| WireGuardError::InvalidAeadTag)
+    }
+
     /// Returns the estimated downstream packet loss for this session
     pub(super) fn current_packet_cnt(&self) -> (u64, u64) {
         let counter_validator = self.receiving_key_counter.lock();
@@ -274,6 +381,25 @@ impl Session {
     }
 }
 
+/// Called with the counter of each data packet the replay window drops, see
+/// `Tunn::on_replay_reject`
+pub(super) type ReplayRejectCallback = Box<dyn Fn(u64) + Send + Sync>;
+
+/// Kept off the receive path, which only reads the callback once a packet was already rejected.
+/// `authentic` is only asked with a callback set.
+#[cold]
+fn report_replay(
+    on_replay_reject: &RwLock<Option<ReplayRejectCallback>>,
+    counter: u64,
+    authentic: impl FnOnce() -> bool,
+) {
+    if let Some(cb) = on_replay_reject.read().as_ref() {
+        if authentic() {
+            cb(counter);
+        }
+    }
+}
+
 #[inline(always)]
 pub fn message_data_len(plain_text_len: usize) -> usize {
     // See:
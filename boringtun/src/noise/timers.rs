@@ -5,8 +5,11 @@ use super::errors::WireGuardError;
 use crate::noise::{safe_duration::SafeDuration as Duration, Tunn, TunnResult};
 use std::mem;
 use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 use std::time::SystemTime;
 
+// With the `mock-instant` feature `SystemClock` reads the time from `mock_instant::MockClock`,
+// which tests advance to drive the timer state machine without sleeping
 #[cfg(feature = "mock-instant")]
 use mock_instant::Instant;
 
@@ -35,11 +38,15 @@ use _instant_boottime::Instant;
 // Some constants, represent time in seconds
 // https://www.wireguard.com/papers/wireguard.pdf#page=14
 pub(crate) const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
-const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+pub(crate) const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
 const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
 pub(crate) const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
 const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
 const COOKIE_EXPIRATION_TIME: Duration = Duration::from_secs(120);
+/// Most time a single `update_timers` call counts towards the rekey and keepalive triggers.
+/// Longer gaps, like a VM or the process being suspended, are mostly skipped by them, so they
+/// don't all fire at once on resume. Sessions and connections still expire by the real time.
+pub(crate) const MAX_TIMER_STEP: Duration = REKEY_AFTER_TIME;
 
 #[derive(Debug)]
 pub enum TimerName {
@@ -66,19 +73,78 @@ pub enum TimerName {
 
 use self::TimerName::*;
 
-#[derive(Debug)]
+/// The monotonic time source of the timers of a tunnel, see `Tunn::set_clock`
+pub trait Clock: Send + Sync {
+    /// The time since some point fixed for the clock, never going backwards
+    fn now(&self) -> std::time::Duration;
+}
+
+/// The monotonic clock of the system, counting the time asleep on the platforms that can. The
+/// clock of every tunnel unless replaced.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> std::time::Duration {
+        Instant::now().duration_since(self.start)
+    }
+}
+
+/// A clock standing still until advanced, so tests can run the timers through hours in no time.
+/// Only built with the `test-utils` feature.
+#[cfg(feature = "test-utils")]
+#[derive(Default)]
+pub struct MockClock {
+    now: parking_lot::Mutex<std::time::Duration>,
+}
+
+#[cfg(feature = "test-utils")]
+impl MockClock {
+    /// Move the clock forward by `by`, for every tunnel given it
+    pub fn advance(&self, by: std::time::Duration) {
+        *self.now.lock() += by;
+    }
+}
+
+#[cfg(feature = "test-utils")]
+impl Clock for MockClock {
+    fn now(&self) -> std::time::Duration {
+        *self.now.lock()
+    }
+}
+
 pub struct Timers {
     /// Is the owner of the timer the initiator or the responder for the last handshake?
     is_initiator: bool,
-    /// Start time of the tunnel
-    time_started: Instant,
+    clock: Arc<dyn Clock>,
+    /// The reading of `clock` that `elapsed` counts from
+    clock_start: std::time::Duration,
+    /// Time since the start of the tunnel at `clock_start`, kept over a change of clock
+    clock_offset: std::time::Duration,
+    /// Time the last handshake was completed as seen by the rekey triggers, clamped to
+    /// `MAX_TIMER_STEP` over clock jumps like the other trigger stamps
+    rekey_established: Duration,
     timers: [Duration; TimerName::Top as usize],
     pub(super) session_timers: [Duration; super::N_SESSIONS],
     /// Did we receive data without sending anything back?
     want_keepalive: bool,
     /// How long ago did we send data without hearing back?
     want_handshake_since: Option<Duration>,
-    persistent_keepalive: usize,
+    /// Time the handshake we initiated was last cancelled, see `Tunn::cancel_handshake`
+    handshake_cancelled: Duration,
+    /// Persistent keepalive interval in seconds, `None` when disabled
+    persistent_keepalive: Option<u16>,
+    /// Skip persistent keepalives when other packets passed within the interval
+    adaptive_keepalive: bool,
     /// Should this timer call reset rr function (if not a shared rr instance)
     pub(super) should_reset_rr: bool,
 }
@@ -87,27 +153,86 @@ impl Timers {
     pub(super) fn new(persistent_keepalive: Option<u16>, reset_rr: bool) -> Timers {
         Timers {
             is_initiator: false,
-            time_started: Instant::now(),
+            clock: Arc::new(SystemClock::default()),
+            clock_start: std::time::Duration::ZERO,
+            clock_offset: std::time::Duration::ZERO,
+            rekey_established: Duration::default(),
             timers: Default::default(),
             session_timers: Default::default(),
             want_keepalive: Default::default(),
             want_handshake_since: Default::default(),
-            persistent_keepalive: usize::from(persistent_keepalive.unwrap_or(0)),
+            handshake_cancelled: Duration::default(),
+            // An interval of 0 means persistent keepalive is off
+            persistent_keepalive: persistent_keepalive.filter(|&keepalive| keepalive > 0),
+            adaptive_keepalive: false,
             should_reset_rr: reset_rr,
         }
     }
 
-    fn is_initiator(&self) -> bool {
+    pub(super) fn is_initiator(&self) -> bool {
         self.is_initiator
     }
 
+    /// Time since the start of the tunnel
+    pub(super) fn elapsed(&self) -> Duration {
+        let now = self.clock.now().saturating_sub(self.clock_start);
+        (self.clock_offset + now).into()
+    }
+
+    /// Read the time from `clock` from now on, going on from the time elapsed so far
+    pub(super) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock_offset = self.elapsed().into();
+        self.clock_start = clock.now();
+        self.clock = clock;
+    }
+
+    /// Move the stamps the rekey and keepalive triggers count from forward by `skipped`, so time
+    /// skipped over a clock jump doesn't count towards them. Unset stamps stay unset.
+    fn skip_triggers(&mut self, skipped: Duration) {
+        let shift = |t: &mut Duration| {
+            if !t.is_zero() {
+                *t = *t + skipped;
+            }
+        };
+        for timer in [
+            TimeLastPacketReceived,
+            TimeLastPacketSent,
+            TimeLastDataPacketReceived,
+            TimeLastDataPacketSent,
+            TimePersistentKeepalive,
+        ] {
+            shift(&mut self[timer]);
+        }
+        shift(&mut self.rekey_established);
+        shift(&mut self.handshake_cancelled);
+        if let Some(since) = self.want_handshake_since.as_mut() {
+            *since = *since + skipped;
+        }
+    }
+
+    /// Keep the triggers from starting a handshake again until a data packet passes, see
+    /// `retriggered`
+    pub(super) fn cancel_initiations(&mut self) {
+        self.handshake_cancelled = self.elapsed().max(Duration::from_millis(1));
+        self.want_handshake_since = None;
+    }
+
+    /// Whether a data packet was sent or received since the handshake was last cancelled, if
+    /// ever, so the rekey triggers may start a new one
+    fn retriggered(&self) -> bool {
+        let cancelled = self.handshake_cancelled;
+        cancelled.is_zero()
+            || self[TimeLastDataPacketSent].max(self[TimeLastDataPacketReceived]) > cancelled
+    }
+
     // We don't really clear the timers, but we set them to the current time to
     // so the reference time frame is the same
     pub(super) fn clear(&mut self) {
-        let now = Instant::now().duration_since(self.time_started).into();
+        let now = self.elapsed();
         for t in &mut self.timers[..] {
             *t = now;
         }
+        self.rekey_established = now;
         self.want_handshake_since = None;
         self.want_keepalive = false;
     }
@@ -133,8 +258,6 @@ impl Tunn {
             TimeLastPacketReceived => {
                 self.timers.want_keepalive = true;
                 self.timers.want_handshake_since = None;
-                // This timer is never read
-                return;
             }
             TimeLastPacketSent => {
                 self.timers.want_keepalive = false;
@@ -158,16 +281,19 @@ impl Tunn {
         session_idx: usize,
     ) {
         self.timer_tick(TimeSessionEstablished);
+        self.timers.rekey_established = self.timers[TimeSessionEstablished];
         self.timers.session_timers[session_idx % crate::noise::N_SESSIONS] =
             self.timers[TimeCurrent];
         self.timers.is_initiator = is_initiator;
+        self.handshake_completed = true;
     }
 
     // We don't really clear the timers, but we set them to the current time to
     // so the reference time frame is the same
-    fn clear_all(&mut self) {
-        for session in &mut self.sessions {
-            *session = None;
+    pub(super) fn clear_all(&mut self) {
+        self.clear_sessions();
+        for half_open in &mut self.half_open {
+            *half_open = None;
         }
 
         self.packet_queue.clear();
@@ -180,6 +306,11 @@ impl Tunn {
 
         for (i, t) in timers.session_timers.iter_mut().enumerate() {
             if time_now - *t > REJECT_AFTER_TIME {
+                self.half_open[i] = None;
+                // Only a live session is worth the store, most slots expire empty
+                if self.data_path.sessions[i].is_some() {
+                    self.data_path.sessions[i].store(None);
+                }
                 if let Some(session) = self.sessions[i].take() {
                     tracing::debug!(
                         message = "SESSION_EXPIRED(REJECT_AFTER_TIME)",
@@ -195,26 +326,39 @@ impl Tunn {
         let mut handshake_initiation_required = false;
         let mut keepalive_required = false;
 
-        let time = Instant::now();
-
         if self.timers.should_reset_rr {
             self.rate_limiter.reset_count();
         }
 
         // All the times are counted from tunnel initiation, for efficiency our timers are rounded
         // to a second, as there is no real benefit to having highly accurate timers.
-        let now = time.duration_since(self.timers.time_started).into();
+        let now = self.timers.elapsed();
+        let step = now - self.timers[TimeCurrent];
+        if step > MAX_TIMER_STEP {
+            tracing::warn!(
+                message = "Clock jumped, clamping the rekey and keepalive triggers",
+                elapsed = ?std::time::Duration::from(step)
+            );
+            self.timers.skip_triggers(step - MAX_TIMER_STEP);
+        }
         self.timers[TimeCurrent] = now;
 
+        #[cfg(feature = "test-utils")]
+        if self.delayed_response_due() {
+            return TunnResult::WriteToNetwork(self.release_delayed_response(dst));
+        }
+
         self.update_session_timers(now);
 
         // Load timers only once:
         let session_established = self.timers[TimeSessionEstablished];
+        let rekey_established = self.timers.rekey_established;
         let handshake_started = self.timers[TimeLastHandshakeStarted];
         let aut_packet_sent = self.timers[TimeLastPacketSent];
         let data_packet_received = self.timers[TimeLastDataPacketReceived];
         let data_packet_sent = self.timers[TimeLastDataPacketSent];
         let persistent_keepalive = self.timers.persistent_keepalive;
+        let last_packet = aut_packet_sent.max(self.timers[TimeLastPacketReceived]);
 
         {
             if self.handshake.is_expired() {
@@ -237,7 +381,7 @@ impl Tunn {
                 return TunnResult::Err(WireGuardError::ConnectionExpired);
             }
 
-            if let Some(time_init_sent) = self.handshake.timer() {
+            if let Some(since_init_sent) = self.handshake.time_since_init_sent() {
                 // Handshake Initiation Retransmission
                 if now - handshake_started >= REKEY_ATTEMPT_TIME {
                     // After REKEY_ATTEMPT_TIME ms of trying to initiate a new handshake,
@@ -250,9 +394,7 @@ impl Tunn {
                     return TunnResult::Err(WireGuardError::ConnectionExpired);
                 }
 
-                if time_init_sent.elapsed() >= REKEY_TIMEOUT {
-                    // We avoid using `time` here, because it can be earlier than `time_init_sent`.
-                    // Once `checked_duration_since` is stable we can use that.
+                if since_init_sent >= REKEY_TIMEOUT {
                     // A handshake initiation is retried after REKEY_TIMEOUT + jitter ms,
                     // if a response has not been received, where jitter is some random
                     // value between 0 and 333 ms.
@@ -260,14 +402,16 @@ impl Tunn {
                     handshake_initiation_required = true;
                 }
             } else {
-                if self.timers.is_initiator() {
+                // No rekey after a cancelled handshake until a data packet passes again
+                let retriggered = self.timers.retriggered();
+                if self.timers.is_initiator() && retriggered {
                     // After sending a packet, if the sender was the original initiator
                     // of the handshake and if the current session key is REKEY_AFTER_TIME
                     // ms old, we initiate a new handshake. If the sender was the original
                     // responder of the handshake, it does not re-initiate a new handshake
                     // after REKEY_AFTER_TIME ms like the original initiator does.
-                    if session_established < data_packet_sent
-                        && now - session_established >= REKEY_AFTER_TIME
+                    if rekey_established < data_packet_sent
+                        && now - rekey_established >= REKEY_AFTER_TIME
                     {
                         tracing::debug!("HANDSHAKE(REKEY_AFTER_TIME (on send))");
                         handshake_initiation_required = true;
@@ -277,8 +421,8 @@ impl Tunn {
                     // of the handshake and if the current session key is REJECT_AFTER_TIME
                     // - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT ms old, we initiate a new
                     // handshake.
-                    if session_established < data_packet_received
-                        && now - session_established
+                    if rekey_established < data_packet_received
+                        && now - rekey_established
                             >= REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
                     {
                         tracing::warn!(
@@ -318,14 +462,20 @@ impl Tunn {
                     }
 
                     // Persistent KEEPALIVE
-                    if persistent_keepalive > 0
-                        && ((now - self.timers[TimePersistentKeepalive]
-                            >= Duration::from_secs(persistent_keepalive as _))
-                            || self.time_since_last_handshake().is_none())
-                    {
-                        tracing::debug!("KEEPALIVE(PERSISTENT_KEEPALIVE)");
-                        self.timer_tick(TimePersistentKeepalive);
-                        keepalive_required = true;
+                    if let Some(persistent_keepalive) = persistent_keepalive {
+                        let interval = Duration::from_secs(persistent_keepalive.into());
+                        let refreshed =
+                            self.timers.adaptive_keepalive && now - last_packet < interval;
+                        // Without a session the keepalive starts a handshake
+                        let no_session = self.time_since_last_handshake().is_none();
+                        if ((now - self.timers[TimePersistentKeepalive] >= interval && !refreshed)
+                            || no_session)
+                            && (retriggered || !no_session)
+                        {
+                            tracing::debug!("KEEPALIVE(PERSISTENT_KEEPALIVE)");
+                            self.timer_tick(TimePersistentKeepalive);
+                            keepalive_required = true;
+                        }
                     }
                 }
             }
@@ -345,9 +495,7 @@ impl Tunn {
     pub fn time_since_last_handshake(&self) -> Option<std::time::Duration> {
         let current_session = self.current;
         if self.sessions[current_session % super::N_SESSIONS].is_some() {
-            let duration_since_tun_start: Duration = Instant::now()
-                .duration_since(self.timers.time_started)
-                .into();
+            let duration_since_tun_start = self.timers.elapsed();
             let duration_since_session_established = self.timers[TimeSessionEstablished];
 
             duration_since_tun_start.checked_sub(duration_since_session_established)
@@ -356,6 +504,15 @@ impl Tunn {
         }
     }
 
+    /// Time since the keys of the current session were established, `None` without a session
+    pub fn time_since_key_rotation(&self) -> Option<std::time::Duration> {
+        let current_session = self.current % super::N_SESSIONS;
+        self.sessions[current_session].as_ref()?;
+        self.timers
+            .elapsed()
+            .checked_sub(self.timers.session_timers[current_session])
+    }
+
     pub fn last_handshake_time(&self) -> Option<std::time::Duration> {
         self.time_since_last_handshake().and_then(|d| {
             SystemTime::now()
@@ -365,17 +522,35 @@ impl Tunn {
         })
     }
 
+    /// Check if a handshake completed since the last call, and reset the check
+    pub fn take_handshake_completed(&mut self) -> bool {
+        std::mem::take(&mut self.handshake_completed)
+    }
+
+    /// The round trip time of a handshake this side initiated that completed since the last
+    /// call, from sending the initiation to receiving the response
+    pub fn take_rtt_sample(&mut self) -> Option<std::time::Duration> {
+        self.handshake.rtt_sample.take()
+    }
+
     pub fn persistent_keepalive(&self) -> Option<u16> {
-        let keepalive = self.timers.persistent_keepalive;
+        self.timers.persistent_keepalive
+    }
 
-        if keepalive > 0 {
-            Some(keepalive as u16)
+    /// Set the persistent keepalive interval in seconds, 0 disables persistent keepalive
+    pub fn set_persistent_keepalive(&mut self, keepalive: u16) {
+        if keepalive == 0 {
+            self.timers.persistent_keepalive = None;
+            self.timers[TimePersistentKeepalive] = Duration::default();
         } else {
-            None
+            self.timers.persistent_keepalive = Some(keepalive);
         }
     }
 
-    pub fn set_persistent_keepalive(&mut self, keepalive: u16) {
-        self.timers.persistent_keepalive = keepalive as usize;
+    /// Only send a persistent keepalive once no packet went either way for the whole interval,
+    /// as other traffic keeps NAT mappings open just as well. Off by default: keepalives go out
+    /// on a fixed schedule, as in WireGuard.
+    pub fn set_adaptive_keepalive(&mut self, adaptive: bool) {
+        self.timers.adaptive_keepalive = adaptive;
     }
 }